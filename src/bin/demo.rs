@@ -1,7 +1,8 @@
 #![cfg_attr(test, allow(dead_code))]
+#![allow(clippy::needless_return)]
 
 extern crate mudpie;
-use mudpie::{WebServer, WebRequest, WebResponse};
+use mudpie::{WebServer, WebRequest, WebResponse, Router, RouteParams};
 use mudpie::html_element_escape;
 
 /*
@@ -13,6 +14,7 @@ Example: ./demo 0.0.0.0 8000
 Optional env vars: 
     MUDPIE_THREADS=<NUM>
     MUDPIE_LOGGING=[0|1]
+    MUDPIE_ACCESS_LOG=<PATH>
 
 */
 
@@ -29,7 +31,7 @@ fn main() {
         listen_addr = &args[1];
     }
     if args.len() > 2 {
-        listen_port = match (&args[2]).parse::<i32>() {
+        listen_port = match args[2].parse::<i32>() {
             Ok(n) => n,
             Err(err) => panic!("invalid port number: {}", err)
         }
@@ -40,17 +42,21 @@ fn main() {
     //svr.set_max_request_body_size(10);
 
     // Setup dispatch rules
-    svr.add_path("get", "/bench", bench_page);
-    svr.add_path("GET, HEAD", "/", index_page);
-    svr.add_path("get, head", "/hello", hello_page);
-    svr.add_path_prefix("get,head", "/hello/", hello_page);
-    svr.add_path("get", "/panic", panic_page);
+    let mut router = Router::new();
+    router.add_path("get", "/bench", bench_page);
+    router.add_path("GET, HEAD", "/", index_page);
+    router.add_path("get, head", "/hello", hello_page);
+    router.add_path_prefix("get,head", "/hello/", hello_page);
+    router.add_path("get", "/panic", panic_page);
 
-    svr.add_path("get", "/form_enter", form_enter);
-    svr.add_path("post", "/form_post", form_post);
+    router.add_path("get", "/form_enter", form_enter);
+    router.add_path("post", "/form_post", form_post);
 
-    svr.add_path("put,options,foo", "/silly_methods", hello_page);
+    router.add_path("put,options,foo", "/silly_methods", hello_page);
 
+    router.get("/users/:id/posts/*rest", user_posts_page);
+
+    svr.set_handler(router);
     svr.run(listen_addr, listen_port);
 }
 
@@ -74,8 +80,8 @@ fn get_debug_info(req: &WebRequest) -> String {
     page.push_str("<table>");
     let mut raw_environ = Vec::new();
     for (k, v) in req.get_environ().iter() {
-        let k = String::from_utf8_lossy(&k).into_owned();
-        let v = String::from_utf8_lossy(&v).into_owned();
+        let k = String::from_utf8_lossy(k).into_owned();
+        let v = String::from_utf8_lossy(v).into_owned();
         raw_environ.push((k, v));
     }
     raw_environ.sort();
@@ -89,7 +95,7 @@ fn get_debug_info(req: &WebRequest) -> String {
     page.push_str("</table>");
     page.push_str("<h2>Request Body</h2>");
     let body = req.get_body();
-    let body = String::from_utf8_lossy(&body).into_owned();
+    let body = String::from_utf8_lossy(body).into_owned();
     page.push_str(&html_element_escape(&body));
     return page;
 }
@@ -120,6 +126,9 @@ fn index_page(_req: &WebRequest) -> WebResponse {
 <dt><a href="/silly_methods">/silly_methods</a> 
 <dd>Only allows PUT, OPTIONS, and FOO methods. See Allow: header
 
+<dt><a href="/users/42/posts/2015/06">/users/42/posts/2015/06</a> 
+<dd>Pattern route, shows the captured :id and *rest parameters
+
 <dt><a href="/bench">/bench</a> 
 <dd>A super-tiny resource useful for benchmarking socket performance
 
@@ -142,6 +151,24 @@ fn hello_page(req: &WebRequest) -> WebResponse {
 }
 
 
+fn user_posts_page(req: &WebRequest) -> WebResponse {
+    let params = req.get_extensions().get::<RouteParams>().unwrap();
+    let mut page = String::new();
+    page.push_str("<h1>User Posts</h1>");
+    page.push_str("<table>");
+    for (k, v) in params.iter() {
+        page.push_str("<tr>");
+        page.push_str("<td>");
+        page.push_str(&html_element_escape(k));
+        page.push_str("<td>");
+        page.push_str(&html_element_escape(v));
+    }
+    page.push_str("</table>");
+    page = to_html(page);
+    return WebResponse::new_html(page);
+}
+
+
 fn bench_page(_req: &WebRequest) -> WebResponse {
     let page = "Hello World!".to_string();
     return WebResponse::new_html(page);
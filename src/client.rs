@@ -0,0 +1,464 @@
+//! A small blocking HTTP/1.1 client, for webhooks and the like
+//!
+//! ```ignore
+//! let client = Client::new();
+//! let mut resp = client.post("http://localhost:8080/hook",
+//!         "application/json", b"{}")?;
+//! println!("{} {}", resp.get_code(), String::from_utf8_lossy(&resp.body()?));
+//! ```
+//!
+//! Only http URLs are supported; there's no TLS.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use utils::byteutils;
+use utils::chunked::ChunkedReader;
+use utils::http_response::{read_response_head, BodyLength};
+
+// Idle connections older than this aren't reused; the server has likely
+// closed them
+static IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Idle connections, by "host:port"
+type Pool = Arc<Mutex<HashMap<String, Vec<(BufReader<TcpStream>, Instant)>>>>;
+
+
+/// Sends requests, keeping connections open to be reused.  Can be shared
+/// between threads.
+pub struct Client {
+    timeout: Duration,
+    max_idle: usize,
+    user_agent: String,
+    idle: Pool,
+}
+
+impl Default for Client {
+    fn default() -> Client {
+        return Client::new();
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        return Client {
+            timeout: Duration::from_secs(30),
+            max_idle: 8,
+            user_agent: "mudpie".to_string(),
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        };
+    }
+
+    /// How long to wait to connect, and for each read or write.  Default
+    /// 30 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// The most idle connections kept open for each host.  Default 8; 0
+    /// closes every connection after its response.
+    pub fn set_max_idle(&mut self, max_idle: usize) {
+        self.max_idle = max_idle;
+    }
+
+    /// The User-Agent sent, if a request doesn't have one.  Default
+    /// "mudpie".
+    pub fn set_user_agent(&mut self, user_agent: &str) {
+        self.user_agent = user_agent.to_string();
+    }
+
+    pub fn get(&self, url: &str) -> io::Result<Response> {
+        return self.request("GET", url, &[], b"");
+    }
+
+    pub fn post(&self, url: &str, content_type: &str, body: &[u8])
+            -> io::Result<Response> {
+        return self.request("POST", url, &[("Content-Type", content_type)],
+                body);
+    }
+
+    /// Send a request, and wait for the response head.  The body is the
+    /// response's to read: the connection is only reused once it has
+    /// been read to the end.  Interim (1xx) responses are skipped, but
+    /// for 101.
+    ///
+    /// Fails with InvalidInput for URLs that aren't http, or headers with
+    /// line breaks, and with InvalidData for a bad response.
+    pub fn request(&self, method: &str, url: &str, headers: &[(&str, &str)],
+            body: &[u8]) -> io::Result<Response> {
+        let (host, addr, target) = parse_url(url)?;
+        let method = method.to_ascii_uppercase();
+        let has = |name: &str| headers.iter()
+            .any(|h| h.0.eq_ignore_ascii_case(name));
+
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method,
+                target, host);
+        if !has("User-Agent") {
+            head.push_str(&format!("User-Agent: {}\r\n", self.user_agent));
+        }
+        if !has("Content-Length") && (!body.is_empty()
+                || ["POST", "PUT", "PATCH"].contains(&&method[..])) {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        let mut close = self.max_idle == 0;
+        for &(name, value) in headers {
+            if [name, value].iter().any(|s| s.contains(['\r', '\n'])) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "line break in a header"));
+            }
+            if name.eq_ignore_ascii_case("Connection") {
+                close |= connection_close(value);
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if close && !has("Connection") {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        let mut reader = loop {
+            let (stream, reused) = match self.reuse(&addr) {
+                Some(stream) => (stream, true),
+                None => (BufReader::new(self.connect(&addr)?), false),
+            };
+            match send_request(stream, head.as_bytes(), body) {
+                Ok(reader) => break reader,
+                // The server closed the idle connection; try another.
+                // Not if it's just slow, since it may be at work on it.
+                Err(ref err) if reused && err.kind() != io::ErrorKind::TimedOut
+                        && err.kind() != io::ErrorKind::WouldBlock => (),
+                Err(err) => return Err(err),
+            }
+        };
+        let head = loop {
+            let head = read_response_head(&mut reader)?;
+            if head.code >= 200 || head.code == 101 {
+                break head;
+            }
+        };
+
+        let reuse = !close && head.code != 101 && head.http11
+            && !head.get_all("Connection").iter().any(|v| connection_close(v));
+        let no_body = method == "HEAD" || head.code == 204 || head.code == 304;
+        let framing = match head.body_length(no_body)? {
+            _ if head.code == 101 => Framing::Close(reader),
+            BodyLength::Length(len) => Framing::Length(reader, len),
+            BodyLength::Chunked => Framing::Chunked(ChunkedReader::new(reader)),
+            BodyLength::Close => Framing::Close(reader),
+        };
+        let mut resp = Response {
+            code: head.code,
+            status: head.status,
+            headers: head.headers,
+            framing: Some(framing),
+            pool: match reuse {
+                true => Some((self.idle.clone(), addr, self.max_idle)),
+                false => None,
+            },
+        };
+        if let Some(Framing::Length(_, 0)) = resp.framing {
+            resp.finish();
+        }
+        return Ok(resp);
+    }
+
+    // An idle connection to `addr`, if one is recent enough
+    fn reuse(&self, addr: &str) -> Option<BufReader<TcpStream>> {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(addr)?;
+        while let Some((conn, since)) = conns.pop() {
+            if since.elapsed() < IDLE_TIMEOUT {
+                return Some(conn);
+            }
+        }
+        return None;
+    }
+
+    fn connect(&self, addr: &str) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                },
+                Err(err) => last_err = Some(err),
+            }
+        }
+        return Err(last_err.unwrap_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound, "host has no addresses")));
+    }
+}
+
+
+/// A response, whose body is read from it
+pub struct Response {
+    code: i32,
+    status: String,
+    headers: Vec<(String, String)>,
+    // None once the body has been read
+    framing: Option<Framing>,
+    // Where the connection goes back to at the end, if it can be reused
+    pool: Option<(Pool, String, usize)>,
+}
+
+impl Response {
+    pub fn get_code(&self) -> i32 {
+        return self.code;
+    }
+
+    pub fn get_status(&self) -> &str {
+        return &self.status;
+    }
+
+    /// The first value of a header, if any
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        return self.headers.iter().find(|h| h.0.eq_ignore_ascii_case(name))
+            .map(|h| &h.1[..]);
+    }
+
+    /// (name, value) pairs, in the order they were received
+    pub fn get_headers(&self) -> &[(String, String)] {
+        return &self.headers;
+    }
+
+    /// The rest of the body
+    pub fn body(&mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        self.read_to_end(&mut body)?;
+        return Ok(body);
+    }
+
+    // The body has been read: put the connection back
+    fn finish(&mut self) {
+        let reader = match self.framing.take() {
+            Some(Framing::Length(reader, _)) | Some(Framing::Close(reader))
+                => reader,
+            Some(Framing::Chunked(reader)) => reader.into_inner(),
+            None => return,
+        };
+        if let Some((pool, addr, max_idle)) = self.pool.take() {
+            // Not if the server sent more than the body
+            if !reader.buffer().is_empty() {
+                return;
+            }
+            let mut pool = pool.lock().unwrap();
+            let conns = pool.entry(addr).or_default();
+            if conns.len() < max_idle {
+                conns.push((reader, Instant::now()));
+            }
+        }
+    }
+}
+
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (n, done) = match self.framing {
+            None => return Ok(0),
+            Some(Framing::Length(ref mut reader, ref mut left)) => {
+                let max = (buf.len() as u64).min(*left) as usize;
+                let n = reader.read(&mut buf[..max])?;
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                            "response cut short"));
+                }
+                *left -= n as u64;
+                (n, *left == 0)
+            },
+            Some(Framing::Chunked(ref mut reader)) => {
+                let n = reader.read(buf)?;
+                (n, n == 0)
+            },
+            Some(Framing::Close(ref mut reader)) => {
+                let n = reader.read(buf)?;
+                (n, n == 0)
+            },
+        };
+        if done {
+            self.finish();
+        }
+        return Ok(n);
+    }
+}
+
+// How a response body ends
+enum Framing {
+    // With this many bytes left
+    Length(BufReader<TcpStream>, u64),
+    Chunked(ChunkedReader<BufReader<TcpStream>>),
+    // When the server closes the connection
+    Close(BufReader<TcpStream>),
+}
+
+
+// The Host header, the address to connect to, and the request target of
+// an http URL
+fn parse_url(url: &str) -> io::Result<(String, String, String)> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidInput,
+            format!("not an http URL: {}", url));
+    if url.len() < 7 || !url[..7].eq_ignore_ascii_case("http://") {
+        return Err(bad());
+    }
+    let rest = &url[7..];
+    let rest = match rest.find('#') {
+        Some(i) => &rest[..i],
+        None => rest,
+    };
+    let (host, target) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    if host.is_empty() || host.contains(['@', ' ']) {
+        return Err(bad());
+    }
+    let target = match target {
+        "" => "/".to_string(),
+        t if t.starts_with('?') => format!("/{}", t),
+        t => t.to_string(),
+    };
+    if target.contains([' ', '\r', '\n']) {
+        return Err(bad());
+    }
+    let addr = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => host.to_string(),
+        _ => format!("{}:80", host),
+    };
+    return Ok((host.to_string(), addr, target));
+}
+
+// Whether a Connection header has the "close" option
+fn connection_close(value: &str) -> bool {
+    return byteutils::has_token(value.as_bytes(), b"close");
+}
+
+// Write a request, and wait for the start of the response
+fn send_request(mut reader: BufReader<TcpStream>, head: &[u8], body: &[u8])
+        -> io::Result<BufReader<TcpStream>> {
+    reader.get_mut().write_all(head)?;
+    reader.get_mut().write_all(body)?;
+    if reader.fill_buf()?.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "server closed the connection"));
+    }
+    return Ok(reader);
+}
+
+
+// A server answering each request with its connection number and the
+// request head
+#[cfg(test)]
+fn test_server() -> String {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    ::std::thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            ::std::thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut head = String::new();
+                    let mut line = String::new();
+                    while line != "\r\n" {
+                        line.clear();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        head.push_str(&line);
+                    }
+                    if let Some(i) = head.find("Content-Length: ") {
+                        let len = head[i + 16..].split('\r').next().unwrap();
+                        let mut body = vec![0; len.parse().unwrap()];
+                        reader.read_exact(&mut body).unwrap();
+                    }
+                    let body = format!("{}\n{}", i + 1, head);
+                    let resp = if head.starts_with("GET /chunked ") {
+                        format!("HTTP/1.1 200 OK\r\n\
+                                Transfer-Encoding: chunked\r\n\r\n\
+                                {:x}\r\n{}\r\n0\r\n\r\n", body.len(), body)
+                    } else if head.starts_with("GET /close ") {
+                        format!("HTTP/1.0 200 OK\r\n\r\n{}", body)
+                    } else if head.starts_with("GET /slow ") {
+                        ::std::thread::sleep(Duration::from_millis(500));
+                        continue;
+                    } else if head.starts_with("HEAD ") {
+                        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"
+                            .to_string()
+                    } else {
+                        format!("HTTP/1.1 100 Continue\r\n\r\n\
+                                HTTP/1.1 201 Created\r\n\
+                                Content-Length: {}\r\n\r\n{}", body.len(), body)
+                    };
+                    stream.write_all(resp.as_bytes()).unwrap();
+                    if head.starts_with("GET /close ") {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    return format!("http://{}", addr);
+}
+
+#[test]
+fn test_client() {
+    let server = test_server();
+    let client = Client::new();
+
+    let mut resp = client.post(&format!("{}/a?b", server), "text/plain",
+            b"hi").unwrap();
+    assert_eq!((resp.get_code(), resp.get_status()), (201, "Created"));
+    let body = String::from_utf8(resp.body().unwrap()).unwrap();
+    assert!(body.starts_with("1\nPOST /a?b HTTP/1.1\r\nHost: 127.0.0.1:"));
+    assert!(body.contains("\r\nUser-Agent: mudpie\r\n"));
+    assert!(body.contains("\r\nContent-Length: 2\r\n"));
+    assert!(body.contains("\r\nContent-Type: text/plain\r\n"));
+
+    let mut resp = client.get(&format!("{}/chunked", server)).unwrap();
+    assert_eq!(resp.get_header("transfer-encoding"), Some("chunked"));
+    assert!(resp.body().unwrap().starts_with(b"1\nGET /chunked HTTP/1.1\r\n"));
+    // Reused, now that the body has been read
+    let mut resp = client.get(&format!("{}?x", server)).unwrap();
+    assert!(resp.body().unwrap().starts_with(b"1\nGET /?x HTTP/1.1\r\n"));
+
+    // Not reused after an HTTP/1.0 response
+    let mut resp = client.get(&format!("{}/close", server)).unwrap();
+    assert!(resp.body().unwrap().starts_with(b"1\nGET /close "));
+    let mut resp = client.request("head", &server, &[("X-A", "b")], b"")
+        .unwrap();
+    assert_eq!(resp.get_header("Content-Length"), Some("5"));
+    assert_eq!(resp.body().unwrap(), b"");
+    let mut resp = client.get(&server).unwrap();
+    assert!(resp.body().unwrap().starts_with(b"2\nGET / "));
+
+    let mut client = Client::new();
+    client.set_timeout(Duration::from_millis(100));
+    let err = client.get(&format!("{}/slow", server)).err().unwrap();
+    assert!([io::ErrorKind::TimedOut, io::ErrorKind::WouldBlock]
+            .contains(&err.kind()));
+    let err = client.request("GET", &server, &[("A", "b\r\nC: d")], b"");
+    assert_eq!(err.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_parse_url() {
+    let parse = |url| parse_url(url).map_err(|e| e.kind());
+    let url = |host: &str, addr: &str, target: &str| Ok((host.to_string(),
+            addr.to_string(), target.to_string()));
+    assert_eq!(parse("http://example.com"), url("example.com",
+            "example.com:80", "/"));
+    assert_eq!(parse("HTTP://example.com:8080/a/b?c=d#e"),
+            url("example.com:8080", "example.com:8080", "/a/b?c=d"));
+    assert_eq!(parse("http://[::1]?q"), url("[::1]", "[::1]:80", "/?q"));
+    assert_eq!(parse("http://[::1]:81/"), url("[::1]:81", "[::1]:81", "/"));
+    for bad in ["https://example.com/", "example.com", "http://",
+            "http://user@example.com/", "http://a/b c"] {
+        assert_eq!(parse(bad), Err(io::ErrorKind::InvalidInput));
+    }
+}
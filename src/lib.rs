@@ -1,5 +1,46 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+
 pub use webserver::{WebServer, WebRequest, WebResponse};
-pub use webserver::{PageFunction};
+pub use webserver::{PageFunction, Handler, Extensions};
+pub use webserver::{Router, RouteParams, MatchedRoute, TrailingSlash};
+pub use webserver::{Middleware, Next, Chain, Before, After};
+pub use webserver::{AccessLog, LogFormat, LogField, LogExtras};
+pub use webserver::{SetRequestId, RequestId};
+pub use webserver::{Cors, RateLimit, Throttle};
+pub use webserver::{BasicAuth, Verifier, Htpasswd, AuthUser};
+pub use webserver::{IpFilter, Cidr, client_ip};
+pub use webserver::Compress;
+pub use webserver::{Cookie, SameSite};
+pub use webserver::{Sessions, Session, SessionStore, SessionData, MemoryStore};
+pub use webserver::{Csrf, CsrfToken};
+pub use webserver::SecurityHeaders;
+pub use webserver::VHostMap;
+pub use webserver::Timeout;
+pub use webserver::{Guard, ContentTypeGuard, AcceptGuard, HeaderGuard};
+pub use webserver::{StaticFiles, Symlinks};
+pub use webserver::{WebSocket, WebSocketConnection, WebSocketMessage};
+pub use webserver::SseStream;
+pub use webserver::Upgraded;
+pub use webserver::{ReverseProxy, Balance};
+pub use webserver::Cgi;
+pub use webserver::FastCgi;
+pub use webserver::Scgi;
+pub use webserver::Metrics;
+pub use webserver::{ServerHandle, ServerStats, Latency, Overload};
+pub use webserver::{TraceEvent, Trace, TraceHook};
+pub use webserver::{ErrorSink, ErrorContext, ServerError};
+pub use webserver::SlowLog;
+pub use webserver::{Health, Liveness};
+pub use webserver::StatusPage;
+pub use webserver::ErrorLog;
+pub use webserver::{AcmeChallenge, ACME_CHALLENGE_PATH};
+pub use webserver::{BodyInspector, BodyInspection};
+pub use webserver::ConnLimit;
+pub use webserver::ResponseCache;
+pub use webserver::{parse_head, HeadError};
+pub use webserver::BodyReader;
+pub use utils::headers::Headers;
 pub use utils::escape::html_element_escape;
+pub mod client;
 mod utils;
 mod webserver;
@@ -0,0 +1,71 @@
+//! Base64 encoding and decoding (RFC 4648, standard alphabet with padding)
+
+static ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+
+pub fn encode(data: &[u8]) -> String {
+    let mut ret = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    return ret;
+}
+
+
+/// Decode.  Padding is optional.  Returns None on any invalid input,
+/// including whitespace.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=').as_bytes();
+    if s.len() % 4 == 1 {
+        return None;
+    }
+    let mut ret = Vec::with_capacity(s.len() * 3 / 4);
+    for chunk in s.chunks(4) {
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let v = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            n |= (v as u32) << (18 - 6 * i);
+        }
+        ret.push((n >> 16) as u8);
+        if chunk.len() > 2 {
+            ret.push((n >> 8) as u8);
+        }
+        if chunk.len() > 3 {
+            ret.push(n as u8);
+        }
+    }
+    return Some(ret);
+}
+
+
+#[test]
+fn test_base64() {
+    let cases: [(&[u8], &str); 7] = [(b"", ""), (b"f", "Zg=="),
+        (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"foob", "Zm9vYg=="),
+        (b"fooba", "Zm9vYmE="), (b"foobar", "Zm9vYmFy")];
+    for &(raw, enc) in cases.iter() {
+        assert_eq!(encode(raw), enc);
+        assert_eq!(decode(enc).unwrap(), raw);
+    }
+    assert_eq!(decode("Zm8").unwrap(), b"fo");
+    assert_eq!(encode(&[0xff, 0xfe]), "//4=");
+    assert!(decode("Zm9v!").is_none());
+    assert!(decode("Z").is_none());
+}
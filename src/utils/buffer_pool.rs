@@ -0,0 +1,100 @@
+//! A pool of byte buffers, reused instead of allocated each time
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+
+/// Buffers of at least `size` bytes of capacity, taken with `get` and put
+/// back when dropped.  At most `max_free` are kept; buffers that grew too
+/// much are let go, so one huge request doesn't stay in memory.
+pub struct BufferPool {
+    size: usize,
+    max_free: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+/// A buffer taken from a `BufferPool`, empty at first
+pub struct Buffer<'a> {
+    pool: &'a BufferPool,
+    buf: Vec<u8>,
+}
+
+impl BufferPool {
+    pub fn new(size: usize, max_free: usize) -> BufferPool {
+        return BufferPool {
+            size: size,
+            max_free: max_free,
+            free: Mutex::new(Vec::new()),
+        };
+    }
+
+    /// The capacity buffers start with
+    pub fn buffer_size(&self) -> usize {
+        return self.size;
+    }
+
+    pub fn get(&self) -> Buffer<'_> {
+        let buf = self.lock().pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.size));
+        return Buffer { pool: self, buf: buf };
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+        match self.free.lock() {
+            Ok(guard) => return guard,
+            Err(poisoned) => return poisoned.into_inner(),
+        }
+    }
+}
+
+impl<'a> Drop for Buffer<'a> {
+    fn drop(&mut self) {
+        if self.buf.capacity() > 4 * self.pool.size {
+            return;
+        }
+        let mut free = self.pool.lock();
+        if free.len() < self.pool.max_free {
+            let mut buf = ::std::mem::take(&mut self.buf);
+            buf.clear();
+            free.push(buf);
+        }
+    }
+}
+
+impl<'a> Deref for Buffer<'a> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        return &self.buf;
+    }
+}
+
+impl<'a> DerefMut for Buffer<'a> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        return &mut self.buf;
+    }
+}
+
+
+#[test]
+fn test_buffer_pool() {
+    let pool = BufferPool::new(16, 2);
+    let mut a = pool.get();
+    assert!(a.is_empty() && a.capacity() >= 16);
+    a.extend_from_slice(b"hello");
+    let ptr = a.as_ptr();
+    drop(a);
+    // The same buffer, emptied
+    let b = pool.get();
+    assert!(b.is_empty());
+    assert_eq!(b.as_ptr(), ptr);
+
+    let c = pool.get();
+    let d = pool.get();
+    let mut big = pool.get();
+    big.resize(1000, 0);
+    drop((b, c, d, big));
+    // Two kept, not the one that grew
+    assert_eq!(pool.lock().len(), 2);
+    assert!(pool.lock().iter().all(|buf| buf.capacity() < 1000));
+}
@@ -1,6 +1,36 @@
 //! Byte slice manipulation / searching routines, that
 //! really should be in the stdlib, in an optimized form.
 
+use std::borrow::Cow;
+
+
+// For looking at a u64 as 8 bytes
+static LO_BITS: u64 = 0x0101_0101_0101_0101;
+static HI_BITS: u64 = 0x8080_8080_8080_8080;
+
+
+/// Return position of the first `needle` byte in haystack.
+///
+/// Looks at 8 bytes at a time: XOR with the needle in each byte makes the
+/// matching bytes zero, and `(x - 0x01..) & !x & 0x80..` is non-zero iff
+/// one of x's bytes is zero.
+pub fn memchr(needle: u8, haystack: &[u8]) -> Option<usize> {
+    let repeated = LO_BITS * needle as u64;
+    let mut pos = 0;
+    for chunk in haystack.chunks_exact(8) {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(chunk);
+        let x = u64::from_le_bytes(word) ^ repeated;
+        if x.wrapping_sub(LO_BITS) & !x & HI_BITS != 0 {
+            break;
+        }
+        pos += 8;
+    }
+    // In the chunk found, or the last few bytes
+    return haystack[pos..].iter().position(|&c| c == needle)
+        .map(|idx| pos + idx);
+}
+
 
 /// Return position of needle in haystack.
 ///
@@ -11,22 +41,46 @@ pub fn memmem(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     if needle.is_empty() {
         panic!("memmem: empty needle");
     }
-    let mut idx = 0;
-    for w in haystack.windows(needle.len()) {
-        if w == needle {
+    // Skip to each first byte, then compare
+    let mut start = 0;
+    while haystack.len() - start >= needle.len() {
+        let idx = start + memchr(needle[0], &haystack[start..])?;
+        if haystack[idx..].starts_with(needle) {
             return Some(idx);
         }
-        idx += 1;
+        start = idx + 1;
     }
     return None;
 }
 
 
+/// Return position of needle in haystack, like memmem, but an empty needle
+/// is found at 0.
+pub fn find_subsequence(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    return memmem(haystack, needle);
+}
+
+
+/// Compare, with ASCII letters in any case
+pub fn eq_ignore_ascii_case(a: &[u8], b: &[u8]) -> bool {
+    return a.eq_ignore_ascii_case(b);
+}
+
+
+/// Return position of the \r\n\r\n ending a request or response head
+pub fn find_crlf_crlf(haystack: &[u8]) -> Option<usize> {
+    return find_subsequence(haystack, b"\r\n\r\n");
+}
+
+
 /// Split src on a single byte.  
 ///
 /// Note: Wrapper for splitn()
 pub fn split_bytes_on(src: &[u8], b: u8, max_splits: usize) -> Vec<&[u8]> {
-    let is_match = |f: &u8| { (*f == b) };
+    let is_match = |f: &u8| { *f == b };
     let mut ret = Vec::<&[u8]>::new();
     for x in src.splitn(max_splits + 1, is_match) {
         ret.push(x);
@@ -40,14 +94,17 @@ pub fn split_bytes_on(src: &[u8], b: u8, max_splits: usize) -> Vec<&[u8]> {
 /// Note: a final element without a trailing \r\n will be ignored.
 pub fn split_bytes_on_crlf(src: &[u8]) -> Vec<&[u8]> {
     let mut start_idx = 0;
-    let mut current_idx = 0;
+    let mut search_idx = 0;
     let mut ret = Vec::<&[u8]>::new();
-    for w in src.windows(2) {
-        if w == b"\r\n" {
+    while let Some(idx) = memchr(b'\r', &src[search_idx..]) {
+        let current_idx = search_idx + idx;
+        if src.get(current_idx + 1) == Some(&b'\n') {
             ret.push(&src[start_idx..current_idx]);
             start_idx = current_idx + 2;
+            search_idx = start_idx;
+        } else {
+            search_idx = current_idx + 1;
         }
-        current_idx += 1;
     }
     return ret;
 }
@@ -56,9 +113,9 @@ pub fn split_bytes_on_crlf(src: &[u8]) -> Vec<&[u8]> {
 /// Return hexadecimal value of byte, or None
 fn to_hexval(byte: u8) -> Option<u8> {
     match byte {
-        b'A'...b'F' => Some(byte - b'A' + 10),
-        b'a'...b'f' => Some(byte - b'a' + 10),
-        b'0'...b'9' => Some(byte - b'0'),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'0'..=b'9' => Some(byte - b'0'),
         _ => None
     }
 }
@@ -67,7 +124,7 @@ fn to_hexval(byte: u8) -> Option<u8> {
 /// Return decimal value of byte, or None
 fn to_decval(byte: u8) -> Option<u8> {
     match byte {
-        b'0'...b'9' => Some(byte - b'0'),
+        b'0'..=b'9' => Some(byte - b'0'),
         _ => None
     }
 }
@@ -75,33 +132,86 @@ fn to_decval(byte: u8) -> Option<u8> {
 
 /// Decode %XX hex escapes.
 pub fn percent_decode(input: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(input.len());
+    percent_decode_into(input, &mut ret);
+    return ret;
+}
+
+
+/// Decode %XX hex escapes, borrowing input when it has none.
+pub fn percent_decode_cow(input: &[u8]) -> Cow<'_, [u8]> {
+    if memchr(b'%', input).is_none() {
+        return Cow::Borrowed(input);
+    }
+    return Cow::Owned(percent_decode(input));
+}
+
+
+/// Decode %XX hex escapes, appending to `out`.
+pub fn percent_decode_into(input: &[u8], out: &mut Vec<u8>) {
     let mut i = 0;
-    let mut ret = Vec::new();
     loop {
-        if i == input.len() {
-            return ret;
-        }
-        if input[i] == b'%' && i+2 < input.len() {
+        // Copy up to the next '%' as is
+        let next = match memchr(b'%', &input[i..]) {
+            Some(idx) => i + idx,
+            None => {
+                out.extend_from_slice(&input[i..]);
+                return;
+            },
+        };
+        out.extend_from_slice(&input[i..next]);
+        i = next;
+        if i+2 < input.len() {
             let l = to_hexval(input[i+1]);
             let r = to_hexval(input[i+2]);
-            if l.is_some() && r.is_some() {
-                let l = l.unwrap();
-                let r = r.unwrap();
+            if let (Some(l), Some(r)) = (l, r) {
                 let val = (l << 4) + r;
                 // encoded char
-                ret.push(val);
+                out.push(val);
                 i += 3;
                 continue;
             }
         }
 
         // not encoded
-        ret.push(input[i]);
+        out.push(input[i]);
         i += 1;
     }
 }
 
 
+/// Remove leading and trailing spaces and tabs (optional whitespace,
+/// RFC 7230 3.2.3) from input
+pub fn trim_ows(input: &[u8]) -> &[u8] {
+    let is_ows = |c: &u8| *c == b' ' || *c == b'\t';
+    let start = input.iter().position(|c| !is_ows(c)).unwrap_or(input.len());
+    let end = input.iter().rposition(|c| !is_ows(c)).map_or(start, |i| i + 1);
+    return &input[start..end];
+}
+
+
+/// The elements of a comma separated header value (RFC 7230 7), trimmed,
+/// without empty ones.  ex: b"gzip, , br" gives b"gzip" and b"br"
+pub fn split_list(input: &[u8]) -> impl Iterator<Item = &[u8]> {
+    return input.split(|&c| c == b',').map(trim_ows)
+        .filter(|item| !item.is_empty());
+}
+
+
+/// Whether a comma separated header value has `token`, in any case.
+/// ex: has_token(b"keep-alive, Upgrade", b"upgrade")
+pub fn has_token(list: &[u8], token: &[u8]) -> bool {
+    return split_list(list).any(|item| eq_ignore_ascii_case(item, token));
+}
+
+
+/// Split on runs of spaces and tabs, without empty parts
+pub fn split_whitespace(input: &[u8]) -> impl Iterator<Item = &[u8]> {
+    return input.split(|&c| c == b' ' || c == b'\t')
+        .filter(|item| !item.is_empty());
+}
+
+
 /// Remove leading and trailing spaces (b' ') from input
 pub fn strip(input: &[u8]) -> &[u8] {
     return lstrip(rstrip(input));
@@ -124,7 +234,7 @@ pub fn lstrip(input: &[u8]) -> &[u8] {
 /// Remove trailing spaces (b' ') from input, without copying
 pub fn rstrip(input: &[u8]) -> &[u8] {
     let mut ret = input;
-    while ret.len() > 0 {
+    while !ret.is_empty() {
         if ret[ret.len() - 1] != b' ' {
             break;
         }
@@ -151,6 +261,19 @@ pub fn parse_u64(input: &[u8]) -> Option<u64> {
 }
 
 
+/// Compare without leaking where the first difference is, for secrets
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    return diff == 0;
+}
+
+
 #[test]
 fn test_memmem() {
     let a = b"hello world dude";
@@ -166,6 +289,28 @@ fn test_memmem() {
     assert!(res.unwrap() == 0);
 }
 
+#[test]
+fn test_memchr() {
+    let a = b"0123456789abcdefghij";
+    for (idx, &c) in a.iter().enumerate() {
+        assert_eq!(memchr(c, a), Some(idx));
+        // Every offset, and lengths around the word size
+        assert_eq!(memchr(c, &a[idx..]), Some(0));
+        assert_eq!(memchr(c, &a[..idx]), None);
+    }
+    assert_eq!(memchr(b'x', a), None);
+    assert_eq!(memchr(b'x', b""), None);
+    assert_eq!(memchr(0x80, b"\x7f\x81\x00\x80"), Some(3));
+    assert_eq!(memchr(0, b"\x01\x01\x01\x01\x01\x01\x01\x01\x00"),
+            Some(8));
+
+    assert_eq!(memmem(b"aaab", b"aab"), Some(1));
+    assert_eq!(memmem(b"ab", b"abc"), None);
+    assert_eq!(find_crlf_crlf(b"GET / HTTP/1.1\r\nA: b\r\n\r\nbody"),
+            Some(20));
+    assert_eq!(find_crlf_crlf(b"GET / HTTP/1.1\r\n\r"), None);
+}
+
 #[test]
 fn test_split_bytes() {
     let a = b"hello world dude";
@@ -196,6 +341,9 @@ fn test_split_crlf() {
     assert!(parts[1] == b"dude");
     assert!(parts[2] == b"");
     assert!(parts[3] == b"last one");
+
+    let parts = split_bytes_on_crlf(b"a\rb\r\r\nc\n\r\nd\r");
+    assert_eq!(parts, [&b"a\rb\r"[..], &b"c\n"[..]]);
 }
 
 #[test]
@@ -217,6 +365,34 @@ fn test_percent_decode() {
     assert_eq!(percent_decode(b"%"), b"%");
     assert_eq!(percent_decode(b"%%"), b"%%");
     assert_eq!(percent_decode(b"%%%"), b"%%%");
+
+    let plain = b"/no/escapes";
+    match percent_decode_cow(plain) {
+        Cow::Borrowed(path) => assert_eq!(path, plain),
+        Cow::Owned(_) => panic!("copied"),
+    }
+    assert_eq!(&percent_decode_cow(b"/a%2fb")[..], b"/a/b");
+    let mut out = b"x".to_vec();
+    percent_decode_into(b"%41b%", &mut out);
+    assert_eq!(out, b"xAb%");
+}
+
+#[test]
+fn test_tokens() {
+    assert!(eq_ignore_ascii_case(b"Content-Type", b"content-TYPE"));
+    assert!(!eq_ignore_ascii_case(b"a", b"ab"));
+    assert_eq!(find_subsequence(b"abc", b""), Some(0));
+    assert_eq!(find_subsequence(b"abc", b"bc"), Some(1));
+
+    assert_eq!(trim_ows(b" \ta b\t "), b"a b");
+    assert_eq!(trim_ows(b" \t "), b"");
+    let items: Vec<&[u8]> = split_list(b" gzip,, br ;q=1 ,\t").collect();
+    assert_eq!(items, [&b"gzip"[..], b"br ;q=1"]);
+    assert!(has_token(b"keep-alive, Upgrade", b"upgrade"));
+    assert!(!has_token(b"keep-alive, Upgrade2", b"upgrade"));
+    assert!(!has_token(b"", b""));
+    let words: Vec<&[u8]> = split_whitespace(b"  a\t b  ").collect();
+    assert_eq!(words, [&b"a"[..], b"b"]);
 }
 
 #[test]
@@ -253,3 +429,10 @@ fn test_decval() {
     assert!(parse_u64(b"-123").is_none());
     assert!(parse_u64(b"bcd").is_none());
 }
+
+#[test]
+fn test_constant_time_eq() {
+    assert!(constant_time_eq(b"abc", b"abc"));
+    assert!(!constant_time_eq(b"abc", b"abd"));
+    assert!(!constant_time_eq(b"abc", b"ab"));
+}
@@ -0,0 +1,123 @@
+//! Chunked transfer coding (RFC 7230 4.1), for reading bodies
+
+use std::io::{self, BufRead, Read};
+
+// A chunk size or trailer line longer than this is refused
+static MAX_LINE: usize = 4096;
+
+
+/// Reads the data out of a chunked body, to the end of the last chunk and
+/// the trailers (which are dropped).  Fails with InvalidData on bad
+/// framing, and with UnexpectedEof if the body is cut short.
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+    // Left to read of the current chunk
+    left: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> ChunkedReader<R> {
+        return ChunkedReader {
+            inner: inner,
+            left: 0,
+            done: false,
+        };
+    }
+
+    /// The reader, past the end of the body once `read` has returned 0
+    pub fn into_inner(self) -> R {
+        return self.inner;
+    }
+
+    // One line, without the CRLF (or bare LF)
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        let mut line = Vec::new();
+        (&mut self.inner).take(MAX_LINE as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        if line.last() != Some(&b'\n') {
+            if line.len() > MAX_LINE {
+                return Err(invalid("chunk line too long"));
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "chunked body cut short"));
+        }
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        return Ok(line);
+    }
+
+    // The size line of the next chunk; extensions are ignored
+    fn read_size(&mut self) -> io::Result<u64> {
+        let line = self.read_line()?;
+        let size = match line.iter().position(|&c| c == b';') {
+            Some(i) => &line[..i],
+            None => &line[..],
+        };
+        let size = String::from_utf8_lossy(size);
+        let size = size.trim_matches([' ', '\t']);
+        if size.is_empty() || size.len() > 16 {
+            return Err(invalid("bad chunk size"));
+        }
+        return u64::from_str_radix(size, 16)
+            .map_err(|_| invalid("bad chunk size"));
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            self.left = self.read_size()?;
+            if self.left == 0 {
+                // Skip the trailers, up to the empty line
+                while !self.read_line()?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = (buf.len() as u64).min(self.left) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "chunked body cut short"));
+        }
+        self.left -= n as u64;
+        if self.left == 0 && !self.read_line()?.is_empty() {
+            return Err(invalid("no CRLF after chunk"));
+        }
+        return Ok(n);
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+}
+
+
+#[test]
+fn test_chunked_reader() {
+    let read = |body: &[u8]| {
+        let mut out = Vec::new();
+        let mut reader = ChunkedReader::new(io::BufReader::with_capacity(3,
+                body));
+        return reader.read_to_end(&mut out).map(|_| out);
+    };
+    assert_eq!(read(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\nextra")
+            .unwrap(), b"hello world");
+    assert_eq!(read(b"A\nabcdefghij\n0\nTrailer: x\n\n").unwrap(),
+            b"abcdefghij");
+    assert_eq!(read(b"0\r\n\r\n").unwrap(), b"");
+
+    let kind = |body: &[u8]| read(body).unwrap_err().kind();
+    assert_eq!(kind(b"5\r\nhel"), io::ErrorKind::UnexpectedEof);
+    assert_eq!(kind(b"5\r\nhello\r\n"), io::ErrorKind::UnexpectedEof);
+    assert_eq!(kind(b"5\r\nhelloX\r\n0\r\n\r\n"), io::ErrorKind::InvalidData);
+    assert_eq!(kind(b"x\r\n"), io::ErrorKind::InvalidData);
+    assert_eq!(kind(b"\r\n"), io::ErrorKind::InvalidData);
+    assert_eq!(kind(b"11111111111111111\r\n"), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,678 @@
+//! DEFLATE (RFC 1951) compression and decompression, plus the gzip
+//! (RFC 1952) and zlib (RFC 1950) wrappers.
+//!
+//! The compressor does LZ77 with hash chains and emits fixed Huffman
+//! blocks only (or stored blocks, for data that doesn't compress).  Output
+//! for typical text is 10-30% larger than zlib's, without the complexity
+//! of building dynamic trees.  The decompressor handles all block types.
+
+// Only the tests decompress whole streams so far
+#![allow(dead_code)]
+
+
+// Length codes 257..285: (base length, extra bits)
+static LENGTHS: [(u16, u8); 29] = [
+    (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0),
+    (11, 1), (13, 1), (15, 1), (17, 1), (19, 2), (23, 2), (27, 2), (31, 2),
+    (35, 3), (43, 3), (51, 3), (59, 3), (67, 4), (83, 4), (99, 4), (115, 4),
+    (131, 5), (163, 5), (195, 5), (227, 5), (258, 0)];
+
+// Distance codes 0..29: (base distance, extra bits)
+static DISTANCES: [(u16, u8); 30] = [
+    (1, 0), (2, 0), (3, 0), (4, 0), (5, 1), (7, 1), (9, 2), (13, 2),
+    (17, 3), (25, 3), (33, 4), (49, 4), (65, 5), (97, 5), (129, 6), (193, 6),
+    (257, 7), (385, 7), (513, 8), (769, 8), (1025, 9), (1537, 9),
+    (2049, 10), (3073, 10), (4097, 11), (6145, 11), (8193, 12), (12289, 12),
+    (16385, 13), (24577, 13)];
+
+// Order of the code length code lengths in a dynamic block header
+static CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4,
+    12, 3, 13, 2, 14, 1, 15];
+
+static WINDOW_SIZE: usize = 32768;
+static HASH_BITS: usize = 15;
+static MAX_CHAIN: usize = 64;
+static MIN_MATCH: usize = 3;
+static MAX_MATCH: usize = 258;
+
+
+// Writes bits LSB first, as DEFLATE wants
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, nbits: u32) {
+        self.acc |= value << self.nbits;
+        self.nbits += nbits;
+        while self.nbits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    // Huffman codes are sent MSB first
+    fn write_code(&mut self, code: u32, len: u32) {
+        let mut rev = 0;
+        for i in 0..len {
+            rev |= (code >> i & 1) << (len - 1 - i);
+        }
+        self.write(rev, len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        return self.out;
+    }
+}
+
+fn write_literal(w: &mut BitWriter, sym: u32) {
+    match sym {
+        0..=143 => w.write_code(0x30 + sym, 8),
+        144..=255 => w.write_code(0x190 + sym - 144, 9),
+        256..=279 => w.write_code(sym - 256, 7),
+        _ => w.write_code(0xc0 + sym - 280, 8),
+    }
+}
+
+fn write_match(w: &mut BitWriter, len: usize, dist: usize) {
+    let code = LENGTHS.iter().rposition(|&(base, _)| base as usize <= len)
+        .unwrap();
+    let (base, extra) = LENGTHS[code];
+    write_literal(w, 257 + code as u32);
+    w.write(len as u32 - base as u32, extra as u32);
+
+    let code = DISTANCES.iter().rposition(|&(base, _)| base as usize <= dist)
+        .unwrap();
+    let (base, extra) = DISTANCES[code];
+    w.write_code(code as u32, 5);
+    w.write(dist as u32 - base as u32, extra as u32);
+}
+
+fn insert(data: &[u8], head: &mut [usize], prev: &mut [usize], i: usize) {
+    if i + MIN_MATCH <= data.len() {
+        let h = hash3(data, i);
+        prev[i % WINDOW_SIZE] = head[h];
+        head[h] = i + 1;
+    }
+}
+
+fn hash3(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as usize) << 16 | (data[i + 1] as usize) << 8
+        | data[i + 2] as usize;
+    return v.wrapping_mul(2654435761) >> 8 & ((1 << HASH_BITS) - 1);
+}
+
+
+/// Compress `data` to a raw DEFLATE stream
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    return compress_window(data, WINDOW_SIZE);
+}
+
+/// Like `compress`, with matches at most `window` bytes back, for a
+/// decompressor with a smaller window (a power of 2, at most 32768)
+pub fn compress_window(data: &[u8], window: usize) -> Vec<u8> {
+    assert!(window.is_power_of_two() && window <= WINDOW_SIZE);
+    let mut w = BitWriter { out: Vec::with_capacity(data.len() / 2 + 16),
+        acc: 0, nbits: 0 };
+    // One final block with fixed codes
+    w.write(1, 1);
+    w.write(1, 2);
+
+    // head: most recent position for each hash (+1, 0 = none)
+    // prev: previous position with the same hash, by position % window
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let mut prev = vec![0usize; WINDOW_SIZE];
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        if i + MIN_MATCH <= data.len() {
+            let max_len = (data.len() - i).min(MAX_MATCH);
+            let mut cand = head[hash3(data, i)];
+            let mut chain = 0;
+            while cand > 0 && chain < MAX_CHAIN {
+                let j = cand - 1;
+                if i - j > window - 1 {
+                    break;
+                }
+                let mut len = 0;
+                while len < max_len && data[j + len] == data[i + len] {
+                    len += 1;
+                }
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - j;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                let next = prev[j % WINDOW_SIZE];
+                // Stale entries from an older window point forward
+                if next == 0 || next > j {
+                    break;
+                }
+                cand = next;
+                chain += 1;
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            write_match(&mut w, best_len, best_dist);
+            for k in i..i + best_len {
+                insert(data, &mut head, &mut prev, k);
+            }
+            i += best_len;
+        } else {
+            write_literal(&mut w, data[i] as u32);
+            insert(data, &mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+    write_literal(&mut w, 256);
+    let out = w.finish();
+    if out.len() > data.len() + data.len() / 65535 * 5 + 5 {
+        return compress_stored(data);
+    }
+    return out;
+}
+
+fn compress_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 65535 * 5 + 5);
+    let mut chunks = data.chunks(65535).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        out.push(if chunks.peek().is_none() { 1 } else { 0 });
+        out.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    return out;
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum InflateError {
+    /// The input ended before the final block did
+    Truncated,
+    /// The input isn't valid DEFLATE, or a checksum is wrong
+    Corrupt,
+    /// The output would be larger than the given limit
+    TooLarge,
+}
+
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u64,
+    nbits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn need(&mut self, n: u32) -> Result<(), InflateError> {
+        while self.nbits < n {
+            if self.pos >= self.data.len() {
+                return Err(InflateError::Truncated);
+            }
+            self.acc |= (self.data[self.pos] as u64) << self.nbits;
+            self.pos += 1;
+            self.nbits += 8;
+        }
+        return Ok(());
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32, InflateError> {
+        if n == 0 {
+            return Ok(0);
+        }
+        self.need(n)?;
+        let v = (self.acc & ((1u64 << n) - 1)) as u32;
+        self.acc >>= n;
+        self.nbits -= n;
+        return Ok(v);
+    }
+
+    fn align(&mut self) {
+        let n = self.nbits % 8;
+        self.acc >>= n;
+        self.nbits -= n;
+    }
+}
+
+
+// Canonical Huffman decoding table: count of codes per length, and the
+// symbols sorted by code
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left: i32 = 1;
+        for count in counts.iter().skip(1) {
+            left = left * 2 - *count as i32;
+            if left < 0 {
+                return Err(InflateError::Corrupt);
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = sym as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        return Ok(Huffman { counts: counts, symbols: symbols });
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16, InflateError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..16 {
+            code |= r.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        return Err(InflateError::Corrupt);
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (sym, len) in lengths.iter_mut().enumerate() {
+        *len = match sym {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    return (Huffman::new(&lengths).unwrap(),
+            Huffman::new(&[5u8; 30]).unwrap());
+}
+
+fn dynamic_tables(r: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let nlen = r.bits(5)? as usize + 257;
+    let ndist = r.bits(5)? as usize + 1;
+    let ncode = r.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(InflateError::Corrupt);
+    }
+    let mut clens = [0u8; 19];
+    for &i in CLEN_ORDER.iter().take(ncode) {
+        clens[i] = r.bits(3)? as u8;
+    }
+    let clen_table = Huffman::new(&clens)?;
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let sym = clen_table.decode(r)?;
+        let (value, repeat) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 => match i {
+                0 => return Err(InflateError::Corrupt),
+                _ => (lengths[i - 1], 3 + r.bits(2)? as usize),
+            },
+            17 => (0, 3 + r.bits(3)? as usize),
+            _ => (0, 11 + r.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(InflateError::Corrupt);
+        }
+        for len in lengths[i..i + repeat].iter_mut() {
+            *len = value;
+        }
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(InflateError::Corrupt);
+    }
+    return Ok((Huffman::new(&lengths[..nlen])?,
+            Huffman::new(&lengths[nlen..])?));
+}
+
+
+/// Decompress a raw DEFLATE stream.  Returns the data and the number of
+/// input bytes used.  Fails with TooLarge rather than produce more than
+/// `limit` bytes.
+pub fn decompress(data: &[u8], limit: usize)
+        -> Result<(Vec<u8>, usize), InflateError> {
+    let mut r = BitReader { data: data, pos: 0, acc: 0, nbits: 0 };
+    let mut out = Vec::new();
+    inflate(&mut r, &mut out, limit, false)?;
+    // Give back whole bytes we read ahead
+    let used = r.pos - (r.nbits / 8) as usize;
+    return Ok((out, used));
+}
+
+/// Decompress the DEFLATE blocks in `data`, which may end after any block
+/// rather than with a final one, as a permessage-deflate message (RFC
+/// 7692) does once the 00 00 ff ff is put back.  Matches may refer back
+/// into `window`, the output before this.  Returns only the new output,
+/// failing with TooLarge rather than produce more than `limit` bytes.
+pub fn decompress_sync(window: &[u8], data: &[u8], limit: usize)
+        -> Result<Vec<u8>, InflateError> {
+    let mut r = BitReader { data: data, pos: 0, acc: 0, nbits: 0 };
+    let mut out = window.to_vec();
+    inflate(&mut r, &mut out, limit.saturating_add(window.len()), true)?;
+    return Ok(out.split_off(window.len()));
+}
+
+// Inflate blocks onto `out`, until the final one, or with `until_end`,
+// until `r` has no whole bytes left
+fn inflate(r: &mut BitReader, out: &mut Vec<u8>, limit: usize,
+        until_end: bool) -> Result<(), InflateError> {
+    loop {
+        if until_end && r.pos - (r.nbits / 8) as usize >= r.data.len() {
+            return Ok(());
+        }
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.align();
+                let len = r.bits(16)?;
+                let nlen = r.bits(16)?;
+                if len != !nlen & 0xffff {
+                    return Err(InflateError::Corrupt);
+                }
+                if out.len() + len as usize > limit {
+                    return Err(InflateError::TooLarge);
+                }
+                for _ in 0..len {
+                    out.push(r.bits(8)? as u8);
+                }
+            }
+            kind @ 1..=2 => {
+                let (lit, dist) = match kind {
+                    1 => fixed_tables(),
+                    _ => dynamic_tables(r)?,
+                };
+                inflate_block(r, &lit, &dist, out, limit)?;
+            }
+            _ => return Err(InflateError::Corrupt),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+fn inflate_block(r: &mut BitReader, lit: &Huffman, dist: &Huffman,
+        out: &mut Vec<u8>, limit: usize) -> Result<(), InflateError> {
+    loop {
+        let sym = lit.decode(r)? as usize;
+        if sym < 256 {
+            if out.len() >= limit {
+                return Err(InflateError::TooLarge);
+            }
+            out.push(sym as u8);
+            continue;
+        }
+        if sym == 256 {
+            return Ok(());
+        }
+        if sym - 257 >= LENGTHS.len() {
+            return Err(InflateError::Corrupt);
+        }
+        let (base, extra) = LENGTHS[sym - 257];
+        let len = base as usize + r.bits(extra as u32)? as usize;
+        let dsym = dist.decode(r)? as usize;
+        if dsym >= DISTANCES.len() {
+            return Err(InflateError::Corrupt);
+        }
+        let (base, extra) = DISTANCES[dsym];
+        let d = base as usize + r.bits(extra as u32)? as usize;
+        if d > out.len() {
+            return Err(InflateError::Corrupt);
+        }
+        if out.len() + len > limit {
+            return Err(InflateError::TooLarge);
+        }
+        let start = out.len() - d;
+        for k in 0..len {
+            let b = out[start + k];
+            out.push(b);
+        }
+    }
+}
+
+
+/// CRC-32 as used by gzip (IEEE 802.3, reflected)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    return !crc;
+}
+
+/// Adler-32 as used by zlib
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &x in chunk {
+            a += x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    return b << 16 | a;
+}
+
+
+/// Compress to the gzip file format (Content-Encoding: gzip)
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // No name, no mtime, OS unknown
+    let mut ret = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    ret.extend(compress(data));
+    ret.extend_from_slice(&crc32(data).to_le_bytes());
+    ret.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    return ret;
+}
+
+/// Decompress a gzip stream (the first member only)
+pub fn gunzip(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    if data.len() < 18 {
+        return Err(InflateError::Truncated);
+    }
+    if data[0] != 0x1f || data[1] != 0x8b || data[2] != 8 {
+        return Err(InflateError::Corrupt);
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    if flags & 4 != 0 {
+        // FEXTRA
+        if pos + 2 > data.len() {
+            return Err(InflateError::Truncated);
+        }
+        pos += 2 + (data[pos] as usize | (data[pos + 1] as usize) << 8);
+    }
+    for flag in &[8u8, 16] {
+        // FNAME, FCOMMENT: zero terminated
+        if flags & flag != 0 {
+            match data.get(pos..).and_then(|d| d.iter().position(|&b| b == 0)) {
+                Some(end) => pos += end + 1,
+                None => return Err(InflateError::Truncated),
+            }
+        }
+    }
+    if flags & 2 != 0 {
+        // FHCRC
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err(InflateError::Truncated);
+    }
+    let (out, used) = decompress(&data[pos..], limit)?;
+    let trailer = &data[pos + used..];
+    if trailer.len() < 8 {
+        return Err(InflateError::Truncated);
+    }
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2],
+        trailer[3]]);
+    if crc != crc32(&out) {
+        return Err(InflateError::Corrupt);
+    }
+    return Ok(out);
+}
+
+/// Compress to the zlib format (Content-Encoding: deflate)
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut ret = vec![0x78, 0x01];
+    ret.extend(compress(data));
+    ret.extend_from_slice(&adler32(data).to_be_bytes());
+    return ret;
+}
+
+/// Decompress a zlib stream
+pub fn unzlib(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    if data.len() < 6 {
+        return Err(InflateError::Truncated);
+    }
+    if data[0] & 0x0f != 8 || !(data[0] as u16 * 256 + data[1] as u16).is_multiple_of(31)
+            || data[1] & 0x20 != 0 {
+        return Err(InflateError::Corrupt);
+    }
+    let (out, used) = decompress(&data[2..], limit)?;
+    let trailer = &data[2 + used..];
+    if trailer.len() < 4 {
+        return Err(InflateError::Truncated);
+    }
+    let adler = u32::from_be_bytes([trailer[0], trailer[1], trailer[2],
+        trailer[3]]);
+    if adler != adler32(&out) {
+        return Err(InflateError::Corrupt);
+    }
+    return Ok(out);
+}
+
+
+#[cfg(test)]
+fn test_inputs() -> Vec<Vec<u8>> {
+    let mut noise = Vec::new();
+    let mut x = 12345u32;
+    for _ in 0..70000 {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        noise.push((x >> 16) as u8);
+    }
+    let text = "<li><a href=\"/users/1\">user 1</a></li>\n".repeat(2000);
+    return vec![Vec::new(), b"a".to_vec(), b"aaaaaaaaaaaaaaaaaaaaaaa".to_vec(),
+        b"hello hello hello world".to_vec(), text.into_bytes(), noise,
+        vec![0u8; 100000]];
+}
+
+#[test]
+fn test_deflate_roundtrip() {
+    for input in test_inputs() {
+        let packed = compress(&input);
+        let (unpacked, used) = decompress(&packed, usize::MAX).unwrap();
+        assert_eq!(unpacked, input);
+        assert_eq!(used, packed.len());
+        assert_eq!(gunzip(&gzip(&input), usize::MAX).unwrap(), input);
+        assert_eq!(unzlib(&zlib(&input), usize::MAX).unwrap(), input);
+    }
+    let text = "<li><a href=\"/users/1\">user 1</a></li>\n".repeat(2000);
+    assert!(compress(text.as_bytes()).len() < text.len() / 20);
+    // Random data is stored
+    let noise = &test_inputs()[5];
+    assert_eq!(compress(noise).len(), noise.len() + 10);
+}
+
+#[test]
+fn test_inflate_reference() {
+    // Output of zlib: a stored block, fixed Huffman, and dynamic Huffman
+    let stored = [1, 5, 0, 250, 255, b'h', b'e', b'l', b'l', b'o'];
+    assert_eq!(decompress(&stored, 100).unwrap().0, b"hello");
+    let gz = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03,
+        0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x00, 0x80,
+        0x88, 0xf9, 0xe5, 0x11, 0x00, 0x00, 0x00];
+    assert_eq!(gunzip(&gz, 100).unwrap(), b"hello hello hello");
+    let dynamic = [53, 144, 97, 26, 0, 48, 4, 66, 207, 234, 213, 253, 207, 48, 97, 251,
+        129, 47, 73, 86, 72, 170, 126, 29, 168, 169, 156, 178, 65, 146, 58,
+        58, 32, 201, 65, 150, 69, 29, 189, 155, 50, 78, 135, 193, 13, 52, 83,
+        30, 138, 75, 68, 101, 164, 102, 222, 8, 86, 80, 215, 25, 253, 70, 217,
+        5, 193, 173, 41, 19, 91, 44, 173, 106, 159, 206, 14, 196, 250, 93,
+        190, 214, 29, 55, 16, 131, 73, 202, 93, 49, 119, 148, 59, 114, 70,
+        107, 207, 128, 219, 93, 119, 127, 197, 252, 255, 133, 117, 203, 184,
+        95, 203, 60];
+    let text = concat!("abcccaaaacaabacaaaadcaabccabaabcabadaaaabbadabaababacaabaaab",
+        "acaadaacdbdbaabbcaabadbbbdabcdbaaabdacbabcaaabcaabaabdbcbbaa",
+        "abbacabcaaabaaaabcbbbabaabaacabdcbaabacbaadabbbbaacccdbbcabc",
+        "baaaacabbabaacaaaabbbcbaabacababbacacbcccdaacbaacaaaccaaaacb",
+        "aaaaaaabababbaabaaaaaaaaacaaaadbbabacaaaaaabaabbbabcabcbbaab");
+    assert_eq!((dynamic[0] >> 1) & 3, 2);
+    assert_eq!(decompress(&dynamic, 1000).unwrap(), (text.as_bytes().to_vec(),
+        dynamic.len()));
+
+    assert_eq!(gunzip(&gz, 5), Err(InflateError::TooLarge));
+    assert_eq!(gunzip(&gz[..20], 100), Err(InflateError::Truncated));
+    let mut bad = gz;
+    bad[22] ^= 1;
+    assert_eq!(gunzip(&bad, 100), Err(InflateError::Corrupt));
+    assert_eq!(decompress(&[7], 100), Err(InflateError::Corrupt));
+}
+
+#[test]
+fn test_checksums() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+}
+
+#[test]
+fn test_deflate_window() {
+    let text = "<li><a href=\"/users/1\">user 1</a></li>\n".repeat(100);
+    let packed = compress_window(text.as_bytes(), 256);
+    assert_eq!(decompress(&packed, usize::MAX).unwrap().0, text.as_bytes());
+}
+
+#[test]
+fn test_decompress_sync() {
+    // RFC 7692 7.2.3.2: "Hello" twice, with context takeover
+    let tail = [0x00, 0x00, 0xff, 0xff];
+    let first = [&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00][..], &tail].concat();
+    let second = [&[0xf2, 0x00, 0x11, 0x00, 0x00][..], &tail].concat();
+    let hello = decompress_sync(b"", &first, 100).unwrap();
+    assert_eq!(hello, b"Hello");
+    assert_eq!(decompress_sync(&hello, &second, 100).unwrap(), b"Hello");
+    assert_eq!(decompress_sync(b"", &second, 100),
+            Err(InflateError::Corrupt));
+    assert_eq!(decompress_sync(b"", &first, 4), Err(InflateError::TooLarge));
+    // 7.2.3.3: a final block
+    let last = [0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00, 0x00, 0xff, 0xff];
+    assert_eq!(decompress_sync(b"", &last, 100).unwrap(), b"Hello");
+    // Our own output
+    let packed = [compress(b"hello hello"), tail.to_vec()].concat();
+    assert_eq!(decompress_sync(b"", &packed, 100).unwrap(), b"hello hello");
+}
@@ -14,8 +14,76 @@ pub fn html_element_escape(s: &str) -> String {
 }
 
 
+/// Escape `s` so it is safe in a quoted HTML attribute value, or an
+/// element.  Escapes &, <, >, " and '.
+pub fn html_attribute_escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            _ => ret.push(c),
+        }
+    }
+    return ret;
+}
+
+
+/// Escape `s` for use inside a JSON string literal (without the quotes)
+pub fn json_escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            _ if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => ret.push(c),
+        }
+    }
+    return ret;
+}
+
+
+/// Percent encode everything but the RFC 3986 unreserved characters
+/// (A-Z a-z 0-9 - . _ ~), so the result is safe in any URL component.
+pub fn percent_encode(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+                | b'-' | b'.' | b'_' | b'~' => ret.push(b as char),
+            _ => ret.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    return ret;
+}
+
+
 #[test]
 fn test_html_element_escape() {
     assert_eq!(&*html_element_escape("&&<>hi there"),
         "&amp;&amp;&lt;&gt;hi there");
 }
+
+#[test]
+fn test_html_attribute_escape() {
+    assert_eq!(html_attribute_escape("a\"b'<&>"), "a&quot;b&#39;&lt;&amp;&gt;");
+}
+
+#[test]
+fn test_json_escape() {
+    assert_eq!(&*json_escape("a\"b\\c\nd\u{1}\u{20AC}"),
+        "a\\\"b\\\\c\\nd\\u0001\u{20AC}");
+}
+
+#[test]
+fn test_percent_encode() {
+    assert_eq!(&*percent_encode("a b/c?d=e&f~\u{20AC}"),
+        "a%20b%2Fc%3Fd%3De%26f~%E2%82%AC");
+}
@@ -8,6 +8,9 @@ use std::io;
 pub trait GenericSocket : Send {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error>;
     fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error>;
+    // Write all of `bufs` one after the other, in as few calls as can be
+    // (ex: one writev)
+    fn write_all_vectored(&mut self, bufs: &[&[u8]]) -> Result<(), io::Error>;
 }
 
 impl<T: io::Read + io::Write + Send> GenericSocket for T {
@@ -17,4 +20,70 @@ impl<T: io::Read + io::Write + Send> GenericSocket for T {
     fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error> {
         return self.write_all(buf);
     }
+    fn write_all_vectored(&mut self, bufs: &[&[u8]]) -> Result<(), io::Error> {
+        let mut slices: Vec<io::IoSlice> = bufs.iter()
+            .map(|buf| io::IoSlice::new(buf)).collect();
+        let mut slices = &mut slices[..];
+        // Skip empty ones
+        io::IoSlice::advance_slices(&mut slices, 0);
+        while !slices.is_empty() {
+            match self.write_vectored(slices) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero,
+                        "failed to write whole buffer")),
+                Ok(n) => io::IoSlice::advance_slices(&mut slices, n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        return Ok(());
+    }
+}
+
+
+/// Fill `buf`, failing with UnexpectedEof if the connection closes first
+pub fn read_exact(stream: &mut dyn GenericSocket, mut buf: &mut [u8])
+        -> io::Result<()> {
+    while !buf.is_empty() {
+        match stream.read(buf)? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "connection closed")),
+            n => buf = &mut buf[n..],
+        }
+    }
+    return Ok(());
+}
+
+
+#[test]
+fn test_write_all_vectored() {
+    // Takes 3 bytes at most per call
+    struct Slow(Vec<u8>, usize);
+    impl io::Read for Slow {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            return Ok(0);
+        }
+    }
+    impl io::Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.write_vectored(&[io::IoSlice::new(buf)]);
+        }
+        fn write_vectored(&mut self, bufs: &[io::IoSlice])
+                -> io::Result<usize> {
+            self.1 += 1;
+            let all: Vec<u8> = bufs.iter().flat_map(|b| b.iter().cloned())
+                .take(3).collect();
+            self.0.extend_from_slice(&all);
+            return Ok(all.len());
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    let mut out = Slow(Vec::new(), 0);
+    out.write_all_vectored(&[b"he", b"", b"llo", b" world"]).unwrap();
+    assert_eq!(&out.0[..], b"hello world");
+    assert_eq!(out.1, 4);
+    out.write_all_vectored(&[b"", b""]).unwrap();
+    assert_eq!(out.1, 4);
 }
@@ -0,0 +1,195 @@
+//! The request environ: its fixed keys and headers, in order
+
+use std::ops::Index;
+use std::slice;
+
+
+/// A request's environ (see `WebRequest::get_environ`): a list of keys and
+/// values, which can be used like the map it used to be (`get`, `insert`,
+/// `iter`, `keys`, `values`, `for (key, value) in env`, `environ[key]`).
+///
+/// For a dozen headers a linear scan beats hashing them, and the list
+/// keeps the request's order: the fixed keys (method, path, and the like)
+/// first, then the headers as they came.  A repeated header stays where it
+/// first came, with the values joined.
+///
+/// ex:
+///
+/// ```ignore
+/// let env = req.get_environ();
+/// assert_eq!(env.header("User-Agent"), env.get(b"http_user-agent")
+///     .map(|v| &v[..]));
+/// for (name, value) in env.headers() {
+///     println!("{}: {}", String::from_utf8_lossy(name),
+///         String::from_utf8_lossy(value));
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        return Headers::default();
+    }
+
+    pub fn with_capacity(n: usize) -> Headers {
+        return Headers { entries: Vec::with_capacity(n) };
+    }
+
+    /// The value of `key`, ex: b"path" or b"http_user-agent"
+    pub fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        return self.entries.iter().find(|&(k, _)| k == key).map(|(_, v)| v);
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        return self.get(key).is_some();
+    }
+
+    /// Set `key`, in its place if it's there already, else at the end.
+    /// Returns the old value.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>)
+            -> Option<Vec<u8>> {
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(&mut (_, ref mut old)) =>
+                return Some(::std::mem::replace(old, value)),
+            None => self.entries.push((key, value)),
+        }
+        return None;
+    }
+
+    /// Add to the value of `key` after a comma, as for a repeated header,
+    /// or set it if it isn't there.
+    pub fn append(&mut self, key: Vec<u8>, value: &[u8]) {
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(&mut (_, ref mut old)) => {
+                old.push(b',');
+                old.extend_from_slice(value);
+            },
+            None => self.entries.push((key, value.to_vec())),
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let idx = self.entries.iter().position(|(k, _)| k == key)?;
+        return Some(self.entries.remove(idx).1);
+    }
+
+    /// All the keys and values, in order
+    pub fn iter(&self) -> Iter<'_> {
+        return Iter(self.entries.iter());
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> + '_ {
+        return self.iter().map(|(k, _)| k);
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Vec<u8>> + '_ {
+        return self.iter().map(|(_, v)| v);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    /// The value of a header, by its name in any case, ex: "User-Agent"
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        return self.headers().find(|&(k, _)| k.eq_ignore_ascii_case(
+                name.as_bytes())).map(|(_, v)| v);
+    }
+
+    /// The headers, in the order they came: names in lowercase, without
+    /// the "http_" of their keys
+    pub fn headers(&self) -> impl Iterator<Item = (&[u8], &[u8])> + '_ {
+        return self.entries.iter().filter_map(|(k, v)| {
+            return k.strip_prefix(&b"http_"[..]).map(|name| (name, &v[..]));
+        });
+    }
+}
+
+/// The keys and values of a `Headers`, in order
+pub struct Iter<'a>(slice::Iter<'a, (Vec<u8>, Vec<u8>)>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Vec<u8>, &'a Vec<u8>);
+
+    fn next(&mut self) -> Option<(&'a Vec<u8>, &'a Vec<u8>)> {
+        return self.0.next().map(|(k, v)| (k, v));
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        return self.0.size_hint();
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a Vec<u8>, &'a Vec<u8>);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        return self.iter();
+    }
+}
+
+impl Index<&[u8]> for Headers {
+    type Output = Vec<u8>;
+
+    /// Panics if `key` isn't there
+    fn index(&self, key: &[u8]) -> &Vec<u8> {
+        match self.get(key) {
+            Some(value) => return value,
+            None => panic!("no {:?} in environ",
+                    String::from_utf8_lossy(key)),
+        }
+    }
+}
+
+
+#[test]
+fn test_headers() {
+    let mut env = Headers::new();
+    env.insert(b"path".to_vec(), b"/".to_vec());
+    env.append(b"http_b".to_vec(), b"1");
+    env.insert(b"http_a".to_vec(), b"x".to_vec());
+    env.append(b"http_b".to_vec(), b"2");
+    assert_eq!(env.insert(b"path".to_vec(), b"/a".to_vec()),
+            Some(b"/".to_vec()));
+    assert_eq!(env.len(), 3);
+    assert_eq!(&env[&b"path"[..]], b"/a");
+    assert!(env.contains_key(b"http_a") && !env.contains_key(b"a"));
+
+    let keys: Vec<&[u8]> = env.iter().map(|(k, _)| &k[..]).collect();
+    assert_eq!(keys, [&b"path"[..], b"http_b", b"http_a"]);
+    let headers: Vec<(&[u8], &[u8])> = env.headers().collect();
+    assert_eq!(headers, [(&b"b"[..], &b"1,2"[..]), (b"a", b"x")]);
+    assert_eq!(env.header("B"), Some(&b"1,2"[..]));
+    assert_eq!(env.header("path"), None);
+
+    assert_eq!(env.remove(b"http_b"), Some(b"1,2".to_vec()));
+    assert_eq!(env.remove(b"http_b"), None);
+    assert_eq!(env.len(), 2);
+}
+
+#[test]
+fn test_headers_as_map() {
+    let (req, _) = ::webserver::parse_head(
+            b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n").ok().unwrap();
+    // As code written for the HashMap did
+    let mut seen = Vec::new();
+    for (k, v) in req.get_environ() {
+        seen.push((k.clone(), v.clone()));
+    }
+    assert!(seen.contains(&(b"path".to_vec(), b"/a".to_vec())));
+    assert_eq!(seen.last(), Some(&(b"http_host".to_vec(), b"x".to_vec())));
+    let env = req.get_environ();
+    assert_eq!(env.iter().len(), seen.len());
+    assert!(env.keys().zip(env.values()).eq(env.iter()));
+    assert_eq!(env.keys().last(), Some(&b"http_host".to_vec()));
+}
@@ -1,13 +1,10 @@
 //! Low level parsing of an HTTP Request (path and headers)
 
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::ascii::AsciiExt;
-
 use super::byteutils;
+use super::headers::Headers;
 
 pub struct Request {
-    pub environ: HashMap<Vec<u8>, Vec<u8>>,
+    pub environ: Headers,
     pub path: String,
     pub method: String,
 }
@@ -57,9 +54,12 @@ pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
         return Err(ParseError::BadRequestLine);
     }
 
-    let method = request_parts[0].to_vec().to_ascii_lowercase();
+    // Copied once each, and lowercased in place
+    let mut method = request_parts[0].to_vec();
+    method.make_ascii_lowercase();
     let path = request_parts[1];  // NB: don't copy yet
-    let protocol = request_parts[2].to_vec().to_ascii_lowercase();
+    let mut protocol = request_parts[2].to_vec();
+    protocol.make_ascii_lowercase();
 
     // Split doesn't coalesce spaces for us
     if method.is_empty() || path.is_empty() || protocol.is_empty() {
@@ -70,14 +70,19 @@ pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
         return Err(ParseError::BadVersion);
     }
 
-    let mut environ = HashMap::<Vec<u8>, Vec<u8>>::new();
-    environ.insert(b"method".to_vec(), method.clone());
-    environ.insert(b"protocol".to_vec(), protocol.clone());
+    // Decode method too, to make application code simpler
+    let method_utf8 = String::from_utf8_lossy(&method).into_owned();
+
+    // The fixed keys below, and one per header line
+    let mut environ = Headers::with_capacity(6 + lines.len());
+    environ.insert(b"method".to_vec(), method);
+    environ.insert(b"protocol".to_vec(), protocol);
+    environ.insert(b"request_uri".to_vec(), path.to_vec());
 
     // Parse path and query string
     // The OPTIONS method is allowed a path of '*'.
     // All other methods must have a path starting with '/'.
-    if method == b"options" && path == b"*" {
+    if method_utf8 == "options" && path == b"*" {
         environ.insert(b"path".to_vec(), path.to_vec());
         environ.insert(b"query_string".to_vec(), b"".to_vec());
     } else {
@@ -94,16 +99,16 @@ pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
         }
     }
 
+    // Nothing consumed by a mounted router yet
+    environ.insert(b"script_name".to_vec(), b"".to_vec());
+
     // Also decode path into a normalized form.
-    let path_decoded = byteutils::percent_decode(
-            &environ.get(&b"path"[..]).unwrap());
+    // Most paths have no escapes: no copy to decode them then
+    let path_decoded = byteutils::percent_decode_cow(
+            environ.get(&b"path"[..]).unwrap());
     let path_decoded_utf8 = String::from_utf8_lossy(
             &path_decoded).into_owned();
 
-    // Decode method too, to make application code simpler
-    let method_utf8 = String::from_utf8_lossy(
-            &method).into_owned();
-
     // Now process the headers
     for line in lines.iter().skip(1) {
         if line.is_empty() {
@@ -112,7 +117,7 @@ pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
         }
 
         // "Header: Value"
-        let header_parts = byteutils::split_bytes_on(*line, b':', 1);
+        let header_parts = byteutils::split_bytes_on(line, b':', 1);
         if header_parts.len() != 2 {
             return Err(ParseError::InvalidHeaderSeparator);
         }
@@ -124,24 +129,17 @@ pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
             return Err(ParseError::InvalidHeaderWhitespace);
         }
 
-        let mut nice_header_name = b"http_".to_vec();
-        nice_header_name.extend(header_parts[0]);
-        let nice_header_name = nice_header_name.to_ascii_lowercase();
-        
+        // One allocation for the name, lowercased in place
+        let mut nice_header_name = Vec::with_capacity(5 + header_name.len());
+        nice_header_name.extend(b"http_");
+        nice_header_name.extend(header_name);
+        nice_header_name.make_ascii_lowercase();
+
         // Strip optional whitespace around header value
-        let header_value = byteutils::strip(header_parts[1]).to_vec();
+        let header_value = byteutils::strip(header_parts[1]);
 
         // If a header is repeated, make the values comma separated.
-        // Entry API is nice (gets around borrow checker frustration)
-        match environ.entry(nice_header_name) {
-            Entry::Vacant(entry) => { 
-                entry.insert(header_value); 
-            },
-            Entry::Occupied(mut entry) => {
-                (*entry.get_mut()).extend(b",");
-                (*entry.get_mut()).extend(&header_value);
-            }
-        }
+        environ.append(nice_header_name, header_value);
     }
 
     return Ok(Request {
@@ -151,6 +149,29 @@ pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
     });
 }
 
+
+/// Parse an application/x-www-form-urlencoded query string or body into
+/// (name, value) pairs, in order.  '+' is a space; invalid UTF-8 is
+/// replaced.
+pub fn parse_urlencoded(input: &[u8]) -> Vec<(String, String)> {
+    let mut ret = Vec::new();
+    for pair in input.split(|&b| b == b'&') {
+        if pair.is_empty() {
+            continue;
+        }
+        let pair: Vec<u8> = pair.iter()
+            .map(|&b| if b == b'+' { b' ' } else { b }).collect();
+        let parts = byteutils::split_bytes_on(&pair, b'=', 1);
+        let value = if parts.len() > 1 { parts[1] } else { b"" };
+        ret.push((
+            String::from_utf8_lossy(&byteutils::percent_decode(parts[0]))
+                .into_owned(),
+            String::from_utf8_lossy(&byteutils::percent_decode(value))
+                .into_owned()));
+    }
+    return ret;
+}
+
 #[cfg(test)]
 fn assert_header_eq(req: &Request, header: &[u8], val: &[u8]) {
     assert_eq!(&**req.environ.get(header).unwrap(), val);
@@ -225,3 +246,12 @@ fn test_request_bad() {
     let r = parse(s);
     assert_eq!(r.err().unwrap(), ParseError::InvalidHeaderWhitespace);
 }
+
+#[test]
+fn test_parse_urlencoded() {
+    let pairs = parse_urlencoded(b"a=1&b=x+y%21&&c&d=%E2%82%AC&a=2");
+    let pairs: Vec<(&str, &str)> = pairs.iter()
+        .map(|(k, v)| (&k[..], &v[..])).collect();
+    assert_eq!(pairs, vec![("a", "1"), ("b", "x y!"), ("c", ""),
+        ("d", "\u{20AC}"), ("a", "2")]);
+}
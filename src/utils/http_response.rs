@@ -0,0 +1,156 @@
+//! Low level parsing of an HTTP response (status line and headers), for
+//! the client side of a connection
+
+use std::io::{self, BufRead, Read};
+
+// A response head larger than this is refused
+static MAX_HEAD_SIZE: usize = 65536;
+
+
+/// The status line and headers of a response
+pub struct ResponseHead {
+    /// Not 1.0, so the connection may be kept open
+    pub http11: bool,
+    pub code: i32,
+    pub status: String,
+    /// (name, value) pairs, in order
+    pub headers: Vec<(String, String)>,
+}
+
+/// How a response body ends (RFC 7230 3.3.3)
+#[derive(Debug, PartialEq)]
+pub enum BodyLength {
+    Length(u64),
+    Chunked,
+    /// When the server closes the connection
+    Close,
+}
+
+impl ResponseHead {
+    /// The values of a header, trimmed
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        return self.headers.iter()
+            .filter(|h| h.0.eq_ignore_ascii_case(name))
+            .map(|h| h.1.trim()).collect();
+    }
+
+    /// How the body ends.  `no_body` is for responses that have none
+    /// whatever the headers say: to HEAD requests, 204 and 304.
+    pub fn body_length(&self, no_body: bool) -> io::Result<BodyLength> {
+        if no_body {
+            return Ok(BodyLength::Length(0));
+        }
+        if let Some(te) = self.get_all("Transfer-Encoding").last() {
+            let last = te.rsplit(',').next().unwrap().trim();
+            if last.eq_ignore_ascii_case("chunked") {
+                return Ok(BodyLength::Chunked);
+            }
+            return Ok(BodyLength::Close);
+        }
+        let lengths = self.get_all("Content-Length");
+        match lengths.first() {
+            Some(&length) => {
+                let agree = lengths.iter().all(|&l| l == length);
+                match length.parse::<u64>() {
+                    Ok(len) if agree => return Ok(BodyLength::Length(len)),
+                    _ => return Err(invalid("bad Content-Length")),
+                }
+            },
+            None => return Ok(BodyLength::Close),
+        }
+    }
+}
+
+/// Read a response's status line and headers, up to the empty line
+pub fn read_response_head<R: BufRead>(reader: &mut R)
+        -> io::Result<ResponseHead> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = Vec::new();
+        reader.by_ref().take((MAX_HEAD_SIZE - size) as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        size += line.len();
+        if size > MAX_HEAD_SIZE {
+            return Err(invalid("response head too large"));
+        }
+        if line.last() != Some(&b'\n') {
+            return Err(invalid("connection closed in the response head"));
+        }
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.is_empty() && !lines.is_empty() {
+            break;
+        }
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+    }
+
+    let mut status_line = lines[0].splitn(3, ' ');
+    let version = status_line.next().unwrap();
+    let code = status_line.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") || code.len() != 3
+            || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(invalid("bad status line"));
+    }
+    let status = status_line.next().unwrap_or("").to_string();
+    let mut headers = Vec::new();
+    for line in lines[1..].iter() {
+        let (name, value) = match line.find(':') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => return Err(invalid("bad header line")),
+        };
+        // No folded lines, or space before the colon
+        if name.is_empty() || name.trim() != name {
+            return Err(invalid("bad header line"));
+        }
+        headers.push((name.to_string(), value.to_string()));
+    }
+    return Ok(ResponseHead {
+        http11: version != "HTTP/1.0",
+        code: code.parse().unwrap(),
+        status: status,
+        headers: headers,
+    });
+}
+
+fn invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+}
+
+
+#[test]
+fn test_read_response_head() {
+    let read = |raw: &[u8]| read_response_head(&mut &raw[..]);
+    let head = read(b"HTTP/1.1 404 Not Found\r\nA: 1\r\nB:  2 \r\n\r\nbody")
+        .unwrap();
+    assert!(head.http11);
+    assert_eq!((head.code, &head.status[..]), (404, "Not Found"));
+    assert_eq!(head.headers, [("A".to_string(), "1".to_string()),
+            ("B".to_string(), "2".to_string())]);
+    assert!(!read(b"HTTP/1.0 200\n\n").unwrap().http11);
+
+    for bad in [&b"HTTP/2 200 OK\r\n\r\n"[..], b"HTTP/1.1 20 OK\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nA 1\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\n A: 1\r\n\r\n", b"HTTP/1.1 200 OK\r\n"] {
+        assert_eq!(read(bad).err().unwrap().kind(),
+                io::ErrorKind::InvalidData);
+    }
+
+    let length = |raw: &[u8], no_body| read(raw).unwrap().body_length(no_body)
+        .map_err(|e| e.kind());
+    assert_eq!(length(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", false),
+            Ok(BodyLength::Length(5)));
+    assert_eq!(length(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", true),
+            Ok(BodyLength::Length(0)));
+    assert_eq!(length(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\
+            Content-Length: 5\r\n\r\n", false), Ok(BodyLength::Chunked));
+    assert_eq!(length(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n",
+            false), Ok(BodyLength::Close));
+    assert_eq!(length(b"HTTP/1.1 200 OK\r\n\r\n", false),
+            Ok(BodyLength::Close));
+    assert_eq!(length(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\
+            Content-Length: 6\r\n\r\n", false),
+            Err(io::ErrorKind::InvalidData));
+}
@@ -0,0 +1,139 @@
+//! MD5 (RFC 1321).  Broken as a hash; needed by htpasswd $apr1$ entries.
+
+pub struct Md5 {
+    state: [u32; 4],
+    buf: Vec<u8>,
+    len: u64,
+}
+
+// The shift amounts of each round
+static SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23,
+    6, 10, 15, 21];
+
+// floor(abs(sin(i + 1)) * 2^32)
+static K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee,
+    0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be,
+    0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa,
+    0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+    0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c,
+    0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05,
+    0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039,
+    0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1,
+    0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+impl Default for Md5 {
+    fn default() -> Md5 {
+        return Md5::new();
+    }
+}
+
+impl Md5 {
+    pub fn new() -> Md5 {
+        return Md5 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            buf: Vec::with_capacity(64),
+            len: 0,
+        };
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.buf.is_empty() {
+            let n = (64 - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() < 64 {
+                return;
+            }
+            let block = self.buf.clone();
+            self.compress(&block);
+            self.buf.clear();
+        }
+        while data.len() >= 64 {
+            self.compress(&data[..64]);
+            data = &data[64..];
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn finish(mut self) -> [u8; 16] {
+        let bits = self.len.wrapping_mul(8);
+        let mut pad = vec![0x80u8];
+        while (self.buf.len() + pad.len()) % 64 != 56 {
+            pad.push(0);
+        }
+        pad.extend_from_slice(&bits.to_le_bytes());
+        let len = self.len;
+        self.update(&pad);
+        self.len = len;
+
+        let mut ret = [0u8; 16];
+        for (i, word) in self.state.iter().enumerate() {
+            ret[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        return ret;
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut m = [0u32; 16];
+        for i in 0..16 {
+            m[i] = u32::from_le_bytes([block[i * 4], block[i * 4 + 1],
+                block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i {
+                0..=15 => ((b & c) | (!b & d), i),
+                16..=31 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                32..=47 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let t = a.wrapping_add(f).wrapping_add(K[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(t.rotate_left(SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        let add = [a, b, c, d];
+        for (s, x) in self.state.iter_mut().zip(add.iter()) {
+            *s = s.wrapping_add(*x);
+        }
+    }
+}
+
+
+/// MD5 digest of `data`
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    let mut h = Md5::new();
+    h.update(data);
+    return h.finish();
+}
+
+
+#[cfg(test)]
+fn hex(d: &[u8]) -> String {
+    return d.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+#[test]
+fn test_md5() {
+    assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+    assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+    assert_eq!(hex(&md5(b"12345678901234567890123456789012345678901234567890\
+        123456789012345678901234567890")), "57edf4a22be3c955ac49da2e2107b67a");
+    let mut h = Md5::new();
+    for _ in 0..1000 {
+        h.update(&[b'a'; 1000]);
+    }
+    assert_eq!(hex(&h.finish()), "7707d6ae4e027c70eea2a935c2296f21");
+}
@@ -3,3 +3,18 @@ pub mod byteutils;
 pub mod http_request;
 pub mod escape;
 pub mod genericsocket;
+pub mod regex;
+pub mod time;
+pub mod base64;
+pub mod sha1;
+pub mod md5;
+pub mod deflate;
+pub mod negotiate;
+pub mod sha256;
+pub mod random;
+pub mod mime;
+pub mod range;
+pub mod chunked;
+pub mod http_response;
+pub mod buffer_pool;
+pub mod headers;
@@ -0,0 +1,149 @@
+//! Content negotiation: parsing Accept-* headers with quality values
+
+/// Parse a header like "gzip;q=0.8, br, *;q=0.1" into lowercase
+/// (value, q) pairs, in the order given.  Other parameters are dropped.
+/// Malformed q values count as 1, like a missing one.
+pub fn parse_quality_list(header: &str) -> Vec<(String, f32)> {
+    let mut ret = Vec::new();
+    for item in header.split(',') {
+        let mut parts = item.split(';');
+        let value = parts.next().unwrap().trim().to_ascii_lowercase();
+        if value.is_empty() {
+            continue;
+        }
+        let mut q = 1.0;
+        for param in parts {
+            let param = param.trim();
+            if param.len() > 2 && param[..2].eq_ignore_ascii_case("q=") {
+                q = match param[2..].trim().parse::<f32>() {
+                    Ok(v) if (0.0..=1.0).contains(&v) => v,
+                    _ => 1.0,
+                };
+            }
+        }
+        ret.push((value, q));
+    }
+    return ret;
+}
+
+
+/// The quality `list` gives `value`.  An exact match wins over "*".
+/// Returns 0 if neither is listed.
+pub fn quality(list: &[(String, f32)], value: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for &(ref v, q) in list {
+        if v.eq_ignore_ascii_case(value) {
+            return q;
+        }
+        if v == "*" {
+            wildcard = q;
+        }
+    }
+    return wildcard;
+}
+
+
+/// The quality an Accept `list` gives the media type `value`, ex:
+/// "text/html".  The most specific match wins: "text/html", then
+/// "text/*", then "*/*".  Returns 0 if none is listed.
+pub fn media_quality(list: &[(String, f32)], value: &str) -> f32 {
+    let major = value.split('/').next().unwrap_or("");
+    let mut best = (0, 0.0);
+    for &(ref v, q) in list {
+        let specificity = if v.eq_ignore_ascii_case(value) {
+            3
+        } else if v.strip_suffix("/*")
+                .is_some_and(|m| m.eq_ignore_ascii_case(major)) {
+            2
+        } else if v == "*/*" {
+            1
+        } else {
+            continue;
+        };
+        if specificity > best.0 {
+            best = (specificity, q);
+        }
+    }
+    return best.1;
+}
+
+
+/// The quality an Accept-Language `list` gives the language tag `value`,
+/// ex: "en-US".  A range matches the tag itself, and longer tags that
+/// start with it and a '-', so "en" matches "en-us" (RFC 4647 basic
+/// filtering).  The longest matching range wins, then "*".  Returns 0 if
+/// none matches.
+pub fn language_quality(list: &[(String, f32)], value: &str) -> f32 {
+    let value = value.to_ascii_lowercase();
+    let mut best = (0, 0.0);
+    for &(ref v, q) in list {
+        let specificity = if v == "*" {
+            1
+        } else if value == *v || (value.starts_with(&v[..])
+                && value.as_bytes()[v.len()] == b'-') {
+            v.len() + 1
+        } else {
+            continue;
+        };
+        if specificity > best.0 {
+            best = (specificity, q);
+        }
+    }
+    return best.1;
+}
+
+
+/// Pick the best of `offered` for `header`.  Ties go to the one offered
+/// first.  Returns None if nothing offered is acceptable (q > 0).
+pub fn choose<'a>(header: &str, offered: &[&'a str]) -> Option<&'a str> {
+    let list = parse_quality_list(header);
+    let mut best = None;
+    let mut best_q = 0.0;
+    for &value in offered {
+        let q = quality(&list, value);
+        if q > best_q {
+            best = Some(value);
+            best_q = q;
+        }
+    }
+    return best;
+}
+
+
+#[test]
+fn test_negotiate() {
+    assert_eq!(parse_quality_list("gzip;q=0.8, BR , ,*;q=x"),
+        vec![("gzip".to_string(), 0.8), ("br".to_string(), 1.0),
+             ("*".to_string(), 1.0)]);
+    let offered = ["gzip", "deflate"];
+    assert_eq!(choose("gzip, deflate", &offered), Some("gzip"));
+    assert_eq!(choose("deflate, gzip;q=0.5", &offered), Some("deflate"));
+    assert_eq!(choose("br", &offered), None);
+    assert_eq!(choose("*", &offered), Some("gzip"));
+    assert_eq!(choose("*;q=0.5, gzip;q=0", &offered), Some("deflate"));
+    assert_eq!(choose("", &offered), None);
+}
+
+#[test]
+fn test_media_quality() {
+    let list = parse_quality_list("text/html, text/*;q=0.5, */*;q=0.1");
+    assert_eq!(media_quality(&list, "text/html"), 1.0);
+    assert_eq!(media_quality(&list, "TEXT/plain"), 0.5);
+    assert_eq!(media_quality(&list, "image/png"), 0.1);
+    assert_eq!(media_quality(&parse_quality_list("text/plain"),
+            "text/html"), 0.0);
+}
+
+#[test]
+fn test_language_quality() {
+    let list = parse_quality_list("de-CH, de;q=0.8, en;q=0.5, *;q=0.1");
+    assert_eq!(language_quality(&list, "de-ch"), 1.0);
+    assert_eq!(language_quality(&list, "de"), 0.8);
+    assert_eq!(language_quality(&list, "de-AT"), 0.8);
+    assert_eq!(language_quality(&list, "en-US"), 0.5);
+    assert_eq!(language_quality(&list, "fr"), 0.1);
+    // "de-ch" doesn't match plain "de", nor "en" "eng"
+    let list = parse_quality_list("de-ch, en");
+    assert_eq!(language_quality(&list, "de"), 0.0);
+    assert_eq!(language_quality(&list, "eng"), 0.0);
+}
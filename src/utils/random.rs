@@ -0,0 +1,80 @@
+//! Unpredictable bytes for session ids and tokens
+
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::sha256::Sha256;
+
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Fill `buf` from the OS (/dev/urandom).  Where that can't be read (ex:
+/// Windows, or a chroot without /dev), from the keys the standard library
+/// seeds `RandomState` with, which also come from the OS: std panics
+/// rather than hand out keys without them, and so does this.
+pub fn fill(buf: &mut [u8]) {
+    if let Ok(mut f) = File::open("/dev/urandom") {
+        if f.read_exact(buf).is_ok() {
+            return;
+        }
+    }
+    fill_from_random_state(buf);
+}
+
+// SipHash is keyed with the OS-seeded keys, so its outputs can't be told
+// without them; hashed again with the time, pid and a counter, which make
+// each chunk different
+fn fill_from_random_state(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(32) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+            .unwrap_or(0);
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed) as u64;
+        let mut h = Sha256::new();
+        for i in 0..4u64 {
+            let mut sip = RandomState::new().build_hasher();
+            sip.write_u64(count);
+            sip.write_u64(i);
+            sip.write_u64(now);
+            h.update(&sip.finish().to_le_bytes());
+        }
+        h.update(&now.to_le_bytes());
+        h.update(&process::id().to_le_bytes());
+        h.update(&count.to_le_bytes());
+        let digest = h.finish();
+        let n = chunk.len();
+        chunk.copy_from_slice(&digest[..n]);
+    }
+}
+
+
+/// `nbytes` random bytes as lowercase hex
+pub fn hex_token(nbytes: usize) -> String {
+    let mut buf = vec![0u8; nbytes];
+    fill(&mut buf);
+    return buf.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+
+#[test]
+fn test_hex_token() {
+    let a = hex_token(16);
+    assert_eq!(a.len(), 32);
+    assert!(a.bytes().all(|b| b.is_ascii_hexdigit()));
+    assert!(a != hex_token(16));
+}
+
+#[test]
+fn test_fill_from_random_state() {
+    let mut a = [0u8; 40];
+    let mut b = [0u8; 40];
+    fill_from_random_state(&mut a);
+    fill_from_random_state(&mut b);
+    assert!(a != b && a != [0; 40]);
+}
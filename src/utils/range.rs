@@ -0,0 +1,108 @@
+//! Range requests (RFC 7233), for single byte ranges
+
+/// What to do with a Range request header
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    /// Send the whole body (no valid, single range asked for)
+    Full,
+    /// Send bytes start..=end
+    Partial(u64, u64),
+    /// Nothing asked for is in the body: 416
+    Unsatisfiable,
+}
+
+
+/// Interpret a Range header for a body of `len` bytes.
+///
+/// Only a single range is supported; a list of several, or anything that
+/// doesn't parse, gets the full body, which the RFC allows.
+pub fn parse_range(header: &str, len: u64) -> ByteRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) => spec.trim(),
+        None => return ByteRange::Full,
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let (first, last) = match spec.find('-') {
+        Some(i) => (spec[..i].trim(), spec[i + 1..].trim()),
+        None => return ByteRange::Full,
+    };
+    let number = |s: &str| -> Option<u64> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        return s.parse().ok();
+    };
+
+    if first.is_empty() {
+        // The last N bytes
+        let suffix = match number(last) {
+            Some(n) => n,
+            None => return ByteRange::Full,
+        };
+        if suffix == 0 || len == 0 {
+            return ByteRange::Unsatisfiable;
+        }
+        return ByteRange::Partial(len.saturating_sub(suffix), len - 1);
+    }
+    let start = match number(first) {
+        Some(n) => n,
+        None => return ByteRange::Full,
+    };
+    let end = if last.is_empty() {
+        u64::MAX
+    } else {
+        match number(last) {
+            Some(n) if n >= start => n,
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    return ByteRange::Partial(start, end.min(len - 1));
+}
+
+
+/// Whether an If-Range header still matches the representation, so the
+/// Range can be honored.  An entity tag must match `etag` strongly; a date
+/// must equal `last_modified` exactly.
+pub fn if_range_matches(if_range: &str, etag: Option<&str>,
+        last_modified: Option<&str>) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return !if_range.starts_with("W/")
+            && etag.is_some_and(|e| !e.starts_with("W/") && e == if_range);
+    }
+    return last_modified == Some(if_range);
+}
+
+
+#[test]
+fn test_parse_range() {
+    assert_eq!(parse_range("bytes=0-499", 1000), ByteRange::Partial(0, 499));
+    assert_eq!(parse_range("bytes=500-", 1000), ByteRange::Partial(500, 999));
+    assert_eq!(parse_range("bytes=900-2000", 1000),
+            ByteRange::Partial(900, 999));
+    assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Partial(900, 999));
+    assert_eq!(parse_range("bytes=-2000", 1000), ByteRange::Partial(0, 999));
+    assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range("bytes=0-1,5-6", 1000), ByteRange::Full);
+    assert_eq!(parse_range("bytes=5-1", 1000), ByteRange::Full);
+    assert_eq!(parse_range("bytes=a-", 1000), ByteRange::Full);
+    assert_eq!(parse_range("bytes=+1-2", 1000), ByteRange::Full);
+    assert_eq!(parse_range("items=0-1", 1000), ByteRange::Full);
+}
+
+#[test]
+fn test_if_range_matches() {
+    let date = "Tue, 10 Oct 2000 13:55:36 GMT";
+    assert!(if_range_matches(date, None, Some(date)));
+    assert!(!if_range_matches(date, None, None));
+    assert!(if_range_matches("\"abc\"", Some("\"abc\""), Some(date)));
+    assert!(!if_range_matches("\"abc\"", Some("\"abd\""), Some(date)));
+    assert!(!if_range_matches("W/\"abc\"", Some("W/\"abc\""), None));
+}
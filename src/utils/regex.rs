@@ -0,0 +1,657 @@
+//! A small regular expression engine, for regex route matching.
+//!
+//! Supports the common subset of Perl/RE2 syntax: literals, `.`, classes
+//! (`[a-z]`, `[^/]`, `\d`, `\w`, `\s` and negations), anchors, alternation,
+//! groups (capturing, `(?:...)` and named `(?P<name>...)` / `(?<name>...)`),
+//! and the `* + ? {n} {n,} {n,m}` quantifiers, greedy or lazy.
+//!
+//! Matching is a backtracking search that never visits the same
+//! (instruction, position) pair twice, so it runs in O(pattern * input) time
+//! no matter how the pattern is written.
+
+use std::fmt;
+
+
+// Limits for counted repetition, to keep programs small
+const MAX_REPEAT: u32 = 1000;
+const MAX_PROGRAM_SIZE: usize = 10_000;
+
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum RegexError {
+    UnexpectedEnd,
+    UnbalancedParen,
+    NothingToRepeat,
+    BadEscape,
+    BadClass,
+    BadRepeat,
+    BadGroupName,
+    TooLarge,
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            RegexError::UnexpectedEnd => "unexpected end of pattern",
+            RegexError::UnbalancedParen => "unbalanced parenthesis",
+            RegexError::NothingToRepeat => "quantifier without a target",
+            RegexError::BadEscape => "unknown escape sequence",
+            RegexError::BadClass => "invalid character class",
+            RegexError::BadRepeat => "invalid repetition count",
+            RegexError::BadGroupName => "invalid group name",
+            RegexError::TooLarge => "pattern too large",
+        };
+        return write!(f, "{}", msg);
+    }
+}
+
+
+#[derive(Clone)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        let found = self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+        return found != self.negated;
+    }
+}
+
+fn perl_class(c: char) -> Option<Class> {
+    let (ranges, negated) = match c {
+        'd' => (vec![('0', '9')], false),
+        'D' => (vec![('0', '9')], true),
+        'w' => (vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')], false),
+        'W' => (vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')], true),
+        's' => (vec![('\t', '\r'), (' ', ' ')], false),
+        'S' => (vec![('\t', '\r'), (' ', ' ')], true),
+        _ => return None,
+    };
+    return Some(Class { ranges: ranges, negated: negated });
+}
+
+fn escaped_char(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        _ if c.is_ascii_alphanumeric() => None,
+        _ => Some(c),
+    }
+}
+
+
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>, bool),
+}
+
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    // Name (if any) of each capture group, group 0 is the whole match
+    names: Vec<Option<String>>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.pos).cloned();
+    }
+
+    fn next(&mut self) -> Result<char, RegexError> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                return Ok(c);
+            }
+            None => return Err(RegexError::UnexpectedEnd),
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+        return false;
+    }
+
+    fn parse_alt(&mut self) -> Result<Node, RegexError> {
+        let mut alts = vec![self.parse_concat()?];
+        while self.eat('|') {
+            alts.push(self.parse_concat()?);
+        }
+        if alts.len() == 1 {
+            return Ok(alts.pop().unwrap());
+        }
+        return Ok(Node::Alt(alts));
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, RegexError> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                _ => items.push(self.parse_repeat()?),
+            }
+        }
+        match items.len() {
+            0 => return Ok(Node::Empty),
+            1 => return Ok(items.pop().unwrap()),
+            _ => return Ok(Node::Concat(items)),
+        }
+    }
+
+    fn parse_repeat(&mut self) -> Result<Node, RegexError> {
+        let mut node = self.parse_atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => { self.pos += 1; (0, None) }
+                Some('+') => { self.pos += 1; (1, None) }
+                Some('?') => { self.pos += 1; (0, Some(1)) }
+                Some('{') => {
+                    self.pos += 1;
+                    self.parse_counts()?
+                }
+                _ => return Ok(node),
+            };
+            let greedy = !self.eat('?');
+            node = Node::Repeat(Box::new(node), min, max, greedy);
+        }
+    }
+
+    // After '{': "n}", "n,}", or "n,m}"
+    fn parse_counts(&mut self) -> Result<(u32, Option<u32>), RegexError> {
+        let min = self.parse_number()?;
+        let max = if self.eat(',') {
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.parse_number()?)
+            }
+        } else {
+            Some(min)
+        };
+        if !self.eat('}') {
+            return Err(RegexError::BadRepeat);
+        }
+        if min > MAX_REPEAT || max.is_some_and(|m| m > MAX_REPEAT || m < min) {
+            return Err(RegexError::BadRepeat);
+        }
+        return Ok((min, max));
+    }
+
+    fn parse_number(&mut self) -> Result<u32, RegexError> {
+        let mut ret: u32 = 0;
+        let mut digits = 0;
+        while let Some(d) = self.peek().and_then(|c| c.to_digit(10)) {
+            self.pos += 1;
+            digits += 1;
+            ret = ret.saturating_mul(10).saturating_add(d);
+        }
+        if digits == 0 {
+            return Err(RegexError::BadRepeat);
+        }
+        return Ok(ret);
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, RegexError> {
+        let c = self.next()?;
+        match c {
+            '(' => return self.parse_group(),
+            '[' => return Ok(Node::Class(self.parse_class()?)),
+            '.' => return Ok(Node::Any),
+            '^' => return Ok(Node::Start),
+            '$' => return Ok(Node::End),
+            '*' | '+' | '?' => return Err(RegexError::NothingToRepeat),
+            '\\' => {
+                let e = self.next()?;
+                if let Some(class) = perl_class(e) {
+                    return Ok(Node::Class(class));
+                }
+                match escaped_char(e) {
+                    Some(lit) => return Ok(Node::Char(lit)),
+                    None => return Err(RegexError::BadEscape),
+                }
+            }
+            _ => return Ok(Node::Char(c)),
+        }
+    }
+
+    // After '('
+    fn parse_group(&mut self) -> Result<Node, RegexError> {
+        let mut index = None;
+        if self.eat('?') {
+            if self.eat(':') {
+                // non-capturing
+            } else if self.eat('<') || (self.eat('P') && self.eat('<')) {
+                let mut name = String::new();
+                loop {
+                    let c = self.next()?;
+                    if c == '>' {
+                        break;
+                    }
+                    if !c.is_ascii_alphanumeric() && c != '_' {
+                        return Err(RegexError::BadGroupName);
+                    }
+                    name.push(c);
+                }
+                if name.is_empty() || self.names.iter().any(
+                        |n| n.as_ref() == Some(&name)) {
+                    return Err(RegexError::BadGroupName);
+                }
+                index = Some(self.names.len());
+                self.names.push(Some(name));
+            } else {
+                return Err(RegexError::BadGroupName);
+            }
+        } else {
+            index = Some(self.names.len());
+            self.names.push(None);
+        }
+        let inner = self.parse_alt()?;
+        if !self.eat(')') {
+            return Err(RegexError::UnbalancedParen);
+        }
+        return Ok(Node::Group(Box::new(inner), index));
+    }
+
+    // After '['
+    fn parse_class(&mut self) -> Result<Class, RegexError> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.next()?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = if c == '\\' {
+                let e = self.next()?;
+                if let Some(class) = perl_class(e) {
+                    if class.negated {
+                        return Err(RegexError::BadClass);
+                    }
+                    ranges.extend(class.ranges);
+                    continue;
+                }
+                match escaped_char(e) {
+                    Some(lit) => lit,
+                    None => return Err(RegexError::BadEscape),
+                }
+            } else {
+                c
+            };
+            // A range, unless the '-' is last in the class
+            if self.peek() == Some('-')
+                    && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let mut hi = self.next()?;
+                if hi == '\\' {
+                    hi = match escaped_char(self.next()?) {
+                        Some(lit) => lit,
+                        None => return Err(RegexError::BadClass),
+                    };
+                }
+                if hi < lo {
+                    return Err(RegexError::BadClass);
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        return Ok(Class { ranges: ranges, negated: negated });
+    }
+}
+
+
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    // Try the first branch, backtrack to the second
+    Split(usize, usize),
+    Jmp(usize),
+    Save(usize),
+    Match,
+}
+
+
+struct Compiler {
+    prog: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, RegexError> {
+        if self.prog.len() >= MAX_PROGRAM_SIZE {
+            return Err(RegexError::TooLarge);
+        }
+        self.prog.push(inst);
+        return Ok(self.prog.len() - 1);
+    }
+
+    fn pc(&self) -> usize {
+        return self.prog.len();
+    }
+
+    fn emit(&mut self, node: &Node) -> Result<(), RegexError> {
+        match *node {
+            Node::Empty => (),
+            Node::Char(c) => { self.push(Inst::Char(c))?; }
+            Node::Any => { self.push(Inst::Any)?; }
+            Node::Class(ref class) => { self.push(Inst::Class(class.clone()))?; }
+            Node::Start => { self.push(Inst::Start)?; }
+            Node::End => { self.push(Inst::End)?; }
+            Node::Group(ref inner, index) => {
+                if let Some(i) = index {
+                    self.push(Inst::Save(2 * i))?;
+                }
+                self.emit(inner)?;
+                if let Some(i) = index {
+                    self.push(Inst::Save(2 * i + 1))?;
+                }
+            }
+            Node::Concat(ref items) => {
+                for item in items.iter() {
+                    self.emit(item)?;
+                }
+            }
+            Node::Alt(ref alts) => {
+                let mut jumps = Vec::new();
+                for (i, alt) in alts.iter().enumerate() {
+                    if i + 1 < alts.len() {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.emit(alt)?;
+                        jumps.push(self.push(Inst::Jmp(0))?);
+                        let next = self.pc();
+                        self.prog[split] = Inst::Split(split + 1, next);
+                    } else {
+                        self.emit(alt)?;
+                    }
+                }
+                let end = self.pc();
+                for j in jumps {
+                    self.prog[j] = Inst::Jmp(end);
+                }
+            }
+            Node::Repeat(ref inner, min, max, greedy) => {
+                for _ in 0..min {
+                    self.emit(inner)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.emit(inner)?;
+                        self.push(Inst::Jmp(split))?;
+                        let out = self.pc();
+                        self.prog[split] = branch(split + 1, out, greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in min..max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.emit(inner)?;
+                        }
+                        let out = self.pc();
+                        for split in splits {
+                            self.prog[split] = branch(split + 1, out, greedy);
+                        }
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn branch(body: usize, out: usize, greedy: bool) -> Inst {
+    if greedy {
+        return Inst::Split(body, out);
+    }
+    return Inst::Split(out, body);
+}
+
+
+/// A compiled regular expression
+pub struct Regex {
+    prog: Vec<Inst>,
+    names: Vec<Option<String>>,
+}
+
+/// Capture groups from a successful match
+pub struct Captures<'t> {
+    text: &'t str,
+    // Byte offsets, (start, end) for each group
+    slots: Vec<Option<usize>>,
+    names: &'t [Option<String>],
+}
+
+impl<'t> Captures<'t> {
+    /// Text matched by group `i`.  Group 0 is the whole match.
+    pub fn get(&self, i: usize) -> Option<&'t str> {
+        match (self.slots.get(2 * i), self.slots.get(2 * i + 1)) {
+            (Some(&Some(start)), Some(&Some(end))) =>
+                return Some(&self.text[start..end]),
+            _ => return None,
+        }
+    }
+
+    /// (name, text) for each named group that participated in the match
+    pub fn named(&self) -> Vec<(&'t str, &'t str)> {
+        let mut ret = Vec::new();
+        for (i, n) in self.names.iter().enumerate() {
+            if let (Some(name), Some(val)) = (n.as_ref(), self.get(i)) {
+                ret.push((name.as_str(), val));
+            }
+        }
+        return ret;
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, RegexError> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            names: vec![None],
+        };
+        let node = parser.parse_alt()?;
+        if parser.pos != parser.chars.len() {
+            // Only a stray ')' stops the top level parse early
+            return Err(RegexError::UnbalancedParen);
+        }
+        let mut compiler = Compiler { prog: Vec::new() };
+        compiler.emit(&Node::Group(Box::new(node), Some(0)))?;
+        compiler.push(Inst::Match)?;
+        return Ok(Regex { prog: compiler.prog, names: parser.names });
+    }
+
+    /// Find the leftmost match in `text`
+    pub fn captures<'t>(&'t self, text: &'t str) -> Option<Captures<'t>> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut offsets: Vec<usize> = text.char_indices()
+            .map(|(i, _)| i).collect();
+        offsets.push(text.len());
+
+        let n = chars.len();
+        let mut visited = vec![false; self.prog.len() * (n + 1)];
+        let mut slots = vec![None; 2 * self.names.len()];
+        for start in 0..(n + 1) {
+            if self.exec(&chars, start, &mut visited, &mut slots) {
+                let slots = slots.iter()
+                    .map(|s| s.map(|pos| offsets[pos])).collect();
+                return Some(Captures {
+                    text: text,
+                    slots: slots,
+                    names: &self.names,
+                });
+            }
+            if let Some(&Inst::Start) = self.prog.get(1) {
+                // Anchored, other start positions can't match
+                break;
+            }
+        }
+        return None;
+    }
+
+    // Whether a state succeeds depends only on (pc, pos), so the visited set
+    // stays valid across start positions.
+    fn exec(&self, input: &[char], start: usize, visited: &mut [bool],
+            slots: &mut [Option<usize>]) -> bool {
+        enum Job {
+            Run(usize, usize),
+            Restore(usize, Option<usize>),
+        }
+        let n = input.len();
+        let mut stack = vec![Job::Run(0, start)];
+        while let Some(job) = stack.pop() {
+            let (mut pc, mut pos) = match job {
+                Job::Run(pc, pos) => (pc, pos),
+                Job::Restore(slot, val) => {
+                    slots[slot] = val;
+                    continue;
+                }
+            };
+            loop {
+                let key = pc * (n + 1) + pos;
+                if visited[key] {
+                    break;
+                }
+                visited[key] = true;
+                match self.prog[pc] {
+                    Inst::Char(c) => {
+                        if pos < n && input[pos] == c {
+                            pc += 1;
+                            pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    Inst::Any => {
+                        if pos < n {
+                            pc += 1;
+                            pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    Inst::Class(ref class) => {
+                        if pos < n && class.matches(input[pos]) {
+                            pc += 1;
+                            pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    Inst::Start => {
+                        if pos != 0 { break; }
+                        pc += 1;
+                    }
+                    Inst::End => {
+                        if pos != n { break; }
+                        pc += 1;
+                    }
+                    Inst::Split(x, y) => {
+                        stack.push(Job::Run(y, pos));
+                        pc = x;
+                    }
+                    Inst::Jmp(x) => pc = x,
+                    Inst::Save(slot) => {
+                        stack.push(Job::Restore(slot, slots[slot]));
+                        slots[slot] = Some(pos);
+                        pc += 1;
+                    }
+                    Inst::Match => return true,
+                }
+            }
+        }
+        return false;
+    }
+}
+
+
+#[cfg(test)]
+fn find(pattern: &str, text: &str) -> Option<String> {
+    let re = Regex::new(pattern).unwrap();
+    return re.captures(text).map(|c| c.get(0).unwrap().to_string());
+}
+
+#[test]
+fn test_regex_basic() {
+    assert_eq!(find("abc", "xxabcxx").unwrap(), "abc");
+    assert_eq!(find("a.c", "abc").unwrap(), "abc");
+    assert_eq!(find("^abc$", "abc").unwrap(), "abc");
+    assert!(find("^abc$", "abcd").is_none());
+    assert!(find("^bc", "abc").is_none());
+    assert_eq!(find("a|bc|d", "xbcx").unwrap(), "bc");
+    assert_eq!(find("colou?r", "my color").unwrap(), "color");
+    assert_eq!(find("\\d+", "abc 123 def").unwrap(), "123");
+    assert_eq!(find("[a-c]+", "xxabcabd").unwrap(), "abcab");
+    assert_eq!(find("[^/]+", "/foo/bar").unwrap(), "foo");
+    assert_eq!(find("[\\w-]+", "--a_b-c!").unwrap(), "--a_b-c");
+    assert_eq!(find("a\\.b", "axb a.b").unwrap(), "a.b");
+    assert_eq!(find("\\/x", "/x").unwrap(), "/x");
+    assert_eq!(find("\u{03A6}+", "a\u{03A6}\u{03A6}b").unwrap(),
+            "\u{03A6}\u{03A6}");
+}
+
+#[test]
+fn test_regex_repeat() {
+    assert_eq!(find("a{3}", "aaaa").unwrap(), "aaa");
+    assert_eq!(find("a{2,}", "aaaa").unwrap(), "aaaa");
+    assert_eq!(find("a{1,2}", "aaaa").unwrap(), "aa");
+    assert_eq!(find("a+?", "aaaa").unwrap(), "a");
+    assert_eq!(find("<.*>", "<a><b>").unwrap(), "<a><b>");
+    assert_eq!(find("<.*?>", "<a><b>").unwrap(), "<a>");
+    assert_eq!(find("(a*)*b", "aaab").unwrap(), "aaab");
+    // Pathological for naive backtracking
+    let text = "a".repeat(200);
+    assert!(find("^(a|a)*(a|a)*(a|a)*b$", &text).is_none());
+}
+
+#[test]
+fn test_regex_captures() {
+    let re = Regex::new("^/posts/(?P<year>\\d{4})/(?<slug>[^/]+)(/)?$")
+        .unwrap();
+    let caps = re.captures("/posts/2015/hello").unwrap();
+    assert_eq!(caps.get(1).unwrap(), "2015");
+    assert_eq!(caps.get(2).unwrap(), "hello");
+    assert!(caps.get(3).is_none());
+    assert_eq!(caps.named(), vec![("year", "2015"), ("slug", "hello")]);
+    assert!(re.captures("/posts/15/hello").is_none());
+
+    let re = Regex::new("(?:x(y))+").unwrap();
+    assert_eq!(re.captures("xyxy").unwrap().get(1).unwrap(), "y");
+}
+
+#[test]
+fn test_regex_errors() {
+    assert_eq!(Regex::new("(abc").err().unwrap(), RegexError::UnbalancedParen);
+    assert_eq!(Regex::new("abc)").err().unwrap(), RegexError::UnbalancedParen);
+    assert_eq!(Regex::new("*a").err().unwrap(), RegexError::NothingToRepeat);
+    assert_eq!(Regex::new("[abc").err().unwrap(), RegexError::UnexpectedEnd);
+    assert_eq!(Regex::new("[z-a]").err().unwrap(), RegexError::BadClass);
+    assert_eq!(Regex::new("\\q").err().unwrap(), RegexError::BadEscape);
+    assert_eq!(Regex::new("a{2,1}").err().unwrap(), RegexError::BadRepeat);
+    assert_eq!(Regex::new("a{x}").err().unwrap(), RegexError::BadRepeat);
+    assert_eq!(Regex::new("(?P<a>x)(?P<a>y)").err().unwrap(),
+            RegexError::BadGroupName);
+    assert_eq!(Regex::new("(x{1000}){1000}").err().unwrap(),
+            RegexError::TooLarge);
+}
@@ -0,0 +1,122 @@
+//! SHA-1 (FIPS 180-4).  Not for new security designs; needed by htpasswd
+//! {SHA} entries and the WebSocket handshake.
+
+pub struct Sha1 {
+    state: [u32; 5],
+    buf: Vec<u8>,
+    len: u64,
+}
+
+impl Default for Sha1 {
+    fn default() -> Sha1 {
+        return Sha1::new();
+    }
+}
+
+impl Sha1 {
+    pub fn new() -> Sha1 {
+        return Sha1 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            buf: Vec::with_capacity(64),
+            len: 0,
+        };
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.buf.is_empty() {
+            let n = (64 - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() < 64 {
+                return;
+            }
+            let block = self.buf.clone();
+            self.compress(&block);
+            self.buf.clear();
+        }
+        while data.len() >= 64 {
+            self.compress(&data[..64]);
+            data = &data[64..];
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn finish(mut self) -> [u8; 20] {
+        let bits = self.len.wrapping_mul(8);
+        let mut pad = vec![0x80u8];
+        while (self.buf.len() + pad.len()) % 64 != 56 {
+            pad.push(0);
+        }
+        pad.extend_from_slice(&bits.to_be_bytes());
+        let len = self.len;
+        self.update(&pad);
+        self.len = len;
+
+        let mut ret = [0u8; 20];
+        for (i, word) in self.state.iter().enumerate() {
+            ret[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        return ret;
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1],
+                block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e)
+                .wrapping_add(k).wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        let add = [a, b, c, d, e];
+        for (s, x) in self.state.iter_mut().zip(add.iter()) {
+            *s = s.wrapping_add(*x);
+        }
+    }
+}
+
+
+/// SHA-1 digest of `data`
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h = Sha1::new();
+    h.update(data);
+    return h.finish();
+}
+
+
+#[cfg(test)]
+fn hex(d: &[u8]) -> String {
+    return d.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+#[test]
+fn test_sha1() {
+    assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(hex(&sha1(
+        b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+        "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    let mut h = Sha1::new();
+    for _ in 0..1000 {
+        h.update(&[b'a'; 1000]);
+    }
+    assert_eq!(hex(&h.finish()), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+}
@@ -0,0 +1,221 @@
+//! Calendar conversion and timestamp formatting (UTC only)
+
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+static MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+    "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+static WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+// The current time as an HTTP date, and its second
+static NOW: RwLock<(i64, String)> = RwLock::new((i64::MIN, String::new()));
+
+
+/// A broken down UTC time
+#[derive(Debug, PartialEq)]
+pub struct DateTime {
+    pub year: i64,
+    /// 1 - 12
+    pub month: u32,
+    /// 1 - 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// 0 = Sunday
+    pub weekday: u32,
+}
+
+
+/// Seconds since the epoch (negative before 1970)
+pub fn to_unix(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => return d.as_secs() as i64,
+        Err(e) => return -(e.duration().as_secs() as i64),
+    }
+}
+
+
+/// Convert seconds since the epoch to a calendar date and time.
+///
+/// Uses the days-to-civil algorithm from
+/// http://howardhinnant.github.io/date_algorithms.html
+pub fn from_unix(secs: i64) -> DateTime {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400) as u32;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return DateTime {
+        year: year,
+        month: month,
+        day: day,
+        hour: rem / 3600,
+        minute: rem / 60 % 60,
+        second: rem % 60,
+        weekday: (days + 4).rem_euclid(7) as u32,
+    };
+}
+
+
+/// Seconds since the epoch of a UTC date and time; the inverse of
+/// `from_unix` (the weekday is ignored)
+pub fn to_unix_secs(dt: &DateTime) -> i64 {
+    let year = if dt.month <= 2 { dt.year - 1 } else { dt.year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if dt.month > 2 { dt.month - 3 } else { dt.month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + dt.day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    return days * 86400 + (dt.hour * 3600 + dt.minute * 60 + dt.second) as i64;
+}
+
+
+/// Common Log Format timestamp, ex: "10/Oct/2000:13:55:36 +0000"
+pub fn format_clf(t: SystemTime) -> String {
+    let dt = from_unix(to_unix(t));
+    return format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        dt.day, MONTHS[dt.month as usize - 1], dt.year,
+        dt.hour, dt.minute, dt.second);
+}
+
+
+/// ISO 8601 / RFC 3339 timestamp, ex: "2000-10-10T13:55:36Z"
+pub fn format_iso8601(t: SystemTime) -> String {
+    let dt = from_unix(to_unix(t));
+    return format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second);
+}
+
+
+/// HTTP date (RFC 7231 IMF-fixdate), ex: "Tue, 10 Oct 2000 13:55:36 GMT"
+pub fn format_http_date(t: SystemTime) -> String {
+    let dt = from_unix(to_unix(t));
+    return format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[dt.weekday as usize], dt.day, MONTHS[dt.month as usize - 1],
+        dt.year, dt.hour, dt.minute, dt.second);
+}
+
+
+/// Call `f` with the current time as an HTTP date, for the Date of a
+/// response.  It's made once a second, and shared by all threads.
+pub fn with_http_date_now<F: FnOnce(&str) -> R, R>(f: F) -> R {
+    let now = SystemTime::now();
+    let secs = to_unix(now);
+    {
+        let cached = match NOW.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if cached.0 == secs {
+            return f(&cached.1);
+        }
+    }
+    let mut cached = match NOW.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    // Unless another thread just did
+    if cached.0 != secs {
+        *cached = (secs, format_http_date(now));
+    }
+    return f(&cached.1);
+}
+
+
+/// Parse an HTTP date in the IMF-fixdate format (what `format_http_date`
+/// makes, and what clients send nowadays) to seconds since the epoch.
+/// The obsolete RFC 850 and asctime formats aren't supported.
+pub fn parse_http_date(s: &str) -> Option<i64> {
+    // "Tue, 10 Oct 2000 13:55:36 GMT"
+    let parts: Vec<&str> = s.trim().split(' ').collect();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+    let weekday = parts[0].strip_suffix(',')?;
+    let weekday = WEEKDAYS.iter().position(|&d| d == weekday)? as u32;
+    let month = MONTHS.iter().position(|&m| m == parts[2])? as u32 + 1;
+    let number = |s: &str, digits: usize| -> Option<u32> {
+        if s.len() != digits || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        return s.parse().ok();
+    };
+    let time: Vec<&str> = parts[4].split(':').collect();
+    if time.len() != 3 {
+        return None;
+    }
+    let dt = DateTime {
+        year: number(parts[3], 4)? as i64,
+        month: month,
+        day: number(parts[1], 2)?,
+        hour: number(time[0], 2)?,
+        minute: number(time[1], 2)?,
+        second: number(time[2], 2)?,
+        weekday: weekday,
+    };
+    if dt.day == 0 || dt.day > 31 || dt.hour > 23 || dt.minute > 59
+            || dt.second > 60 {
+        return None;
+    }
+    let secs = to_unix_secs(&dt);
+    // Catches days past the end of the month, and wrong weekdays
+    if from_unix(secs).day != dt.day || from_unix(secs).weekday != weekday {
+        return None;
+    }
+    return Some(secs);
+}
+
+
+#[test]
+fn test_from_unix() {
+    assert_eq!(from_unix(0), DateTime { year: 1970, month: 1, day: 1,
+        hour: 0, minute: 0, second: 0, weekday: 4 });
+    assert_eq!(from_unix(971186136), DateTime { year: 2000, month: 10,
+        day: 10, hour: 13, minute: 55, second: 36, weekday: 2 });
+    // Leap day
+    assert_eq!(from_unix(951782400), DateTime { year: 2000, month: 2,
+        day: 29, hour: 0, minute: 0, second: 0, weekday: 2 });
+    assert_eq!(from_unix(-1), DateTime { year: 1969, month: 12, day: 31,
+        hour: 23, minute: 59, second: 59, weekday: 3 });
+}
+
+#[test]
+fn test_format_clf() {
+    use std::time::Duration;
+    let t = UNIX_EPOCH + Duration::from_secs(971186136);
+    assert_eq!(format_clf(t), "10/Oct/2000:13:55:36 +0000");
+    assert_eq!(format_iso8601(t), "2000-10-10T13:55:36Z");
+    assert_eq!(format_http_date(t), "Tue, 10 Oct 2000 13:55:36 GMT");
+}
+
+#[test]
+fn test_to_unix_secs() {
+    for &secs in [0, 971186136, 951782400, -1, -62135596800].iter() {
+        assert_eq!(to_unix_secs(&from_unix(secs)), secs);
+    }
+}
+
+#[test]
+fn test_parse_http_date() {
+    assert_eq!(parse_http_date("Tue, 10 Oct 2000 13:55:36 GMT"),
+            Some(971186136));
+    assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+    // Wrong weekday, no such day, not GMT, obsolete formats
+    assert_eq!(parse_http_date("Wed, 10 Oct 2000 13:55:36 GMT"), None);
+    assert_eq!(parse_http_date("Thu, 31 Feb 2000 00:00:00 GMT"), None);
+    assert_eq!(parse_http_date("Tue, 10 Oct 2000 13:55:36 +0000"), None);
+    assert_eq!(parse_http_date("Tuesday, 10-Oct-00 13:55:36 GMT"), None);
+    assert_eq!(parse_http_date("Tue Oct 10 13:55:36 2000"), None);
+    assert_eq!(parse_http_date("Tue, 10 Oct 2000 1:55:36 GMT"), None);
+}
@@ -0,0 +1,593 @@
+//! Access logging in Common / Combined Log Format, or JSON lines
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use utils::time;
+use utils::escape::json_escape;
+use super::{WebRequest, WebResponse, ConnInfo};
+#[cfg(test)]
+use super::slow_log::Timings;
+
+// Rotated logs kept by default
+static DEFAULT_KEEP: usize = 7;
+
+
+/// Access log line format
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `host ident user [time] "request" status bytes`
+    Common,
+    /// Common, plus `"referer" "user-agent"`
+    Combined,
+    /// Combined, plus the time taken in microseconds (like Apache's `%D`)
+    CombinedLatency,
+    /// One JSON object per line, with the given fields in order.  Values
+    /// that aren't known (e.g. headers for an unparseable request) are null.
+    /// See `LogFormat::json`.
+    Json(Vec<LogField>),
+}
+
+impl LogFormat {
+    /// JSON lines with the same data as `CombinedLatency`, plus the Host
+    /// header and the "request_id" extra (see `LogExtras`).
+    pub fn json() -> LogFormat {
+        return LogFormat::Json(vec![
+            LogField::Time,
+            LogField::RemoteAddr,
+            LogField::Host,
+            LogField::Method,
+            LogField::Uri,
+            LogField::Protocol,
+            LogField::Status,
+            LogField::Bytes,
+            LogField::Referer,
+            LogField::UserAgent,
+            LogField::LatencyMicros,
+            LogField::Extra("request_id".to_string()),
+        ]);
+    }
+}
+
+
+/// A field of a `LogFormat::Json` line.  The JSON key is shown in quotes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogField {
+    /// "time": ISO 8601 UTC time the connection was accepted
+    Time,
+    /// "remote_addr": client IP
+    RemoteAddr,
+    /// "host": Host header, i.e. the virtual host
+    Host,
+    /// "method": uppercase method
+    Method,
+    /// "uri": request target as sent
+    Uri,
+    /// "protocol": ex: "HTTP/1.1"
+    Protocol,
+    /// "status": number
+    Status,
+    /// "bytes": body bytes sent, number
+    Bytes,
+    /// "referer"
+    Referer,
+    /// "user_agent"
+    UserAgent,
+    /// "latency_us": microseconds from accept until the response was sent
+    LatencyMicros,
+    /// Any request header, by name.  The key is the lowercase name.
+    Header(String),
+    /// A value stored in the request's `LogExtras` under this name, e.g. by
+    /// middleware.  The key is the name.
+    Extra(String),
+}
+
+
+/// Extra values for the access log, stored in the request extensions.
+///
+/// Middleware and handlers can attach data that `LogFormat::Json` picks up
+/// with `LogField::Extra(name)`: `RequestId` sets "request_id", and
+/// `ReverseProxy` "upstream_time_us" and "upstream_retries".
+#[derive(Clone, Debug, Default)]
+pub struct LogExtras {
+    fields: Vec<(String, String)>,
+}
+
+impl LogExtras {
+    /// Set `name` to `value`, replacing any previous value
+    pub fn set(&mut self, name: &str, value: &str) {
+        for field in self.fields.iter_mut() {
+            if field.0 == name {
+                field.1 = value.to_string();
+                return;
+            }
+        }
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        for (k, v) in self.fields.iter() {
+            if k == name {
+                return Some(v);
+            }
+        }
+        return None;
+    }
+}
+
+
+/// Writes one line per response to a file or any `Write`.
+///
+/// The line is written after the response has been sent, so the byte count is
+/// what actually went out and the latency covers reading the request,
+/// running the handler, and writing the response.
+///
+/// A log opened from a path can be reopened, for logrotate, or rotated by
+/// size or age.  Clones write to the same log, so one kept after giving
+/// the log to the server can reopen it later:
+///
+/// ```ignore
+/// let mut log = AccessLog::open("access.log", LogFormat::Combined)?;
+/// log.set_max_size(100_000_000);
+/// log.set_keep(3);
+/// server.set_access_log(log.clone());
+/// // ... and once logrotate moved the file, on SIGHUP:
+/// log.reopen()?;
+/// ```
+#[derive(Clone)]
+pub struct AccessLog {
+    format: LogFormat,
+    out: Arc<Mutex<Output>>,
+}
+
+struct Output {
+    writer: Box<dyn Write + Send>,
+    // For logs opened from a path
+    file: Option<LogFile>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    size: u64,
+    opened: Instant,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+}
+
+impl AccessLog {
+    pub fn new<W: Write + Send + 'static>(out: W, format: LogFormat)
+            -> AccessLog {
+        return AccessLog {
+            format: format,
+            out: Arc::new(Mutex::new(Output {
+                writer: Box::new(out),
+                file: None,
+            })),
+        };
+    }
+
+    /// Append to the file at `path`, creating it if needed.
+    pub fn open(path: &str, format: LogFormat) -> io::Result<AccessLog> {
+        let (file, size) = open_append(path.as_ref())?;
+        let log = AccessLog::new(file, format);
+        log.output().file = Some(LogFile {
+            path: PathBuf::from(path),
+            size: size,
+            opened: Instant::now(),
+            max_size: None,
+            max_age: None,
+            keep: DEFAULT_KEEP,
+        });
+        return Ok(log);
+    }
+
+    /// Rotate the log before it grows over `bytes`.  Only for logs opened
+    /// from a path, like the other rotation settings.
+    pub fn set_max_size(&mut self, bytes: u64) {
+        if let Some(ref mut file) = self.output().file {
+            file.max_size = Some(bytes);
+        }
+    }
+
+    /// Rotate the log once it was written to for `age`, counted from when
+    /// it was opened.
+    pub fn set_max_age(&mut self, age: Duration) {
+        if let Some(ref mut file) = self.output().file {
+            file.max_age = Some(age);
+        }
+    }
+
+    /// How many rotated logs to keep, as `path.1` (the newest) to
+    /// `path.N`.  Default is 7.
+    pub fn set_keep(&mut self, keep: usize) {
+        if let Some(ref mut file) = self.output().file {
+            file.keep = keep;
+        }
+    }
+
+    /// Open the log's path again, to write to a new file once the old
+    /// one was moved away.  Logs not opened from a path are left alone.
+    pub fn reopen(&self) -> io::Result<()> {
+        let mut out = self.output();
+        return out.reopen();
+    }
+
+    /// Move the log to `path.1` (and older ones up, dropping those past
+    /// `set_keep`), and start a new one.
+    pub fn rotate(&self) -> io::Result<()> {
+        let mut out = self.output();
+        return out.rotate();
+    }
+
+    // NB: A poisoned lock just means a panic while holding it; the
+    // writer itself is still usable.
+    fn output(&self) -> MutexGuard<'_, Output> {
+        match self.out.lock() {
+            Ok(guard) => return guard,
+            Err(poisoned) => return poisoned.into_inner(),
+        }
+    }
+
+    /// Write the line for one response.  Errors writing the log are ignored,
+    /// a full disk shouldn't take the site down.
+    pub(crate) fn log(&self, req: Option<&WebRequest>, resp: &WebResponse,
+            conn: &ConnInfo, body_bytes: usize) {
+        let line = self.format_line(req, resp, conn, body_bytes);
+        let mut out = self.output();
+        if out.due(line.len() as u64) {
+            let _ = out.rotate();
+        }
+        if out.writer.write_all(line.as_bytes()).is_ok() {
+            if let Some(ref mut file) = out.file {
+                file.size += line.len() as u64;
+            }
+        }
+        let _ = out.writer.flush();
+    }
+
+    fn format_line(&self, req: Option<&WebRequest>, resp: &WebResponse,
+            conn: &ConnInfo, body_bytes: usize) -> String {
+        if let LogFormat::Json(ref fields) = self.format {
+            return format_json(fields, req, resp, conn, body_bytes);
+        }
+        let request_line = match req {
+            Some(req) => format!("{} {} {}",
+                req.get_method().to_ascii_uppercase(),
+                escape(environ_str(req, "request_uri")),
+                environ_str(req, "protocol").to_ascii_uppercase()),
+            None => "-".to_string(),
+        };
+        let bytes = if body_bytes == 0 {
+            "-".to_string()
+        } else {
+            body_bytes.to_string()
+        };
+        let mut line = format!("{} - - [{}] \"{}\" {} {}",
+            conn.peer_addr.ip(), time::format_clf(conn.start_time),
+            request_line, resp.code, bytes);
+
+        if self.format != LogFormat::Common {
+            let (referer, agent) = match req {
+                Some(req) => (header_or_dash(req, "http_referer"),
+                              header_or_dash(req, "http_user-agent")),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            line.push_str(&format!(" \"{}\" \"{}\"", referer, agent));
+        }
+        if self.format == LogFormat::CombinedLatency {
+            line.push_str(&format!(" {}", micros(conn.start.elapsed())));
+        }
+        line.push('\n');
+        return line;
+    }
+}
+
+
+fn format_json(fields: &[LogField], req: Option<&WebRequest>,
+        resp: &WebResponse, conn: &ConnInfo, body_bytes: usize) -> String {
+    let mut line = String::from("{");
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        let (key, value) = match *field {
+            LogField::Time =>
+                ("time".to_string(),
+                 Some(time::format_iso8601(conn.start_time))),
+            LogField::RemoteAddr =>
+                ("remote_addr".to_string(),
+                 Some(conn.peer_addr.ip().to_string())),
+            LogField::Host =>
+                ("host".to_string(), req.and_then(|r| header(r, "host"))),
+            LogField::Method =>
+                ("method".to_string(),
+                 req.map(|r| r.get_method().to_ascii_uppercase())),
+            LogField::Uri =>
+                ("uri".to_string(),
+                 req.map(|r| environ_str(r, "request_uri"))),
+            LogField::Protocol =>
+                ("protocol".to_string(),
+                 req.map(|r| environ_str(r, "protocol").to_ascii_uppercase())),
+            LogField::Referer =>
+                ("referer".to_string(), req.and_then(|r| header(r, "referer"))),
+            LogField::UserAgent =>
+                ("user_agent".to_string(),
+                 req.and_then(|r| header(r, "user-agent"))),
+            LogField::Header(ref name) => {
+                let name = name.to_ascii_lowercase();
+                let value = req.and_then(|r| header(r, &name));
+                (name, value)
+            }
+            LogField::Extra(ref name) => {
+                let value = req
+                    .and_then(|r| r.get_extensions().get::<LogExtras>())
+                    .and_then(|extras| extras.get(name))
+                    .map(|v| v.to_string());
+                (name.clone(), value)
+            }
+            // Numbers
+            LogField::Status => {
+                line.push_str(&format!("\"status\":{}", resp.code));
+                continue;
+            }
+            LogField::Bytes => {
+                line.push_str(&format!("\"bytes\":{}", body_bytes));
+                continue;
+            }
+            LogField::LatencyMicros => {
+                line.push_str(&format!("\"latency_us\":{}",
+                        micros(conn.start.elapsed())));
+                continue;
+            }
+        };
+        line.push_str(&format!("\"{}\":", json_escape(&key)));
+        match value {
+            Some(v) => line.push_str(&format!("\"{}\"", json_escape(&v))),
+            None => line.push_str("null"),
+        }
+    }
+    line.push_str("}\n");
+    return line;
+}
+
+
+fn header(req: &WebRequest, name: &str) -> Option<String> {
+    let key = format!("http_{}", name);
+    return req.environ.get(key.as_bytes())
+        .map(|v| String::from_utf8_lossy(v).into_owned());
+}
+
+fn environ_str(req: &WebRequest, key: &str) -> String {
+    match req.environ.get(key.as_bytes()) {
+        Some(val) => return String::from_utf8_lossy(val).into_owned(),
+        None => return String::new(),
+    }
+}
+
+fn header_or_dash(req: &WebRequest, key: &str) -> String {
+    match req.environ.get(key.as_bytes()) {
+        Some(val) => return escape(String::from_utf8_lossy(val).into_owned()),
+        None => return "-".to_string(),
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    return d.as_secs() * 1_000_000 + d.subsec_micros() as u64;
+}
+
+// Escape quotes, backslashes and control characters, so a client can't
+// forge log lines.  Same convention as Apache.
+fn escape(s: String) -> String {
+    if !s.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+        return s;
+    }
+    let mut ret = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            _ if c.is_control() => ret.push_str(&format!("\\x{:02x}", c as u32)),
+            _ => ret.push(c),
+        }
+    }
+    return ret;
+}
+
+// A Write that tests can read back
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct SharedBuf(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    // Whether writing `len` more bytes should rotate the log first
+    fn due(&self, len: u64) -> bool {
+        let file = match self.file {
+            Some(ref file) if file.size > 0 => file,
+            _ => return false,
+        };
+        let too_large = file.max_size.map(|max| file.size + len > max);
+        let too_old = file.max_age.map(|max| file.opened.elapsed() >= max);
+        return too_large == Some(true) || too_old == Some(true);
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Ok(()),
+        };
+        let (writer, size) = open_append(&file.path)?;
+        self.writer = Box::new(writer);
+        file.size = size;
+        file.opened = Instant::now();
+        return Ok(());
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let (path, keep) = match self.file {
+            Some(ref file) => (file.path.clone(), file.keep),
+            None => return Ok(()),
+        };
+        let numbered = |n: usize| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            return PathBuf::from(name);
+        };
+        if keep == 0 {
+            fs::remove_file(&path)?;
+        } else {
+            for n in (1..keep).rev() {
+                if let Err(e) = fs::rename(numbered(n), numbered(n + 1)) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e);
+                    }
+                }
+            }
+            fs::rename(&path, numbered(1))?;
+        }
+        return self.reopen();
+    }
+}
+
+// The file, and its size so far
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    return Ok((file, size));
+}
+
+
+#[cfg(test)]
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend(buf);
+        return Ok(buf.len());
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[test]
+fn test_access_log_formats() {
+    use std::time::{Instant, UNIX_EPOCH};
+    let conn = ConnInfo {
+        peer_addr: "10.1.2.3:5555".parse().unwrap(),
+        start: Instant::now(),
+        start_time: UNIX_EPOCH + Duration::from_secs(971186136),
+        timings: Timings::default(),
+    };
+    let req = WebRequest::parse_for_test("GET /a%20b?q=\"x\" HTTP/1.1\r\n\
+            Referer: http://x/\r\nUser-Agent: t\r\n\r\n");
+    let mut resp = WebResponse::new();
+    resp.set_code(404, "Not Found");
+
+    let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+    let log = AccessLog::new(buf.clone(), LogFormat::Common);
+    log.log(Some(&req), &resp, &conn, 1234);
+    log.log(None, &resp, &conn, 0);
+    let log = AccessLog::new(buf.clone(), LogFormat::Combined);
+    log.log(Some(&req), &resp, &conn, 1234);
+    let log = AccessLog::new(buf.clone(), LogFormat::CombinedLatency);
+    log.log(None, &resp, &conn, 1);
+
+    let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "10.1.2.3 - - [10/Oct/2000:13:55:36 +0000] \
+        \"GET /a%20b?q=\\\"x\\\" HTTP/1.1\" 404 1234");
+    assert_eq!(lines[1], "10.1.2.3 - - [10/Oct/2000:13:55:36 +0000] \
+        \"-\" 404 -");
+    assert!(lines[2].ends_with(" 404 1234 \"http://x/\" \"t\""));
+    assert!(lines[3].starts_with("10.1.2.3 - - [10/Oct/2000:13:55:36 +0000] \
+        \"-\" 404 1 \"-\" \"-\" "));
+}
+
+#[test]
+fn test_access_log_json() {
+    use std::time::{Instant, UNIX_EPOCH};
+    let conn = ConnInfo {
+        peer_addr: "[::1]:5555".parse().unwrap(),
+        start: Instant::now(),
+        start_time: UNIX_EPOCH + Duration::from_secs(971186136),
+        timings: Timings::default(),
+    };
+    let mut req = WebRequest::parse_for_test("GET /x HTTP/1.0\r\n\
+            Host: example.com\r\nUser-Agent: \"t\"\r\nX-A: 1\r\n\r\n");
+    req.get_extensions_mut().insert(LogExtras::default());
+    req.get_extensions_mut().get_mut::<LogExtras>().unwrap()
+        .set("request_id", "abc");
+    let resp = WebResponse::new();
+
+    let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+    let log = AccessLog::new(buf.clone(), LogFormat::Json(vec![
+        LogField::Time, LogField::RemoteAddr, LogField::Host, LogField::Method,
+        LogField::Uri, LogField::Protocol, LogField::Status, LogField::Bytes,
+        LogField::Referer, LogField::UserAgent, LogField::Header("X-A".into()),
+        LogField::Extra("request_id".into()),
+    ]));
+    log.log(Some(&req), &resp, &conn, 12);
+    log.log(None, &resp, &conn, 0);
+
+    let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "{\"time\":\"2000-10-10T13:55:36Z\",\
+        \"remote_addr\":\"::1\",\"host\":\"example.com\",\"method\":\"GET\",\
+        \"uri\":\"/x\",\"protocol\":\"HTTP/1.0\",\"status\":200,\"bytes\":12,\
+        \"referer\":null,\"user_agent\":\"\\\"t\\\"\",\"x-a\":\"1\",\
+        \"request_id\":\"abc\"}");
+    assert!(lines[1].contains("\"host\":null,\"method\":null"));
+    assert!(lines[1].ends_with("\"request_id\":null}"));
+}
+
+#[test]
+fn test_access_log_rotation() {
+    use std::time::{Instant, UNIX_EPOCH};
+    let root = ::std::env::temp_dir().join(format!("mudpie-test-rotate-{}",
+            ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let path = root.join("access.log");
+    let conn = ConnInfo {
+        peer_addr: "10.1.2.3:5555".parse().unwrap(),
+        start: Instant::now(),
+        start_time: UNIX_EPOCH,
+        timings: Timings::default(),
+    };
+    let resp = WebResponse::new();
+    let read = |name: &str| fs::read_to_string(root.join(name)).ok()
+        .map(|log| log.lines().count());
+
+    let mut log = AccessLog::open(path.to_str().unwrap(), LogFormat::Common)
+        .unwrap();
+    // Lines are 50 bytes: two to a file
+    log.set_max_size(120);
+    log.set_keep(2);
+    let server_log = log.clone();
+    for _ in 0..7 {
+        server_log.log(None, &resp, &conn, 0);
+    }
+    assert_eq!((read("access.log"), read("access.log.1"),
+            read("access.log.2"), read("access.log.3")),
+            (Some(1), Some(2), Some(2), None));
+
+    // Moved away by logrotate: the log goes on in the old file until
+    // reopened
+    fs::rename(&path, root.join("moved")).unwrap();
+    server_log.log(None, &resp, &conn, 0);
+    log.reopen().unwrap();
+    server_log.log(None, &resp, &conn, 0);
+    assert_eq!((read("moved"), read("access.log")), (Some(2), Some(1)));
+
+    log.rotate().unwrap();
+    assert_eq!((read("access.log"), read("access.log.1")), (Some(0), Some(1)));
+
+    // Not from a path: nothing to do
+    let log = AccessLog::new(io::sink(), LogFormat::Common);
+    log.reopen().unwrap();
+    log.rotate().unwrap();
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,99 @@
+//! Answers to ACME HTTP-01 challenges (RFC 8555 8.3)
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use super::{Handler, WebRequest, WebResponse};
+
+/// Where the challenges are fetched from
+pub static ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+
+/// Answers the HTTP-01 challenges of an ACME client (ex: to get a
+/// certificate from Let's Encrypt), while the server goes on serving.
+///
+/// The client puts each challenge's token and key authorization in with
+/// `set`, asks the CA to validate, and then takes it out with `remove`.
+/// The handler answers `GET /.well-known/acme-challenge/<token>` with the
+/// key authorization, and a 404 for unknown tokens.  Clones share the
+/// tokens.
+///
+/// ex:
+///
+/// ```ignore
+/// let acme = server.add_acme_challenge();
+/// // ... then, from the ACME client
+/// acme.set(&token, &key_authorization);
+/// ```
+#[derive(Clone, Default)]
+pub struct AcmeChallenge {
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl AcmeChallenge {
+    pub fn new() -> AcmeChallenge {
+        return AcmeChallenge::default();
+    }
+
+    /// Answer the challenge for `token` with `key_authorization`
+    pub fn set(&self, token: &str, key_authorization: &str) {
+        self.tokens.write().unwrap().insert(token.to_string(),
+                key_authorization.to_string());
+    }
+
+    /// Stop answering the challenge for `token`, once validated
+    pub fn remove(&self, token: &str) -> Option<String> {
+        return self.tokens.write().unwrap().remove(token);
+    }
+
+    pub fn get(&self, token: &str) -> Option<String> {
+        return self.tokens.read().unwrap().get(token).cloned();
+    }
+}
+
+impl Handler for AcmeChallenge {
+    /// Answers for the token at the end of the path, so it can be routed
+    /// as a prefix or mounted
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        let token = req.get_path().rsplit('/').next().unwrap();
+        // Tokens are base64url (RFC 8555 8.1)
+        let valid = !token.is_empty() && token.bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_');
+        let mut resp = WebResponse::new();
+        match self.get(token) {
+            Some(ref key_authorization) if valid => {
+                resp.set_header("Content-Type", "application/octet-stream");
+                resp.set_body_str(key_authorization);
+            },
+            _ => {
+                resp.set_code(404, "Not Found");
+                resp.set_body_str("Error 404: Not Found");
+            },
+        }
+        return resp;
+    }
+}
+
+
+#[test]
+fn test_acme_challenge() {
+    let acme = AcmeChallenge::new();
+    acme.clone().set("tok_en-1", "tok_en-1.thumbprint");
+    let get = |path: &str| {
+        let mut resp = acme.handle(&mut WebRequest::new_for_test("GET", path));
+        return (resp.get_code(), resp.get_header("Content-Type")
+                .map(|t| t.to_string()), resp.body_for_test());
+    };
+    assert_eq!(get("/.well-known/acme-challenge/tok_en-1"), (200,
+            Some("application/octet-stream".to_string()),
+            b"tok_en-1.thumbprint".to_vec()));
+    // Mounted
+    assert_eq!(get("/tok_en-1").0, 200);
+    assert_eq!(get("/.well-known/acme-challenge/other").0, 404);
+    assert_eq!(get("/.well-known/acme-challenge/").0, 404);
+
+    acme.set("a.b", "x");
+    assert_eq!(get("/.well-known/acme-challenge/a.b").0, 404);
+    assert_eq!(acme.remove("tok_en-1"), Some("tok_en-1.thumbprint".into()));
+    assert_eq!(get("/.well-known/acme-challenge/tok_en-1").0, 404);
+}
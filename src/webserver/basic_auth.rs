@@ -0,0 +1,320 @@
+//! HTTP Basic authentication middleware (RFC 7617)
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::str;
+
+use utils::base64;
+use utils::byteutils;
+use utils::byteutils::constant_time_eq;
+use utils::md5::{md5, Md5};
+use utils::sha1::sha1;
+use super::{WebRequest, WebResponse, Middleware, Next};
+
+
+/// Checks a user name and password.
+///
+/// Implemented for `HashMap<String, String>` (user to plain password),
+/// `Htpasswd`, and closures of the form `|user: &str, password: &str| -> bool`.
+pub trait Verifier: Send + Sync {
+    fn verify(&self, user: &str, password: &str) -> bool;
+}
+
+impl<F> Verifier for F where F: Fn(&str, &str) -> bool + Send + Sync {
+    fn verify(&self, user: &str, password: &str) -> bool {
+        return (self)(user, password);
+    }
+}
+
+impl Verifier for HashMap<String, String> {
+    fn verify(&self, user: &str, password: &str) -> bool {
+        match self.get(user) {
+            Some(expected) => return constant_time_eq(expected.as_bytes(),
+                    password.as_bytes()),
+            None => return false,
+        }
+    }
+}
+
+
+/// Credentials from an Apache htpasswd file.
+///
+/// Supports MD5 entries (`$apr1$`, what `htpasswd` writes by default, and
+/// `$1$`), `{SHA}` entries (`htpasswd -s`) and plain text entries
+/// (`htpasswd -p`).  Files with bcrypt (`$2y$`, `htpasswd -B`), crypt() or
+/// other entries are refused when loaded, rather than their users failing
+/// to log in.  Like Apache, a 13 character entry is taken to be crypt(),
+/// so plain text passwords of that length are refused too, as are empty
+/// entries.
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+}
+
+impl Htpasswd {
+    /// Read and parse the file at `path`
+    pub fn open(path: &str) -> io::Result<Htpasswd> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        return Htpasswd::parse(&contents);
+    }
+
+    /// Parse `user:hash` lines.  Blank lines and # comments are skipped.
+    /// An InvalidData error names the first user whose entry isn't
+    /// supported.
+    pub fn parse(contents: &str) -> io::Result<Htpasswd> {
+        let mut users = HashMap::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(colon) = line.find(':') {
+                let (user, hash) = (&line[..colon], &line[colon + 1..]);
+                if !supported(hash) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            format!("htpasswd entry of {:?} isn't MD5, SHA \
+                            or plain text", user)));
+                }
+                users.insert(user.to_string(), hash.to_string());
+            }
+        }
+        return Ok(Htpasswd { users: users });
+    }
+}
+
+// Whether Htpasswd can check entries like hash.  An empty one would let
+// in an empty password.
+fn supported(hash: &str) -> bool {
+    if hash.is_empty() {
+        return false;
+    }
+    if hash.starts_with('$') {
+        return md5_entry(hash).is_some();
+    }
+    return hash.starts_with("{SHA}") || hash.len() != 13;
+}
+
+// "$apr1$salt$..." => ("$apr1$", "salt")
+fn md5_entry(hash: &str) -> Option<(&str, &str)> {
+    let magic = ["$apr1$", "$1$"].iter().find(|m| hash.starts_with(**m))?;
+    let rest = &hash[magic.len()..];
+    let salt = &rest[..rest.find('$')?];
+    if salt.len() > 8 {
+        return None;
+    }
+    return Some((magic, salt));
+}
+
+// The MD5 crypt() of password (FreeBSD's, and Apache's with "$apr1$"):
+// "$apr1$salt$" and 22 characters of the digest stretched 1000 times
+fn md5_crypt(password: &[u8], magic: &str, salt: &str) -> String {
+    let salt = salt.as_bytes();
+    let alt = md5(&[password, salt, password].concat());
+
+    let mut h = Md5::new();
+    h.update(password);
+    h.update(magic.as_bytes());
+    h.update(salt);
+    for chunk in password.chunks(16) {
+        h.update(&alt[..chunk.len()]);
+    }
+    let mut n = password.len();
+    while n > 0 {
+        match n & 1 {
+            1 => h.update(&[0]),
+            _ => h.update(&password[..1]),
+        }
+        n >>= 1;
+    }
+    let mut digest = h.finish();
+
+    for i in 0..1000 {
+        let mut h = Md5::new();
+        match i & 1 {
+            1 => h.update(password),
+            _ => h.update(&digest),
+        }
+        if i % 3 != 0 {
+            h.update(salt);
+        }
+        if i % 7 != 0 {
+            h.update(password);
+        }
+        match i & 1 {
+            1 => h.update(&digest),
+            _ => h.update(password),
+        }
+        digest = h.finish();
+    }
+
+    static ALPHABET: &[u8] =
+        b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut ret = format!("{}{}$", magic, String::from_utf8_lossy(salt));
+    let mut push = |mut v: u32, n: usize| {
+        for _ in 0..n {
+            ret.push(ALPHABET[(v & 0x3f) as usize] as char);
+            v >>= 6;
+        }
+    };
+    for &(a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15),
+            (4, 10, 5)].iter() {
+        push((digest[a] as u32) << 16 | (digest[b] as u32) << 8
+                | digest[c] as u32, 4);
+    }
+    push(digest[11] as u32, 2);
+    return ret;
+}
+
+impl Verifier for Htpasswd {
+    fn verify(&self, user: &str, password: &str) -> bool {
+        let hash = match self.users.get(user) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        if let Some(expected) = hash.strip_prefix("{SHA}") {
+            let digest = base64::encode(&sha1(password.as_bytes()));
+            return constant_time_eq(expected.as_bytes(), digest.as_bytes());
+        }
+        if let Some((magic, salt)) = md5_entry(hash) {
+            let crypted = md5_crypt(password.as_bytes(), magic, salt);
+            return constant_time_eq(hash.as_bytes(), crypted.as_bytes());
+        }
+        // Refused by parse
+        if hash.starts_with('$') || hash.len() == 13 {
+            return false;
+        }
+        return constant_time_eq(hash.as_bytes(), password.as_bytes());
+    }
+}
+
+
+/// The user name checked by `BasicAuth`, stored in the request extensions
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthUser(pub String);
+
+
+/// Middleware that requires HTTP Basic auth.
+///
+/// Requests without valid credentials get a `401 Unauthorized` challenge
+/// for `realm`.  Otherwise the user name is stored as an `AuthUser`
+/// extension.  To protect part of a site, add it to a `Router` mounted at
+/// that prefix (see `Router::mount` and `Router::add_middleware`).
+///
+/// Basic auth sends passwords in the clear, so use it over TLS only.
+pub struct BasicAuth {
+    realm: String,
+    verifier: Box<dyn Verifier>,
+}
+
+impl BasicAuth {
+    pub fn new<V: Verifier + 'static>(realm: &str, verifier: V) -> BasicAuth {
+        return BasicAuth {
+            realm: realm.to_string(),
+            verifier: Box::new(verifier),
+        };
+    }
+
+    fn challenge(&self) -> WebResponse {
+        let mut resp = WebResponse::new();
+        resp.set_code(401, "Unauthorized");
+        resp.set_header("WWW-Authenticate", &format!(
+                "Basic realm=\"{}\", charset=\"UTF-8\"",
+                self.realm.replace('\\', "\\\\").replace('"', "\\\"")));
+        resp.set_body_str("Error 401: Unauthorized");
+        return resp;
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let user = match req.get_header("Authorization").and_then(parse_basic) {
+            Some((user, password)) => match self.verifier.verify(&user,
+                    &password) {
+                true => user,
+                false => return self.challenge(),
+            },
+            None => return self.challenge(),
+        };
+        req.get_extensions_mut().insert(AuthUser(user));
+        return next.run(req);
+    }
+}
+
+
+// "Basic dXNlcjpwYXNz" => ("user", "pass")
+fn parse_basic(header: &str) -> Option<(String, String)> {
+    let mut parts = byteutils::split_whitespace(header.as_bytes());
+    let (scheme, credentials) = (parts.next()?, parts.next()?);
+    if parts.next().is_some()
+            || !byteutils::eq_ignore_ascii_case(scheme, b"basic") {
+        return None;
+    }
+    let decoded = base64::decode(str::from_utf8(credentials).ok()?)?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let colon = decoded.find(':')?;
+    return Some((decoded[..colon].to_string(),
+            decoded[colon + 1..].to_string()));
+}
+
+#[cfg(test)]
+fn auth_request(auth: &str) -> WebRequest {
+    return WebRequest::parse_for_test(&format!(
+            "GET / HTTP/1.1\r\nAuthorization: {}\r\n\r\n", auth));
+}
+
+#[test]
+fn test_basic_auth() {
+    use super::{Chain, Handler};
+
+    let mut users = HashMap::new();
+    users.insert("aladdin".to_string(), "open sesame".to_string());
+    let mut chain = Chain::new(|req: &WebRequest| {
+        let user = req.get_extensions().get::<AuthUser>().unwrap();
+        return WebResponse::new_html(user.0.clone());
+    });
+    chain.add(BasicAuth::new("my \"site\"", users));
+
+    let resp = chain.handle(&mut WebRequest::new_for_test("GET", "/"));
+    assert_eq!(resp.code, 401);
+    assert_eq!(resp.get_header("WWW-Authenticate"),
+            Some("Basic realm=\"my \\\"site\\\"\", charset=\"UTF-8\""));
+
+    let resp = chain.handle(&mut auth_request(
+            &format!("Basic {}", base64::encode(b"aladdin:open sesame"))));
+    assert_eq!(resp.code, 200);
+    assert_eq!(resp.body, b"aladdin");
+
+    for bad in &["Basic ", "Bearer xyz", "Basic !!!",
+            &format!("basic {}", base64::encode(b"aladdin:open")),
+            &format!("Basic {}", base64::encode(b"nobody:open sesame"))] {
+        assert_eq!(chain.handle(&mut auth_request(bad)).code, 401);
+    }
+}
+
+#[test]
+fn test_htpasswd() {
+    // From `openssl passwd -apr1` and `-1`
+    let h = Htpasswd::parse("# comment\n\
+        sha:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n\
+        plain:secret\n\
+        apr1:$apr1$xxxxxxxx$bVgkO/6SF.W5IAebidVp11\n\
+        short:$apr1$r31$hjZ9dRSC/WZ.WgLYK3DIs.\n\
+        md5:$1$xxxxxxxx$wz2aUJM1jIhLD11nGib0D.\n").unwrap();
+    assert!(h.verify("sha", "password"));
+    assert!(!h.verify("sha", "Password"));
+    assert!(h.verify("plain", "secret"));
+    assert!(h.verify("apr1", "xxxxxxxx"));
+    assert!(!h.verify("apr1", "xxxxxxxy"));
+    assert!(h.verify("short", "password"));
+    assert!(h.verify("md5", "xxxxxxxx"));
+    assert!(!h.verify("md5", "$1$xxxxxxxx$wz2aUJM1jIhLD11nGib0D."));
+    assert!(!h.verify("nobody", ""));
+    for bad in &["b:$2y$05$abcdefghijklmnopqrstuu", "c:abJnggxhB/yWI",
+            "d:$5$salt$hash", "e:$apr1$toolongsalt$x", "f:"] {
+        let err = Htpasswd::parse(bad).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+    assert!((|u: &str, p: &str| u == p).verify("x", "x"));
+}
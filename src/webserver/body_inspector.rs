@@ -0,0 +1,111 @@
+//! Looking at request bodies as they come in, before the handler
+
+use super::WebRequest;
+
+
+/// Shown the body of every request as it's read, before the handler runs,
+/// and able to turn the request down; see `WebServer::set_body_inspector`.
+///
+/// For each request, `start` is given its head and makes a
+/// `BodyInspection`, which is given the body's data as it comes in, and
+/// then told of its end.  Each of them can reject the request with the
+/// code and status to send instead of running the handler, ex: a 413 once
+/// too much was sent, or a 422 for a body that isn't what it should be.
+/// Requests without a body are inspected too, with no data.
+///
+/// ex:
+///
+/// ```ignore
+/// struct NoZip;
+/// struct Sniff(bool);
+///
+/// impl BodyInspector for NoZip {
+///     fn start(&self, _req: &WebRequest)
+///             -> Result<Box<dyn BodyInspection>, (i32, String)> {
+///         return Ok(Box::new(Sniff(true)));
+///     }
+/// }
+///
+/// impl BodyInspection for Sniff {
+///     fn data(&mut self, data: &[u8]) -> Result<(), (i32, String)> {
+///         if self.0 && data.starts_with(b"PK") {
+///             return Err((415, "Unsupported Media Type".to_string()));
+///         }
+///         self.0 = false;
+///         return Ok(());
+///     }
+/// }
+/// ```
+pub trait BodyInspector: Send + Sync {
+    fn start(&self, req: &WebRequest)
+            -> Result<Box<dyn BodyInspection>, (i32, String)>;
+}
+
+/// The inspection of one request's body, see `BodyInspector`
+pub trait BodyInspection {
+    /// The next part of the body
+    fn data(&mut self, data: &[u8]) -> Result<(), (i32, String)>;
+
+    /// All of the body was shown
+    fn end(&mut self) -> Result<(), (i32, String)> {
+        return Ok(());
+    }
+}
+
+
+#[test]
+fn test_body_inspector() {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use utils::buffer_pool::BufferPool;
+    use super::read_request::{read_request, Error};
+
+    // Counts the bytes, refusing more than `limit`
+    struct Counter(Arc<Mutex<Vec<String>>>, usize);
+    struct Counting(Arc<Mutex<Vec<String>>>, usize, usize);
+    impl BodyInspector for Counter {
+        fn start(&self, req: &WebRequest)
+                -> Result<Box<dyn BodyInspection>, (i32, String)> {
+            if req.get_path() == "/forbidden" {
+                return Err((403, "Forbidden".to_string()));
+            }
+            return Ok(Box::new(Counting(self.0.clone(), self.1, 0)));
+        }
+    }
+    impl BodyInspection for Counting {
+        fn data(&mut self, data: &[u8]) -> Result<(), (i32, String)> {
+            self.0.lock().unwrap().push(String::from_utf8_lossy(data).into());
+            self.2 += data.len();
+            if self.2 > self.1 {
+                return Err((413, "Too Much".to_string()));
+            }
+            return Ok(());
+        }
+        fn end(&mut self) -> Result<(), (i32, String)> {
+            self.0.lock().unwrap().push(format!("end {}", self.2));
+            return Ok(());
+        }
+    }
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let inspector = Counter(seen.clone(), 8);
+    let read = |raw: &str| {
+        let mut stream = io::Cursor::new(raw.as_bytes().to_vec());
+        return read_request(&mut stream, 1000, 1000, None, None,
+                Some(&inspector), &BufferPool::new(64, 1));
+    };
+    let (req, extra) = read("POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\n\
+            helloGET").ok().unwrap();
+    assert_eq!((&req.body[..], &extra[..]), (&b"hello"[..], &b"GET"[..]));
+    assert!(read("GET /a HTTP/1.1\r\n\r\n").is_ok());
+    assert_eq!(*seen.lock().unwrap(), ["hello", "end 5", "end 0"]);
+
+    match read("POST /a HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789") {
+        Err(Error::Rejected(413, status)) => assert_eq!(status, "Too Much"),
+        _ => panic!("not rejected"),
+    }
+    match read("GET /forbidden HTTP/1.1\r\n\r\n") {
+        Err(Error::Rejected(403, _)) => (),
+        _ => panic!("not rejected"),
+    }
+}
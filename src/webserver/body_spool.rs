@@ -0,0 +1,151 @@
+//! Request bodies kept in temporary files instead of memory
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Tells apart the files of this process
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+
+// A request body in a temporary file.  The file is removed as soon as it's
+// made, so it goes away with the last handle however the process ends;
+// where open files can't be removed (Windows), once it's closed.
+pub(crate) struct Spool {
+    file: File,
+    len: u64,
+    // The whole body read back, if the handler asked for it so
+    loaded: OnceLock<Vec<u8>>,
+    // After the file, to be dropped once it's closed
+    _remove: RemoveOnDrop,
+}
+
+struct RemoveOnDrop(Option<PathBuf>);
+
+/// A request's body to read, and seek in: from its buffer, or from a
+/// temporary file for bodies that were spooled (see
+/// `WebServer::set_body_spool`).  From `WebRequest::body_reader`.
+pub struct BodyReader<'a> {
+    inner: Inner<'a>,
+}
+
+enum Inner<'a> {
+    Memory(Cursor<&'a [u8]>),
+    File(File),
+}
+
+impl Spool {
+    pub(crate) fn create() -> io::Result<Spool> {
+        let name = format!("mudpie-body-{}-{}", process::id(),
+                SPOOLED.fetch_add(1, Ordering::Relaxed));
+        let path = env::temp_dir().join(name);
+        let file = OpenOptions::new().read(true).write(true)
+            .create_new(true).open(&path)?;
+        let left = match fs::remove_file(&path) {
+            Ok(()) => None,
+            Err(_) => Some(path),
+        };
+        return Ok(Spool {
+            file: file,
+            len: 0,
+            loaded: OnceLock::new(),
+            _remove: RemoveOnDrop(left),
+        });
+    }
+
+    pub(crate) fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        return Ok(());
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        return self.len;
+    }
+
+    // The whole body, read back into memory the first time.  A file that
+    // can't be read back gives what could be.
+    pub(crate) fn bytes(&self) -> &[u8] {
+        return self.loaded.get_or_init(|| {
+            let mut body = Vec::with_capacity(self.len as usize);
+            let _ = self.file().and_then(|mut f| f.read_to_end(&mut body));
+            return body;
+        });
+    }
+
+    // A handle on the file, at its start.  They share their position, so
+    // one at a time.
+    pub(crate) fn file(&self) -> io::Result<File> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(file);
+    }
+}
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl<'a> BodyReader<'a> {
+    pub(crate) fn memory(body: &'a [u8]) -> BodyReader<'a> {
+        return BodyReader { inner: Inner::Memory(Cursor::new(body)) };
+    }
+
+    pub(crate) fn file(file: File) -> BodyReader<'a> {
+        return BodyReader { inner: Inner::File(file) };
+    }
+}
+
+impl<'a> Read for BodyReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner {
+            Inner::Memory(ref mut cursor) => return cursor.read(buf),
+            Inner::File(ref mut file) => return file.read(buf),
+        }
+    }
+}
+
+impl<'a> Seek for BodyReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.inner {
+            Inner::Memory(ref mut cursor) => return cursor.seek(pos),
+            Inner::File(ref mut file) => return file.seek(pos),
+        }
+    }
+}
+
+
+#[test]
+fn test_spool() {
+    let mut spool = Spool::create().unwrap();
+    spool.write_all(b"hello ").unwrap();
+    spool.write_all(b"world").unwrap();
+    assert_eq!(spool.len(), 11);
+
+    let mut reader = BodyReader::file(spool.file().unwrap());
+    let mut start = [0; 5];
+    reader.read_exact(&mut start).unwrap();
+    assert_eq!(&start, b"hello");
+    reader.seek(SeekFrom::Start(6)).unwrap();
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "world");
+    drop(reader);
+    assert_eq!(spool.bytes(), b"hello world");
+    // Read back once
+    assert_eq!(spool.bytes().len(), 11);
+
+    let mut reader = BodyReader::memory(b"abc");
+    reader.seek(SeekFrom::End(-1)).unwrap();
+    let mut last = Vec::new();
+    reader.read_to_end(&mut last).unwrap();
+    assert_eq!(last, b"c");
+}
@@ -0,0 +1,478 @@
+//! Running CGI/1.1 scripts (RFC 3875)
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use utils::byteutils;
+use super::{Handler, WebRequest, WebResponse, AuthUser};
+
+// The most that's read of a script's headers
+static MAX_HEADER_SIZE: usize = 65536;
+
+
+/// A handler that runs the CGI scripts under a directory.
+///
+/// The request path (without the prefix when mounted, see `Router::mount`)
+/// is looked up under the root one segment at a time, and the first file
+/// found is the script; the rest of the path is its PATH_INFO.  ex: with
+/// `router.mount("/cgi-bin", Cgi::new("/srv/cgi-bin"))`, a request for
+/// "/cgi-bin/app.cgi/users/1" runs /srv/cgi-bin/app.cgi with SCRIPT_NAME
+/// "/cgi-bin/app.cgi" and PATH_INFO "/users/1".  Paths with ".." or
+/// dotfile segments, or whose script is outside the root after resolving
+/// symlinks, get a 404.
+///
+/// Scripts must be executable.  They run in their own directory, with the
+/// request body on stdin and the standard CGI variables (QUERY_STRING,
+/// REMOTE_ADDR, HTTP_xxx for the headers, ...) as their environment,
+/// which is otherwise empty but for PATH.  Authorization and Proxy headers
+/// aren't passed on; REMOTE_USER is the `AuthUser`, if any.  Their
+/// stderr is the server's.
+///
+/// The headers a script prints are the response's; a Status header sets
+/// the code, and a Location without one makes it a 302.  The rest of its
+/// output is streamed to the client as the body.  Scripts whose output
+/// doesn't start with headers get a 500.
+///
+/// A script still running after the timeout (see `set_timeout`) is
+/// killed: the client gets a 504 if no headers were printed by then, or a
+/// cut short body.  Processes the script started itself are left running.
+pub struct Cgi {
+    root: PathBuf,
+    timeout: Duration,
+}
+
+impl Cgi {
+    pub fn new<P: AsRef<Path>>(root: P) -> Cgi {
+        return Cgi {
+            root: root.as_ref().to_path_buf(),
+            timeout: Duration::from_secs(30),
+        };
+    }
+
+    /// How long a script may run, including sending its output.  Default
+    /// 30 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // The script, and the path segments to it and after it
+    fn resolve(&self, path: &str) -> io::Result<(PathBuf, Vec<String>,
+            Vec<String>)> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound,
+                "no script");
+        let segments: Vec<&str> = path.split('/')
+            .filter(|s| !s.is_empty() && *s != ".").collect();
+        let mut script = self.root.clone();
+        for (i, segment) in segments.iter().enumerate() {
+            if segment.starts_with('.') || segment.contains(['\0', '\\']) {
+                return Err(not_found());
+            }
+            script.push(segment);
+            let meta = fs::metadata(&script)?;
+            if meta.is_file() {
+                let root = fs::canonicalize(&self.root)?;
+                if !fs::canonicalize(&script)?.starts_with(root) {
+                    return Err(not_found());
+                }
+                let to = |s: &[&str]| s.iter().map(|s| s.to_string())
+                    .collect();
+                return Ok((script, to(&segments[..i + 1]),
+                        to(&segments[i + 1..])));
+            }
+        }
+        return Err(not_found());
+    }
+
+    fn run(&self, req: &WebRequest) -> io::Result<WebResponse> {
+        let start = Instant::now();
+        let (script, script_path, path_info) = self.resolve(&req.path)?;
+        let mut command = Command::new(&script);
+        if let Some(dir) = script.parent() {
+            command.current_dir(dir);
+        }
+        let script_name = format!("{}/{}", mount_prefix(req),
+                script_path.join("/"));
+        let mut path_info = match path_info.is_empty() {
+            true => String::new(),
+            false => format!("/{}", path_info.join("/")),
+        };
+        if !path_info.is_empty() && req.path.ends_with('/') {
+            path_info.push('/');
+        }
+        command.env_clear()
+            .envs(environment(req, &script, &script_name, &path_info))
+            .stdin(Stdio::piped()).stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        if let Some(path) = ::std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        let mut child = command.spawn()?;
+
+        // From a thread, so a script can write before it reads
+        let mut stdin = child.stdin.take().unwrap();
+        let mut body = req.owned_body_reader()?;
+        thread::spawn(move || {
+            let _ = io::copy(&mut body, &mut stdin);
+        });
+        let deadline = start + self.timeout;
+        let output = pump(child.stdout.take().unwrap());
+        let finished = watch(child, deadline);
+        let mut body = BufReader::new(ScriptOutput {
+            output: output,
+            pending: Vec::new(),
+            pos: 0,
+            deadline: deadline,
+            finished: Some(finished),
+        });
+
+        let headers = match read_headers(&mut body) {
+            Ok(headers) => headers,
+            Err(_) if start.elapsed() >= self.timeout =>
+                return Ok(error_response(504, "Gateway Timeout")),
+            Err(err) => return Err(err),
+        };
+        let (mut resp, length) = script_response(headers)?;
+        resp.set_body_reader(body, length);
+        return Ok(resp);
+    }
+}
+
+impl Handler for Cgi {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        match self.run(req) {
+            Ok(resp) => return resp,
+            Err(err) => match err.kind() {
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory =>
+                    return error_response(404, "Not Found"),
+                io::ErrorKind::PermissionDenied =>
+                    return error_response(403, "Forbidden"),
+                _ => return error_response(500, "Internal Server Error"),
+            },
+        }
+    }
+}
+
+
+// The CGI variables for a request (RFC 3875 4.1), for the script at
+// `script` (if not empty).  `script_name` is its URL path, and
+// `path_info` what follows.
+pub(crate) fn environment(req: &WebRequest, script: &Path, script_name: &str,
+        path_info: &str) -> Vec<(String, String)> {
+    let environ = |key: &str| {
+        let value = req.environ.get(key.as_bytes()).map(|v| &v[..])
+            .unwrap_or(b"");
+        return String::from_utf8_lossy(value).into_owned();
+    };
+    let mut env = Vec::new();
+    let mut set = |name: &str, value: String| {
+        env.push((name.to_string(), value));
+    };
+    set("GATEWAY_INTERFACE", "CGI/1.1".to_string());
+    set("SERVER_SOFTWARE", "mudpie".to_string());
+    set("SERVER_PROTOCOL", environ("protocol").to_ascii_uppercase());
+    let host = req.get_header("Host").unwrap_or("");
+    let (name, port) = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => (&host[..i], &host[i + 1..]),
+        _ => (host, "80"),
+    };
+    set("SERVER_NAME", name.to_string());
+    set("SERVER_PORT", port.to_string());
+    set("REQUEST_METHOD", req.method.to_ascii_uppercase());
+    set("REQUEST_URI", environ("request_uri"));
+    set("SCRIPT_NAME", script_name.to_string());
+    if !script.as_os_str().is_empty() {
+        set("SCRIPT_FILENAME", script.to_string_lossy().into_owned());
+    }
+    if !path_info.is_empty() {
+        set("PATH_INFO", path_info.to_string());
+    }
+    set("QUERY_STRING", environ("query_string"));
+    if let Some(addr) = req.get_remote_addr() {
+        set("REMOTE_ADDR", addr.ip().to_string());
+        set("REMOTE_PORT", addr.port().to_string());
+    }
+    if let Some(user) = req.get_extensions().get::<AuthUser>() {
+        set("AUTH_TYPE", "Basic".to_string());
+        set("REMOTE_USER", user.0.clone());
+    }
+    if req.body_len() > 0 || req.get_header("Content-Length").is_some() {
+        set("CONTENT_LENGTH", req.body_len().to_string());
+    }
+    if let Some(content_type) = req.get_header("Content-Type") {
+        set("CONTENT_TYPE", content_type.to_string());
+    }
+
+    let mut headers: Vec<(String, String)> = req.environ.iter()
+        .filter(|&(k, _)| k.starts_with(b"http_"))
+        .map(|(k, v)| (String::from_utf8_lossy(&k[5..]).into_owned(),
+                String::from_utf8_lossy(v).into_owned()))
+        // Proxy: a client could set the script's HTTP proxy ("httpoxy")
+        .filter(|h| !["content-length", "content-type", "authorization",
+                "proxy"].contains(&&h.0[..]))
+        .map(|(k, v)| (format!("HTTP_{}",
+                k.to_ascii_uppercase().replace('-', "_")), v))
+        .collect();
+    headers.sort();
+    env.extend(headers);
+    return env;
+}
+
+// The prefix removed by `Router::mount`, decoded, ex: "/cgi-bin"
+pub(crate) fn mount_prefix(req: &WebRequest) -> String {
+    let raw = req.environ.get(&b"script_name"[..]).map(|v| &v[..])
+        .unwrap_or(b"");
+    return String::from_utf8_lossy(&byteutils::percent_decode(raw))
+        .into_owned();
+}
+
+// The header lines a script prints first, up to an empty line
+pub(crate) fn read_headers<R: BufRead>(reader: &mut R)
+        -> io::Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = Vec::new();
+        reader.by_ref().take((MAX_HEADER_SIZE - size) as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        size += line.len();
+        if size > MAX_HEADER_SIZE || line.last() != Some(&b'\n') {
+            return Err(invalid("no end to the script's headers"));
+        }
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        let line = String::from_utf8_lossy(&line);
+        match line.find(':') {
+            Some(i) if i > 0 => headers.push((line[..i].trim().to_string(),
+                    line[i + 1..].trim().to_string())),
+            _ => return Err(invalid("bad header line from the script")),
+        }
+    }
+}
+
+// The response for the headers a script printed, and the body length
+// they give, if any
+pub(crate) fn script_response(headers: Vec<(String, String)>)
+        -> io::Result<(WebResponse, Option<u64>)> {
+    let mut resp = WebResponse::new();
+    let mut length = None;
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Status") {
+            let (code, status) = match value.find(' ') {
+                Some(i) => (&value[..i], value[i + 1..].trim()),
+                None => (&value[..], ""),
+            };
+            match code.parse() {
+                Ok(code) if (100..1000).contains(&code) =>
+                    resp.set_code(code, status),
+                _ => return Err(invalid("bad Status header")),
+            }
+        } else if name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(value.parse::<u64>()
+                .map_err(|_| invalid("bad Content-Length"))?);
+        } else {
+            if name.eq_ignore_ascii_case("Location")
+                    && resp.get_code() == 200 {
+                resp.set_code(302, "Found");
+            }
+            resp.add_header(&name, &value);
+        }
+    }
+    return Ok((resp, length));
+}
+
+// Kill `child` at `deadline`, or when the returned sender is dropped
+// without sending.  Sending means it's done writing, and may exit
+// until the deadline.  It's waited for, either way.
+fn watch(mut child: Child, deadline: Instant) -> Sender<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if receiver.recv_timeout(timeout).is_ok() {
+            while Instant::now() < deadline {
+                if let Ok(Some(_)) = child.try_wait() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+    });
+    return sender;
+}
+
+// Read `stdout` from a thread, since a process the script started may keep
+// it open after the script is killed
+fn pump(mut stdout: ChildStdout) -> Receiver<io::Result<Vec<u8>>> {
+    let (sender, receiver) = mpsc::sync_channel(4);
+    thread::spawn(move || {
+        let mut buf = vec![0; 16384];
+        loop {
+            let ret = stdout.read(&mut buf).map(|n| buf[..n].to_vec());
+            let end = !matches!(ret, Ok(ref data) if !data.is_empty());
+            if sender.send(ret).is_err() || end {
+                return;
+            }
+        }
+    });
+    return receiver;
+}
+
+// The script's output, until it ends or the deadline
+struct ScriptOutput {
+    output: Receiver<io::Result<Vec<u8>>>,
+    // The chunk being read
+    pending: Vec<u8>,
+    pos: usize,
+    deadline: Instant,
+    finished: Option<Sender<()>>,
+}
+
+impl Read for ScriptOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.pending.len() {
+            let timeout = self.deadline
+                .saturating_duration_since(Instant::now());
+            self.pending = match self.output.recv_timeout(timeout) {
+                Ok(Ok(data)) => data,
+                Ok(Err(err)) => return Err(err),
+                Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(
+                        io::ErrorKind::TimedOut, "script timed out")),
+                Err(RecvTimeoutError::Disconnected) => Vec::new(),
+            };
+            self.pos = 0;
+            if self.pending.is_empty() {
+                if let Some(finished) = self.finished.take() {
+                    let _ = finished.send(());
+                }
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+fn error_response(code: i32, status: &str) -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(code, status);
+    resp.set_body_str(&format!("Error {}: {}", code, status));
+    return resp;
+}
+
+fn invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+}
+
+
+// A fresh directory for a test, with the given scripts
+#[cfg(test)]
+fn test_root(name: &str, scripts: &[(&str, &str)]) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = ::std::env::temp_dir().join(format!("mudpie-test-{}-{}", name,
+            ::std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    for &(path, script) in scripts {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    return root;
+}
+
+#[test]
+fn test_cgi() {
+    let root = test_root("cgi", &[
+        ("bin/env.cgi", "printf 'Content-Type: text/plain\\r\\n\\r\\n'\n\
+            env | sort; cat"),
+        ("status.cgi", "echo 'Status: 404 Gone'; echo 'X-A: 1'; echo\n\
+            printf missing"),
+        ("redirect.cgi", "echo 'Location: /elsewhere'; echo"),
+        ("length.cgi", "echo 'Content-Length: 2'; echo; printf abcd"),
+        ("broken.cgi", "echo 'no headers here'"),
+        (".hidden.cgi", "echo"),
+    ]);
+    let cgi = Cgi::new(&root);
+    let run = |raw: &str, body: &[u8]| {
+        let mut req = WebRequest::parse_for_test(raw);
+        req.body = body.to_vec();
+        req.environ.insert(b"remote_address".to_vec(), b"192.0.2.1:1234"
+                .to_vec());
+        req.environ.insert(b"script_name".to_vec(), b"/cgi".to_vec());
+        let mut resp = cgi.handle(&mut req);
+        let body = String::from_utf8(resp.body_for_test()).unwrap();
+        return (resp, body);
+    };
+
+    let (resp, body) = run("POST /bin/env.cgi/a%20b/c/?x=1 HTTP/1.1\r\n\
+        Host: example.com:8080\r\nContent-Type: text/plain\r\n\
+        Content-Length: 5\r\nX-Custom-Header: yes\r\nProxy: evil\r\n\
+        Authorization: secret\r\n\r\n", b"hello");
+    assert_eq!(resp.get_code(), 200);
+    assert_eq!(resp.get_header("Content-Type"), Some("text/plain"));
+    for line in ["GATEWAY_INTERFACE=CGI/1.1", "REQUEST_METHOD=POST",
+            "SCRIPT_NAME=/cgi/bin/env.cgi", "PATH_INFO=/a b/c/",
+            "QUERY_STRING=x=1", "SERVER_NAME=example.com", "SERVER_PORT=8080",
+            "SERVER_PROTOCOL=HTTP/1.1", "CONTENT_LENGTH=5",
+            "CONTENT_TYPE=text/plain", "REMOTE_ADDR=192.0.2.1",
+            "REMOTE_PORT=1234", "HTTP_X_CUSTOM_HEADER=yes",
+            "HTTP_HOST=example.com:8080",
+            "REQUEST_URI=/bin/env.cgi/a%20b/c/?x=1"].iter() {
+        assert!(body.lines().any(|l| l == *line), "{} in {}", line, body);
+    }
+    assert!(!body.contains("HTTP_PROXY") && !body.contains("secret"));
+    assert!(body.ends_with("hello"));
+
+    let (resp, body) = run("GET /status.cgi HTTP/1.1\r\n\r\n", b"");
+    assert_eq!((resp.get_code(), resp.get_status()), (404, "Gone"));
+    assert_eq!(resp.get_header("X-A"), Some("1"));
+    assert_eq!(body, "missing");
+    let (resp, _) = run("GET /redirect.cgi HTTP/1.1\r\n\r\n", b"");
+    assert_eq!(resp.get_code(), 302);
+    assert_eq!(resp.get_header("Location"), Some("/elsewhere"));
+    assert_eq!(run("GET /length.cgi HTTP/1.1\r\n\r\n", b"").1, "ab");
+
+    for (path, code) in [("/broken.cgi", 500), ("/missing.cgi", 404),
+            ("/bin", 404), ("/bin/../status.cgi", 404),
+            ("/.hidden.cgi", 404)].iter() {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        assert_eq!(run(&raw, b"").0.get_code(), *code, "{}", path);
+    }
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn test_cgi_timeout() {
+    let root = test_root("cgi-timeout", &[
+        ("slow.cgi", "sleep 5"),
+        ("partial.cgi", "echo; echo start; sleep 5; echo end"),
+    ]);
+    let mut cgi = Cgi::new(&root);
+    cgi.set_timeout(Duration::from_millis(200));
+    let start = Instant::now();
+    let mut req = WebRequest::new_for_test("GET", "/slow.cgi");
+    assert_eq!(cgi.handle(&mut req).get_code(), 504);
+    let mut req = WebRequest::new_for_test("GET", "/partial.cgi");
+    let mut resp = cgi.handle(&mut req);
+    assert_eq!(resp.get_code(), 200);
+    let mut body = Vec::new();
+    let err = resp.reader.take().unwrap().read_to_end(&mut body).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(body, b"start\n");
+    assert!(start.elapsed() < Duration::from_secs(2));
+    fs::remove_dir_all(root).unwrap();
+}
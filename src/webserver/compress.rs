@@ -0,0 +1,211 @@
+//! Response compression middleware (gzip / deflate)
+
+use utils::deflate;
+use utils::negotiate;
+use super::{WebRequest, WebResponse, Middleware, Next};
+
+
+/// Middleware that compresses response bodies the client accepts
+/// compressed.
+///
+/// The encoding is picked from Accept-Encoding (gzip, then deflate).  Only
+/// responses of at least `min_size` bytes with a compressible Content-Type
+/// are compressed: text/*, JSON, JavaScript, XML and SVG by default.
+/// Responses that already have a Content-Encoding, are partial (206), or
+/// say Cache-Control: no-transform are left alone, as are bodies that
+/// don't get smaller.  So are streamed bodies (`set_body_reader`): they
+/// go out as their reader hands them over, and the DEFLATE encoder only
+/// compresses a whole body.  A middleware inside this one can buffer
+/// them first (`WebResponse::buffer_body`) to have them compressed.
+///
+/// Compressible responses always get `Vary: Accept-Encoding`, so caches
+/// keep the compressed and uncompressed versions apart.
+///
+/// Brotli is not supported.
+pub struct Compress {
+    min_size: usize,
+    content_types: Vec<String>,
+}
+
+impl Default for Compress {
+    fn default() -> Compress {
+        return Compress::new();
+    }
+}
+
+impl Compress {
+    pub fn new() -> Compress {
+        return Compress {
+            min_size: 1024,
+            content_types: vec!["text/*".to_string(),
+                "application/json".to_string(),
+                "application/javascript".to_string(),
+                "application/xml".to_string(),
+                "image/svg+xml".to_string()],
+        };
+    }
+
+    /// Don't compress bodies smaller than this (default 1024 bytes)
+    pub fn set_min_size(&mut self, size: usize) {
+        self.min_size = size;
+    }
+
+    /// Also compress this Content-Type, ex: "application/wasm".  A trailing
+    /// "/*" matches all subtypes, ex: "font/*".
+    pub fn add_content_type(&mut self, content_type: &str) {
+        self.content_types.push(content_type.to_ascii_lowercase());
+    }
+
+    fn compressible(&self, resp: &WebResponse) -> bool {
+        let content_type = match resp.get_header("Content-Type") {
+            Some(ct) => ct.split(';').next().unwrap().trim()
+                .to_ascii_lowercase(),
+            None => return false,
+        };
+        // application/vnd.api+json and the like
+        if content_type.ends_with("+json") || content_type.ends_with("+xml") {
+            return true;
+        }
+        return self.content_types.iter().any(|t| {
+            match t.strip_suffix("/*") {
+                Some(major) => content_type.starts_with(major)
+                    && content_type[major.len()..].starts_with('/'),
+                None => *t == content_type,
+            }
+        });
+    }
+
+    fn compress(&self, req: &WebRequest, resp: &mut WebResponse) {
+        if resp.is_streamed() || resp.code < 200 || resp.code == 204
+                || resp.code == 206
+                || resp.code == 304
+                || resp.get_header("Content-Encoding").is_some()
+                || resp.get_header("Content-Range").is_some()
+                || !self.compressible(resp) {
+            return;
+        }
+        resp.append_header("Vary", "Accept-Encoding");
+        let no_transform = resp.get_header("Cache-Control")
+            .is_some_and(|cc| cc.to_ascii_lowercase().contains("no-transform"));
+        if no_transform || resp.body.len() < self.min_size {
+            return;
+        }
+
+        let accept = req.get_header("Accept-Encoding").unwrap_or("");
+        let (encoding, body) = match negotiate::choose(accept,
+                &["gzip", "deflate"]) {
+            Some("gzip") => ("gzip", deflate::gzip(&resp.body)),
+            Some(_) => ("deflate", deflate::zlib(&resp.body)),
+            None => return,
+        };
+        if body.len() >= resp.body.len() {
+            return;
+        }
+        resp.body = body;
+        resp.set_header("Content-Encoding", encoding);
+        // A strong ETag names exact bytes, so the compressed body needs
+        // its own
+        if let Some(etag) = resp.remove_header("ETag") {
+            match etag.strip_suffix('"') {
+                Some(tag) if !etag.starts_with("W/") =>
+                    resp.set_header("ETag", &format!("{}-{}\"", tag, encoding)),
+                _ => resp.set_header("ETag", &etag),
+            }
+        }
+    }
+}
+
+impl Middleware for Compress {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let mut resp = next.run(req);
+        self.compress(req, &mut resp);
+        return resp;
+    }
+}
+
+
+#[cfg(test)]
+fn compress_test(accept: &str, content_type: &str, body: &str,
+        extra: &[(&str, &str)]) -> WebResponse {
+    use super::{Chain, Handler};
+
+    let (content_type, body) = (content_type.to_string(), body.to_string());
+    let extra: Vec<(String, String)> = extra.iter()
+        .map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+    let mut chain = Chain::new(move |_req: &WebRequest| {
+        let mut resp = WebResponse::new();
+        resp.set_header("Content-Type", &content_type);
+        resp.set_body_str(&body);
+        for (k, v) in extra.iter() {
+            resp.set_header(k, v);
+        }
+        return resp;
+    });
+    chain.add(Compress::new());
+    let mut req = WebRequest::parse_for_test(&format!(
+            "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", accept));
+    return chain.handle(&mut req);
+}
+
+#[test]
+fn test_compress() {
+    let big = "<p>hello</p>\n".repeat(200);
+    let resp = compress_test("gzip, deflate", "text/html; charset=utf-8", &big,
+            &[("ETag", "\"abc\"")]);
+    assert_eq!(resp.get_header("Content-Encoding"), Some("gzip"));
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Encoding"));
+    assert_eq!(resp.get_header("ETag"), Some("\"abc-gzip\""));
+    assert_eq!(deflate::gunzip(&resp.body, 1 << 20).unwrap(), big.as_bytes());
+
+    let resp = compress_test("gzip;q=0.5, deflate", "application/json", &big,
+            &[("ETag", "W/\"abc\"")]);
+    assert_eq!(resp.get_header("Content-Encoding"), Some("deflate"));
+    assert_eq!(resp.get_header("ETag"), Some("W/\"abc\""));
+    assert_eq!(deflate::unzlib(&resp.body, 1 << 20).unwrap(), big.as_bytes());
+
+    // Not accepted, too small, or not compressible
+    let resp = compress_test("br", "text/plain", &big, &[]);
+    assert!(resp.get_header("Content-Encoding").is_none());
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Encoding"));
+    let resp = compress_test("gzip", "text/plain", "small", &[]);
+    assert!(resp.get_header("Content-Encoding").is_none());
+    assert_eq!(resp.body, b"small");
+    let resp = compress_test("gzip", "image/png", &big, &[]);
+    assert!(resp.get_header("Content-Encoding").is_none());
+    assert!(resp.get_header("Vary").is_none());
+
+    // Never twice
+    let resp = compress_test("gzip", "text/plain", &big,
+            &[("Content-Encoding", "br")]);
+    assert_eq!(resp.body, big.as_bytes());
+    let resp = compress_test("gzip", "text/plain", &big,
+            &[("Cache-Control", "no-transform"), ("Vary", "Cookie")]);
+    assert_eq!(resp.body, big.as_bytes());
+    assert_eq!(resp.get_header("Vary"), Some("Cookie, Accept-Encoding"));
+
+    let resp = compress_test("gzip", "application/problem+json", &big, &[]);
+    assert_eq!(resp.get_header("Content-Encoding"), Some("gzip"));
+}
+
+#[test]
+fn test_compress_streamed() {
+    use std::io::Cursor;
+    use super::{Chain, Handler};
+
+    let big = "<p>hello</p>\n".repeat(200);
+    let body = big.clone();
+    let mut chain = Chain::new(move |_req: &WebRequest| {
+        let mut resp = WebResponse::new();
+        resp.set_header("Content-Type", "text/html");
+        resp.set_body_reader(Cursor::new(body.clone().into_bytes()), None);
+        return resp;
+    });
+    chain.add(Compress::new());
+    let mut req = WebRequest::parse_for_test(
+            "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+    let mut resp = chain.handle(&mut req);
+    assert!(resp.is_streamed());
+    assert!(resp.get_header("Content-Encoding").is_none());
+    assert!(resp.get_header("Vary").is_none());
+    assert_eq!(resp.body_for_test(), big.as_bytes());
+}
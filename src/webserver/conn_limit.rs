@@ -0,0 +1,117 @@
+//! A limit on the connections open from each client address
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use super::Cidr;
+use super::ip_filter::parse_or_panic;
+
+
+/// Limits the connections each client IP address has open at once, so one
+/// client can't take all the worker threads (each connection has one).
+///
+/// Connections over the limit are answered `429 Too Many Requests` and
+/// closed, without reading their request.  Addresses of the networks
+/// added with `exempt` (ex: a proxy in front, whose connections are those
+/// of many clients) aren't limited.  See `WebServer::set_conn_limit`.
+///
+/// ex:
+///
+/// ```ignore
+/// let mut limit = ConnLimit::new(8);
+/// limit.exempt("10.0.0.0/8");
+/// server.set_conn_limit(limit);
+/// ```
+pub struct ConnLimit {
+    max_per_ip: usize,
+    exempt: Vec<Cidr>,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+// An open connection, counted until dropped
+pub(crate) struct ConnSlot<'a> {
+    limit: &'a ConnLimit,
+    ip: IpAddr,
+}
+
+impl ConnLimit {
+    /// `max_per_ip` must be >= 1.
+    pub fn new(max_per_ip: usize) -> ConnLimit {
+        assert!(max_per_ip >= 1);
+        return ConnLimit {
+            max_per_ip: max_per_ip,
+            exempt: Vec::new(),
+            open: Mutex::new(HashMap::new()),
+        };
+    }
+
+    /// Don't limit addresses in this network.  Panics if `cidr` is invalid.
+    pub fn exempt(&mut self, cidr: &str) {
+        self.exempt.push(parse_or_panic(cidr));
+    }
+
+    // Count a new connection from ip, or None if it has too many already.
+    // Exempt addresses aren't counted.
+    pub(crate) fn open(&self, ip: IpAddr) -> Result<Option<ConnSlot<'_>>, ()> {
+        if self.exempt.iter().any(|cidr| cidr.contains(ip)) {
+            return Ok(None);
+        }
+        let mut open = self.lock();
+        let count = open.entry(ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return Err(());
+        }
+        *count += 1;
+        return Ok(Some(ConnSlot { limit: self, ip: ip }));
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, HashMap<IpAddr, usize>> {
+        match self.open.lock() {
+            Ok(guard) => return guard,
+            Err(poisoned) => return poisoned.into_inner(),
+        }
+    }
+}
+
+impl<'a> Drop for ConnSlot<'a> {
+    fn drop(&mut self) {
+        let mut open = self.limit.lock();
+        let done = match open.get_mut(&self.ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false,
+        };
+        // Only keep the addresses with connections open
+        if done {
+            open.remove(&self.ip);
+        }
+    }
+}
+
+
+#[test]
+fn test_conn_limit() {
+    let mut limit = ConnLimit::new(2);
+    limit.exempt("10.0.0.0/8");
+    let a: IpAddr = "192.0.2.1".parse().unwrap();
+    let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+    let first = limit.open(a).unwrap();
+    let second = limit.open(a).unwrap();
+    assert!(first.is_some() && second.is_some());
+    assert!(limit.open(a).is_err());
+    assert!(limit.open(b).is_ok());
+    drop(first);
+    let third = limit.open(a);
+    assert!(third.is_ok());
+    drop((second, third));
+    assert!(limit.lock().is_empty());
+
+    let proxy: IpAddr = "10.1.2.3".parse().unwrap();
+    let proxied: Vec<_> = (0..5).map(|_| limit.open(proxy).unwrap()).collect();
+    assert!(proxied.iter().all(|slot| slot.is_none()));
+    assert!(limit.lock().is_empty());
+}
@@ -0,0 +1,122 @@
+//! Cookies (RFC 6265): parsing the Cookie header and building Set-Cookie
+
+use std::fmt;
+
+
+/// The SameSite cookie attribute
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+
+/// A cookie to send with `WebResponse::add_cookie`.
+///
+/// `Cookie::new` gives a session cookie (no Max-Age) for the whole site,
+/// with no other attributes set.  The value is sent as is, so it must not
+/// contain spaces, quotes, commas, semicolons or backslashes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    /// Seconds until the cookie expires; 0 deletes it
+    pub max_age: Option<i64>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        return Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: Some("/".to_string()),
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        };
+    }
+
+    /// A cookie that makes the browser delete `name` (for the same path
+    /// and domain)
+    pub fn removal(name: &str) -> Cookie {
+        let mut ret = Cookie::new(name, "");
+        ret.max_age = Some(0);
+        return ret;
+    }
+}
+
+impl fmt::Display for Cookie {
+    /// The Set-Cookie header value
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(ref path) = self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(ref domain) = self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict")?,
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax")?,
+            Some(SameSite::None) => write!(f, "; SameSite=None")?,
+            None => (),
+        }
+        return Ok(());
+    }
+}
+
+
+/// Parse a Cookie request header into (name, value) pairs.  Values in
+/// double quotes are unquoted.
+pub fn parse_cookie_header(header: &str) -> Vec<(&str, &str)> {
+    let mut ret = Vec::new();
+    for pair in header.split(';') {
+        let pair = pair.trim();
+        if let Some(eq) = pair.find('=') {
+            let name = pair[..eq].trim();
+            let mut value = pair[eq + 1..].trim();
+            if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                value = &value[1..value.len() - 1];
+            }
+            if !name.is_empty() {
+                ret.push((name, value));
+            }
+        }
+    }
+    return ret;
+}
+
+
+#[test]
+fn test_cookie() {
+    assert_eq!(parse_cookie_header("a=1; b = \"two\" ;c=;bad; =x"),
+        vec![("a", "1"), ("b", "two"), ("c", "")]);
+
+    let mut c = Cookie::new("sid", "abc");
+    assert_eq!(c.to_string(), "sid=abc; Path=/");
+    c.max_age = Some(3600);
+    c.secure = true;
+    c.http_only = true;
+    c.same_site = Some(SameSite::Lax);
+    c.domain = Some("example.com".to_string());
+    assert_eq!(c.to_string(), "sid=abc; Path=/; Domain=example.com; \
+        Max-Age=3600; Secure; HttpOnly; SameSite=Lax");
+    assert_eq!(Cookie::removal("sid").to_string(), "sid=; Path=/; Max-Age=0");
+}
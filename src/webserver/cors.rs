@@ -0,0 +1,313 @@
+//! Cross-Origin Resource Sharing (CORS) middleware
+//!
+//! https://fetch.spec.whatwg.org/#http-cors-protocol
+
+use super::{WebRequest, WebResponse, Middleware, Next};
+
+
+enum Origins {
+    List(Vec<String>),
+    Any,
+    Callback(Box<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+
+/// Middleware that answers CORS preflight requests and adds the
+/// Access-Control-* headers to responses for allowed origins.
+///
+/// By default no origin is allowed; see `add_origin`, `set_any_origin`
+/// and `set_origin_fn`.  Requests from other origins are passed through
+/// untouched (the browser then blocks the response), except for preflight
+/// requests which get a 403.
+///
+/// ex:
+///
+/// ```ignore
+/// let mut cors = Cors::new();
+/// cors.add_origin("https://*.example.com");
+/// cors.set_headers("Content-Type, Authorization");
+/// svr.add_middleware(cors);
+/// ```
+pub struct Cors {
+    origins: Origins,
+    methods: Vec<String>,
+    // None: allow whatever the client asks for
+    headers: Option<Vec<String>>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<u32>,
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        return Cors::new();
+    }
+}
+
+impl Cors {
+    /// Allow no origins, the methods GET, HEAD and POST, and no extra
+    /// request headers.
+    pub fn new() -> Cors {
+        return Cors {
+            origins: Origins::List(Vec::new()),
+            methods: parse_list("GET, HEAD, POST"),
+            headers: Some(Vec::new()),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        };
+    }
+
+    /// Allow an origin, ex: "https://example.com".  A "*" in the host
+    /// matches one or more labels, ex: "https://*.example.com".
+    pub fn add_origin(&mut self, origin: &str) {
+        if let Origins::List(ref mut list) = self.origins {
+            list.push(origin.to_ascii_lowercase());
+            return;
+        }
+        self.origins = Origins::List(vec![origin.to_ascii_lowercase()]);
+    }
+
+    /// Allow all origins
+    pub fn set_any_origin(&mut self) {
+        self.origins = Origins::Any;
+    }
+
+    /// Decide per origin with a function, ex: `|origin| origin.ends_with(..)`
+    pub fn set_origin_fn<F>(&mut self, f: F)
+            where F: Fn(&str) -> bool + Send + Sync + 'static {
+        self.origins = Origins::Callback(Box::new(f));
+    }
+
+    /// Comma separated list of allowed methods, ex: "GET, PUT, DELETE"
+    pub fn set_methods(&mut self, methods: &str) {
+        self.methods = parse_list(&methods.to_ascii_uppercase());
+    }
+
+    /// Comma separated list of allowed request headers, or "*" for any
+    pub fn set_headers(&mut self, headers: &str) {
+        match headers.trim() {
+            "*" => self.headers = None,
+            _ => self.headers = Some(parse_list(&headers.to_ascii_lowercase())),
+        }
+    }
+
+    /// Comma separated list of response headers scripts may read
+    pub fn set_expose_headers(&mut self, headers: &str) {
+        self.expose_headers = parse_list(headers);
+    }
+
+    /// Allow cookies and HTTP auth (Access-Control-Allow-Credentials)
+    pub fn set_credentials(&mut self, on: bool) {
+        self.credentials = on;
+    }
+
+    /// How long browsers may cache a preflight result, in seconds
+    pub fn set_max_age(&mut self, secs: u32) {
+        self.max_age = Some(secs);
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        match self.origins {
+            Origins::Any => return true,
+            Origins::Callback(ref f) => return f(origin),
+            Origins::List(ref list) => {
+                let origin = origin.to_ascii_lowercase();
+                return list.iter().any(|o| origin_matches(o, &origin));
+            }
+        }
+    }
+
+    // Access-Control-Allow-Origin and friends, common to both responses
+    fn add_origin_headers(&self, resp: &mut WebResponse, origin: &str) {
+        match self.origins {
+            Origins::Any if !self.credentials => {
+                resp.set_header("Access-Control-Allow-Origin", "*");
+            }
+            _ => {
+                resp.set_header("Access-Control-Allow-Origin", origin);
+                resp.append_header("Vary", "Origin");
+            }
+        }
+        if self.credentials {
+            resp.set_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    fn preflight(&self, req: &WebRequest, origin: &str) -> WebResponse {
+        let mut resp = WebResponse::new();
+        let method = req.get_header("Access-Control-Request-Method")
+            .unwrap_or("");
+        let requested = parse_list(req.get_header(
+                "Access-Control-Request-Headers").unwrap_or(""));
+        let headers_ok = match self.headers {
+            None => true,
+            Some(ref allowed) => requested.iter().all(|h|
+                allowed.contains(&h.to_ascii_lowercase())),
+        };
+        if !self.origin_allowed(origin)
+                || !self.methods.iter().any(|m| m == method) || !headers_ok {
+            resp.set_code(403, "Forbidden");
+            resp.set_body_str("Error 403: CORS request not allowed");
+            return resp;
+        }
+
+        resp.set_code(204, "No Content");
+        self.add_origin_headers(&mut resp, origin);
+        resp.set_header("Access-Control-Allow-Methods",
+                &self.methods.join(", "));
+        if !requested.is_empty() {
+            resp.set_header("Access-Control-Allow-Headers",
+                    &requested.join(", "));
+        }
+        if let Some(secs) = self.max_age {
+            resp.set_header("Access-Control-Max-Age", &secs.to_string());
+        }
+        resp.append_header("Vary",
+                "Access-Control-Request-Method, Access-Control-Request-Headers");
+        return resp;
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let origin = match req.get_header("Origin") {
+            Some(origin) => origin.to_string(),
+            None => return next.run(req),
+        };
+        if req.get_method() == "options"
+                && req.get_header("Access-Control-Request-Method").is_some() {
+            return self.preflight(req, &origin);
+        }
+
+        let mut resp = next.run(req);
+        if self.origin_allowed(&origin) {
+            self.add_origin_headers(&mut resp, &origin);
+            if !self.expose_headers.is_empty() {
+                resp.set_header("Access-Control-Expose-Headers",
+                        &self.expose_headers.join(", "));
+            }
+        } else if let Origins::List(..) = self.origins {
+            // The answer depends on the origin, even when it's a no
+            resp.append_header("Vary", "Origin");
+        }
+        return resp;
+    }
+}
+
+
+fn parse_list(s: &str) -> Vec<String> {
+    return s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty())
+        .map(|x| x.to_string()).collect();
+}
+
+// pattern and origin are lowercase.  "*" matches one or more host labels.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    match pattern.find('*') {
+        None => return pattern == origin,
+        Some(star) => {
+            let (head, tail) = (&pattern[..star], &pattern[star + 1..]);
+            if origin.len() <= head.len() + tail.len()
+                    || !origin.starts_with(head) || !origin.ends_with(tail) {
+                return false;
+            }
+            let middle = &origin[head.len()..origin.len() - tail.len()];
+            return !middle.starts_with('.') && !middle.ends_with('.')
+                && middle.bytes().all(|b| b.is_ascii_alphanumeric()
+                    || b == b'-' || b == b'.');
+        }
+    }
+}
+
+
+#[cfg(test)]
+fn cors_request(method: &str, headers: &str) -> WebRequest {
+    return WebRequest::parse_for_test(
+            &format!("{} /api HTTP/1.1\r\n{}\r\n", method, headers));
+}
+
+#[test]
+fn test_cors() {
+    use super::{Chain, Handler};
+
+    let mut cors = Cors::new();
+    cors.add_origin("https://*.example.com");
+    cors.add_origin("http://localhost:8080");
+    cors.set_methods("GET, PUT");
+    cors.set_headers("Content-Type");
+    cors.set_max_age(600);
+    let mut chain = Chain::new(|_req: &WebRequest| {
+        return WebResponse::new_html("hi".to_string());
+    });
+    chain.add(cors);
+
+    // Not a CORS request
+    let resp = chain.handle(&mut cors_request("GET", ""));
+    assert!(resp.get_header("Access-Control-Allow-Origin").is_none());
+
+    let resp = chain.handle(&mut cors_request("GET",
+            "Origin: https://api.example.com\r\n"));
+    assert_eq!(resp.get_header("Access-Control-Allow-Origin"),
+            Some("https://api.example.com"));
+    assert_eq!(resp.get_header("Vary"), Some("Origin"));
+    assert_eq!(resp.body, b"hi");
+
+    for origin in &["https://example.com", "https://evil.com",
+            "https://.example.com", "http://a.example.com"] {
+        let resp = chain.handle(&mut cors_request("GET",
+                &format!("Origin: {}\r\n", origin)));
+        assert!(resp.get_header("Access-Control-Allow-Origin").is_none());
+        assert_eq!(resp.code, 200);
+    }
+
+    let resp = chain.handle(&mut cors_request("OPTIONS",
+            "Origin: http://localhost:8080\r\n\
+            Access-Control-Request-Method: PUT\r\n\
+            Access-Control-Request-Headers: content-type\r\n"));
+    assert_eq!(resp.code, 204);
+    assert_eq!(resp.body, b"");
+    assert_eq!(resp.get_header("Access-Control-Allow-Methods"),
+            Some("GET, PUT"));
+    assert_eq!(resp.get_header("Access-Control-Allow-Headers"),
+            Some("content-type"));
+    assert_eq!(resp.get_header("Access-Control-Max-Age"), Some("600"));
+
+    let resp = chain.handle(&mut cors_request("OPTIONS",
+            "Origin: http://localhost:8080\r\n\
+            Access-Control-Request-Method: DELETE\r\n"));
+    assert_eq!(resp.code, 403);
+    let resp = chain.handle(&mut cors_request("OPTIONS",
+            "Origin: http://localhost:8080\r\n\
+            Access-Control-Request-Method: GET\r\n\
+            Access-Control-Request-Headers: X-Secret\r\n"));
+    assert_eq!(resp.code, 403);
+}
+
+#[test]
+fn test_cors_any_origin() {
+    use super::{Chain, Handler};
+
+    let mut cors = Cors::new();
+    cors.set_any_origin();
+    cors.set_expose_headers("X-Total");
+    let mut chain = Chain::new(|_req: &WebRequest| WebResponse::new());
+    chain.add(cors);
+    let resp = chain.handle(&mut cors_request("GET", "Origin: http://a\r\n"));
+    assert_eq!(resp.get_header("Access-Control-Allow-Origin"), Some("*"));
+    assert_eq!(resp.get_header("Access-Control-Expose-Headers"),
+            Some("X-Total"));
+    assert!(resp.get_header("Vary").is_none());
+
+    // Credentials can't be used with "*", so the origin is echoed
+    let mut cors = Cors::new();
+    cors.set_origin_fn(|origin| origin.starts_with("http://"));
+    cors.set_credentials(true);
+    let mut chain = Chain::new(|_req: &WebRequest| WebResponse::new());
+    chain.add(cors);
+    let resp = chain.handle(&mut cors_request("GET", "Origin: http://a\r\n"));
+    assert_eq!(resp.get_header("Access-Control-Allow-Origin"), Some("http://a"));
+    assert_eq!(resp.get_header("Access-Control-Allow-Credentials"),
+            Some("true"));
+    let resp = chain.handle(&mut cors_request("GET", "Origin: https://a\r\n"));
+    assert!(resp.get_header("Access-Control-Allow-Origin").is_none());
+}
@@ -0,0 +1,195 @@
+//! Cross-site request forgery (CSRF) protection middleware
+
+use utils::byteutils::constant_time_eq;
+use utils::random;
+use super::{WebRequest, WebResponse, Middleware, Next, Cookie, SameSite,
+    Session};
+
+
+// Session key for the synchronizer token
+static SESSION_KEY: &str = "_csrf_token";
+
+
+/// The CSRF token for the current request, stored in the request
+/// extensions by `Csrf`.  Put it in forms as a hidden field, or send it
+/// back in a header from scripts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsrfToken(pub String);
+
+
+/// Middleware that rejects unsafe requests (anything but GET, HEAD,
+/// OPTIONS and TRACE) that don't carry the right token, with a 403.
+///
+/// The token is read from the X-CSRF-Token header, or else the
+/// "csrf_token" field of a urlencoded form body.
+///
+/// If a `Session` is available (add `Sessions` before this), the token is
+/// kept in the session (synchronizer token pattern).  Otherwise it's sent
+/// as a cookie that the request must echo (double submit cookie).  That
+/// cookie isn't HttpOnly, so scripts can copy it into the header.
+pub struct Csrf {
+    cookie_name: String,
+    header_name: String,
+    field_name: String,
+    secure: bool,
+}
+
+impl Default for Csrf {
+    fn default() -> Csrf {
+        return Csrf::new();
+    }
+}
+
+impl Csrf {
+    pub fn new() -> Csrf {
+        return Csrf {
+            cookie_name: "mudpie_csrf".to_string(),
+            header_name: "X-CSRF-Token".to_string(),
+            field_name: "csrf_token".to_string(),
+            secure: false,
+        };
+    }
+
+    /// Cookie name for double submit mode (default "mudpie_csrf")
+    pub fn set_cookie_name(&mut self, name: &str) {
+        self.cookie_name = name.to_string();
+    }
+
+    /// Request header with the token (default "X-CSRF-Token")
+    pub fn set_header_name(&mut self, name: &str) {
+        self.header_name = name.to_string();
+    }
+
+    /// Form field with the token (default "csrf_token")
+    pub fn set_field_name(&mut self, name: &str) {
+        self.field_name = name.to_string();
+    }
+
+    /// Only send the double submit cookie over HTTPS
+    pub fn set_secure(&mut self, on: bool) {
+        self.secure = on;
+    }
+
+    fn submitted(&self, req: &WebRequest) -> Option<String> {
+        if let Some(token) = req.get_header(&self.header_name) {
+            return Some(token.to_string());
+        }
+        return req.get_form().into_iter()
+            .find(|(k, _)| *k == self.field_name).map(|(_, v)| v);
+    }
+}
+
+impl Middleware for Csrf {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let from_session = req.get_extensions().get::<Session>()
+            .map(|s| s.get(SESSION_KEY));
+        let has_session = from_session.is_some();
+        let expected = match from_session {
+            Some(token) => token,
+            None => req.get_cookie(&self.cookie_name)
+                .filter(|t| !t.is_empty()).map(|t| t.to_string()),
+        };
+
+        let safe = matches!(req.get_method(), "get" | "head" | "options" | "trace");
+        if !safe {
+            let ok = match (expected.as_ref(), self.submitted(req)) {
+                (Some(expected), Some(submitted)) =>
+                    constant_time_eq(expected.as_bytes(), submitted.as_bytes()),
+                _ => false,
+            };
+            if !ok {
+                let mut resp = WebResponse::new();
+                resp.set_code(403, "Forbidden");
+                resp.set_body_str("Error 403: CSRF token missing or invalid");
+                return resp;
+            }
+        }
+
+        let (token, is_new) = match expected {
+            Some(token) => (token, false),
+            None => (random::hex_token(16), true),
+        };
+        if is_new && has_session {
+            req.get_extensions().get::<Session>().unwrap()
+                .set(SESSION_KEY, &token);
+        }
+        req.get_extensions_mut().insert(CsrfToken(token.clone()));
+        let mut resp = next.run(req);
+        if is_new && !has_session {
+            let mut cookie = Cookie::new(&self.cookie_name, &token);
+            cookie.secure = self.secure;
+            cookie.same_site = Some(SameSite::Strict);
+            resp.add_cookie(&cookie);
+        }
+        return resp;
+    }
+}
+
+
+#[cfg(test)]
+fn csrf_request(method: &str, headers: &str, body: &str) -> WebRequest {
+    let mut req = WebRequest::parse_for_test(&format!(
+            "{} / HTTP/1.1\r\n{}\r\n", method, headers));
+    req.body = body.as_bytes().to_vec();
+    return req;
+}
+
+#[test]
+fn test_csrf_double_submit() {
+    use super::{Chain, Handler};
+
+    let mut chain = Chain::new(|req: &WebRequest| {
+        let token = req.get_extensions().get::<CsrfToken>().unwrap();
+        return WebResponse::new_html(token.0.clone());
+    });
+    chain.add(Csrf::new());
+
+    let resp = chain.handle(&mut csrf_request("GET", "", ""));
+    assert_eq!(resp.code, 200);
+    let token = String::from_utf8(resp.body.clone()).unwrap();
+    assert_eq!(resp.get_header("Set-Cookie").unwrap(),
+            format!("mudpie_csrf={}; Path=/; SameSite=Strict", token));
+    let cookie = format!("Cookie: mudpie_csrf={}\r\n", token);
+
+    // Token known: no new cookie
+    let resp = chain.handle(&mut csrf_request("GET", &cookie, ""));
+    assert!(resp.get_header("Set-Cookie").is_none());
+    assert_eq!(resp.body, token.as_bytes());
+
+    assert_eq!(chain.handle(&mut csrf_request("POST", "", "")).code, 403);
+    assert_eq!(chain.handle(&mut csrf_request("POST", &cookie, "")).code, 403);
+    assert_eq!(chain.handle(&mut csrf_request("POST",
+            &format!("{}X-CSRF-Token: {}x\r\n", cookie, token), "")).code, 403);
+    assert_eq!(chain.handle(&mut csrf_request("DELETE",
+            &format!("{}X-CSRF-Token: {}\r\n", cookie, token), "")).code, 200);
+    assert_eq!(chain.handle(&mut csrf_request("POST",
+            &format!("{}Content-Type: application/x-www-form-urlencoded\r\n",
+                cookie),
+            &format!("a=1&csrf_token={}", token))).code, 200);
+}
+
+#[test]
+fn test_csrf_session() {
+    use super::{Chain, Handler, Sessions};
+
+    let mut chain = Chain::new(|req: &WebRequest| {
+        let token = req.get_extensions().get::<CsrfToken>().unwrap();
+        return WebResponse::new_html(token.0.clone());
+    });
+    chain.add(Sessions::signed(&[1; 32]));
+    chain.add(Csrf::new());
+
+    let resp = chain.handle(&mut csrf_request("GET", "", ""));
+    let token = String::from_utf8(resp.body.clone()).unwrap();
+    let session = resp.get_header("Set-Cookie").unwrap();
+    assert!(session.starts_with("mudpie_session="));
+    let cookie = format!("Cookie: {}\r\n",
+            session.split(';').next().unwrap());
+
+    // A double submit cookie doesn't help here
+    assert_eq!(chain.handle(&mut csrf_request("POST",
+            &format!("Cookie: mudpie_csrf={0}\r\nX-CSRF-Token: {0}\r\n",
+                token), "")).code, 403);
+    assert_eq!(chain.handle(&mut csrf_request("POST",
+            &format!("{}X-CSRF-Token: {}\r\n", cookie, token), "")).code, 200);
+}
@@ -0,0 +1,146 @@
+//! A log of the error responses, for security review
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use utils::time;
+use utils::escape::json_escape;
+use super::{WebRequest, WebResponse, ConnInfo};
+
+// Headers whose values are credentials, logged as "[redacted]"
+static REDACTED: [&str; 4] = ["authorization", "proxy-authorization",
+    "cookie", "x-csrf-token"];
+
+
+/// Writes a JSON line for each 4xx and 5xx response, apart from the
+/// access log: the time, the client's address, the request head, the
+/// response's status, and what caused it when the server made it itself
+/// (ex: an invalid request, a panic in the handler, an upstream a
+/// `ReverseProxy` couldn't reach), else null:
+///
+/// ```text
+/// {"time":"2000-10-10T13:55:36Z","remote_addr":"10.1.2.3",
+///     "method":"GET","uri":"/x","protocol":"HTTP/1.1",
+///     "headers":{"host":"example.com"},"status":500,
+///     "cause":"Handler panicked: oops"}
+/// ```
+///
+/// (on a single line).  Requests that couldn't be read have no head:
+/// "method" and the like are null.  Headers are sorted by name, and
+/// credentials (Authorization, Cookie, and the like) are redacted.
+pub struct ErrorLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl ErrorLog {
+    pub fn new<W: Write + Send + 'static>(out: W) -> ErrorLog {
+        return ErrorLog { out: Mutex::new(Box::new(out)) };
+    }
+
+    /// Append to the file at `path`, creating it if needed.
+    pub fn open(path: &str) -> io::Result<ErrorLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        return Ok(ErrorLog::new(file));
+    }
+
+    // Called after the response was written, like the access log.  Errors
+    // writing the log are ignored.
+    pub(crate) fn log(&self, req: Option<&WebRequest>, resp: &WebResponse,
+            conn: &ConnInfo) {
+        if resp.code < 400 {
+            return;
+        }
+        let string = |s: Option<&str>| match s {
+            Some(s) => format!("\"{}\"", json_escape(s)),
+            None => "null".to_string(),
+        };
+        let environ = |key: &str| req
+            .and_then(|r| r.environ.get(key.as_bytes()))
+            .map(|v| String::from_utf8_lossy(v).into_owned());
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        if let Some(req) = req {
+            for (key, value) in req.environ.iter() {
+                if let Some(name) = key.strip_prefix(&b"http_"[..]) {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    let value = match REDACTED.contains(&&name[..]) {
+                        true => "[redacted]".to_string(),
+                        false => String::from_utf8_lossy(value).into_owned(),
+                    };
+                    headers.push((name, value));
+                }
+            }
+        }
+        headers.sort();
+        let headers = match req {
+            Some(_) => {
+                let fields: Vec<String> = headers.iter()
+                    .map(|(n, v)| format!("\"{}\":\"{}\"", json_escape(n),
+                            json_escape(v)))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            },
+            None => "null".to_string(),
+        };
+
+        let line = format!("{{\"time\":\"{}\",\"remote_addr\":\"{}\",\
+                \"method\":{},\"uri\":{},\"protocol\":{},\"headers\":{},\
+                \"status\":{},\"cause\":{}}}\n",
+                time::format_iso8601(conn.start_time), conn.peer_addr.ip(),
+                string(req.map(|r| r.get_method().to_ascii_uppercase())
+                    .as_deref()),
+                string(environ("request_uri").as_deref()),
+                string(environ("protocol").map(|p| p.to_ascii_uppercase())
+                    .as_deref()),
+                headers, resp.code, string(resp.cause.as_deref()));
+        let mut out = match self.out.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let _ = out.write_all(line.as_bytes());
+        let _ = out.flush();
+    }
+}
+
+
+#[test]
+fn test_error_log() {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use super::access_log::SharedBuf;
+    use super::slow_log::Timings;
+
+    let conn = ConnInfo {
+        peer_addr: "10.1.2.3:5555".parse().unwrap(),
+        start: Instant::now(),
+        start_time: UNIX_EPOCH + Duration::from_secs(971186136),
+        timings: Timings::default(),
+    };
+    let req = WebRequest::parse_for_test("GET /x?\"a\" HTTP/1.1\r\n\
+            Host: example.com\r\nCookie: s=secret\r\nAccept: */*\r\n\r\n");
+    let mut resp = WebResponse::new();
+
+    let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+    let log = ErrorLog::new(buf.clone());
+    log.log(Some(&req), &resp, &conn);
+    resp.set_code(404, "Not Found");
+    log.log(Some(&req), &resp, &conn);
+    resp.set_code(400, "Bad Request");
+    resp.cause = Some("Invalid request, answered with 400".to_string());
+    log.log(None, &resp, &conn);
+
+    let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines, [
+        "{\"time\":\"2000-10-10T13:55:36Z\",\"remote_addr\":\"10.1.2.3\",\
+            \"method\":\"GET\",\"uri\":\"/x?\\\"a\\\"\",\
+            \"protocol\":\"HTTP/1.1\",\"headers\":{\"accept\":\"*/*\",\
+            \"cookie\":\"[redacted]\",\"host\":\"example.com\"},\
+            \"status\":404,\"cause\":null}",
+        "{\"time\":\"2000-10-10T13:55:36Z\",\"remote_addr\":\"10.1.2.3\",\
+            \"method\":null,\"uri\":null,\"protocol\":null,\"headers\":null,\
+            \"status\":400,\
+            \"cause\":\"Invalid request, answered with 400\"}",
+    ]);
+}
@@ -0,0 +1,141 @@
+//! Reporting of the errors the server runs into
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use super::WebRequest;
+
+
+/// An error the server ran into, on its own or from a handler
+#[derive(Debug)]
+pub enum ServerError<'a> {
+    /// Accepting a connection failed
+    Accept(&'a io::Error),
+    /// Reading a request failed
+    Read(&'a io::Error),
+    /// Reading a request timed out
+    Timeout(&'a io::Error),
+    /// A request that wasn't valid, answered with this error code
+    BadRequest(i32),
+    /// A request the `BodyInspector` turned down, with this error code
+    Rejected(i32),
+    /// An HTTP/2 connection was closed for breaking the protocol, with
+    /// this error code (RFC 7540 7)
+    Protocol(u32),
+    /// The handler panicked, with this message if it was a string.  The
+    /// client was sent a 500 error.
+    Panic(Option<&'a str>),
+    /// Sending a response failed
+    Write(&'a io::Error),
+}
+
+impl<'a> fmt::Display for ServerError<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::Accept(e) =>
+                return write!(f, "Error from accept(): {}", e),
+            ServerError::Read(e) =>
+                return write!(f, "Error when reading request: {}", e),
+            ServerError::Timeout(e) =>
+                return write!(f, "Timeout when reading request: {}", e),
+            ServerError::BadRequest(code) =>
+                return write!(f, "Invalid request, answered with {}", code),
+            ServerError::Rejected(code) =>
+                return write!(f, "Request rejected by the body inspector \
+                        with {}", code),
+            ServerError::Protocol(code) =>
+                return write!(f, "HTTP/2 protocol error {:#x}", code),
+            ServerError::Panic(Some(msg)) =>
+                return write!(f, "Handler panicked: {}", msg),
+            ServerError::Panic(None) => return write!(f, "Handler panicked"),
+            ServerError::Write(e) =>
+                return write!(f, "Error when sending response: {}", e),
+        }
+    }
+}
+
+/// Where an error happened
+pub struct ErrorContext<'a> {
+    /// The client's address; none for accept errors
+    pub peer_addr: Option<SocketAddr>,
+    /// The request, once it was read
+    pub request: Option<&'a WebRequest>,
+}
+
+/// Told about every error the server runs into, on the thread it happens
+/// on; see `WebServer::set_error_sink`.
+///
+/// Without one, errors are printed to stdout when logging is enabled.
+///
+/// ex:
+///
+/// ```ignore
+/// server.set_error_sink(|err: &ServerError, ctx: &ErrorContext| {
+///     eprintln!("{:?}: {}", ctx.peer_addr, err);
+/// });
+/// ```
+pub trait ErrorSink: Send + Sync {
+    fn report(&self, error: &ServerError, ctx: &ErrorContext);
+}
+
+impl<F> ErrorSink for F
+        where F: Fn(&ServerError, &ErrorContext) + Send + Sync {
+    fn report(&self, error: &ServerError, ctx: &ErrorContext) {
+        (self)(error, ctx);
+    }
+}
+
+// A read error, or a timeout
+pub(crate) fn read_error(e: &io::Error) -> ServerError<'_> {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock =>
+            return ServerError::Timeout(e),
+        _ => return ServerError::Read(e),
+    }
+}
+
+// The message of a panic, from its payload
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send))
+        -> Option<&str> {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        return Some(msg);
+    }
+    return payload.downcast_ref::<String>().map(|msg| &msg[..]);
+}
+
+
+#[test]
+fn test_server_error() {
+    use std::panic;
+    use std::sync::{Arc, Mutex};
+
+    let timeout = io::Error::new(io::ErrorKind::TimedOut, "slow");
+    assert_eq!(read_error(&timeout).to_string(),
+            "Timeout when reading request: slow");
+    let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+    assert_eq!(read_error(&reset).to_string(),
+            "Error when reading request: reset");
+
+    let payload = panic::catch_unwind(|| panic!("at {}", 3)).unwrap_err();
+    assert_eq!(panic_message(&*payload), Some("at 3"));
+    let payload = panic::catch_unwind(|| panic!("static")).unwrap_err();
+    assert_eq!(panic_message(&*payload), Some("static"));
+    let payload = panic::catch_unwind(|| panic::panic_any(3)).unwrap_err();
+    assert_eq!(panic_message(&*payload), None);
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink_seen = seen.clone();
+    let sink: Box<dyn ErrorSink> = Box::new(
+            move |err: &ServerError, ctx: &ErrorContext| {
+        let path = ctx.request.map(|r| r.get_path().to_string());
+        sink_seen.lock().unwrap().push((err.to_string(), path));
+    });
+    let req = WebRequest::new_for_test("GET", "/a");
+    sink.report(&ServerError::Panic(Some("oops")), &ErrorContext {
+        peer_addr: None,
+        request: Some(&req),
+    });
+    assert_eq!(*seen.lock().unwrap(), [("Handler panicked: oops".to_string(),
+            Some("/a".to_string()))]);
+}
@@ -0,0 +1,77 @@
+//! Per-request storage for data attached by the server, routers, and
+//! middleware.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+
+/// A map holding at most one value of each type.
+///
+/// Used to carry typed data alongside a `WebRequest`, e.g. the parameters
+/// captured by a `Router` (see `RouteParams`).
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Create an empty map
+    pub fn new() -> Extensions {
+        return Extensions { map: HashMap::new() };
+    }
+
+    /// Insert a value, returning the previous value of the same type (if any).
+    pub fn insert<T: Any + Send + Sync>(&mut self, val: T) -> Option<T> {
+        let old = self.map.insert(TypeId::of::<T>(), Box::new(val));
+        return old.and_then(|b| b.downcast::<T>().ok()).map(|b| *b);
+    }
+
+    /// Get a reference to the value of type `T`, if present.
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        return self.map.get(&TypeId::of::<T>())
+            .and_then(|b| b.downcast_ref::<T>());
+    }
+
+    /// Get a mutable reference to the value of type `T`, if present.
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        return self.map.get_mut(&TypeId::of::<T>())
+            .and_then(|b| b.downcast_mut::<T>());
+    }
+
+    /// Remove and return the value of type `T`, if present.
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        return self.map.remove(&TypeId::of::<T>())
+            .and_then(|b| b.downcast::<T>().ok()).map(|b| *b);
+    }
+
+    /// Get a mutable reference to the value of type `T`, inserting
+    /// `T::default()` first if it isn't present.
+    pub fn get_or_default<T: Any + Send + Sync + Default>(&mut self) -> &mut T {
+        return self.map.entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(T::default()))
+            .downcast_mut::<T>().unwrap();
+    }
+
+    /// Return true if a value of type `T` is present.
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        return self.map.contains_key(&TypeId::of::<T>());
+    }
+}
+
+
+#[test]
+fn test_extensions() {
+    let mut ext = Extensions::new();
+    assert!(ext.get::<i32>().is_none());
+    assert!(ext.insert(5i32).is_none());
+    assert!(ext.insert("hi".to_string()).is_none());
+    assert_eq!(*ext.get::<i32>().unwrap(), 5);
+    assert_eq!(ext.insert(6i32), Some(5));
+    *ext.get_mut::<i32>().unwrap() += 1;
+    assert_eq!(ext.remove::<i32>(), Some(7));
+    assert!(!ext.contains::<i32>());
+    assert_eq!(ext.get::<String>().unwrap(), "hi");
+    ext.get_or_default::<Vec<i32>>().push(1);
+    ext.get_or_default::<Vec<i32>>().push(2);
+    assert_eq!(ext.get::<Vec<i32>>().unwrap(), &vec![1, 2]);
+}
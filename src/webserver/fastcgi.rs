@@ -0,0 +1,742 @@
+//! A FastCGI client, for running requests in application servers such as
+//! PHP-FPM
+//!
+//! https://fastcgi-archives.github.io/FastCGI_Specification.html
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use super::{Handler, WebRequest, WebResponse};
+use super::cgi::{environment, mount_prefix, read_headers, script_response};
+use super::write_response::read_chunk;
+
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+
+static RESPONDER: u16 = 1;
+static KEEP_CONN: u8 = 1;
+// Idle connections kept, when the application doesn't multiplex
+static MAX_IDLE: usize = 8;
+
+
+/// A handler that sends requests to a FastCGI application, ex: PHP-FPM.
+///
+/// The application is told about a request with the CGI variables (see
+/// `Cgi`), and gets the body as its stdin.  Its output is parsed as a CGI
+/// script's would be, and the body streamed to the client.  What it
+/// writes to stderr goes to the server's.
+///
+/// SCRIPT_FILENAME, the script the application runs, is the request path
+/// under the root (see `set_root`), or always the same script (see
+/// `set_script`), for applications with one entry point.  Then the
+/// request path is its PATH_INFO.
+///
+/// If the application says it can (FCGI_MPXS_CONNS), requests share one
+/// connection.  Otherwise each has a connection of its own, which is kept
+/// open to be reused.  If it can't be reached, or doesn't answer within
+/// the timeout (see `set_timeout`), the client gets a 502 or a 504.
+///
+/// ex:
+///
+/// ```ignore
+/// let mut php = FastCgi::new("unix:/run/php/php-fpm.sock");
+/// php.set_root("/srv/www");
+/// router.mount("/app", php);
+/// ```
+pub struct FastCgi {
+    addr: String,
+    root: PathBuf,
+    script: Option<PathBuf>,
+    timeout: Duration,
+    // Whether the application multiplexes, once it has been asked
+    multiplex: Mutex<Option<bool>>,
+    mux: Mutex<Option<Arc<Mux>>>,
+    idle: Arc<Mutex<Vec<Stream>>>,
+}
+
+impl FastCgi {
+    /// Connect to the application at `addr`: "host:port", or "unix:path"
+    /// for a unix socket.
+    pub fn new(addr: &str) -> FastCgi {
+        return FastCgi {
+            addr: addr.to_string(),
+            root: PathBuf::from("/"),
+            script: None,
+            timeout: Duration::from_secs(60),
+            multiplex: Mutex::new(None),
+            mux: Mutex::new(None),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        };
+    }
+
+    /// The directory request paths are taken relative to, for
+    /// SCRIPT_FILENAME and DOCUMENT_ROOT.  Default "/".
+    pub fn set_root<P: AsRef<Path>>(&mut self, root: P) {
+        self.root = root.as_ref().to_path_buf();
+    }
+
+    /// Run `script` for every request, with the path as PATH_INFO
+    pub fn set_script<P: AsRef<Path>>(&mut self, script: P) {
+        self.script = Some(script.as_ref().to_path_buf());
+    }
+
+    /// How long to wait to connect, and for each part of the response.
+    /// Default 60 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn connect(&self) -> io::Result<Stream> {
+        return Stream::connect(&self.addr, self.timeout);
+    }
+
+    // Ask the application on a new connection whether it multiplexes
+    // requests, the first time.  The connection is kept for reuse.
+    fn multiplexes(&self) -> io::Result<bool> {
+        let mut multiplex = self.multiplex.lock().unwrap();
+        if let Some(multiplex) = *multiplex {
+            return Ok(multiplex);
+        }
+        let mut stream = self.connect()?;
+        let mut query = Vec::new();
+        push_param(&mut query, "FCGI_MPXS_CONNS", "");
+        write_record(&mut stream, GET_VALUES, 0, &query)?;
+        let mut reader = BufReader::new(stream);
+        let (kind, _, content) = read_record(&mut reader)?;
+        let answer = match kind {
+            GET_VALUES_RESULT => parse_params(&content)?
+                .iter().any(|p| p.0 == "FCGI_MPXS_CONNS" && p.1 == "1"),
+            _ => false,
+        };
+        *multiplex = Some(answer);
+        if reader.buffer().is_empty() {
+            let stream = reader.into_inner();
+            if answer {
+                *self.mux.lock().unwrap() = Some(Mux::start(stream)?);
+            } else {
+                push_idle(&self.idle, stream);
+            }
+        }
+        return Ok(answer);
+    }
+
+    // The shared connection, opened again if it broke
+    fn mux(&self) -> io::Result<Arc<Mux>> {
+        let mut mux = self.mux.lock().unwrap();
+        if let Some(ref current) = *mux {
+            if !current.broken.load(Ordering::SeqCst) {
+                return Ok(current.clone());
+            }
+        }
+        let new = Mux::start(self.connect()?)?;
+        *mux = Some(new.clone());
+        return Ok(new);
+    }
+
+    // The records of the request, from BEGIN_REQUEST to the end of PARAMS;
+    // STDIN follows with send_stdin
+    fn request_records(&self, req: &WebRequest, id: u16) -> Vec<u8> {
+        let path = req.path.clone();
+        let mount = mount_prefix(req);
+        let (script, script_name, path_info) = match self.script {
+            Some(ref script) => (script.clone(), mount, path),
+            None => (self.root.join(path.trim_start_matches('/')),
+                    format!("{}{}", mount, path), String::new()),
+        };
+        let mut params = Vec::new();
+        let mut env = environment(req, &script, &script_name, &path_info);
+        env.push(("DOCUMENT_ROOT".to_string(),
+                self.root.to_string_lossy().into_owned()));
+        for (name, value) in env {
+            push_param(&mut params, &name, &value);
+        }
+
+        let mut out = Vec::new();
+        let mut begin = RESPONDER.to_be_bytes().to_vec();
+        begin.extend_from_slice(&[KEEP_CONN, 0, 0, 0, 0, 0]);
+        push_record(&mut out, BEGIN_REQUEST, id, &begin);
+        push_stream(&mut out, PARAMS, id, &params);
+        return out;
+    }
+
+    fn run(&self, req: &WebRequest) -> io::Result<WebResponse> {
+        let source = match self.multiplexes()? {
+            true => {
+                let mux = self.mux()?;
+                let (id, records) = mux.open()?;
+                let sent = mux.send(&self.request_records(req, id))
+                    .and_then(|()| send_stdin(req, id, &mut |records| {
+                        return mux.send(records);
+                    }));
+                let source = Source::Mux {
+                    mux: mux,
+                    id: id,
+                    records: records,
+                    timeout: self.timeout,
+                };
+                sent?;
+                source
+            },
+            false => {
+                let reused = self.idle.lock().unwrap().pop();
+                let mut stream = match reused {
+                    Some(stream) => stream,
+                    None => self.connect()?,
+                };
+                let records = self.request_records(req, 1);
+                if stream.write_all(&records).is_err() {
+                    // It was closed while idle
+                    stream = self.connect()?;
+                    stream.write_all(&records)?;
+                }
+                send_stdin(req, 1, &mut |records| stream.write_all(records))?;
+                Source::Conn(BufReader::new(stream))
+            },
+        };
+
+        let mut body = BufReader::new(AppOutput {
+            source: Some(source),
+            pending: Vec::new(),
+            pos: 0,
+            idle: self.idle.clone(),
+        });
+        let headers = read_headers(&mut body)?;
+        let (mut resp, length) = script_response(headers)?;
+        resp.set_body_reader(body, length);
+        return Ok(resp);
+    }
+}
+
+impl Handler for FastCgi {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        match self.run(req) {
+            Ok(resp) => return resp,
+            Err(err) => match err.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock =>
+                    return error_response(504, "Gateway Timeout"),
+                _ => return error_response(502, "Bad Gateway"),
+            },
+        }
+    }
+}
+
+
+// A connection to an application, over TCP or a unix socket
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    // To "host:port", or "unix:path"
+    pub(crate) fn connect(addr: &str, timeout: Duration)
+            -> io::Result<Stream> {
+        let stream = match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Stream::Unix(UnixStream::connect(path)?),
+            #[cfg(not(unix))]
+            Some(_) => return Err(io::Error::new(io::ErrorKind::Unsupported,
+                    "no unix sockets")),
+            None => {
+                let mut last_err = None;
+                let mut stream = None;
+                for addr in addr.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, timeout) {
+                        Ok(s) => {
+                            stream = Some(s);
+                            break;
+                        },
+                        Err(err) => last_err = Some(err),
+                    }
+                }
+                match (stream, last_err) {
+                    (Some(stream), _) => Stream::Tcp(stream),
+                    (None, Some(err)) => return Err(err),
+                    (None, None) => return Err(io::Error::new(
+                            io::ErrorKind::NotFound, "no addresses")),
+                }
+            },
+        };
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(timeout)?;
+        return Ok(stream);
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>)
+            -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => return s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => return s.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref s) => return s.set_write_timeout(Some(timeout)),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => return s.set_write_timeout(Some(timeout)),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Tcp(ref s) => return Ok(Stream::Tcp(s.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(ref s) => return Ok(Stream::Unix(s.try_clone()?)),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => return s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => return s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut s) => return s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut s) => return s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+
+// A record: type, request id, and content
+type Record = (u8, u16, Vec<u8>);
+
+fn push_record(out: &mut Vec<u8>, kind: u8, id: u16, content: &[u8]) {
+    // Padded to a multiple of 8, as recommended
+    let padding = (8 - content.len() % 8) % 8;
+    out.extend_from_slice(&[1, kind]);
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[padding as u8, 0]);
+    out.extend_from_slice(content);
+    out.extend_from_slice(&[0; 8][..padding]);
+}
+
+// A stream's records, ending with an empty one
+fn push_stream(out: &mut Vec<u8>, kind: u8, id: u16, data: &[u8]) {
+    for chunk in data.chunks(65535) {
+        push_record(out, kind, id, chunk);
+    }
+    push_record(out, kind, id, &[]);
+}
+
+// The request body as STDIN records, read a record at a time, so a spooled
+// body isn't read into memory
+fn send_stdin(req: &WebRequest, id: u16,
+        send: &mut dyn FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+    let mut body = req.body_reader()?;
+    let mut chunk = vec![0; cmp::min(req.body_len(), 65535) as usize];
+    loop {
+        let len = read_chunk(&mut body, &mut chunk)?;
+        let mut out = Vec::with_capacity(16 + len);
+        push_record(&mut out, STDIN, id, &chunk[..len]);
+        send(&out)?;
+        if len == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn write_record(stream: &mut Stream, kind: u8, id: u16, content: &[u8])
+        -> io::Result<()> {
+    let mut out = Vec::new();
+    push_record(&mut out, kind, id, content);
+    return stream.write_all(&out);
+}
+
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Record> {
+    let mut head = [0; 8];
+    reader.read_exact(&mut head)?;
+    if head[0] != 1 {
+        return Err(invalid("not FastCGI"));
+    }
+    let len = u16::from_be_bytes([head[4], head[5]]) as usize;
+    let mut content = vec![0; len + head[6] as usize];
+    reader.read_exact(&mut content)?;
+    content.truncate(len);
+    return Ok((head[1], u16::from_be_bytes([head[2], head[3]]), content));
+}
+
+fn push_length(out: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+// A name-value pair, as in PARAMS and GET_VALUES
+fn push_param(out: &mut Vec<u8>, name: &str, value: &str) {
+    push_length(out, name.len());
+    push_length(out, value.len());
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn parse_params(mut data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut params = Vec::new();
+    let length = |data: &mut &[u8]| -> io::Result<usize> {
+        match data.first() {
+            Some(&b) if b < 128 => {
+                *data = &data[1..];
+                return Ok(b as usize);
+            },
+            Some(_) if data.len() >= 4 => {
+                let len = u32::from_be_bytes([data[0] & 0x7f, data[1],
+                        data[2], data[3]]);
+                *data = &data[4..];
+                return Ok(len as usize);
+            },
+            _ => return Err(invalid("bad name-value pair")),
+        }
+    };
+    while !data.is_empty() {
+        let name_len = length(&mut data)?;
+        let value_len = length(&mut data)?;
+        if data.len() < name_len + value_len {
+            return Err(invalid("bad name-value pair"));
+        }
+        let name = String::from_utf8_lossy(&data[..name_len]).into_owned();
+        let value = String::from_utf8_lossy(&data[name_len..][..value_len])
+            .into_owned();
+        params.push((name, value));
+        data = &data[name_len + value_len..];
+    }
+    return Ok(params);
+}
+
+
+// A connection shared by requests, with a thread passing the records it
+// reads on to each request
+struct Mux {
+    writer: Mutex<Stream>,
+    routes: Mutex<HashMap<u16, Sender<io::Result<Record>>>>,
+    broken: AtomicBool,
+}
+
+impl Mux {
+    fn start(stream: Stream) -> io::Result<Arc<Mux>> {
+        // The reader waits for as long as requests take; each has its
+        // own timeout
+        stream.set_read_timeout(None)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mux = Arc::new(Mux {
+            writer: Mutex::new(stream),
+            routes: Mutex::new(HashMap::new()),
+            broken: AtomicBool::new(false),
+        });
+        let shared = mux.clone();
+        thread::spawn(move || {
+            loop {
+                let record = read_record(&mut reader);
+                let mut routes = shared.routes.lock().unwrap();
+                match record {
+                    Ok(record) => {
+                        let id = record.1;
+                        let end = record.0 == END_REQUEST;
+                        if let Some(route) = routes.get(&id) {
+                            let _ = route.send(Ok(record));
+                        }
+                        if end {
+                            routes.remove(&id);
+                        }
+                    },
+                    Err(err) => {
+                        shared.broken.store(true, Ordering::SeqCst);
+                        for (_, route) in routes.drain() {
+                            let _ = route.send(Err(io::Error::new(
+                                    err.kind(), err.to_string())));
+                        }
+                        return;
+                    },
+                }
+            }
+        });
+        return Ok(mux);
+    }
+
+    // A request id of our own, and where its records will come
+    fn open(&self) -> io::Result<(u16, Receiver<io::Result<Record>>)> {
+        let (sender, receiver) = mpsc::channel();
+        let mut routes = self.routes.lock().unwrap();
+        let id = match (1..=u16::MAX).find(|id| !routes.contains_key(id)) {
+            Some(id) => id,
+            None => return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                    "too many requests")),
+        };
+        routes.insert(id, sender);
+        return Ok((id, receiver));
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        let ret = self.writer.lock().unwrap().write_all(data);
+        if ret.is_err() {
+            self.broken.store(true, Ordering::SeqCst);
+        }
+        return ret;
+    }
+}
+
+
+// Where a request's response records come from
+enum Source {
+    // A connection of its own
+    Conn(BufReader<Stream>),
+    Mux {
+        mux: Arc<Mux>,
+        id: u16,
+        records: Receiver<io::Result<Record>>,
+        timeout: Duration,
+    },
+}
+
+impl Source {
+    fn next(&mut self) -> io::Result<Record> {
+        match *self {
+            Source::Conn(ref mut reader) => return read_record(reader),
+            Source::Mux { ref records, timeout, .. } => {
+                match records.recv_timeout(timeout) {
+                    Ok(record) => return record,
+                    Err(RecvTimeoutError::Timeout) => return Err(
+                        io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+                    Err(RecvTimeoutError::Disconnected) => return Err(
+                        invalid("connection closed")),
+                }
+            },
+        }
+    }
+}
+
+// The application's stdout, for the response
+struct AppOutput {
+    // None once the request is over
+    source: Option<Source>,
+    // The record being read
+    pending: Vec<u8>,
+    pos: usize,
+    // Where the connection goes back to, when it's done
+    idle: Arc<Mutex<Vec<Stream>>>,
+}
+
+impl Read for AppOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.pending.len() {
+            let (kind, _, content) = match self.source {
+                Some(ref mut source) => source.next()?,
+                None => return Ok(0),
+            };
+            match kind {
+                STDOUT => {
+                    self.pending = content;
+                    self.pos = 0;
+                },
+                STDERR => {
+                    let _ = io::stderr().write_all(&content);
+                },
+                END_REQUEST if content.len() >= 5 => {
+                    self.finish();
+                    // The protocolStatus: CANT_MPX_CONN, OVERLOADED, or
+                    // UNKNOWN_ROLE
+                    if content[4] != 0 {
+                        return Err(io::Error::other(
+                                "request refused by the application"));
+                    }
+                    return Ok(0);
+                },
+                _ => return Err(invalid("unexpected record")),
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+impl AppOutput {
+    // The request is done: keep its connection
+    fn finish(&mut self) {
+        if let Some(Source::Conn(reader)) = self.source.take() {
+            if reader.buffer().is_empty() {
+                push_idle(&self.idle, reader.into_inner());
+            }
+        }
+    }
+}
+
+impl Drop for AppOutput {
+    // Given up on before the end
+    fn drop(&mut self) {
+        if let Some(Source::Mux { ref mux, id, .. }) = self.source {
+            mux.routes.lock().unwrap().remove(&id);
+            let mut out = Vec::new();
+            push_record(&mut out, ABORT_REQUEST, id, &[]);
+            let _ = mux.send(&out);
+        }
+    }
+}
+
+fn push_idle(idle: &Mutex<Vec<Stream>>, stream: Stream) {
+    let mut idle = idle.lock().unwrap();
+    if idle.len() < MAX_IDLE {
+        idle.push(stream);
+    }
+}
+
+fn error_response(code: i32, status: &str) -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(code, status);
+    resp.set_body_str(&format!("Error {}: {}", code, status));
+    return resp;
+}
+
+fn invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+}
+
+
+// A FastCGI application answering with the connection number, some of
+// the params, and the body.  If it multiplexes, it answers two requests
+// at once, the last one first.
+#[cfg(test)]
+fn test_app(multiplex: bool) -> String {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut params = HashMap::new();
+                let mut stdin = HashMap::new();
+                let mut done = Vec::new();
+                while let Ok((kind, id, content)) = read_record(&mut reader) {
+                    match kind {
+                        GET_VALUES => {
+                            let mut out = Vec::new();
+                            push_param(&mut out, "FCGI_MPXS_CONNS",
+                                    if multiplex { "1" } else { "0" });
+                            let mut out_record = Vec::new();
+                            push_record(&mut out_record, GET_VALUES_RESULT, 0,
+                                    &out);
+                            stream.write_all(&out_record).unwrap();
+                        },
+                        BEGIN_REQUEST => {
+                            assert_eq!(content, [0, 1, 1, 0, 0, 0, 0, 0]);
+                        },
+                        PARAMS => params.entry(id).or_insert_with(Vec::new)
+                            .extend(content),
+                        STDIN if !content.is_empty() => stdin.entry(id)
+                            .or_insert_with(Vec::new).extend(content),
+                        STDIN => done.push(id),
+                        _ => panic!("unexpected record {}", kind),
+                    }
+                    if done.len() < if multiplex { 2 } else { 1 } {
+                        continue;
+                    }
+                    while let Some(id) = done.pop() {
+                        let params: HashMap<String, String> = parse_params(
+                                &params.remove(&id).unwrap()).unwrap()
+                            .into_iter().collect();
+                        let param = |name: &str| params.get(name).cloned()
+                            .unwrap_or_default();
+                        let body = format!("{} {} {} {} {}", i + 1,
+                                param("SCRIPT_FILENAME"), param("PATH_INFO"),
+                                param("QUERY_STRING"), String::from_utf8(
+                                    stdin.remove(&id).unwrap_or_default())
+                                .unwrap());
+                        let mut out = Vec::new();
+                        push_record(&mut out, STDOUT, id,
+                                b"Content-Type: text/plain\r\n");
+                        push_record(&mut out, STDERR, id, b"");
+                        push_stream(&mut out, STDOUT, id,
+                                format!("\r\n{}", body).as_bytes());
+                        push_record(&mut out, END_REQUEST, id,
+                                &[0, 0, 0, 0, 0, 0, 0, 0]);
+                        stream.write_all(&out).unwrap();
+                    }
+                }
+            });
+        }
+    });
+    return addr;
+}
+
+#[test]
+fn test_fastcgi() {
+    let mut app = FastCgi::new(&test_app(false));
+    app.set_root("/srv");
+    let mut req = WebRequest::parse_for_test("POST /index.php?a=b HTTP/1.1\r\n\
+            Content-Length: 5\r\n\r\n");
+    req.body = b"hello".to_vec();
+    let mut resp = app.handle(&mut req);
+    assert_eq!(resp.get_code(), 200);
+    assert_eq!(resp.get_header("Content-Type"), Some("text/plain"));
+    assert_eq!(resp.body_for_test(), b"1 /srv/index.php  a=b hello");
+
+    // A spooled body, streamed from its file
+    let mut spool = ::webserver::body_spool::Spool::create().unwrap();
+    spool.write_all(b"spool").unwrap();
+    req.body.clear();
+    req.spool = Some(spool);
+    let mut resp = app.handle(&mut req);
+    assert_eq!(resp.body_for_test(), b"1 /srv/index.php  a=b spool");
+
+    // A front controller, on the connection kept from before
+    app.set_script("/srv/index.php");
+    let mut req = WebRequest::new_for_test("GET", "/some/page");
+    let mut resp = app.handle(&mut req);
+    assert_eq!(resp.body_for_test(), b"1 /srv/index.php /some/page  ");
+
+    let app = FastCgi::new("127.0.0.1:1");
+    let mut req = WebRequest::new_for_test("GET", "/");
+    assert_eq!(app.handle(&mut req).get_code(), 502);
+}
+
+#[test]
+fn test_fastcgi_multiplex() {
+    let app = Arc::new(FastCgi::new(&test_app(true)));
+    let threads: Vec<_> = ["/a", "/b"].iter().map(|&path| {
+        let app = app.clone();
+        return thread::spawn(move || {
+            let mut req = WebRequest::new_for_test("GET", path);
+            return app.handle(&mut req).body_for_test();
+        });
+    }).collect();
+    let bodies: Vec<_> = threads.into_iter().map(|t| t.join().unwrap())
+        .collect();
+    assert_eq!(bodies, [&b"1 /a   "[..], &b"1 /b   "[..]]);
+}
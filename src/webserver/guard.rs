@@ -0,0 +1,142 @@
+//! Route guards: extra conditions a request must meet for a `Router` rule
+
+use utils::negotiate;
+use super::WebRequest;
+
+
+/// A condition on a `Router` rule, see `Router::add_guard`.
+///
+/// Implemented for closures of the form `|req: &WebRequest| -> bool`.
+pub trait Guard: Send + Sync {
+    /// Whether the rule may handle the request
+    fn check(&self, req: &WebRequest) -> bool;
+
+    /// The code and status to send if the request matched no rule because
+    /// of this guard.  Defaults to a 404.
+    fn rejection(&self) -> (i32, &str) {
+        return (404, "Not Found");
+    }
+}
+
+impl<F> Guard for F where F: Fn(&WebRequest) -> bool + Send + Sync {
+    fn check(&self, req: &WebRequest) -> bool {
+        return (self)(req);
+    }
+}
+
+
+/// Only match requests whose Content-Type is one of the given types (the
+/// parameters, like charset, are ignored).  Otherwise a 415.
+///
+/// ex: `ContentTypeGuard::new("application/json")`
+pub struct ContentTypeGuard {
+    types: Vec<String>,
+}
+
+impl ContentTypeGuard {
+    /// `types` is a comma separated list
+    pub fn new(types: &str) -> ContentTypeGuard {
+        return ContentTypeGuard { types: parse_types(types) };
+    }
+}
+
+impl Guard for ContentTypeGuard {
+    fn check(&self, req: &WebRequest) -> bool {
+        let content_type = req.get_header("Content-Type").unwrap_or("")
+            .split(';').next().unwrap().trim().to_ascii_lowercase();
+        return self.types.contains(&content_type);
+    }
+
+    fn rejection(&self) -> (i32, &str) {
+        return (415, "Unsupported Media Type");
+    }
+}
+
+
+/// Only match requests that accept one of the given media types (by the
+/// Accept header; a request without one accepts anything).  Otherwise a
+/// 406.
+///
+/// ex: `AcceptGuard::new("application/json")`
+pub struct AcceptGuard {
+    types: Vec<String>,
+}
+
+impl AcceptGuard {
+    /// `types` is a comma separated list
+    pub fn new(types: &str) -> AcceptGuard {
+        return AcceptGuard { types: parse_types(types) };
+    }
+}
+
+impl Guard for AcceptGuard {
+    fn check(&self, req: &WebRequest) -> bool {
+        let accept = match req.get_header("Accept") {
+            Some(accept) => negotiate::parse_quality_list(accept),
+            None => return true,
+        };
+        return self.types.iter()
+            .any(|t| negotiate::media_quality(&accept, t) > 0.0);
+    }
+
+    fn rejection(&self) -> (i32, &str) {
+        return (406, "Not Acceptable");
+    }
+}
+
+
+/// Only match requests with a header, optionally with a given value
+/// (compared case-insensitively).  Otherwise the rule is skipped as if the
+/// path didn't match.
+///
+/// ex: `HeaderGuard::new("X-Api-Version", Some("2"))`
+pub struct HeaderGuard {
+    name: String,
+    value: Option<String>,
+}
+
+impl HeaderGuard {
+    pub fn new(name: &str, value: Option<&str>) -> HeaderGuard {
+        return HeaderGuard {
+            name: name.to_string(),
+            value: value.map(|v| v.to_string()),
+        };
+    }
+}
+
+impl Guard for HeaderGuard {
+    fn check(&self, req: &WebRequest) -> bool {
+        match (req.get_header(&self.name), self.value.as_ref()) {
+            (Some(v), Some(expected)) =>
+                return v.trim().eq_ignore_ascii_case(expected),
+            (Some(_), None) => return true,
+            (None, _) => return false,
+        }
+    }
+}
+
+
+fn parse_types(types: &str) -> Vec<String> {
+    return types.split(',').map(|t| t.trim().to_ascii_lowercase())
+        .filter(|t| !t.is_empty()).collect();
+}
+
+
+#[test]
+fn test_guards() {
+    let req = WebRequest::parse_for_test("POST / HTTP/1.1\r\n\
+            Content-Type: Application/JSON; charset=utf-8\r\n\
+            Accept: text/*\r\nX-Api-Version: 2\r\n\r\n");
+    assert!(ContentTypeGuard::new("text/plain, application/json").check(&req));
+    assert!(!ContentTypeGuard::new("text/plain").check(&req));
+    assert!(AcceptGuard::new("text/html").check(&req));
+    assert!(!AcceptGuard::new("application/json").check(&req));
+    assert!(HeaderGuard::new("x-api-version", Some("2")).check(&req));
+    assert!(!HeaderGuard::new("X-Api-Version", Some("3")).check(&req));
+    assert!(!HeaderGuard::new("X-Other", None).check(&req));
+
+    // No Accept header: anything goes
+    let req = WebRequest::new_for_test("GET", "/");
+    assert!(AcceptGuard::new("application/json").check(&req));
+    assert!(!ContentTypeGuard::new("application/json").check(&req));
+}
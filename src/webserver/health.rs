@@ -0,0 +1,117 @@
+//! Liveness and readiness checks, for orchestrators and load balancers
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Handler, WebRequest, WebResponse, ServerHandle};
+
+
+/// Liveness check: always a 200, since a server that can run it is up
+pub struct Liveness;
+
+impl Handler for Liveness {
+    fn handle(&self, _req: &mut WebRequest) -> WebResponse {
+        return text_response(200, "OK", "ok\n");
+    }
+}
+
+/// Readiness check: a 200 when the server is ready for requests, else a
+/// 503 saying why.  It's ready when it's running, isn't draining (see
+/// `ServerHandle::set_draining`), and the application said so with
+/// `set_ready`, which it is from the start.  Clones share the flag.
+///
+/// `WebServer::add_health_checks` routes it at `/readyz`, and `Liveness`
+/// at `/healthz`.
+///
+/// ex:
+///
+/// ```ignore
+/// let health = server.add_health_checks();
+/// health.set_ready(false);
+/// thread::spawn(move || {
+///     warm_caches();
+///     health.set_ready(true);
+/// });
+/// ```
+#[derive(Clone)]
+pub struct Health {
+    ready: Arc<AtomicBool>,
+    server: ServerHandle,
+}
+
+impl Health {
+    pub fn new(server: ServerHandle) -> Health {
+        return Health {
+            ready: Arc::new(AtomicBool::new(true)),
+            server: server,
+        };
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Why the server isn't ready, if it isn't
+    pub fn not_ready(&self) -> Option<&'static str> {
+        if self.server.is_draining() {
+            return Some("draining");
+        }
+        if !self.ready.load(Ordering::Relaxed) {
+            return Some("not ready");
+        }
+        if !self.server.is_running() {
+            return Some("not running");
+        }
+        return None;
+    }
+}
+
+impl Handler for Health {
+    fn handle(&self, _req: &mut WebRequest) -> WebResponse {
+        match self.not_ready() {
+            Some(why) => return text_response(503, "Service Unavailable",
+                    &format!("{}\n", why)),
+            None => return text_response(200, "OK", "ok\n"),
+        }
+    }
+}
+
+fn text_response(code: i32, status: &str, body: &str) -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(code, status);
+    resp.set_header("Content-Type", "text/plain");
+    resp.set_header("Cache-Control", "no-store");
+    resp.set_body_str(body);
+    return resp;
+}
+
+
+#[test]
+fn test_health() {
+    use std::time::Instant;
+    use super::stats::Stats;
+
+    let stats = Arc::new(Stats::default());
+    let server = ServerHandle::new(stats.clone());
+    let health = Health::new(server.clone());
+    let check = |handler: &dyn Handler| {
+        let mut resp = handler.handle(&mut WebRequest::new_for_test("GET",
+                "/"));
+        let body = String::from_utf8(resp.body_for_test()).unwrap();
+        return (resp.get_code(), body);
+    };
+    let unavailable = |why: &str| (503, format!("{}\n", why));
+
+    assert_eq!(check(&Liveness), (200, "ok\n".to_string()));
+    assert_eq!(check(&health), unavailable("not running"));
+    *stats.started.lock().unwrap() = Some(Instant::now());
+    assert_eq!(check(&health), (200, "ok\n".to_string()));
+    health.clone().set_ready(false);
+    assert_eq!(check(&health), unavailable("not ready"));
+    server.set_draining(true);
+    assert_eq!(check(&health), unavailable("draining"));
+    health.set_ready(true);
+    server.set_draining(false);
+    assert_eq!(health.not_ready(), None);
+    assert_eq!(check(&Liveness).0, 200);
+}
@@ -0,0 +1,408 @@
+//! HPACK (RFC 7541) header compression
+//!
+//! The decoder is complete.  The encoder only uses the static table, and
+//! sends strings as they are, which is always valid but not as small as it
+//! could be.
+
+use std::collections::VecDeque;
+
+
+pub type Header = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug, PartialEq)]
+pub enum HpackError {
+    /// A connection error (COMPRESSION_ERROR)
+    Corrupt,
+    /// The headers add up to more than the limit.  The block was decoded
+    /// nonetheless, so the connection can go on.
+    TooLarge,
+}
+
+
+static STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""), (":method", "GET"), (":method", "POST"),
+    (":path", "/"), (":path", "/index.html"), (":scheme", "http"),
+    (":scheme", "https"), (":status", "200"), (":status", "204"),
+    (":status", "206"), (":status", "304"), (":status", "400"),
+    (":status", "404"), (":status", "500"), ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"), ("accept-language", ""),
+    ("accept-ranges", ""), ("accept", ""),
+    ("access-control-allow-origin", ""), ("age", ""), ("allow", ""),
+    ("authorization", ""), ("cache-control", ""),
+    ("content-disposition", ""), ("content-encoding", ""),
+    ("content-language", ""), ("content-length", ""),
+    ("content-location", ""), ("content-range", ""), ("content-type", ""),
+    ("cookie", ""), ("date", ""), ("etag", ""), ("expect", ""),
+    ("expires", ""), ("from", ""), ("host", ""), ("if-match", ""),
+    ("if-modified-since", ""), ("if-none-match", ""), ("if-range", ""),
+    ("if-unmodified-since", ""), ("last-modified", ""), ("link", ""),
+    ("location", ""), ("max-forwards", ""), ("proxy-authenticate", ""),
+    ("proxy-authorization", ""), ("range", ""), ("referer", ""),
+    ("refresh", ""), ("retry-after", ""), ("server", ""),
+    ("set-cookie", ""), ("strict-transport-security", ""),
+    ("transfer-encoding", ""), ("user-agent", ""), ("vary", ""),
+    ("via", ""), ("www-authenticate", ""),
+];
+
+// Huffman code lengths of bytes 0..255 and EOS (256).  The code is
+// canonical, so this is all that's needed to decode it.
+static HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28,
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6,
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5,
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23,
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23,
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25,
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23,
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30,
+];
+
+static EOS: u16 = 256;
+
+// Every entry in the dynamic table counts this much on top of its size
+static ENTRY_OVERHEAD: usize = 32;
+
+
+// Canonical Huffman decoding tables
+struct Huffman {
+    // Number of codes of each length
+    counts: [u16; 31],
+    // Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new() -> Huffman {
+        let mut counts = [0; 31];
+        for &len in HUFFMAN_LENGTHS.iter() {
+            counts[len as usize] += 1;
+        }
+        let mut symbols: Vec<u16> = (0..257).collect();
+        symbols.sort_by_key(|&sym| HUFFMAN_LENGTHS[sym as usize]);
+        return Huffman { counts: counts, symbols: symbols };
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, HpackError> {
+        let mut out = Vec::with_capacity(data.len() * 8 / 5);
+        // The code so far, and where codes of its length start
+        let (mut code, mut first, mut index, mut len) = (0u32, 0u32, 0, 0);
+        for &byte in data {
+            for i in (0..8).rev() {
+                code |= (byte >> i & 1) as u32;
+                len += 1;
+                let count = self.counts[len] as u32;
+                if code < first + count {
+                    let sym = self.symbols[index + (code - first) as usize];
+                    if sym == EOS {
+                        return Err(HpackError::Corrupt);
+                    }
+                    out.push(sym as u8);
+                    code = 0;
+                    first = 0;
+                    index = 0;
+                    len = 0;
+                    continue;
+                }
+                if len == 30 {
+                    return Err(HpackError::Corrupt);
+                }
+                index += count as usize;
+                first = (first + count) << 1;
+                code <<= 1;
+            }
+        }
+        // Padding: at most 7 bits, the start of EOS (all ones)
+        if len > 7 || code >> 1 != (1 << len) - 1 {
+            return Err(HpackError::Corrupt);
+        }
+        return Ok(out);
+    }
+}
+
+
+/// Decodes the header blocks of one connection, which share a table
+pub struct Decoder {
+    // Newest first
+    table: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+    // What we allowed the encoder (SETTINGS_HEADER_TABLE_SIZE)
+    limit: usize,
+    huffman: Huffman,
+}
+
+impl Decoder {
+    pub fn new(limit: usize) -> Decoder {
+        return Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit: limit,
+            huffman: Huffman::new(),
+        };
+    }
+
+    /// Decode a header block.  `max_size` limits the decoded headers, each
+    /// counted as name, value, and 32 bytes.
+    pub fn decode(&mut self, block: &[u8], max_size: usize)
+            -> Result<Vec<Header>, HpackError> {
+        let mut headers = Vec::new();
+        let mut size = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let byte = block[pos];
+            let header = if byte & 0x80 != 0 {
+                let index = integer(block, &mut pos, 7)?;
+                self.entry(index)?
+            } else if byte & 0xe0 == 0x20 {
+                // Only allowed before any header
+                let new_size = integer(block, &mut pos, 5)?;
+                if new_size > self.limit || !headers.is_empty() || size > 0 {
+                    return Err(HpackError::Corrupt);
+                }
+                self.max_size = new_size;
+                self.evict(0);
+                continue;
+            } else {
+                // Literal: with incremental indexing, without, or never
+                let (prefix, indexed) = match byte & 0xc0 {
+                    0x40 => (6, true),
+                    _ => (4, false),
+                };
+                let name = match integer(block, &mut pos, prefix)? {
+                    0 => self.string(block, &mut pos)?,
+                    index => self.entry(index)?.0,
+                };
+                let value = self.string(block, &mut pos)?;
+                if indexed {
+                    self.insert((name.clone(), value.clone()));
+                }
+                (name, value)
+            };
+            size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            // Keep decoding, to keep the table right, but drop the headers
+            if size <= max_size {
+                headers.push(header);
+            }
+        }
+        if size > max_size {
+            return Err(HpackError::TooLarge);
+        }
+        return Ok(headers);
+    }
+
+    fn entry(&self, index: usize) -> Result<Header, HpackError> {
+        if index == 0 {
+            return Err(HpackError::Corrupt);
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        return match self.table.get(index - STATIC_TABLE.len() - 1) {
+            Some(header) => Ok(header.clone()),
+            None => Err(HpackError::Corrupt),
+        };
+    }
+
+    fn insert(&mut self, header: Header) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // Too big for the table: it's just emptied
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    // Evict entries until there's room for `size` more
+    fn evict(&mut self, size: usize) {
+        while self.size + size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) =>
+                    self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => return,
+            }
+        }
+    }
+
+    fn string(&self, block: &[u8], pos: &mut usize)
+            -> Result<Vec<u8>, HpackError> {
+        let huffman = block.get(*pos).map(|b| b & 0x80 != 0).unwrap_or(false);
+        let len = integer(block, pos, 7)?;
+        if block.len() - *pos < len {
+            return Err(HpackError::Corrupt);
+        }
+        let data = &block[*pos..*pos + len];
+        *pos += len;
+        if huffman {
+            return self.huffman.decode(data);
+        }
+        return Ok(data.to_vec());
+    }
+}
+
+
+// Read an integer with an N bit prefix at `pos`, moving past it
+fn integer(block: &[u8], pos: &mut usize, prefix: u32)
+        -> Result<usize, HpackError> {
+    let mask = (1 << prefix) - 1;
+    let mut value = match block.get(*pos) {
+        Some(&byte) => (byte & mask) as usize,
+        None => return Err(HpackError::Corrupt),
+    };
+    *pos += 1;
+    if value < mask as usize {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let byte = match block.get(*pos) {
+            // Anything this big is an attack anyway
+            Some(&byte) if shift <= 21 => byte,
+            _ => return Err(HpackError::Corrupt),
+        };
+        *pos += 1;
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn write_integer(out: &mut Vec<u8>, first: u8, prefix: u32, mut value: usize) {
+    let mask = (1 << prefix) - 1;
+    if value < mask {
+        out.push(first | value as u8);
+        return;
+    }
+    out.push(first | mask as u8);
+    value -= mask;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_string(out: &mut Vec<u8>, s: &[u8]) {
+    write_integer(out, 0, 7, s.len());
+    out.extend_from_slice(s);
+}
+
+
+/// Encode headers (with lowercase names) as a header block, without
+/// touching the decoder's table
+pub fn encode(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for &(name, value) in headers {
+        let exact = STATIC_TABLE.iter().position(|&h| h == (name, value));
+        if let Some(i) = exact {
+            write_integer(&mut out, 0x80, 7, i + 1);
+            continue;
+        }
+        // Literal without indexing
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => write_integer(&mut out, 0, 4, i + 1),
+            None => {
+                out.push(0);
+                write_string(&mut out, name.as_bytes());
+            }
+        }
+        write_string(&mut out, value.as_bytes());
+    }
+    return out;
+}
+
+
+#[cfg(test)]
+pub(crate) fn test_headers(headers: &[(&str, &str)]) -> Vec<Header> {
+    return headers.iter()
+        .map(|&(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+        .collect();
+}
+
+#[test]
+fn test_hpack_decode() {
+    // RFC 7541 C.3: requests on one connection, without Huffman coding
+    let mut decoder = Decoder::new(4096);
+    let block = b"\x82\x86\x84\x41\x0fwww.example.com";
+    assert_eq!(decoder.decode(block, 1000).unwrap(), test_headers(&[
+        (":method", "GET"), (":scheme", "http"), (":path", "/"),
+        (":authority", "www.example.com")]));
+    assert_eq!(decoder.size, 57);
+    let block = b"\x82\x86\x84\xbe\x58\x08no-cache";
+    assert_eq!(decoder.decode(block, 1000).unwrap(), test_headers(&[
+        (":method", "GET"), (":scheme", "http"), (":path", "/"),
+        (":authority", "www.example.com"), ("cache-control", "no-cache")]));
+    let block = b"\x82\x87\x85\xbf\x40\x0acustom-key\x0ccustom-value";
+    assert_eq!(decoder.decode(block, 1000).unwrap(), test_headers(&[
+        (":method", "GET"), (":scheme", "https"), (":path", "/index.html"),
+        (":authority", "www.example.com"), ("custom-key", "custom-value")]));
+    assert_eq!(decoder.size, 164);
+    assert_eq!(decoder.table[0],
+            test_headers(&[("custom-key", "custom-value")])[0]);
+
+    // C.4: the same, with Huffman coding
+    let mut decoder = Decoder::new(4096);
+    let blocks: [&[u8]; 3] = [
+        b"\x82\x86\x84\x41\x8c\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\
+            \xf4\xff",
+        b"\x82\x86\x84\xbe\x58\x86\xa8\xeb\x10\x64\x9c\xbf",
+        b"\x82\x87\x85\xbf\x40\x88\x25\xa8\x49\xe9\x5b\xa9\x7d\x7f\
+            \x89\x25\xa8\x49\xe9\x5b\xb8\xe8\xb4\xbf",
+    ];
+    for block in blocks.iter() {
+        decoder.decode(block, 1000).unwrap();
+    }
+    assert_eq!(decoder.table.iter().cloned().collect::<Vec<Header>>(),
+        test_headers(&[("custom-key", "custom-value"),
+            ("cache-control", "no-cache"),
+            (":authority", "www.example.com")]));
+
+    // Shrinking the table evicts the oldest entries
+    assert_eq!(decoder.decode(b"\x3f\x5a", 1000).unwrap(), Vec::new());
+    assert_eq!(decoder.max_size, 121);
+    assert_eq!(decoder.table.len(), 2);
+    assert_eq!(decoder.decode(b"\xc0", 1000), Err(HpackError::Corrupt));
+    // Larger than allowed
+    assert_eq!(decoder.decode(b"\x3f\xe2\x1f", 1000),
+            Err(HpackError::Corrupt));
+    // Too large, yet still indexed
+    assert_eq!(decoder.decode(b"\x40\x01a\x01b", 10),
+            Err(HpackError::TooLarge));
+    assert_eq!(decoder.table[0], test_headers(&[("a", "b")])[0]);
+    // Truncated, bad index, bad padding, EOS
+    assert_eq!(decoder.decode(b"\x04\x05/ab", 1000), Err(HpackError::Corrupt));
+    assert_eq!(decoder.decode(b"\x80", 1000), Err(HpackError::Corrupt));
+    assert_eq!(decoder.decode(b"\x04\x81\x00", 1000),
+            Err(HpackError::Corrupt));
+    assert_eq!(decoder.decode(b"\x04\x84\xff\xff\xff\xff", 1000),
+            Err(HpackError::Corrupt));
+}
+
+#[test]
+fn test_hpack_encode() {
+    let headers = [(":status", "200"), (":status", "302"),
+        ("content-type", "text/html"), ("x-custom", "1")];
+    let block = encode(&headers);
+    assert_eq!(&block[..6], b"\x88\x08\x03302");
+    let mut decoder = Decoder::new(4096);
+    assert_eq!(decoder.decode(&block, 1000).unwrap(), test_headers(&headers));
+    assert_eq!(decoder.size, 0);
+
+    let mut block = Vec::new();
+    write_integer(&mut block, 0, 5, 1337);
+    assert_eq!(block, [31, 154, 10]);
+    assert_eq!(integer(&block, &mut 0, 5), Ok(1337));
+}
@@ -0,0 +1,1229 @@
+//! HTTP/2 (RFC 7540) on cleartext connections (h2c)
+//!
+//! https://tools.ietf.org/html/rfc7540
+//!
+//! The connection's worker thread reads frames.  Each request runs once
+//! it's complete on a thread of the server's `StreamPool`, as many as
+//! there are workers, and sends its response, taking turns on the socket
+//! and waiting for the client's flow control window as needed.  When the
+//! pool's queue is full too, new streams are refused.  Request bodies are
+//! read in full, as with HTTP/1.1; the window for them is given back as
+//! soon as they arrive.
+
+mod hpack;
+mod streams;
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::Ordering;
+use std::time::{Instant, SystemTime};
+
+use utils::base64;
+use utils::byteutils;
+use utils::genericsocket::{GenericSocket, read_exact};
+use utils::http_request;
+use utils::time;
+use self::hpack::{Decoder, Header, HpackError};
+pub(crate) use self::streams::StreamPool;
+use super::{ConnInfo, WebRequest, WebResponse, WorkerSharedContext};
+use super::write_response::{valid_header_name, clean_header_value};
+use super::write_response::read_chunk;
+use super::TraceEvent;
+use super::metrics::Open;
+use super::memory_budget::MemoryBudget;
+use super::slow_log::Timings;
+use super::BodyInspection;
+use super::error_sink::{ServerError, panic_message};
+
+
+/// What a client with prior knowledge starts with
+pub static PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+static DEFAULT_WINDOW: i64 = 65535;
+static MAX_WINDOW: i64 = (1 << 31) - 1;
+// The frames we take; the default, so it's not sent
+static MAX_FRAME_SIZE: usize = 16384;
+static MAX_STREAMS: usize = 32;
+static HEADER_TABLE_SIZE: usize = 4096;
+
+// Not allowed in HTTP/2 (RFC 7540 8.1.2.2)
+static CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive",
+    "proxy-connection", "transfer-encoding", "upgrade"];
+
+
+/// Read the start of a connection: true if it's the HTTP/2 preface, which
+/// is gone then.  Returns what was read past it, or everything that was
+/// read if it's not the preface.
+pub fn read_preface(stream: &mut dyn GenericSocket)
+        -> io::Result<(bool, Vec<u8>)> {
+    let mut start = Vec::new();
+    let mut buf = [0; 4096];
+    while start.len() < PREFACE.len() && PREFACE.starts_with(&start) {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => start.extend_from_slice(&buf[..n]),
+        }
+    }
+    if start.starts_with(PREFACE) {
+        return Ok((true, start.split_off(PREFACE.len())));
+    }
+    return Ok((false, start));
+}
+
+
+/// The settings from the HTTP2-Settings header, if `req` asks for an
+/// upgrade to h2c (RFC 7540 3.2)
+pub fn upgrade_settings(req: &WebRequest) -> Option<Vec<u8>> {
+    let has = |name: &str, token: &str| {
+        return byteutils::has_token(req.get_header(name).unwrap_or("")
+                .as_bytes(), token.as_bytes());
+    };
+    if !has("Upgrade", "h2c") || !has("Connection", "upgrade")
+            || !has("Connection", "http2-settings") {
+        return None;
+    }
+    // base64url, without padding
+    let settings = req.get_header("HTTP2-Settings")?.trim()
+        .replace('-', "+").replace('_', "/");
+    return base64::decode(&settings).filter(|s| s.len().is_multiple_of(6));
+}
+
+
+// Why a connection ends
+enum Error {
+    // A connection error: send GOAWAY with this code and close
+    Connection(u32),
+    // Reading failed, or the client closed the connection
+    Io,
+}
+
+impl From<io::Error> for Error {
+    fn from(_: io::Error) -> Error {
+        return Error::Io;
+    }
+}
+
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+// A request whose body is still coming
+struct Incoming {
+    req: WebRequest,
+    conn: ConnInfo,
+    inspection: Option<Box<dyn BodyInspection>>,
+}
+
+
+// Shared by the connection's thread and the ones sending responses
+struct Shared {
+    writer: Mutex<Box<dyn GenericSocket>>,
+    state: Mutex<State>,
+    // Windows grew, a stream was reset, or a response was sent
+    changed: Condvar,
+}
+
+struct State {
+    // The flow control windows for sending, of the connection and of each
+    // open stream.  A stream that's not here was reset or is done.
+    window: i64,
+    streams: HashMap<u32, i64>,
+    initial_window: i64,
+    max_frame_size: usize,
+    // Responses still being made or sent
+    running: usize,
+    closed: bool,
+    // Nothing more is read, so the windows won't grow
+    done: bool,
+}
+
+
+/// Serve an HTTP/2 connection, until the client closes it.
+///
+/// `reader` and `writer` are the same connection, so responses can be sent
+/// while reading.  With `upgrade`, this follows the 101 response to an
+/// h2c upgrade of that request, with its HTTP2-Settings; else the preface
+/// was read already.
+// The stream pool for a server of `threads` workers: as many threads, and
+// room in its queue for all the streams their connections can have open
+pub(crate) fn stream_pool(threads: usize) -> StreamPool {
+    return StreamPool::new(threads, threads * MAX_STREAMS);
+}
+
+pub fn serve(ctx: Arc<WorkerSharedContext>, reader: Box<dyn GenericSocket>,
+        writer: Box<dyn GenericSocket>, peer_addr: SocketAddr,
+        upgrade: Option<(WebRequest, Vec<u8>)>) {
+    let shared = Arc::new(Shared {
+        writer: Mutex::new(writer),
+        state: Mutex::new(State {
+            window: DEFAULT_WINDOW,
+            streams: HashMap::new(),
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: MAX_FRAME_SIZE,
+            running: 0,
+            closed: false,
+            done: false,
+        }),
+        changed: Condvar::new(),
+    });
+    let mut conn = Connection {
+        reader: reader,
+        shared: shared.clone(),
+        ctx: ctx,
+        peer_addr: peer_addr,
+        decoder: Decoder::new(HEADER_TABLE_SIZE),
+        incoming: HashMap::new(),
+        last_stream: 0,
+    };
+    // Idle whenever no request is running
+    let stats = conn.ctx.stats.clone();
+    stats.idle_connections.fetch_add(1, Ordering::Relaxed);
+    let result = conn.run(upgrade);
+    if let Err(Error::Connection(code)) = result {
+        conn.ctx.logger.log_error(ServerError::Protocol(code),
+                Some(peer_addr), None);
+        let mut payload = conn.last_stream.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        let _ = write_frame(&shared, GOAWAY, 0, 0, &payload);
+    }
+
+    // Let the responses still being made finish.  If the client went
+    // away, or broke the protocol, they're not sent.
+    let mut state = shared.state.lock().unwrap();
+    state.closed = result.is_err();
+    state.done = true;
+    shared.changed.notify_all();
+    while state.running > 0 {
+        state = shared.changed.wait(state).unwrap();
+    }
+    stats.idle_connections.fetch_sub(1, Ordering::Relaxed);
+}
+
+
+struct Connection {
+    reader: Box<dyn GenericSocket>,
+    shared: Arc<Shared>,
+    ctx: Arc<WorkerSharedContext>,
+    peer_addr: SocketAddr,
+    decoder: Decoder,
+    incoming: HashMap<u32, Incoming>,
+    // The highest stream the client opened
+    last_stream: u32,
+}
+
+impl Connection {
+    fn run(&mut self, upgrade: Option<(WebRequest, Vec<u8>)>)
+            -> Result<(), Error> {
+        let mut settings = Vec::new();
+        for &(id, value) in [(SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS),
+                (SETTINGS_MAX_HEADER_LIST_SIZE, self.ctx.max_head_size)]
+                .iter() {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        write_frame(&self.shared, SETTINGS, 0, 0, &settings)?;
+
+        if let Some((req, settings)) = upgrade {
+            self.settings(&settings)?;
+            // The request is stream 1, which the client is done with
+            self.last_stream = 1;
+            self.open(1);
+            let conn = self.conn_info();
+            self.respond(1, Some(req), None, conn);
+            let mut preface = vec![0; PREFACE.len()];
+            read_exact(&mut *self.reader, &mut preface)?;
+            if preface != PREFACE {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+        }
+
+        // A header block split over CONTINUATION frames: stream, flags of
+        // the HEADERS frame, and the block so far
+        let mut continuation: Option<(u32, u8, Vec<u8>)> = None;
+        let mut first = true;
+        let mut going_away = false;
+        loop {
+            let frame = match self.read_frame() {
+                Ok(frame) => frame,
+                // It said it would
+                Err(Error::Io) if going_away => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            if first && frame.kind != SETTINGS {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            first = false;
+
+            if let Some((stream, flags, mut block)) = continuation.take() {
+                if frame.kind != CONTINUATION || frame.stream != stream {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                block.extend_from_slice(&frame.payload);
+                if block.len() > 2 * self.ctx.max_head_size {
+                    return Err(Error::Connection(ENHANCE_YOUR_CALM));
+                }
+                if frame.flags & END_HEADERS != 0 {
+                    self.headers(stream, flags, &block)?;
+                } else {
+                    continuation = Some((stream, flags, block));
+                }
+                continue;
+            }
+
+            match frame.kind {
+                HEADERS => {
+                    if frame.stream == 0 {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    let mut block = unpad(&frame)?;
+                    if frame.flags & PRIORITY_FLAG != 0 {
+                        if block.len() < 5 {
+                            return Err(Error::Connection(PROTOCOL_ERROR));
+                        }
+                        block = &block[5..];
+                    }
+                    if frame.flags & END_HEADERS != 0 {
+                        self.headers(frame.stream, frame.flags, block)?;
+                    } else {
+                        continuation = Some((frame.stream, frame.flags,
+                                block.to_vec()));
+                    }
+                }
+                DATA => self.data(&frame)?,
+                PRIORITY => {
+                    if frame.stream == 0 {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    if frame.payload.len() != 5 {
+                        self.reset(frame.stream, FRAME_SIZE_ERROR)?;
+                    }
+                }
+                RST_STREAM => {
+                    if frame.stream == 0 || frame.stream > self.last_stream {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    if frame.payload.len() != 4 {
+                        return Err(Error::Connection(FRAME_SIZE_ERROR));
+                    }
+                    self.close(frame.stream);
+                }
+                SETTINGS => {
+                    if frame.stream != 0 {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    if frame.flags & ACK != 0 {
+                        if !frame.payload.is_empty() {
+                            return Err(Error::Connection(FRAME_SIZE_ERROR));
+                        }
+                        continue;
+                    }
+                    self.settings(&frame.payload)?;
+                    write_frame(&self.shared, SETTINGS, ACK, 0, &[])?;
+                }
+                PING => {
+                    if frame.stream != 0 {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    if frame.payload.len() != 8 {
+                        return Err(Error::Connection(FRAME_SIZE_ERROR));
+                    }
+                    if frame.flags & ACK == 0 {
+                        write_frame(&self.shared, PING, ACK, 0,
+                                &frame.payload)?;
+                    }
+                }
+                WINDOW_UPDATE => self.window_update(&frame)?,
+                // The client is going away; the responses can finish, and
+                // still need WINDOW_UPDATEs
+                GOAWAY => {
+                    if frame.stream != 0 {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    going_away = true;
+                }
+                PUSH_PROMISE | CONTINUATION =>
+                    return Err(Error::Connection(PROTOCOL_ERROR)),
+                // Unknown frames are ignored
+                _ => (),
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, Error> {
+        let mut head = [0; 9];
+        read_exact(&mut *self.reader, &mut head)?;
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::Connection(FRAME_SIZE_ERROR));
+        }
+        let mut payload = vec![0; len];
+        read_exact(&mut *self.reader, &mut payload)?;
+        return Ok(Frame {
+            kind: head[3],
+            flags: head[4],
+            stream: u32::from_be_bytes([head[5], head[6], head[7], head[8]])
+                & 0x7fff_ffff,
+            payload: payload,
+        });
+    }
+
+    // A complete header block: a new request, or trailers
+    fn headers(&mut self, stream: u32, flags: u8, block: &[u8])
+            -> Result<(), Error> {
+        // Decoded in any case, to keep the table in step with the client
+        let max = self.ctx.max_head_size;
+        let headers = match self.decoder.decode(block, max) {
+            Ok(headers) => Some(headers),
+            Err(HpackError::TooLarge) => None,
+            Err(HpackError::Corrupt) =>
+                return Err(Error::Connection(COMPRESSION_ERROR)),
+        };
+        if stream <= self.last_stream {
+            if !self.incoming.contains_key(&stream) {
+                return self.reset(stream, STREAM_CLOSED);
+            }
+            // Trailers, which are dropped, must end the request
+            if flags & END_STREAM == 0 {
+                return self.reset(stream, PROTOCOL_ERROR);
+            }
+            let incoming = self.incoming.remove(&stream).unwrap();
+            return self.finish(stream, incoming);
+        }
+        if stream.is_multiple_of(2) {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        self.last_stream = stream;
+        if self.shared.state.lock().unwrap().streams.len() >= MAX_STREAMS {
+            return self.reset(stream, REFUSED_STREAM);
+        }
+        self.open(stream);
+        let conn = self.conn_info();
+
+        let headers = match headers {
+            Some(headers) => headers,
+            None => {
+                let resp = error_response(431,
+                        "Request Header Fields Too Large");
+                self.respond(stream, None, Some(resp), conn);
+                return Ok(());
+            }
+        };
+        let mut req = match request(headers) {
+            Some(req) => req,
+            None => return self.reset(stream, PROTOCOL_ERROR),
+        };
+        req.environ.insert(b"remote_address".to_vec(),
+                self.peer_addr.to_string().into_bytes());
+        let inspection = match self.ctx.body_inspector {
+            Some(ref inspector) => match inspector.start(&req) {
+                Ok(inspection) => Some(inspection),
+                Err(rejection) => return self.reject(stream, req, conn,
+                        rejection),
+            },
+            None => None,
+        };
+        let incoming = Incoming {
+            req: req,
+            conn: conn,
+            inspection: inspection,
+        };
+        if flags & END_STREAM != 0 {
+            return self.finish(stream, incoming);
+        }
+        self.incoming.insert(stream, incoming);
+        return Ok(());
+    }
+
+    fn data(&mut self, frame: &Frame) -> Result<(), Error> {
+        let stream = frame.stream;
+        if stream == 0 || stream > self.last_stream {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        let data = unpad(frame)?;
+        let len = frame.payload.len() as u32;
+        let done = frame.flags & END_STREAM != 0;
+        // Give the window back; the body size limit bounds it instead
+        if len > 0 {
+            write_frame(&self.shared, WINDOW_UPDATE, 0, 0, &len.to_be_bytes())?;
+        }
+        let mut incoming = match self.incoming.remove(&stream) {
+            Some(incoming) => incoming,
+            None => return self.reset(stream, STREAM_CLOSED),
+        };
+        if incoming.req.body.len() + data.len()
+                > self.ctx.max_request_body_size {
+            let resp = error_response(413, "Request Entity Too Large");
+            self.respond(stream, Some(incoming.req), Some(resp), incoming.conn);
+            return Ok(());
+        }
+        if !self.budget_for(&mut incoming.req, data.len()) {
+            let mut resp = error_response(503, "Service Unavailable");
+            resp.set_header("Retry-After", "1");
+            self.respond(stream, Some(incoming.req), Some(resp), incoming.conn);
+            return Ok(());
+        }
+        let inspected = match incoming.inspection {
+            Some(ref mut inspection) => inspection.data(data),
+            None => Ok(()),
+        };
+        if let Err(rejection) = inspected {
+            return self.reject(stream, incoming.req, incoming.conn, rejection);
+        }
+        incoming.req.body.extend_from_slice(data);
+        if done {
+            return self.finish(stream, incoming);
+        }
+        if len > 0 {
+            write_frame(&self.shared, WINDOW_UPDATE, 0, stream,
+                    &len.to_be_bytes())?;
+        }
+        self.incoming.insert(stream, incoming);
+        return Ok(());
+    }
+
+    // Take size more bytes of the memory budget for the body of req, if
+    // there's one; false if they aren't left
+    fn budget_for(&self, req: &mut WebRequest, size: usize) -> bool {
+        let budget = match self.ctx.memory_budget {
+            Some(ref budget) => budget,
+            None => return true,
+        };
+        match req.budget {
+            Some(ref mut reservation) => return reservation.grow(size),
+            None => {
+                req.budget = MemoryBudget::reserve(budget, size);
+                return req.budget.is_some();
+            },
+        }
+    }
+
+    // The whole request is in: check the body is as long as it said
+    fn finish(&mut self, stream: u32, incoming: Incoming)
+            -> Result<(), Error> {
+        let req = incoming.req;
+        if let Some(len) = req.get_header("Content-Length") {
+            if len.parse::<usize>().ok() != Some(req.body.len()) {
+                return self.reset(stream, PROTOCOL_ERROR);
+            }
+        }
+        if let Some(mut inspection) = incoming.inspection {
+            if let Err(rejection) = inspection.end() {
+                return self.reject(stream, req, incoming.conn, rejection);
+            }
+        }
+        self.respond(stream, Some(req), None, incoming.conn);
+        return Ok(());
+    }
+
+    // Turned down by the body inspector: answer with its code and status,
+    // without reading the rest of the body
+    fn reject(&mut self, stream: u32, req: WebRequest, conn: ConnInfo,
+            (code, status): (i32, String)) -> Result<(), Error> {
+        let mut resp = error_response(code, &status);
+        let error = ServerError::Rejected(code);
+        resp.cause = Some(error.to_string());
+        self.ctx.logger.log_error(error, Some(self.peer_addr), Some(&req));
+        self.respond(stream, Some(req), Some(resp), conn);
+        return Ok(());
+    }
+
+    fn settings(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(Error::Connection(FRAME_SIZE_ERROR));
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3],
+                    setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 =>
+                    return Err(Error::Connection(PROTOCOL_ERROR)),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+                    let delta = value - state.initial_window;
+                    for window in state.streams.values_mut() {
+                        *window += delta;
+                        if *window > MAX_WINDOW {
+                            return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                        }
+                    }
+                    state.initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(16384..=16_777_215).contains(&value) {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    state.max_frame_size = value as usize;
+                }
+                // Our responses don't use the table the client sets up
+                _ => (),
+            }
+        }
+        self.shared.changed.notify_all();
+        return Ok(());
+    }
+
+    fn window_update(&mut self, frame: &Frame) -> Result<(), Error> {
+        if frame.payload.len() != 4 {
+            return Err(Error::Connection(FRAME_SIZE_ERROR));
+        }
+        let p = &frame.payload;
+        let increment = (u32::from_be_bytes([p[0], p[1], p[2], p[3]])
+            & 0x7fff_ffff) as i64;
+        let mut state = self.shared.state.lock().unwrap();
+        if frame.stream == 0 {
+            if increment == 0 {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+            state.window += increment;
+            if state.window > MAX_WINDOW {
+                return Err(Error::Connection(FLOW_CONTROL_ERROR));
+            }
+        } else {
+            let code = match state.streams.get_mut(&frame.stream) {
+                Some(_) if increment == 0 => PROTOCOL_ERROR,
+                Some(window) => {
+                    *window += increment;
+                    match *window > MAX_WINDOW {
+                        true => FLOW_CONTROL_ERROR,
+                        false => NO_ERROR,
+                    }
+                }
+                // Done with already
+                None => NO_ERROR,
+            };
+            if code != NO_ERROR {
+                drop(state);
+                return self.reset(frame.stream, code);
+            }
+        }
+        self.shared.changed.notify_all();
+        return Ok(());
+    }
+
+    fn open(&mut self, stream: u32) {
+        let mut state = self.shared.state.lock().unwrap();
+        let window = state.initial_window;
+        state.streams.insert(stream, window);
+    }
+
+    // Forget a stream, stopping its response
+    fn close(&mut self, stream: u32) {
+        self.incoming.remove(&stream);
+        self.shared.state.lock().unwrap().streams.remove(&stream);
+        self.shared.changed.notify_all();
+    }
+
+    fn reset(&mut self, stream: u32, code: u32) -> Result<(), Error> {
+        self.close(stream);
+        write_frame(&self.shared, RST_STREAM, 0, stream, &code.to_be_bytes())?;
+        return Ok(());
+    }
+
+    fn conn_info(&self) -> ConnInfo {
+        return ConnInfo {
+            peer_addr: self.peer_addr,
+            start: Instant::now(),
+            start_time: SystemTime::now(),
+            timings: Timings::default(),
+        };
+    }
+
+    // Run the handler for `req` (unless there's a response already), and
+    // send the response, on a thread of the stream pool; the stream is
+    // refused if its queue is full
+    fn respond(&mut self, stream: u32, req: Option<WebRequest>,
+            resp: Option<WebResponse>, mut conn: ConnInfo) {
+        conn.timings.read = Some(Instant::now());
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.running == 0 {
+                let idle = &self.ctx.stats.idle_connections;
+                idle.fetch_sub(1, Ordering::Relaxed);
+            }
+            state.running += 1;
+        }
+        let shared = self.shared.clone();
+        let ctx = self.ctx.clone();
+        let job = Box::new(move || {
+            let in_flight = (ctx.logger.metrics().map(|m| m.track_request()),
+                    Open::new(&ctx.stats.requests));
+            let tracer = &ctx.tracer;
+            let peer = conn.peer_addr;
+            let mut req = req;
+            if req.is_some() {
+                tracer.emit(TraceEvent::HeadParsed, peer, req.as_ref(), None);
+            }
+            let mut resp = match (resp, req.as_mut()) {
+                // Made in advance for requests that weren't valid
+                (Some(mut resp), req) => {
+                    let error = ServerError::BadRequest(resp.code);
+                    resp.cause = Some(error.to_string());
+                    ctx.logger.log_error(error, Some(peer),
+                            req.map(|req| &*req));
+                    resp
+                },
+                (None, Some(req)) => {
+                    tracer.emit(TraceEvent::HandlerStart, peer, Some(req),
+                            None);
+                    conn.timings.handler_start = Some(Instant::now());
+                    let handler = &ctx.handler;
+                    let resp = match panic::catch_unwind(AssertUnwindSafe(|| {
+                        return handler.handle(req);
+                    })) {
+                        Ok(resp) => resp,
+                        Err(payload) => {
+                            let error = ServerError::Panic(
+                                    panic_message(&*payload));
+                            let mut resp = error_response(500, "Uh oh :-(");
+                            resp.cause = Some(error.to_string());
+                            ctx.logger.log_error(error, Some(peer), Some(req));
+                            resp
+                        },
+                    };
+                    conn.timings.handler_end = Some(Instant::now());
+                    tracer.emit(TraceEvent::HandlerEnd, peer, Some(req),
+                            Some(&resp));
+                    resp
+                }
+                (None, None) => error_response(500, "Uh oh :-("),
+            };
+            let sent = send_response(&shared, stream, req.as_ref(), &mut resp);
+            ctx.logger.log_request_response(req.as_ref(), &resp, &conn, sent);
+            tracer.emit(TraceEvent::ResponseFlushed, peer, req.as_ref(),
+                    Some(&resp));
+            drop(in_flight);
+
+            if let Some(ref req) = req {
+                ctx.stats.record(req, conn.start.elapsed());
+            }
+            let mut state = shared.state.lock().unwrap();
+            state.streams.remove(&stream);
+            state.running -= 1;
+            if state.running == 0 {
+                ctx.stats.idle_connections.fetch_add(1, Ordering::Relaxed);
+            }
+            shared.changed.notify_all();
+        });
+        let refused = match self.ctx.h2_streams {
+            Some(ref pool) => pool.execute(job).is_err(),
+            None => true,
+        };
+        if refused {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                state.running -= 1;
+                if state.running == 0 {
+                    let idle = &self.ctx.stats.idle_connections;
+                    idle.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            // The client can try it again (RFC 7540 8.1.4)
+            let _ = self.reset(stream, REFUSED_STREAM);
+        }
+    }
+}
+
+
+// The payload of a DATA or HEADERS frame, without padding
+fn unpad(frame: &Frame) -> Result<&[u8], Error> {
+    let payload = &frame.payload[..];
+    if frame.flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    match payload.first() {
+        Some(&pad) if (pad as usize) < payload.len() =>
+            return Ok(&payload[1..payload.len() - pad as usize]),
+        _ => return Err(Error::Connection(PROTOCOL_ERROR)),
+    }
+}
+
+
+// The request for a header block, or None if it's malformed
+fn request(headers: Vec<Header>) -> Option<WebRequest> {
+    let (mut method, mut path, mut scheme, mut authority) =
+        (None, None, None, None);
+    let mut fields = Vec::new();
+    let mut cookies = Vec::new();
+    for (name, value) in headers {
+        // Nothing that would break up the request as HTTP/1.1
+        let bad = |b: &u8| *b == b'\r' || *b == b'\n' || *b == 0;
+        if name.iter().any(bad) || value.iter().any(bad) {
+            return None;
+        }
+        if name.starts_with(b":") {
+            let slot = match &name[..] {
+                b":method" => &mut method,
+                b":path" => &mut path,
+                b":scheme" => &mut scheme,
+                b":authority" => &mut authority,
+                _ => return None,
+            };
+            // Only once each, and before the other headers
+            if slot.is_some() || !fields.is_empty() || !cookies.is_empty() {
+                return None;
+            }
+            *slot = Some(value);
+            continue;
+        }
+        let name = String::from_utf8(name).ok()?;
+        if name.is_empty() || name.bytes().any(|b| b.is_ascii_uppercase()
+                || b == b':' || b == b' ')
+                || CONNECTION_HEADERS.contains(&name.as_str())
+                || (name == "te" && value != b"trailers") {
+            return None;
+        }
+        // Cookies may be split up (8.1.2.5)
+        if name == "cookie" {
+            cookies.push(value);
+        } else {
+            fields.push((name, value));
+        }
+    }
+    let (method, path) = match (method, path, scheme) {
+        (Some(method), Some(path), Some(_)) if !path.is_empty() =>
+            (method, path),
+        _ => return None,
+    };
+
+    let mut raw = method;
+    raw.push(b' ');
+    raw.extend_from_slice(&path);
+    raw.extend_from_slice(b" HTTP/1.1\r\n");
+    if let Some(authority) = authority {
+        if !fields.iter().any(|(name, _)| name == "host") {
+            fields.insert(0, ("host".to_string(), authority));
+        }
+    }
+    if !cookies.is_empty() {
+        fields.push(("cookie".to_string(), cookies.join(&b"; "[..])));
+    }
+    for (name, value) in fields {
+        raw.extend_from_slice(name.as_bytes());
+        raw.extend_from_slice(b": ");
+        raw.extend_from_slice(&value);
+        raw.extend_from_slice(b"\r\n");
+    }
+    raw.extend_from_slice(b"\r\n");
+    let mut req = WebRequest::from_parsed(http_request::parse(&raw).ok()?);
+    req.environ.insert(b"protocol".to_vec(), b"http/2.0".to_vec());
+    return Some(req);
+}
+
+
+// Send a frame, in one piece
+fn write_frame(shared: &Shared, kind: u8, flags: u8, stream: u32,
+        payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 9);
+    push_frame(&mut frame, kind, flags, stream, payload);
+    return shared.writer.lock().unwrap().write_all(&frame);
+}
+
+fn push_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32,
+        payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+
+// Send a response on `stream`.  Returns the number of body bytes sent.
+fn send_response(shared: &Shared, stream: u32, req: Option<&WebRequest>,
+        resp: &mut WebResponse) -> usize {
+    // As in write_response
+    let bodiless = resp.code < 200 || resp.code == 204 || resp.code == 304;
+    let len = match resp.reader {
+        _ if bodiless => Some(0),
+        Some(_) => resp.reader_len,
+        None => Some(resp.body.len() as u64),
+    };
+    let code = resp.code.to_string();
+    let mut headers = vec![(":status".to_string(), code)];
+    if !bodiless {
+        if let Some(len) = len {
+            headers.push(("content-length".to_string(), len.to_string()));
+        }
+    }
+    if resp.get_header("Date").is_none() {
+        headers.push(("date".to_string(),
+                time::with_http_date_now(|date| date.to_string())));
+    }
+    for (name, value) in resp.headers.iter() {
+        let name = name.to_ascii_lowercase();
+        // The server's content-length, as in write_response
+        if valid_header_name(&name) && name != "content-length"
+                && !CONNECTION_HEADERS.contains(&name.as_str()) {
+            headers.push((name, clean_header_value(value).into_owned()));
+        }
+    }
+    let headers: Vec<(&str, &str)> = headers.iter()
+        .map(|(n, v)| (n.as_str(), v.as_str())).collect();
+
+    let head = req.map(|r| r.method == "head").unwrap_or(false);
+    let no_body = head || len == Some(0);
+    if send_headers(shared, stream, &hpack::encode(&headers), no_body).is_err()
+            || no_body {
+        return 0;
+    }
+    let sent = match resp.reader.take() {
+        Some(reader) => send_body(shared, stream,
+                &mut reader.take(len.unwrap_or(u64::MAX)), len,
+                resp.chunk_size),
+        None => send_body(shared, stream, &mut &resp.body[..], len, None),
+    };
+    return sent as usize;
+}
+
+// A HEADERS frame, and CONTINUATION frames if needed
+fn send_headers(shared: &Shared, stream: u32, block: &[u8], end_stream: bool)
+        -> io::Result<()> {
+    let max_frame_size = {
+        let state = shared.state.lock().unwrap();
+        if state.closed || !state.streams.contains_key(&stream) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "reset"));
+        }
+        state.max_frame_size
+    };
+    let mut frames = Vec::with_capacity(block.len() + 18);
+    let chunks: Vec<&[u8]> = if block.is_empty() {
+        vec![block]
+    } else {
+        block.chunks(max_frame_size).collect()
+    };
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut flags = 0;
+        if i == chunks.len() - 1 {
+            flags |= END_HEADERS;
+        }
+        if i == 0 && end_stream {
+            flags |= END_STREAM;
+        }
+        let kind = if i == 0 { HEADERS } else { CONTINUATION };
+        push_frame(&mut frames, kind, flags, stream, chunk);
+    }
+    return shared.writer.lock().unwrap().write_all(&frames);
+}
+
+// DATA frames with what `body` reads, as the windows allow.  The last one
+// ends the stream: the one with the `len`th byte, or an empty one after
+// the body ends.  With a chunk_size, reads are gathered into frames of
+// that size (split if it's more than the client takes).
+fn send_body(shared: &Shared, stream: u32, body: &mut dyn Read,
+        len: Option<u64>, chunk_size: Option<usize>) -> u64 {
+    let mut buf = vec![0; chunk_size.unwrap_or(MAX_FRAME_SIZE)];
+    let mut sent = 0;
+    loop {
+        let read = match chunk_size {
+            Some(_) => read_chunk(body, &mut buf),
+            None => body.read(&mut buf),
+        };
+        let n = match read {
+            Ok(n) => n,
+            Err(_) => {
+                // Not the whole body: the client mustn't take it as such
+                let code = INTERNAL_ERROR.to_be_bytes();
+                let _ = write_frame(shared, RST_STREAM, 0, stream, &code);
+                return sent;
+            }
+        };
+        if n == 0 {
+            let _ = write_frame(shared, DATA, END_STREAM, stream, &[]);
+            return sent;
+        }
+        let mut chunk = &buf[..n];
+        while !chunk.is_empty() {
+            let size = match reserve(shared, stream, chunk.len()) {
+                Some(size) => size,
+                None => return sent,
+            };
+            sent += size as u64;
+            let flags = if Some(sent) == len { END_STREAM } else { 0 };
+            let data = &chunk[..size];
+            if write_frame(shared, DATA, flags, stream, data).is_err() {
+                return sent;
+            }
+            if flags != 0 {
+                return sent;
+            }
+            chunk = &chunk[size..];
+        }
+    }
+}
+
+// Wait until up to `want` bytes may be sent on `stream`, and take them out
+// of the windows.  None if the stream or connection is gone.
+fn reserve(shared: &Shared, stream: u32, want: usize) -> Option<usize> {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.closed {
+            return None;
+        }
+        let window = *state.streams.get(&stream)?;
+        let size = (want as i64).min(window).min(state.window)
+            .min(state.max_frame_size as i64);
+        if size > 0 {
+            state.window -= size;
+            *state.streams.get_mut(&stream).unwrap() -= size;
+            return Some(size as usize);
+        }
+        if state.done {
+            return None;
+        }
+        state = shared.changed.wait(state).unwrap();
+    }
+}
+
+
+fn error_response(code: i32, status: &str) -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(code, status);
+    resp.set_body_str(&format!("Error {}: {}", code, status));
+    return resp;
+}
+
+
+// Collects what the server sends; reads nothing
+#[cfg(test)]
+struct TestOutput(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Read for TestOutput {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        return Ok(0);
+    }
+}
+
+#[cfg(test)]
+impl io::Write for TestOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+// Serve `input` (after the preface), returning the frames sent back
+#[cfg(test)]
+fn test_serve(input: Vec<u8>) -> Vec<Frame> {
+    use super::Logger;
+    let handler = |req: &WebRequest| {
+        if req.get_path() == "/big" {
+            let mut resp = WebResponse::new();
+            resp.set_body(&[b'x'; 70000]);
+            return resp;
+        }
+        let mut resp = WebResponse::new();
+        resp.set_header("Connection", "close");
+        resp.set_body_str(&format!("{} {} {} {} {} {}", req.get_method(),
+            req.get_path(), String::from_utf8_lossy(req.get_body()),
+            req.get_header("Host").unwrap_or(""),
+            req.get_header("Cookie").unwrap_or(""),
+            String::from_utf8_lossy(&req.get_environ()[&b"protocol"[..]])));
+        return resp;
+    };
+    let ctx = Arc::new(WorkerSharedContext {
+        handler: Box::new(handler),
+        logger: Logger::new(false, None, None, None, None, None, None),
+        max_request_body_size: 10,
+        max_head_size: 65536,
+        body_spool: None,
+        memory_budget: None,
+        listen_sock: ::std::net::TcpListener::bind("127.0.0.1:0").unwrap(),
+        http2: true,
+        stats: Default::default(),
+        tracer: Default::default(),
+        body_inspector: None,
+        conn_limit: None,
+        buffers: ::utils::buffer_pool::BufferPool::new(16, 0),
+        accept_watermarks: None,
+        overload: super::Overload::Pause,
+        h2_streams: Some(StreamPool::new(4, 32)),
+    });
+    let output = Arc::new(Mutex::new(Vec::new()));
+    serve(ctx, Box::new(io::Cursor::new(input)),
+            Box::new(TestOutput(output.clone())),
+            "127.0.0.1:1234".parse().unwrap(), None);
+
+    let output = output.lock().unwrap();
+    let mut frames = Vec::new();
+    let mut rest = &output[..];
+    while !rest.is_empty() {
+        let len = u32::from_be_bytes([0, rest[0], rest[1], rest[2]]) as usize;
+        frames.push(Frame {
+            kind: rest[3],
+            flags: rest[4],
+            stream: u32::from_be_bytes([rest[5], rest[6], rest[7], rest[8]]),
+            payload: rest[9..9 + len].to_vec(),
+        });
+        rest = &rest[9 + len..];
+    }
+    return frames;
+}
+
+#[cfg(test)]
+fn test_frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    push_frame(&mut frame, kind, flags, stream, payload);
+    return frame;
+}
+
+#[test]
+fn test_http2_requests() {
+    let mut input = test_frame(SETTINGS, 0, 0, &[]);
+    input.extend(test_frame(HEADERS, END_HEADERS | END_STREAM, 1,
+        &hpack::encode(&[(":method", "GET"), (":scheme", "http"),
+            (":path", "/a%20b?q"), (":authority", "example.com"),
+            ("cookie", "a=1"), ("cookie", "b=2")])));
+    // Split over CONTINUATION, with the body in two DATA frames
+    let block = hpack::encode(&[(":method", "POST"), (":scheme", "http"),
+        (":path", "/post"), ("content-length", "5")]);
+    input.extend(test_frame(HEADERS, 0, 3, &block[..4]));
+    input.extend(test_frame(CONTINUATION, END_HEADERS, 3, &block[4..]));
+    input.extend(test_frame(DATA, PADDED, 3, b"\x02hel\0\0"));
+    input.extend(test_frame(PING, 0, 0, b"12345678"));
+    input.extend(test_frame(DATA, END_STREAM, 3, b"lo"));
+    // Malformed, too large, and refused for a body that's too short
+    input.extend(test_frame(HEADERS, END_HEADERS | END_STREAM, 5,
+        &hpack::encode(&[(":method", "GET"), (":scheme", "http"),
+            (":path", "/"), ("X-Upper", "1")])));
+    input.extend(test_frame(HEADERS, END_HEADERS, 7,
+        &hpack::encode(&[(":method", "POST"), (":scheme", "http"),
+            (":path", "/")])));
+    input.extend(test_frame(DATA, END_STREAM, 7, b"01234567890"));
+    input.extend(test_frame(HEADERS, END_HEADERS, 9,
+        &hpack::encode(&[(":method", "POST"), (":scheme", "http"),
+            (":path", "/"), ("content-length", "3")])));
+    input.extend(test_frame(DATA, END_STREAM, 9, b"ab"));
+    input.extend(test_frame(GOAWAY, 0, 0, &[0; 8]));
+    let frames = test_serve(input);
+
+    assert_eq!((frames[0].kind, frames[0].flags), (SETTINGS, 0));
+    assert!(frames.iter().any(|f| f.kind == SETTINGS && f.flags == ACK));
+    assert!(frames.iter().any(|f| f.kind == PING && f.flags == ACK
+            && f.payload == b"12345678"));
+    let resets: Vec<(u32, &[u8])> = frames.iter()
+        .filter(|f| f.kind == RST_STREAM)
+        .map(|f| (f.stream, &f.payload[..])).collect();
+    assert_eq!(resets, [(5, &[0, 0, 0, 1][..]), (9, &[0, 0, 0, 1][..])]);
+
+    let mut decoder = Decoder::new(4096);
+    let response = |stream: u32, decoder: &mut Decoder| {
+        let head = frames.iter()
+            .find(|f| f.stream == stream && f.kind == HEADERS).unwrap();
+        assert_eq!(head.flags, END_HEADERS);
+        let mut headers = decoder.decode(&head.payload, 1000).unwrap();
+        // Each has one, that changes
+        let date = headers.iter().position(|(name, _)| name == b"date");
+        headers.remove(date.unwrap());
+        let data: Vec<&Frame> = frames.iter()
+            .filter(|f| f.stream == stream && f.kind == DATA).collect();
+        assert_eq!(data.last().unwrap().flags, END_STREAM);
+        let body: Vec<u8> = data.iter()
+            .flat_map(|f| f.payload.iter().cloned()).collect();
+        return (headers, String::from_utf8(body).unwrap());
+    };
+    let (headers, body) = response(1, &mut decoder);
+    assert_eq!(headers, hpack::test_headers(&[(":status", "200"),
+        ("content-length", "39")]));
+    assert_eq!(body, "get /a b  example.com a=1; b=2 http/2.0");
+    assert_eq!(response(3, &mut decoder).1, "post /post hello   http/2.0");
+    let (headers, body) = response(7, &mut decoder);
+    assert_eq!(headers[0], hpack::test_headers(&[(":status", "413")])[0]);
+    assert_eq!(body, "Error 413: Request Entity Too Large");
+}
+
+#[test]
+fn test_http2_flow_control() {
+    let mut input = test_frame(SETTINGS, 0, 0,
+        &[0, 4, 0, 2, 0, 0, 0, 5, 0, 0, 0x40, 0]);
+    input.extend(test_frame(HEADERS, END_HEADERS | END_STREAM, 1,
+        &hpack::encode(&[(":method", "GET"), (":scheme", "http"),
+            (":path", "/big")])));
+    input.extend(test_frame(WINDOW_UPDATE, 0, 0, &10000u32.to_be_bytes()));
+    input.extend(test_frame(GOAWAY, 0, 0, &[0; 8]));
+    let frames = test_serve(input);
+    let data: Vec<&Frame> = frames.iter().filter(|f| f.kind == DATA).collect();
+    // The connection window allows 65535 + 10000 in frames of 16384
+    assert_eq!(data.iter().map(|f| f.payload.len()).sum::<usize>(), 70000);
+    assert!(data.iter().all(|f| f.payload.len() <= 16384));
+    assert_eq!(data.last().unwrap().flags, END_STREAM);
+
+    // Not enough window: the response can't finish before the end
+    let mut input = test_frame(SETTINGS, 0, 0, &[]);
+    input.extend(test_frame(HEADERS, END_HEADERS | END_STREAM, 1,
+        &hpack::encode(&[(":method", "GET"), (":scheme", "http"),
+            (":path", "/big")])));
+    let frames = test_serve(input);
+    let data: Vec<&Frame> = frames.iter().filter(|f| f.kind == DATA).collect();
+    assert!(data.iter().map(|f| f.payload.len()).sum::<usize>() <= 65535);
+
+    // Not starting with SETTINGS
+    let frames = test_serve(test_frame(PING, 0, 0, b"1234"));
+    let last = frames.last().unwrap();
+    assert_eq!((last.kind, &last.payload[..]),
+            (GOAWAY, &[0, 0, 0, 0, 0, 0, 0, 1][..]));
+}
+
+#[test]
+fn test_http2_upgrade_settings() {
+    let head = "GET / HTTP/1.1\r\nUpgrade: h2c\r\n\
+        Connection: Upgrade, HTTP2-Settings\r\n\
+        HTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n\r\n";
+    let req = WebRequest::parse_for_test(head);
+    assert_eq!(upgrade_settings(&req), Some(vec![0, 3, 0, 0, 0, 100,
+        0, 4, 0, 160, 0, 0, 0, 2, 0, 0, 0, 0]));
+    let req = WebRequest::parse_for_test(&head.replace(", HTTP2-Settings", ""));
+    assert_eq!(upgrade_settings(&req), None);
+}
@@ -0,0 +1,91 @@
+//! The threads HTTP/2 requests run on
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+pub(crate) type Job = Box<dyn FnOnce() + Send>;
+
+
+// A fixed set of threads shared by all HTTP/2 connections, so a client
+// opening streams can't make more threads than there are in the pool.
+// Requests wait in a bounded queue for one.
+pub(crate) struct StreamPool {
+    jobs: SyncSender<Job>,
+}
+
+impl StreamPool {
+    // `threads` threads, with up to `queue` requests waiting for them.  The
+    // threads end once the pool is dropped and the queue is empty.
+    pub(crate) fn new(threads: usize, queue: usize) -> StreamPool {
+        let (jobs, queued) = mpsc::sync_channel::<Job>(queue);
+        let queued = Arc::new(Mutex::new(queued));
+        for _ in 0..threads {
+            let queued = queued.clone();
+            thread::spawn(move || loop {
+                let next = match queued.lock() {
+                    Ok(guard) => guard.recv(),
+                    Err(poisoned) => poisoned.into_inner().recv(),
+                };
+                let job = match next {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                // Handlers' panics are caught by the job already; anything
+                // else mustn't take a thread from the pool
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            });
+        }
+        return StreamPool { jobs: jobs };
+    }
+
+    // Queue `job`, without waiting: it's given back if the queue is full,
+    // as the connection's reader must go on reading (the running requests
+    // may wait on its window updates)
+    pub(crate) fn execute(&self, job: Job) -> Result<(), Job> {
+        match self.jobs.try_send(job) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(job)) => return Err(job),
+            Err(TrySendError::Disconnected(job)) => return Err(job),
+        }
+    }
+}
+
+
+#[test]
+fn test_stream_pool() {
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    let pool = StreamPool::new(2, 1);
+    let (started, running) = mpsc::channel();
+    // Runs until the test and the other one meet it at `barrier`
+    let job = |barrier: &Arc<Barrier>| -> Job {
+        let (barrier, started) = (barrier.clone(), started.clone());
+        return Box::new(move || {
+            started.send(()).unwrap();
+            barrier.wait();
+        });
+    };
+    let wait = || running.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let barrier = Arc::new(Barrier::new(3));
+    // Until a thread took the first from the queue
+    while pool.execute(job(&barrier)).is_err() {}
+    while pool.execute(job(&barrier)).is_err() {}
+    wait();
+    wait();
+    // Both threads busy: one waits in the queue, the next is refused
+    assert!(pool.execute(Box::new(|| panic!("in a job"))).is_ok());
+    assert!(pool.execute(Box::new(|| ())).is_err());
+    barrier.wait();
+
+    // The panic didn't take a thread: two run at once again
+    let barrier = Arc::new(Barrier::new(3));
+    while pool.execute(job(&barrier)).is_err() {}
+    while pool.execute(job(&barrier)).is_err() {}
+    wait();
+    wait();
+    barrier.wait();
+}
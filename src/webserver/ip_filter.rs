@@ -0,0 +1,282 @@
+//! Client IP allow/deny lists with CIDR ranges
+
+use std::fmt;
+use std::net::IpAddr;
+
+use super::{WebRequest, WebResponse, Middleware, Next};
+
+
+/// An IP network, ex: "10.0.0.0/8", "2001:db8::/32", or a single address.
+///
+/// IPv4-mapped IPv6 addresses (::ffff:1.2.3.4) match IPv4 networks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parse "addr/len" or "addr".  Host bits set in addr are ignored.
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let (addr, len) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = match addr.trim().parse() {
+            Ok(addr) => addr,
+            Err(..) => return Err(format!("bad IP address in {:?}", s)),
+        };
+        let max = match addr {
+            IpAddr::V4(..) => 32,
+            IpAddr::V6(..) => 128,
+        };
+        let prefix_len = match len {
+            Some(len) => match len.trim().parse::<u8>() {
+                Ok(n) if n <= max => n,
+                _ => return Err(format!("bad prefix length in {:?}", s)),
+            },
+            None => max,
+        };
+        return Ok(Cidr { addr: addr, prefix_len: prefix_len });
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => ip,
+            },
+            IpAddr::V4(..) => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) =>
+                return prefix_eq(&net.octets(), &ip.octets(), self.prefix_len),
+            (IpAddr::V6(net), IpAddr::V6(ip)) =>
+                return prefix_eq(&net.octets(), &ip.octets(), self.prefix_len),
+            _ => return false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}/{}", self.addr, self.prefix_len);
+    }
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], bits: u8) -> bool {
+    let bytes = (bits / 8) as usize;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    let rem = bits % 8;
+    if rem == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rem);
+    return a[bytes] & mask == b[bytes] & mask;
+}
+
+pub(crate) fn parse_or_panic(cidr: &str) -> Cidr {
+    match Cidr::parse(cidr) {
+        Ok(cidr) => return cidr,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+
+/// The client's IP address.
+///
+/// If the connection comes from one of the `trusted_proxies`, the
+/// X-Forwarded-For header is used instead: its last address that isn't a
+/// trusted proxy.  Otherwise clients could pick their own address.
+pub fn client_ip(req: &WebRequest, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let mut ip = req.get_remote_addr()?.ip();
+    if !trusted_proxies.iter().any(|c| c.contains(ip)) {
+        return Some(ip);
+    }
+    let forwarded = match req.get_header("X-Forwarded-For") {
+        Some(forwarded) => forwarded,
+        None => return Some(ip),
+    };
+    for hop in forwarded.rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(hop) => {
+                ip = hop;
+                if !trusted_proxies.iter().any(|c| c.contains(ip)) {
+                    break;
+                }
+            }
+            // Garbage, probably sent by the client.  The last good hop is
+            // as far as we can trust.
+            Err(..) => break,
+        }
+    }
+    return Some(ip);
+}
+
+
+/// Middleware that answers `403 Forbidden` to clients by IP address.
+///
+/// Rules are checked in the order added, and the first matching network
+/// decides.  If none match, the default applies (allow, unless
+/// `set_default_allow(false)`).  Requests with no known address are
+/// treated like a non-matching address.
+///
+/// Add it with `WebServer::add_middleware` to filter everything, or
+/// `Router::add_middleware` on a mounted router to filter one prefix.
+///
+/// ex: allow only the local network
+///
+/// ```ignore
+/// let mut filter = IpFilter::new();
+/// filter.allow("192.168.0.0/16");
+/// filter.allow("::1");
+/// filter.set_default_allow(false);
+/// ```
+pub struct IpFilter {
+    rules: Vec<(bool, Cidr)>,
+    default_allow: bool,
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl Default for IpFilter {
+    fn default() -> IpFilter {
+        return IpFilter::new();
+    }
+}
+
+impl IpFilter {
+    pub fn new() -> IpFilter {
+        return IpFilter {
+            rules: Vec::new(),
+            default_allow: true,
+            trusted_proxies: Vec::new(),
+        };
+    }
+
+    /// Allow a network.  Panics if `cidr` is invalid.
+    pub fn allow(&mut self, cidr: &str) {
+        self.rules.push((true, parse_or_panic(cidr)));
+    }
+
+    /// Deny a network.  Panics if `cidr` is invalid.
+    pub fn deny(&mut self, cidr: &str) {
+        self.rules.push((false, parse_or_panic(cidr)));
+    }
+
+    /// What to do with addresses no rule matches
+    pub fn set_default_allow(&mut self, allow: bool) {
+        self.default_allow = allow;
+    }
+
+    /// Trust X-Forwarded-For from these networks (see `client_ip`).
+    /// Panics if a network is invalid.
+    pub fn add_trusted_proxy(&mut self, cidr: &str) {
+        self.trusted_proxies.push(parse_or_panic(cidr));
+    }
+
+    pub fn is_allowed(&self, ip: Option<IpAddr>) -> bool {
+        if let Some(ip) = ip {
+            for &(allow, ref cidr) in self.rules.iter() {
+                if cidr.contains(ip) {
+                    return allow;
+                }
+            }
+        }
+        return self.default_allow;
+    }
+}
+
+impl Middleware for IpFilter {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        if self.is_allowed(client_ip(req, &self.trusted_proxies)) {
+            return next.run(req);
+        }
+        let mut resp = WebResponse::new();
+        resp.set_code(403, "Forbidden");
+        resp.set_body_str("Error 403: Forbidden");
+        return resp;
+    }
+}
+
+
+#[test]
+fn test_cidr() {
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    let net = Cidr::parse("10.1.0.0/16").unwrap();
+    assert!(net.contains(ip("10.1.2.3")));
+    assert!(!net.contains(ip("10.2.0.0")));
+    assert!(net.contains(ip("::ffff:10.1.0.1")));
+    assert!(!net.contains(ip("::1")));
+    let net = Cidr::parse("192.168.1.129/25").unwrap();
+    assert!(net.contains(ip("192.168.1.200")));
+    assert!(!net.contains(ip("192.168.1.127")));
+    assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+    let net = Cidr::parse("2001:db8::/32").unwrap();
+    assert!(net.contains(ip("2001:db8:ffff::1")));
+    assert!(!net.contains(ip("2001:db9::1")));
+    assert!(Cidr::parse("::1").unwrap().contains(ip("::1")));
+    assert_eq!(Cidr::parse("::1").unwrap().to_string(), "::1/128");
+    assert!(Cidr::parse("10.0.0.0/33").is_err());
+    assert!(Cidr::parse("10.0.0/8").is_err());
+    assert!(Cidr::parse("10.0.0.0/").is_err());
+}
+
+#[cfg(test)]
+fn request_from(addr: &str, forwarded: Option<&str>) -> WebRequest {
+    let header = match forwarded {
+        Some(f) => format!("X-Forwarded-For: {}\r\n", f),
+        None => String::new(),
+    };
+    let mut req = WebRequest::parse_for_test(
+            &format!("GET / HTTP/1.1\r\n{}\r\n", header));
+    req.environ.insert(b"remote_address".to_vec(), addr.as_bytes().to_vec());
+    return req;
+}
+
+#[test]
+fn test_client_ip() {
+    let proxies = [Cidr::parse("10.0.0.0/8").unwrap()];
+    let ip = |req: &WebRequest| client_ip(req, &proxies).unwrap().to_string();
+    assert_eq!(ip(&request_from("1.2.3.4:5", Some("9.9.9.9"))), "1.2.3.4");
+    assert_eq!(ip(&request_from("10.0.0.1:5", None)), "10.0.0.1");
+    assert_eq!(ip(&request_from("10.0.0.1:5", Some("6.6.6.6, 1.2.3.4"))),
+            "1.2.3.4");
+    assert_eq!(ip(&request_from("10.0.0.1:5",
+            Some("6.6.6.6, 1.2.3.4, 10.0.0.2"))), "1.2.3.4");
+    assert_eq!(ip(&request_from("10.0.0.1:5", Some("junk, 10.0.0.2"))),
+            "10.0.0.2");
+    assert!(client_ip(&WebRequest::new_for_test("GET", "/"), &proxies)
+            .is_none());
+}
+
+#[test]
+fn test_ip_filter() {
+    use super::{Chain, Handler};
+
+    let mut filter = IpFilter::new();
+    filter.deny("192.168.1.66");
+    filter.allow("192.168.0.0/16");
+    filter.set_default_allow(false);
+    filter.add_trusted_proxy("127.0.0.1");
+    let mut chain = Chain::new(|_req: &WebRequest| WebResponse::new());
+    chain.add(filter);
+
+    let code = |addr: &str, fwd: Option<&str>|
+        chain.handle(&mut request_from(addr, fwd)).code;
+    assert_eq!(code("192.168.1.1:80", None), 200);
+    assert_eq!(code("192.168.1.66:80", None), 403);
+    assert_eq!(code("8.8.8.8:80", None), 403);
+    assert_eq!(code("127.0.0.1:80", Some("192.168.5.5")), 200);
+    assert_eq!(code("127.0.0.1:80", Some("8.8.8.8")), 403);
+    assert_eq!(code("8.8.8.8:80", Some("192.168.5.5")), 403);
+    assert_eq!(chain.handle(&mut WebRequest::new_for_test("GET", "/")).code,
+            403);
+}
+
+#[test]
+#[should_panic(expected = "bad prefix length")]
+fn test_ip_filter_bad_cidr() {
+    IpFilter::new().allow("10.0.0.0/99");
+}
@@ -1,33 +1,84 @@
-use std::io;
+use std::net::SocketAddr;
+
+use super::{WebRequest, WebResponse, ConnInfo};
+use super::access_log::AccessLog;
+use super::metrics::Metrics;
+use super::slow_log::SlowLog;
+use super::error_log::ErrorLog;
+use super::status::StatusPage;
+use super::error_sink::{ErrorSink, ErrorContext, ServerError};
 
 pub struct Logger {
     logging_enabled: bool,
+    access_log: Option<AccessLog>,
+    metrics: Option<Metrics>,
+    slow_log: Option<SlowLog>,
+    error_log: Option<ErrorLog>,
+    status_page: Option<StatusPage>,
+    error_sink: Option<Box<dyn ErrorSink>>,
 }
 
 impl Logger {
-    pub fn new(logging_enabled: bool) -> Logger {
+    pub fn new(logging_enabled: bool, access_log: Option<AccessLog>,
+            metrics: Option<Metrics>, slow_log: Option<SlowLog>,
+            error_log: Option<ErrorLog>, status_page: Option<StatusPage>,
+            error_sink: Option<Box<dyn ErrorSink>>) -> Logger {
         return Logger { 
             logging_enabled: logging_enabled,
+            access_log: access_log,
+            metrics: metrics,
+            slow_log: slow_log,
+            error_log: error_log,
+            status_page: status_page,
+            error_sink: error_sink,
         }
     }
 
-    pub fn log_accept_error(&self, e: io::Error) {
-        if self.logging_enabled {
-            println!("Error from accept(): {}", e);
-        }
+    pub fn metrics(&self) -> Option<&Metrics> {
+        return self.metrics.as_ref();
     }
 
-    pub fn log_read_request_error(&self, e: io::Error) {
-        if self.logging_enabled {
-            println!("Error when reading request: {}", e);
+    // To the error sink if there's one, else printed if logging is enabled
+    pub fn log_error(&self, error: ServerError, peer_addr: Option<SocketAddr>,
+            req: Option<&WebRequest>) {
+        if let Some(ref sink) = self.error_sink {
+            sink.report(&error, &ErrorContext {
+                peer_addr: peer_addr,
+                request: req,
+            });
+        } else if self.logging_enabled {
+            println!("{}", error);
         }
     }
 
+    // Called after the response was written.  body_bytes is how much of the
+    // body was actually sent.
     // TODO: Better machine parsable output
-    pub fn log_request_response(&self, method: &str, path: &str,
-            code: i32, response_body_len: usize) {
+    pub fn log_request_response(&self, req: Option<&WebRequest>,
+            resp: &WebResponse, conn: &ConnInfo, body_bytes: usize) {
+        if let Some(ref access_log) = self.access_log {
+            access_log.log(req, resp, conn, body_bytes);
+        }
+        if let Some(ref slow_log) = self.slow_log {
+            slow_log.log(req, resp, conn);
+        }
+        if let Some(ref error_log) = self.error_log {
+            error_log.log(req, resp, conn);
+        }
+        if let Some(ref status_page) = self.status_page {
+            status_page.record(req, resp.code, conn.peer_addr,
+                    conn.start.elapsed());
+        }
+        if let Some(ref metrics) = self.metrics {
+            metrics.record(req, resp.code, conn.start.elapsed(),
+                    body_bytes);
+        }
         if ! self.logging_enabled { return; }
+        let (method, path) = match req {
+            Some(req) => (req.get_method(), req.get_path()),
+            None => ("", ""),
+        };
         println!("method={} path={} code={} body_len={}",
-            method, path, code, response_body_len);
+            method, path, resp.code, body_bytes);
     }
 }
@@ -0,0 +1,90 @@
+//! A limit on the memory the bodies of all requests take at once
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+// Bytes the bodies of requests in flight may take in all; see
+// `WebServer::set_memory_budget`
+pub(crate) struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+// Bytes taken from a budget, for one request, until dropped
+pub(crate) struct Reservation {
+    budget: Arc<MemoryBudget>,
+    size: usize,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: usize) -> MemoryBudget {
+        return MemoryBudget {
+            limit: limit,
+            used: AtomicUsize::new(0),
+        };
+    }
+
+    // Take size bytes, or None if there aren't that many left
+    pub(crate) fn reserve(budget: &Arc<MemoryBudget>, size: usize)
+            -> Option<Reservation> {
+        if !budget.take(size) {
+            return None;
+        }
+        return Some(Reservation { budget: budget.clone(), size: size });
+    }
+
+    fn take(&self, size: usize) -> bool {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            if size > self.limit.saturating_sub(used) {
+                return false;
+            }
+            match self.used.compare_exchange_weak(used, used + size,
+                    Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(now) => used = now,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn used(&self) -> usize {
+        return self.used.load(Ordering::Relaxed);
+    }
+}
+
+impl Reservation {
+    // Take size more bytes, for a body that grew; false if there aren't
+    // that many left
+    pub(crate) fn grow(&mut self, size: usize) -> bool {
+        if !self.budget.take(size) {
+            return false;
+        }
+        self.size += size;
+        return true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+
+#[test]
+fn test_memory_budget() {
+    let budget = Arc::new(MemoryBudget::new(100));
+    let a = MemoryBudget::reserve(&budget, 60).unwrap();
+    assert!(MemoryBudget::reserve(&budget, 41).is_none());
+    let mut b = MemoryBudget::reserve(&budget, 40).unwrap();
+    assert_eq!(budget.used(), 100);
+    assert!(!b.grow(1));
+    drop(a);
+    assert!(b.grow(50));
+    assert_eq!(budget.used(), 90);
+    drop(b);
+    assert_eq!(budget.used(), 0);
+    assert!(MemoryBudget::reserve(&budget, 101).is_none());
+}
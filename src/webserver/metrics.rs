@@ -0,0 +1,332 @@
+//! Server metrics, in the Prometheus text format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::{Handler, WebRequest, WebResponse, MatchedRoute};
+
+// Upper bounds of the request duration histogram, in seconds (Prometheus'
+// defaults)
+static BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+    2.5, 5.0, 10.0];
+
+
+/// Counts what a server does, for Prometheus to scrape.
+///
+/// Given to `WebServer::set_metrics`, it counts the server's responses by
+/// status class, requests in progress, connections, request durations,
+/// and bytes of bodies received and sent.  As a handler it responds with
+/// them all in the Prometheus text format, wherever it's routed.  Clones
+/// share the counts.
+///
+/// Requests a `Router` routed are also counted by route, with the route
+/// as it was added (see `MatchedRoute`) rather than the path, so paths
+/// like `/users/12345` don't each get their own series:
+/// `mudpie_route_requests_total` and `mudpie_route_request_duration_seconds`
+/// have a `route` label.
+///
+/// ex:
+///
+/// ```ignore
+/// let metrics = Metrics::new();
+/// server.set_metrics(metrics.clone());
+/// server.add_path("GET", "/metrics", metrics);
+/// ```
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Registry>,
+}
+
+struct Registry {
+    // By status class, 1xx to 5xx
+    responses: [AtomicU64; 5],
+    in_flight: AtomicUsize,
+    connections: AtomicU64,
+    open_connections: AtomicUsize,
+    // Not cumulative; one more for +Inf
+    duration_buckets: [AtomicU64; 12],
+    duration_micros: AtomicU64,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    routes: Mutex<BTreeMap<String, RouteMetrics>>,
+}
+
+#[derive(Default)]
+struct RouteMetrics {
+    responses: [u64; 5],
+    duration_buckets: [u64; 12],
+    duration_micros: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        return Metrics::new();
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        return Metrics {
+            inner: Arc::new(Registry {
+                responses: Default::default(),
+                in_flight: AtomicUsize::new(0),
+                connections: AtomicU64::new(0),
+                open_connections: AtomicUsize::new(0),
+                duration_buckets: Default::default(),
+                duration_micros: AtomicU64::new(0),
+                received_bytes: AtomicU64::new(0),
+                sent_bytes: AtomicU64::new(0),
+                routes: Mutex::new(BTreeMap::new()),
+            }),
+        };
+    }
+
+    // A connection, open until the returned guard is dropped
+    pub(crate) fn track_connection(&self) -> Open<'_> {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+        return Open::new(&self.inner.open_connections);
+    }
+
+    // A request being handled, until the returned guard is dropped
+    pub(crate) fn track_request(&self) -> Open<'_> {
+        return Open::new(&self.inner.in_flight);
+    }
+
+    // A response was sent, `duration` after the request came
+    pub(crate) fn record(&self, req: Option<&WebRequest>, code: i32,
+            duration: Duration, sent: usize) {
+        let inner = &self.inner;
+        let class = match code {
+            100..=599 => Some(code as usize / 100 - 1),
+            _ => None,
+        };
+        if let Some(class) = class {
+            inner.responses[class].fetch_add(1, Ordering::Relaxed);
+        }
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|&b| seconds <= b)
+            .unwrap_or(BUCKETS.len());
+        inner.duration_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        inner.duration_micros.fetch_add(duration.as_micros() as u64,
+                Ordering::Relaxed);
+        let received = req.map(|r| r.body_len() as usize).unwrap_or(0);
+        inner.received_bytes.fetch_add(received as u64, Ordering::Relaxed);
+        inner.sent_bytes.fetch_add(sent as u64, Ordering::Relaxed);
+
+        let route = match req.and_then(|r| r.get_extensions()
+                .get::<MatchedRoute>()) {
+            Some(route) => route,
+            None => return,
+        };
+        let mut routes = match inner.routes.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // NB: No entry() to not clone the route each time
+        if !routes.contains_key(&route.0) {
+            routes.insert(route.0.clone(), RouteMetrics::default());
+        }
+        let metrics = routes.get_mut(&route.0).unwrap();
+        if let Some(class) = class {
+            metrics.responses[class] += 1;
+        }
+        metrics.duration_buckets[bucket] += 1;
+        metrics.duration_micros += duration.as_micros() as u64;
+    }
+
+    /// The metrics, in the Prometheus text format (version 0.0.4)
+    pub fn render(&self) -> String {
+        let inner = &self.inner;
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let head = |out: &mut String, name: &str, kind: &str,
+                help: &str| {
+            let _ = write!(out, "# HELP mudpie_{} {}\n# TYPE mudpie_{} {}\n",
+                    name, help, name, kind);
+        };
+
+        head(&mut out, "requests_total", "counter",
+                "Requests answered, by status class.");
+        for (i, count) in inner.responses.iter().enumerate() {
+            let _ = writeln!(out, "mudpie_requests_total{{code=\"{}xx\"}} {}",
+                    i + 1, load(count));
+        }
+        head(&mut out, "requests_in_flight", "gauge",
+                "Requests being handled.");
+        let _ = writeln!(out, "mudpie_requests_in_flight {}",
+                inner.in_flight.load(Ordering::Relaxed));
+        head(&mut out, "connections_total", "counter",
+                "Connections accepted.");
+        let _ = writeln!(out, "mudpie_connections_total {}",
+                load(&inner.connections));
+        head(&mut out, "connections_open", "gauge", "Connections open.");
+        let _ = writeln!(out, "mudpie_connections_open {}",
+                inner.open_connections.load(Ordering::Relaxed));
+
+        head(&mut out, "request_duration_seconds", "histogram",
+                "Time from accepting a request to sending its response.");
+        let mut buckets = [0; 12];
+        for (bucket, n) in buckets.iter_mut().zip(&inner.duration_buckets) {
+            *bucket = load(n);
+        }
+        histogram(&mut out, "request_duration_seconds", "", &buckets,
+                load(&inner.duration_micros));
+
+        head(&mut out, "received_bytes_total", "counter",
+                "Bytes of request bodies received.");
+        let _ = writeln!(out, "mudpie_received_bytes_total {}",
+                load(&inner.received_bytes));
+        head(&mut out, "sent_bytes_total", "counter",
+                "Bytes of response bodies sent.");
+        let _ = writeln!(out, "mudpie_sent_bytes_total {}",
+                load(&inner.sent_bytes));
+
+        let routes = match inner.routes.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        head(&mut out, "route_requests_total", "counter",
+                "Requests answered, by route and status class.");
+        for (route, metrics) in routes.iter() {
+            for (i, count) in metrics.responses.iter().enumerate() {
+                let _ = writeln!(out, "mudpie_route_requests_total\
+                        {{route=\"{}\",code=\"{}xx\"}} {}",
+                        label_escape(route), i + 1, count);
+            }
+        }
+        head(&mut out, "route_request_duration_seconds", "histogram",
+                "Time from accepting a request to sending its response, by \
+                route.");
+        for (route, metrics) in routes.iter() {
+            let label = format!("route=\"{}\",", label_escape(route));
+            histogram(&mut out, "route_request_duration_seconds", &label,
+                    &metrics.duration_buckets, metrics.duration_micros);
+        }
+        return out;
+    }
+}
+
+impl Handler for Metrics {
+    fn handle(&self, _req: &mut WebRequest) -> WebResponse {
+        let mut resp = WebResponse::new();
+        resp.set_header("Content-Type", "text/plain; version=0.0.4");
+        resp.set_body_str(&self.render());
+        return resp;
+    }
+}
+
+
+// The lines of a histogram, from its counts by bucket (not cumulative).
+// `labels` are the other labels of its series, each followed by a comma.
+fn histogram(out: &mut String, name: &str, labels: &str, buckets: &[u64],
+        micros: u64) {
+    let mut count = 0;
+    for (i, n) in buckets.iter().enumerate() {
+        count += n;
+        let le = match BUCKETS.get(i) {
+            Some(le) => le.to_string(),
+            None => "+Inf".to_string(),
+        };
+        let _ = writeln!(out, "mudpie_{}_bucket{{{}le=\"{}\"}} {}", name,
+                labels, le, count);
+    }
+    let labels = labels.trim_end_matches(',');
+    let labels = match labels {
+        "" => String::new(),
+        _ => format!("{{{}}}", labels),
+    };
+    let _ = writeln!(out, "mudpie_{}_sum{} {}", name, labels,
+            micros as f64 / 1e6);
+    let _ = writeln!(out, "mudpie_{}_count{} {}", name, labels, count);
+}
+
+// A label value, escaped for the text format
+fn label_escape(value: &str) -> String {
+    return value.replace('\\', "\\\\").replace('"', "\\\"")
+        .replace('\n', "\\n");
+}
+
+
+// A gauge counted up while this is around
+pub(crate) struct Open<'a>(&'a AtomicUsize);
+
+impl<'a> Open<'a> {
+    pub(crate) fn new(gauge: &'a AtomicUsize) -> Open<'a> {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        return Open(gauge);
+    }
+}
+
+impl<'a> Drop for Open<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+
+#[test]
+fn test_metrics() {
+    let metrics = Metrics::new();
+    let conn = metrics.track_connection();
+    let in_flight = metrics.track_request();
+    let mut req = WebRequest::new_for_test("POST", "/users/12");
+    req.body = vec![0; 10];
+    metrics.record(Some(&req), 200, Duration::from_millis(3), 100);
+    metrics.record(None, 404, Duration::from_millis(30), 20);
+    metrics.record(None, 204, Duration::from_secs(20), 0);
+    let text = metrics.render();
+    let has = |line: &str| text.lines().any(|l| l == line);
+    assert!(has("# TYPE mudpie_requests_total counter"));
+    assert!(has("mudpie_requests_total{code=\"2xx\"} 2"));
+    assert!(has("mudpie_requests_total{code=\"4xx\"} 1"));
+    assert!(has("mudpie_requests_total{code=\"5xx\"} 0"));
+    assert!(has("mudpie_requests_in_flight 1"));
+    assert!(has("mudpie_connections_total 1"));
+    assert!(has("mudpie_connections_open 1"));
+    assert!(has("mudpie_request_duration_seconds_bucket{le=\"0.005\"} 1"));
+    assert!(has("mudpie_request_duration_seconds_bucket{le=\"0.025\"} 1"));
+    assert!(has("mudpie_request_duration_seconds_bucket{le=\"0.05\"} 2"));
+    assert!(has("mudpie_request_duration_seconds_bucket{le=\"10\"} 2"));
+    assert!(has("mudpie_request_duration_seconds_bucket{le=\"+Inf\"} 3"));
+    assert!(has("mudpie_request_duration_seconds_sum 20.033"));
+    assert!(has("mudpie_request_duration_seconds_count 3"));
+    assert!(has("mudpie_received_bytes_total 10"));
+    assert!(has("mudpie_sent_bytes_total 120"));
+    assert!(has("# TYPE mudpie_route_requests_total counter"));
+    assert!(!text.contains("{route="));
+
+    // By route, for the routed ones
+    req.get_extensions_mut().insert(MatchedRoute("/users/:id".into()));
+    metrics.record(Some(&req), 200, Duration::from_millis(3), 0);
+    metrics.record(Some(&req), 500, Duration::from_millis(30), 0);
+    req.get_extensions_mut().insert(MatchedRoute("/\"a\"\\".into()));
+    metrics.record(Some(&req), 200, Duration::from_millis(3), 0);
+    let text = metrics.render();
+    let has = |line: &str| text.lines().any(|l| l == line);
+    assert!(has("mudpie_requests_total{code=\"2xx\"} 4"));
+    assert!(has("mudpie_route_requests_total{route=\"/users/:id\",\
+            code=\"2xx\"} 1"));
+    assert!(has("mudpie_route_requests_total{route=\"/users/:id\",\
+            code=\"5xx\"} 1"));
+    assert!(has("mudpie_route_requests_total{route=\"/\\\"a\\\"\\\\\",\
+            code=\"2xx\"} 1"));
+    assert!(has("mudpie_route_request_duration_seconds_bucket\
+            {route=\"/users/:id\",le=\"0.005\"} 1"));
+    assert!(has("mudpie_route_request_duration_seconds_bucket\
+            {route=\"/users/:id\",le=\"+Inf\"} 2"));
+    assert!(has("mudpie_route_request_duration_seconds_sum\
+            {route=\"/users/:id\"} 0.033"));
+    assert!(has("mudpie_route_request_duration_seconds_count\
+            {route=\"/users/:id\"} 2"));
+
+    drop((conn, in_flight));
+    let mut resp = metrics.handle(&mut WebRequest::new_for_test("GET", "/"));
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/plain; version=0.0.4"));
+    let body = String::from_utf8(resp.body_for_test()).unwrap();
+    assert!(body.contains("\nmudpie_connections_open 0\n"));
+    assert!(body.contains("\nmudpie_connections_total 1\n"));
+}
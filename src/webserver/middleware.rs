@@ -0,0 +1,227 @@
+//! Middleware: code that runs around handlers
+
+use super::{Handler, WebRequest, WebResponse};
+
+
+/// Wraps a handler, e.g. for auth, logging, or compression.
+///
+/// A middleware gets the request and the rest of the chain.  It can modify
+/// the request, call `next.run(req)` and modify the response, or answer
+/// without calling the handler at all.
+///
+/// Implemented for closures of the form
+/// `|req: &mut WebRequest, next: Next| -> WebResponse`.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse;
+}
+
+impl<F> Middleware for F
+        where F: Fn(&mut WebRequest, Next) -> WebResponse + Send + Sync {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        return (self)(req, next);
+    }
+}
+
+impl Middleware for Box<dyn Middleware> {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        return (**self).handle(req, next);
+    }
+}
+
+
+/// The remaining middleware and the final handler
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    /// A chain that runs `middleware` in order, then `handler`
+    pub fn new(middleware: &'a [Box<dyn Middleware>], handler: &'a dyn Handler)
+            -> Next<'a> {
+        return Next { middleware: middleware, handler: handler };
+    }
+
+    /// Run the rest of the chain
+    pub fn run(self, req: &mut WebRequest) -> WebResponse {
+        match self.middleware.split_first() {
+            Some((first, rest)) => {
+                let next = Next { middleware: rest, handler: self.handler };
+                return first.handle(req, next);
+            }
+            None => return self.handler.handle(req),
+        }
+    }
+}
+
+
+/// A handler wrapped by a list of middleware.
+///
+/// The first middleware added is the outermost: it runs first on the way in,
+/// and last on the way out.
+pub struct Chain {
+    middleware: Vec<Box<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Chain {
+    pub fn new<H: Handler + 'static>(handler: H) -> Chain {
+        return Chain { middleware: Vec::new(), handler: Box::new(handler) };
+    }
+
+    /// Add a middleware inside the ones already added
+    pub fn add<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        return Next::new(&self.middleware, &*self.handler).run(req);
+    }
+}
+
+
+/// Middleware that runs a function before the handler.
+///
+/// If the function returns a response, it is sent and the handler is not run.
+/// ex: `Before::new(|req: &mut WebRequest| { ...; None })`
+pub struct Before<F> {
+    hook: F,
+}
+
+impl<F> Before<F> where F: Fn(&mut WebRequest) -> Option<WebResponse> {
+    pub fn new(hook: F) -> Before<F> {
+        return Before { hook: hook };
+    }
+}
+
+impl<F> Middleware for Before<F>
+        where F: Fn(&mut WebRequest) -> Option<WebResponse> + Send + Sync {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        match (self.hook)(req) {
+            Some(resp) => return resp,
+            None => return next.run(req),
+        }
+    }
+}
+
+
+/// Middleware that runs a function on the response, after the handler.
+/// ex: `After::new(|req: &WebRequest, resp: &mut WebResponse| { ... })`
+///
+/// The function can change anything about the response: the code, the
+/// headers (see `WebResponse::retain_headers`), or the body.  Streamed
+/// bodies can be wrapped with `WebResponse::map_body_reader`, or read into
+/// memory with `WebResponse::buffer_body`.
+pub struct After<F> {
+    hook: F,
+}
+
+impl<F> After<F> where F: Fn(&WebRequest, &mut WebResponse) {
+    pub fn new(hook: F) -> After<F> {
+        return After { hook: hook };
+    }
+}
+
+impl<F> Middleware for After<F>
+        where F: Fn(&WebRequest, &mut WebResponse) + Send + Sync {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let mut resp = next.run(req);
+        (self.hook)(req, &mut resp);
+        return resp;
+    }
+}
+
+
+#[cfg(test)]
+fn trace(req: &WebRequest) -> WebResponse {
+    let mut resp = WebResponse::new();
+    let path = req.get_path().to_string();
+    resp.set_body_str(&format!("handler({})", path));
+    return resp;
+}
+
+#[test]
+fn test_middleware_order() {
+    let mut chain = Chain::new(trace);
+    chain.add(|req: &mut WebRequest, next: Next| {
+        req.path.push_str("+outer");
+        let mut resp = next.run(req);
+        resp.body.extend(b" outer");
+        return resp;
+    });
+    chain.add(After::new(|_req: &WebRequest, resp: &mut WebResponse| {
+        resp.body.extend(b" after");
+    }));
+    chain.add(Before::new(|req: &mut WebRequest| {
+        req.path.push_str("+before");
+        return None;
+    }));
+
+    let mut req = WebRequest::new_for_test("GET", "/x");
+    let resp = chain.handle(&mut req);
+    assert_eq!(resp.body, &b"handler(/x+outer+before) after outer"[..]);
+}
+
+#[test]
+fn test_middleware_short_circuit() {
+    let mut chain = Chain::new(trace);
+    chain.add(Before::new(|req: &mut WebRequest| {
+        if req.get_path() == "/secret" {
+            let mut resp = WebResponse::new();
+            resp.set_code(403, "Forbidden");
+            return Some(resp);
+        }
+        return None;
+    }));
+    assert_eq!(chain.handle(&mut WebRequest::new_for_test("GET", "/secret"))
+            .code, 403);
+    assert_eq!(chain.handle(&mut WebRequest::new_for_test("GET", "/public"))
+            .code, 200);
+}
+
+#[test]
+fn test_middleware_transform() {
+    use std::io::Read;
+
+    let mut chain = Chain::new(|req: &WebRequest| {
+        let mut resp = WebResponse::new();
+        resp.set_header("Server", "mudpie");
+        resp.set_header("Content-Type", "text/html");
+        let page = &b"<body>page</body>"[..];
+        if req.get_path() == "/stream" {
+            resp.set_body_reader(page, Some(page.len() as u64));
+        } else {
+            resp.set_body(page);
+        }
+        return resp;
+    });
+    // Inject a banner into HTML, and drop the Server header
+    chain.add(After::new(|_req: &WebRequest, resp: &mut WebResponse| {
+        resp.retain_headers(|name, _| !name.eq_ignore_ascii_case("server"));
+        if resp.buffer_body(1024).unwrap() {
+            let body = String::from_utf8_lossy(resp.get_body())
+                .replace("<body>", "<body><p>banner</p>");
+            resp.set_body_str(&body);
+        }
+    }));
+
+    for path in &["/", "/stream"] {
+        let resp = chain.handle(&mut WebRequest::new_for_test("GET", path));
+        assert!(!resp.is_streamed());
+        assert_eq!(resp.get_body(), &b"<body><p>banner</p>page</body>"[..]);
+        assert_eq!(resp.get_header("Server"), None);
+        assert_eq!(resp.get_headers().count(), 1);
+    }
+
+    // Too large to buffer: the stream is kept as it was
+    let mut resp = WebResponse::new();
+    resp.set_body_reader(&b"0123456789"[..], Some(10));
+    assert!(!resp.buffer_body(4).unwrap());
+    resp.map_body_reader(|r| Box::new(r.take(8)));
+    let mut body = Vec::new();
+    resp.reader.take().unwrap().read_to_end(&mut body).unwrap();
+    assert_eq!(body, b"01234567");
+    assert_eq!(resp.reader_len, None);
+}
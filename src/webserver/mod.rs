@@ -1,20 +1,118 @@
 use std::env;
-use std::collections::HashMap;
+use std::io::{self, Read};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::str;
 use std::sync::Arc;
 use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::time::{Instant, SystemTime};
 
+use utils::byteutils;
 use utils::threadpool::ThreadPool;
 use utils::genericsocket::GenericSocket;
+use utils::http_request;
+use utils::buffer_pool::BufferPool;
+use utils::headers::Headers;
 use self::write_response::write_response;
-use self::router::{Router, RoutingResult};
+use self::upgrade::SharedStream;
+use self::metrics::Open;
+use self::stats::Stats;
+use self::trace::Tracer;
+use self::slow_log::Timings;
+use self::error_sink::{read_error, panic_message};
+use self::body_spool::Spool;
+use self::memory_budget::{MemoryBudget, Reservation};
+pub use self::router::{Router, RouteParams, MatchedRoute, TrailingSlash};
+pub use self::extensions::Extensions;
+pub use self::middleware::{Middleware, Next, Chain, Before, After};
 pub use self::logger::Logger;
+pub use self::access_log::{AccessLog, LogFormat, LogField, LogExtras};
+pub use self::request_id::{SetRequestId, RequestId};
+pub use self::cors::Cors;
+pub use self::rate_limit::{RateLimit, Throttle};
+pub use self::basic_auth::{BasicAuth, Verifier, Htpasswd, AuthUser};
+pub use self::ip_filter::{IpFilter, Cidr, client_ip};
+pub use self::compress::Compress;
+pub use self::cookie::{Cookie, SameSite};
+pub use self::session::{Sessions, Session, SessionStore, SessionData, MemoryStore};
+pub use self::csrf::{Csrf, CsrfToken};
+pub use self::security_headers::SecurityHeaders;
+pub use self::vhost::VHostMap;
+pub use self::timeout::Timeout;
+pub use self::guard::{Guard, ContentTypeGuard, AcceptGuard, HeaderGuard};
+pub use self::static_files::{StaticFiles, Symlinks};
+pub use self::websocket::{WebSocket, WebSocketConnection, WebSocketMessage};
+pub use self::sse::SseStream;
+pub use self::upgrade::Upgraded;
+pub use self::proxy::{ReverseProxy, Balance};
+pub use self::cgi::Cgi;
+pub use self::fastcgi::FastCgi;
+pub use self::scgi::Scgi;
+pub use self::metrics::Metrics;
+pub use self::stats::{ServerHandle, ServerStats, Latency, Overload};
+pub use self::trace::{TraceEvent, Trace, TraceHook};
+pub use self::error_sink::{ErrorSink, ErrorContext, ServerError};
+pub use self::slow_log::SlowLog;
+pub use self::health::{Health, Liveness};
+pub use self::status::StatusPage;
+pub use self::error_log::ErrorLog;
+pub use self::acme::{AcmeChallenge, ACME_CHALLENGE_PATH};
+pub use self::body_inspector::{BodyInspector, BodyInspection};
+pub use self::conn_limit::ConnLimit;
+pub use self::response_cache::ResponseCache;
+pub use self::read_request::{parse_head, HeadError};
+pub use self::body_spool::BodyReader;
 
 mod read_request;
 mod write_response;
 mod router;
 mod logger;
+mod extensions;
+mod middleware;
+mod access_log;
+mod request_id;
+mod cors;
+mod rate_limit;
+mod basic_auth;
+mod ip_filter;
+mod compress;
+mod cookie;
+mod session;
+mod csrf;
+mod security_headers;
+mod vhost;
+mod timeout;
+mod guard;
+mod static_files;
+mod websocket;
+mod sse;
+mod upgrade;
+mod http2;
+mod proxy;
+mod cgi;
+mod fastcgi;
+mod scgi;
+mod metrics;
+mod stats;
+mod trace;
+mod error_sink;
+mod slow_log;
+mod health;
+mod status;
+mod error_log;
+mod acme;
+mod body_inspector;
+mod conn_limit;
+mod response_cache;
+mod body_spool;
+mod memory_budget;
 
 static DEFAULT_MAX_REQUEST_BODY_SIZE: usize = 1_000_000;
+static DEFAULT_MAX_HEAD_SIZE: usize = 65536;
+
+// Takes over the connection after a 101 response
+type UpgradeFn = Box<dyn FnOnce(Upgraded) + Send>;
 
 
 /// A response that will be sent to the client (code, headers, body)
@@ -22,7 +120,21 @@ pub struct WebResponse {
     code: i32,
     status: String, 
     body: Vec<u8>,
-    headers: HashMap<String, String>,
+    headers: Vec<(String, String)>,
+    // Streamed body, used instead of `body`, and its length if known
+    reader: Option<Box<dyn Read + Send>>,
+    reader_len: Option<u64>,
+    // Gather the reader's reads into writes of this size
+    chunk_size: Option<usize>,
+    upgrade: Option<UpgradeFn>,
+    // What made the server send it, for the error log, if it did
+    cause: Option<String>,
+}
+
+impl Default for WebResponse {
+    fn default() -> WebResponse {
+        return WebResponse::new();
+    }
 }
 
 impl WebResponse {
@@ -36,7 +148,12 @@ impl WebResponse {
                 code: 200,
                 status: "OK".to_string(),
                 body: Vec::new(),
-                headers: HashMap::new(),
+                headers: Vec::new(),
+                reader: None,
+                reader_len: None,
+                chunk_size: None,
+                upgrade: None,
+                cause: None,
             };
     }
 
@@ -65,6 +182,97 @@ impl WebResponse {
     /// Set the response body
     pub fn set_body(&mut self, body: &[u8]) {
         self.body = body.to_vec();
+        self.reader = None;
+        self.reader_len = None;
+    }
+
+    /// Stream the response body from `reader` instead of sending a buffer.
+    ///
+    /// With a `len`, Content-Length is sent and exactly `len` bytes are
+    /// read.  Without one, the body is whatever `reader` returns until EOF,
+    /// sent chunked (Transfer-Encoding: chunked) to HTTP/1.1 clients, and
+    /// ended by closing the connection for HTTP/1.0 ones.
+    pub fn set_body_reader<R: Read + Send + 'static>(&mut self, reader: R,
+            len: Option<u64>) {
+        self.body = Vec::new();
+        self.reader = Some(Box::new(reader));
+        self.reader_len = len;
+    }
+
+    /// Send a streamed body in chunks (HTTP/1.1 chunked encoding), writes
+    /// or HTTP/2 DATA frames of `size` bytes, reading from the reader until
+    /// there are that many or it ends.  By default each read is a chunk of
+    /// its own, written as it returns, so a reader that returns little at a
+    /// time makes as many small chunks and packets; gathering them saves
+    /// the framing and the writes, for readers nobody waits on each piece
+    /// of.  Not for `SseStream`, whose events would wait for the next
+    /// ones.
+    pub fn set_body_chunk_size(&mut self, size: usize) {
+        assert!(size > 0);
+        self.chunk_size = Some(size);
+    }
+
+    /// Hand the connection over to `f` once this response has been sent,
+    /// instead of closing it, to switch to another protocol.  Only done if
+    /// the code is 101 (Switching Protocols); see `Upgraded`.
+    pub fn set_upgrade<F>(&mut self, f: F)
+            where F: FnOnce(Upgraded) + Send + 'static {
+        self.upgrade = Some(Box::new(f));
+    }
+
+    /// Whether the body is streamed (see `set_body_reader`)
+    pub fn is_streamed(&self) -> bool {
+        return self.reader.is_some();
+    }
+
+    /// The body buffer.  Empty if the body is streamed.
+    pub fn get_body(&self) -> &[u8] {
+        return &self.body;
+    }
+
+    /// The body buffer, to edit in place.  Has no effect if the body is
+    /// streamed; see `buffer_body`.
+    pub fn body_mut(&mut self) -> &mut Vec<u8> {
+        return &mut self.body;
+    }
+
+    /// Wrap a streamed body, ex: to filter it as it's sent.  The length
+    /// becomes unknown, since `f` may change it.  Does nothing if the body
+    /// isn't streamed.
+    pub fn map_body_reader<F>(&mut self, f: F)
+            where F: FnOnce(Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        if let Some(reader) = self.reader.take() {
+            self.reader = Some(f(reader));
+            self.reader_len = None;
+        }
+    }
+
+    /// Read a streamed body into the body buffer, so it can be edited.
+    ///
+    /// Returns false, leaving the stream as it was, if the body is more
+    /// than `limit` bytes.  Returns true if the body is buffered (or was
+    /// already).
+    pub fn buffer_body(&mut self, limit: u64) -> io::Result<bool> {
+        let reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => return Ok(true),
+        };
+        let mut buf = Vec::new();
+        let mut reader = match self.reader_len {
+            Some(len) => reader.take(len),
+            None => reader.take(u64::MAX),
+        };
+        let limit = reader.limit().min(limit);
+        (&mut reader).take(limit.saturating_add(1)).read_to_end(&mut buf)?;
+        if buf.len() as u64 > limit {
+            let len = self.reader_len.map(|_| buf.len() as u64 + reader.limit());
+            self.reader = Some(Box::new(io::Cursor::new(buf).chain(reader)));
+            self.reader_len = len;
+            return Ok(false);
+        }
+        self.body = buf;
+        self.reader_len = None;
+        return Ok(true);
     }
 
     /// Set the response body as the UTF-8 encoded bytes from `body`.
@@ -73,10 +281,101 @@ impl WebResponse {
         self.set_body(body.as_bytes());
     }
 
-    /// Set a response header.  If it already exists, it will be overwritten.
-    /// Header names and values should use ASCII/Latin1 characters only.
+    /// Set a response header.  If it already exists, it will be overwritten
+    /// (names are case-insensitive).  Header names and values should use
+    /// ASCII/Latin1 characters only.
+    ///
+    /// Headers whose names aren't valid aren't sent, and control characters
+    /// in values, like CR and LF, are sent as spaces: a value taken from
+    /// the request can't add headers or split the response.
     pub fn set_header(&mut self, name: &str, value: &str) {
-        self.headers.insert(name.to_string(), value.to_string());
+        self.remove_header(name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Add a header line, keeping any others with the same name.  For
+    /// headers that can't be combined into one line, like Set-Cookie.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Add to a comma separated list header, ex: `append_header("Vary",
+    /// "Origin")`.  Values already in the list aren't added twice.  Don't
+    /// use this for headers that can't be joined with commas (Set-Cookie).
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        for &mut (ref k, ref mut old) in self.headers.iter_mut() {
+            if k.eq_ignore_ascii_case(name) {
+                let present = byteutils::has_token(old.as_bytes(),
+                        value.as_bytes());
+                if !present {
+                    old.push_str(", ");
+                    old.push_str(value);
+                }
+                return;
+            }
+        }
+        self.add_header(name, value);
+    }
+
+    /// Send a cookie (adds a Set-Cookie header)
+    pub fn add_cookie(&mut self, cookie: &Cookie) {
+        self.add_header("Set-Cookie", &cookie.to_string());
+    }
+
+    /// Get a response header (the first, if added more than once).  The
+    /// name is case-insensitive.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        for (k, v) in self.headers.iter() {
+            if k.eq_ignore_ascii_case(name) {
+                return Some(v);
+            }
+        }
+        return None;
+    }
+
+    /// Remove all headers called `name`, returning the first value.  The
+    /// name is case-insensitive.
+    pub fn remove_header(&mut self, name: &str) -> Option<String> {
+        let mut ret = None;
+        let mut i = 0;
+        while i < self.headers.len() {
+            if self.headers[i].0.eq_ignore_ascii_case(name) {
+                let (_, v) = self.headers.remove(i);
+                if ret.is_none() {
+                    ret = Some(v);
+                }
+            } else {
+                i += 1;
+            }
+        }
+        return ret;
+    }
+
+    /// The status code, ex: 200
+    pub fn get_code(&self) -> i32 {
+        return self.code;
+    }
+
+    /// The status message, ex: "OK"
+    pub fn get_status(&self) -> &str {
+        return &self.status;
+    }
+
+    /// All (name, value) header pairs, in the order they'll be sent
+    pub fn get_headers(&self) -> slice::Iter<'_, (String, String)> {
+        return self.headers.iter();
+    }
+
+    // The whole body, reading a streamed one, for unit tests
+    #[cfg(test)]
+    fn body_for_test(&mut self) -> Vec<u8> {
+        assert!(self.buffer_body(u64::MAX).unwrap());
+        return self.body.clone();
+    }
+
+    /// Keep only the headers for which `f(name, value)` returns true
+    pub fn retain_headers<F: FnMut(&str, &str) -> bool>(&mut self, mut f: F) {
+        self.headers.retain(|(k, v)| f(k, v));
     }
 }
 
@@ -84,13 +383,45 @@ impl WebResponse {
 /// A request from a client
 ///
 pub struct WebRequest { 
-    environ: HashMap<Vec<u8>, Vec<u8>>,
+    environ: Headers,
     path: String,
     method: String,
     body: Vec<u8>,
+    // In place of the body, if it was spooled to a file
+    spool: Option<Spool>,
+    // The memory budget taken for the body, given back with the request
+    budget: Option<Reservation>,
+    extensions: Extensions,
 }
 
 impl WebRequest {
+    // Build from a parsed request head; the body is filled in later
+    fn from_parsed(req: http_request::Request) -> WebRequest {
+        return WebRequest {
+            environ: req.environ,
+            path: req.path,
+            method: req.method,
+            body: Vec::new(),
+            spool: None,
+            budget: None,
+            extensions: Extensions::new(),
+        };
+    }
+
+    // A request with no headers or body, for unit tests
+    #[cfg(test)]
+    fn new_for_test(method: &str, path: &str) -> WebRequest {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        return WebRequest::parse_for_test(&raw);
+    }
+
+    // Parse a full request head (ending in \r\n\r\n), for unit tests
+    #[cfg(test)]
+    fn parse_for_test(raw: &str) -> WebRequest {
+        return WebRequest::from_parsed(
+                http_request::parse(raw.as_bytes()).ok().unwrap());
+    }
+
     /// The CGI/WSGI like environment dictionary.
     ///
     /// Keys:
@@ -99,6 +430,8 @@ impl WebRequest {
     /// * method = "get", "head", "options", ... 
     /// * path = "/full/path"
     /// * query_string = "k=v&k2=v2" or "" (empty)
+    /// * script_name = path prefix removed by `Router::mount`, or "" (empty)
+    /// * request_uri = the request target exactly as sent, ex: "/a%20b?k=v"
     /// * http_xxx = "Header Value".  ex: http_user-agent = "Mozilla Firefox"
     ///
     /// * remote_address = remote/client IP and port, ex: "1.1.1.1:1234"
@@ -108,13 +441,16 @@ impl WebRequest {
     /// 
     /// If the same header name was repeated in the request, the values will be
     /// concatenated, in order received, separated by a comma.
-    pub fn get_environ(&self) -> &HashMap<Vec<u8>, Vec<u8>> {
+    ///
+    /// The keys are in order: the above, then the headers as they came.
+    pub fn get_environ(&self) -> &Headers {
         return &self.environ;
     }
 
     /// The percent decoded and utf8 (lossy) decoded path.
     ///
-    /// For the raw path, see environ[path].  
+    /// For the raw path, see environ[path].  Inside a handler mounted with
+    /// `Router::mount`, the mount prefix has been removed from both.
     /// Note: This does not normalize '/./' or  '/../' components.
     pub fn get_path(&self) -> &str {
         return &self.path;
@@ -127,10 +463,102 @@ impl WebRequest {
         return &self.method;
     }
 
+    /// A request header, ex: `get_header("User-Agent")`.  The name is
+    /// case-insensitive.  Returns None if the header is missing, or isn't
+    /// valid UTF-8 (see environ for the raw bytes).
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        return self.environ.header(name)
+            .and_then(|v| str::from_utf8(v).ok());
+    }
+
+    /// The value of cookie `name` from the Cookie header
+    pub fn get_cookie(&self, name: &str) -> Option<&str> {
+        let header = self.get_header("Cookie")?;
+        return cookie::parse_cookie_header(header).into_iter()
+            .find(|&(k, _)| k == name).map(|(_, v)| v);
+    }
+
+    /// The address of the connected client (environ[remote_address]).
+    /// Behind a reverse proxy, this is the proxy's address.
+    pub fn get_remote_addr(&self) -> Option<SocketAddr> {
+        return self.environ.get(&b"remote_address"[..])
+            .and_then(|v| str::from_utf8(v).ok())
+            .and_then(|v| v.parse().ok());
+    }
+
     /// The request body.  Note that HTTP requests do not distinguish a null vs
     /// 0 length body, so this no longer returns an Option.
+    ///
+    /// A body spooled to a file (see `WebServer::set_body_spool`) is read
+    /// into memory the first time; use `body_reader` to avoid that.
     pub fn get_body(&self) -> &[u8] {
-        return &self.body;
+        match self.spool {
+            Some(ref spool) => return spool.bytes(),
+            None => return &self.body,
+        }
+    }
+
+    /// The request body to read, and seek in, from its start: from the
+    /// temporary file it was spooled to, if it was, else from memory.
+    pub fn body_reader(&self) -> io::Result<BodyReader<'_>> {
+        match self.spool {
+            Some(ref spool) => return Ok(BodyReader::file(spool.file()?)),
+            None => return Ok(BodyReader::memory(&self.body)),
+        }
+    }
+
+    // The body to read from another thread: its own handle on the spooled
+    // file, or else a copy
+    pub(crate) fn owned_body_reader(&self)
+            -> io::Result<Box<dyn Read + Send>> {
+        match self.spool {
+            Some(ref spool) => return Ok(Box::new(spool.file()?)),
+            None => return Ok(Box::new(io::Cursor::new(self.body.clone()))),
+        }
+    }
+
+    /// The size of the body, without reading a spooled one
+    pub fn body_len(&self) -> u64 {
+        match self.spool {
+            Some(ref spool) => return spool.len(),
+            None => return self.body.len() as u64,
+        }
+    }
+
+    /// Whether the body was spooled to a temporary file
+    pub fn is_body_spooled(&self) -> bool {
+        return self.spool.is_some();
+    }
+
+    /// The query string parameters, in order.  ex: for "/?a=1&b=x+y",
+    /// [("a", "1"), ("b", "x y")]
+    pub fn get_query(&self) -> Vec<(String, String)> {
+        match self.environ.get(&b"query_string"[..]) {
+            Some(qs) => return http_request::parse_urlencoded(qs),
+            None => return Vec::new(),
+        }
+    }
+
+    /// The fields of an application/x-www-form-urlencoded body, in order.
+    /// Empty for other content types.
+    pub fn get_form(&self) -> Vec<(String, String)> {
+        let content_type = self.get_header("Content-Type").unwrap_or("");
+        let mime = content_type.split(';').next().unwrap().trim();
+        if !mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return Vec::new();
+        }
+        return http_request::parse_urlencoded(self.get_body());
+    }
+
+    /// Typed data attached to this request by the server, routers, or
+    /// middleware.  For example, a `Router` stores `RouteParams` here.
+    pub fn get_extensions(&self) -> &Extensions {
+        return &self.extensions;
+    }
+
+    /// Mutable access to the request extensions.
+    pub fn get_extensions_mut(&mut self) -> &mut Extensions {
+        return &mut self.extensions;
     }
 }
 
@@ -139,12 +567,46 @@ impl WebRequest {
 pub type PageFunction = fn(&WebRequest) -> WebResponse;
 
 
+/// Turns a request into a response.
+///
+/// Implemented for page functions and closures taking a `&WebRequest`, and
+/// by `Router`.  If `handle` panics, the client is sent a 500 error.
+pub trait Handler: Send + Sync {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse;
+}
+
+impl<F> Handler for F where F: Fn(&WebRequest) -> WebResponse + Send + Sync {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        return (self)(req);
+    }
+}
+
+impl Handler for Box<dyn Handler> {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        return (**self).handle(req);
+    }
+}
+
+
 // All worker threads have read only access 
 struct WorkerSharedContext {
-    router: Router,
+    handler: Box<dyn Handler>,
     logger: Logger,
     max_request_body_size: usize,
+    max_head_size: usize,
+    body_spool: Option<usize>,
+    memory_budget: Option<Arc<MemoryBudget>>,
     listen_sock: TcpListener,
+    http2: bool,
+    stats: Arc<Stats>,
+    tracer: Tracer,
+    body_inspector: Option<Box<dyn BodyInspector>>,
+    conn_limit: Option<ConnLimit>,
+    buffers: BufferPool,
+    accept_watermarks: Option<(usize, usize)>,
+    overload: Overload,
+    // Where HTTP/2 requests run, with HTTP/2 on
+    h2_streams: Option<http2::StreamPool>,
 }
 
 // Private copy for each worker thread
@@ -152,6 +614,15 @@ struct WorkerPrivateContext {
     shared_ctx: Arc<WorkerSharedContext>,
 }
 
+// Facts about a connection, for logging
+pub(crate) struct ConnInfo {
+    peer_addr: SocketAddr,
+    // When the connection was accepted
+    start: Instant,
+    start_time: SystemTime,
+    timings: Timings,
+}
+
 
 /// Processes HTTP requests
 pub struct WebServer {
@@ -161,9 +632,34 @@ pub struct WebServer {
     nr_threads: i32,
     logging_enabled: bool,
     router: Option<Router>,
+    handler: Option<Box<dyn Handler>>,
+    middleware: Vec<Box<dyn Middleware>>,
+    access_log: Option<AccessLog>,
     thread_pool: ThreadPool,
     worker_shared_context: Option<Arc<WorkerSharedContext>>,
     max_request_body_size: usize,
+    max_head_size: usize,
+    read_buffer_size: usize,
+    body_spool: Option<usize>,
+    memory_budget: Option<usize>,
+    http2: bool,
+    metrics: Option<Metrics>,
+    slow_log: Option<SlowLog>,
+    error_log: Option<ErrorLog>,
+    status_page: Option<StatusPage>,
+    error_sink: Option<Box<dyn ErrorSink>>,
+    stats: Arc<Stats>,
+    tracer: Tracer,
+    body_inspector: Option<Box<dyn BodyInspector>>,
+    conn_limit: Option<ConnLimit>,
+    accept_watermarks: Option<(usize, usize)>,
+    overload: Overload,
+}
+
+impl Default for WebServer {
+    fn default() -> WebServer {
+        return WebServer::new();
+    }
 }
 
 impl WebServer {
@@ -174,7 +670,10 @@ impl WebServer {
     /// * MUDPIE_THREADS=N  Default is 10, use set_num_threads to override.
     ///
     /// * MUDPIE_LOGGING=[0|1]  Default is 1 (true), use set_logging to
-    /// override.  
+    ///   override.
+    ///
+    /// * MUDPIE_ACCESS_LOG=path  Append an access log in Combined Log Format
+    ///   to path.  Default is no access log, see set_access_log.
     pub fn new() -> WebServer {
         let nr_threads = match env::var("MUDPIE_THREADS") {
             Ok(val) => val.parse::<i32>().unwrap(),
@@ -184,13 +683,39 @@ impl WebServer {
             Ok(val) => val.parse::<i32>().unwrap() > 0,
             Err(..) => true,
         };
+        let access_log = match env::var("MUDPIE_ACCESS_LOG") {
+            Ok(path) => match AccessLog::open(&path, LogFormat::Combined) {
+                Ok(log) => Some(log),
+                Err(err) => panic!("can't open access log {}: {}", path, err),
+            },
+            Err(..) => None,
+        };
         let ret = WebServer{
                 nr_threads: nr_threads,
                 logging_enabled: logging_enabled,
                 router: Some(Router::new()),
+                handler: None,
+                middleware: Vec::new(),
+                access_log: access_log,
                 thread_pool: ThreadPool::new(),
                 worker_shared_context: None,
                 max_request_body_size: DEFAULT_MAX_REQUEST_BODY_SIZE,
+                max_head_size: DEFAULT_MAX_HEAD_SIZE,
+                read_buffer_size: read_request::CHUNK_SIZE,
+                body_spool: None,
+                memory_budget: None,
+                http2: false,
+                metrics: None,
+                slow_log: None,
+                error_log: None,
+                status_page: None,
+                error_sink: None,
+                stats: Arc::new(Stats::default()),
+                tracer: Tracer::default(),
+                body_inspector: None,
+                conn_limit: None,
+                accept_watermarks: None,
+                overload: Overload::Pause,
             };
         return ret;
     }
@@ -212,24 +737,183 @@ impl WebServer {
         self.max_request_body_size = size;
     }
 
+    /// Set the maximum size of a request's head: its request line and
+    /// headers.  Larger ones will generate a 431 error.  Default 64 KiB.
+    /// For HTTP/2, this is the largest header list taken.
+    pub fn set_max_head_size(&mut self, size: usize) {
+        self.max_head_size = size;
+    }
+
+    /// Keep request bodies larger than `size` in a temporary file instead
+    /// of memory, or not (the default), so large uploads don't take as
+    /// much memory.  The file is in the system's temporary directory, and
+    /// removed when it's closed.  Raise the maximum body size to take
+    /// larger bodies.
+    ///
+    /// Handlers get a spooled body with `WebRequest::body_reader`;
+    /// `get_body` still works, but reads it all into memory.  HTTP/2
+    /// bodies aren't spooled.
+    pub fn set_body_spool(&mut self, size: Option<usize>) {
+        self.body_spool = size;
+    }
+
+    /// Limit the memory the bodies of all requests being handled take at
+    /// once to `bytes`, so a burst of large uploads can't take it all.  A
+    /// request whose body doesn't fit in what's left is answered 503
+    /// Service Unavailable, before it's read; each body is limited on its
+    /// own by the maximum body size (413).  Default no limit.
+    ///
+    /// Only bodies are counted: heads are limited by the maximum head size
+    /// for each worker, and responses are made by handlers.  Bodies
+    /// spooled to files (`set_body_spool`) don't count.
+    pub fn set_memory_budget(&mut self, bytes: Option<usize>) {
+        self.memory_budget = bytes;
+    }
+
+    /// Set the size of the buffers requests are read with, and of each
+    /// read.  Default 4 KiB.  Each connection being read has two; a head
+    /// that doesn't fit grows its buffer, up to the maximum head size.
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        assert!(size > 0);
+        self.read_buffer_size = size;
+    }
+
+    /// Accept HTTP/2 on cleartext connections (h2c), from clients that
+    /// know the server does and from ones that ask to upgrade from
+    /// HTTP/1.1.  Default false.
+    ///
+    /// The requests on an HTTP/2 connection are handled at the same time,
+    /// up to 32 of them, on a pool of as many threads as there are workers
+    /// (`set_num_threads`), shared by all connections; the connection's
+    /// worker thread reads them.  Browsers only do HTTP/2 over TLS, so this
+    /// is for other clients, or a TLS proxy in front.
+    pub fn set_http2(&mut self, on: bool) {
+        self.http2 = on;
+    }
+
     /// Add an exact path match rule
     /// 
     /// methods: comma separated list of HTTP methods (GET, HEAD, PUT, etc.)
     ///
     /// path: The path component of a URL.  Must start with a '/', except for
     /// OPTIONS requests which can use '*'.
-    pub fn add_path(&mut self, methods: &str, path: &str, 
-            page_fn: PageFunction) {
-        self.router.as_mut().unwrap().add_path(
-                methods, path, page_fn, false);
+    pub fn add_path<H: Handler + 'static>(&mut self, methods: &str,
+            path: &str, page_fn: H) {
+        self.router.as_mut().unwrap().add_path(methods, path, page_fn);
     }
 
     /// Add a prefix path match rule.  Like `add_path`, but matches anything
     /// beginning with `path`.
-    pub fn add_path_prefix(&mut self, methods: &str, path: &str, 
-            page_fn: PageFunction) {
-        self.router.as_mut().unwrap().add_path(
-                methods, path, page_fn, true);
+    pub fn add_path_prefix<H: Handler + 'static>(&mut self, methods: &str,
+            path: &str, page_fn: H) {
+        self.router.as_mut().unwrap().add_path_prefix(methods, path, page_fn);
+    }
+
+    /// Route GET `/healthz` to a `Liveness` check and `/readyz` to the
+    /// returned `Health`, to set the server's readiness with
+    pub fn add_health_checks(&mut self) -> Health {
+        let health = Health::new(self.handle());
+        self.add_path("GET", "/healthz", Liveness);
+        self.add_path("GET", "/readyz", health.clone());
+        return health;
+    }
+
+    /// Route the ACME HTTP-01 challenges to the returned `AcmeChallenge`,
+    /// to put them in
+    pub fn add_acme_challenge(&mut self) -> AcmeChallenge {
+        let acme = AcmeChallenge::new();
+        self.add_path_prefix("GET", ACME_CHALLENGE_PATH, acme.clone());
+        return acme;
+    }
+
+    /// Use `handler` for all requests, instead of the rules added with
+    /// `add_path` and `add_path_prefix`.  Typically a `Router`.
+    pub fn set_handler<H: Handler + 'static>(&mut self, handler: H) {
+        self.handler = Some(Box::new(handler));
+    }
+
+    /// Run `middleware` around the handler for every request.  The first
+    /// middleware added is the outermost.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Write an access log line for every response.  This is separate from
+    /// the basic stdout logging (see `set_logging`).
+    pub fn set_access_log(&mut self, access_log: AccessLog) {
+        self.access_log = Some(access_log);
+    }
+
+    /// Log the requests that were slow, with where their time went
+    pub fn set_slow_log(&mut self, slow_log: SlowLog) {
+        self.slow_log = Some(slow_log);
+    }
+
+    /// Log the error responses, with their request and cause
+    pub fn set_error_log(&mut self, error_log: ErrorLog) {
+        self.error_log = Some(error_log);
+    }
+
+    /// Show the responses on `status_page`.  Route a clone of it to see
+    /// them.
+    pub fn set_status_page(&mut self, status_page: StatusPage) {
+        self.status_page = Some(status_page);
+    }
+
+    /// Count requests, connections, and the like in `metrics`.  Route a
+    /// clone of it to serve them.
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Report the server's errors to `sink`, instead of printing them
+    pub fn set_error_sink<T: ErrorSink + 'static>(&mut self, sink: T) {
+        self.error_sink = Some(Box::new(sink));
+    }
+
+    /// Show the body of each request to `inspector` as it's read, before
+    /// the handler runs; see `BodyInspector`
+    pub fn set_body_inspector<T: BodyInspector + 'static>(&mut self,
+            inspector: T) {
+        self.body_inspector = Some(Box::new(inspector));
+    }
+
+    /// Limit the connections each client has open; see `ConnLimit`
+    pub fn set_conn_limit(&mut self, limit: ConnLimit) {
+        self.conn_limit = Some(limit);
+    }
+
+    /// Stop accepting connections while `high` requests or more are in
+    /// flight, until they're down to `low`, letting the system's backlog
+    /// (and clients retrying) hold the load meanwhile.  See
+    /// `ServerStats::accept_paused`, and `set_overload_policy` to turn
+    /// connections away instead.
+    ///
+    /// HTTP/1 connections have a request each, so there are no more in
+    /// flight than workers; this is for HTTP/2 ones, with up to 32 each.
+    /// Memory use isn't watched, as std has no way to tell it.
+    pub fn set_accept_watermarks(&mut self, high: usize, low: usize) {
+        assert!(low < high);
+        self.accept_watermarks = Some((high, low));
+    }
+
+    /// What to do past the high watermark of `set_accept_watermarks`:
+    /// stop accepting (`Overload::Pause`, the default), which keeps
+    /// clients waiting, or answer 503 at once (`Overload::Reject`), for
+    /// clients to retry elsewhere.
+    pub fn set_overload_policy(&mut self, policy: Overload) {
+        self.overload = policy;
+    }
+
+    /// Tell `hook` about every connection and request as it goes along;
+    /// see `TraceHook`.  Hooks are called in the order they were added.
+    pub fn add_trace_hook<T: TraceHook + 'static>(&mut self, hook: T) {
+        self.tracer.add(Box::new(hook));
+    }
+
+    /// A handle for the server's stats, that stays usable while it runs
+    pub fn handle(&self) -> ServerHandle {
+        return ServerHandle::new(self.stats.clone());
     }
 
     /// Starts worker threads and enters supervisor loop.  If any worker
@@ -245,14 +929,48 @@ impl WebServer {
         };
         
         let router_moved = self.router.take().unwrap();
+        let mut handler = match self.handler.take() {
+            Some(handler) => handler,
+            None => Box::new(router_moved),
+        };
+        if !self.middleware.is_empty() {
+            let mut chain = Chain::new(handler);
+            for m in self.middleware.drain(..) {
+                chain.add(m);
+            }
+            handler = Box::new(chain);
+        }
 
         // Create a read-only context all worker threads can use
         let ctx = WorkerSharedContext {
-            router: router_moved,
-            logger: Logger::new(self.logging_enabled),
+            handler: handler,
+            logger: Logger::new(self.logging_enabled, self.access_log.take(),
+                    self.metrics.take(), self.slow_log.take(),
+                    self.error_log.take(), self.status_page.take(),
+                    self.error_sink.take()),
             max_request_body_size: self.max_request_body_size,
+            max_head_size: self.max_head_size,
+            body_spool: self.body_spool,
+            memory_budget: self.memory_budget
+                .map(|bytes| Arc::new(MemoryBudget::new(bytes))),
             listen_sock: listener,
+            http2: self.http2,
+            stats: self.stats.clone(),
+            tracer: mem::take(&mut self.tracer),
+            body_inspector: self.body_inspector.take(),
+            conn_limit: self.conn_limit.take(),
+            // Two for each connection being read
+            buffers: BufferPool::new(self.read_buffer_size,
+                    2 * self.nr_threads.max(1) as usize),
+            accept_watermarks: self.accept_watermarks,
+            overload: self.overload,
+            h2_streams: match self.http2 {
+                true => Some(http2::stream_pool(self.nr_threads.max(1)
+                        as usize)),
+                false => None,
+            },
         };
+        *self.stats.started.lock().unwrap() = Some(Instant::now());
 
         // We hold a reference too, in case threads die and need restart
         self.worker_shared_context = Some(Arc::new(ctx));
@@ -284,12 +1002,22 @@ impl WebServer {
 
 
 fn worker_thread_main(ctx: WorkerPrivateContext) {
+    let stats = &ctx.shared_ctx.stats;
+    let _worker = Open::new(&stats.workers);
     loop {
+        match ctx.shared_ctx.accept_watermarks {
+            Some((high, low)) if ctx.shared_ctx.overload == Overload::Pause =>
+                stats.wait_to_accept(high, low),
+            _ => (),
+        }
         let res = ctx.shared_ctx.listen_sock.accept();
         match res {
-            Ok((sock, peeraddr)) => 
-                process_http_connection(&ctx, sock, peeraddr),
-            Err(err) => ctx.shared_ctx.logger.log_accept_error(err),
+            Ok((sock, peeraddr)) => {
+                let _busy = Open::new(&stats.busy_workers);
+                process_http_connection(&ctx, sock, peeraddr);
+            },
+            Err(err) => ctx.shared_ctx.logger.log_error(
+                    ServerError::Accept(&err), None, None),
         }
     }
 }
@@ -299,82 +1027,197 @@ fn worker_thread_main(ctx: WorkerPrivateContext) {
 fn process_http_connection(ctx: &WorkerPrivateContext, 
         raw_stream: TcpStream, peer_addr: SocketAddr) {
 
-    // FIXME: not possible in 1.2 and deprecated in nightly
-    // Set nodelay.  We write headers then body,
-    // and don't want to stall.
-    //raw_stream.set_nodelay(true).unwrap();
+    // Set nodelay.  A streamed body is written a piece at a time, as its
+    // reader returns them, and each should go out then, not wait for the
+    // ACK of the last (ex: events).  Best effort.
+    let _ = raw_stream.set_nodelay(true);
 
     let log: &Logger = &ctx.shared_ctx.logger;
+    let _open = log.metrics().map(|m| m.track_connection());
+    let _conn = Open::new(&ctx.shared_ctx.stats.connections);
+    let tracer = &ctx.shared_ctx.tracer;
+    let _closing = tracer.connection(peer_addr);
+    let waiting = Open::new(&ctx.shared_ctx.stats.idle_connections);
+    let mut conn = ConnInfo {
+        peer_addr: peer_addr,
+        start: Instant::now(),
+        start_time: SystemTime::now(),
+        timings: Timings::default(),
+    };
+
 
+    // HTTP/2 sends while reading, and needs a second handle for that
+    let mut h2_writer = match ctx.shared_ctx.http2 {
+        true => raw_stream.try_clone().ok(),
+        false => None,
+    };
 
     // Now is where we could also wrap it with SSL.
-    let mut stream: Box<GenericSocket> = Box::new(raw_stream);
+    let socket = Arc::new(raw_stream);
+    let mut stream: Box<dyn GenericSocket> =
+        Box::new(SharedStream(socket.clone()));
+
+    // Refuse clients with too many connections, before reading anything
+    let _slot = match ctx.shared_ctx.conn_limit {
+        Some(ref limit) => match limit.open(peer_addr.ip()) {
+            Ok(slot) => slot,
+            Err(()) => {
+                let mut resp = WebResponse::new();
+                resp.set_code(429, "Too Many Requests");
+                resp.set_body_str("Error 429: Too Many Connections");
+                resp.cause = Some(format!("Over the limit of connections \
+                        from {}", peer_addr.ip()));
+                write_response(&mut *stream, None, &mut resp, log, &conn);
+                return;
+            },
+        },
+        None => None,
+    };
+
+    // Or any, past the watermark
+    if let Some((high, low)) = ctx.shared_ctx.accept_watermarks {
+        if ctx.shared_ctx.overload == Overload::Reject
+                && ctx.shared_ctx.stats.reject_new(high, low) {
+            let mut resp = WebResponse::new();
+            resp.set_code(503, "Service Unavailable");
+            resp.set_header("Retry-After", "1");
+            resp.set_body_str("Error 503: Service Unavailable");
+            resp.cause = Some("Over the watermark of requests in flight"
+                    .to_string());
+            write_response(&mut *stream, None, &mut resp, log, &conn);
+            return;
+        }
+    }
+
+    // HTTP/2 with prior knowledge starts with its preface, not a request
+    if h2_writer.is_some() {
+        let (is_http2, start) = match http2::read_preface(&mut *stream) {
+            Ok(ret) => ret,
+            Err(e) => {
+                log.log_error(read_error(&e), Some(peer_addr), None);
+                return;
+            }
+        };
+        if is_http2 {
+            // It counts its idle times itself
+            drop(waiting);
+            let writer = h2_writer.take().unwrap();
+            http2::serve(ctx.shared_ctx.clone(),
+                    Box::new(Upgraded::new(stream, start)), Box::new(writer),
+                    peer_addr, None);
+            return;
+        }
+        // Put back what was read
+        stream = Box::new(Upgraded::new(stream, start));
+    }
 
 
     // Read full request (headers and body)
-    let mut req = match read_request::read_request(&mut *stream,
-            ctx.shared_ctx.max_request_body_size) {
+    let (mut req, extra) = match read_request::read_request(&mut *stream,
+            ctx.shared_ctx.max_request_body_size,
+            ctx.shared_ctx.max_head_size,
+            ctx.shared_ctx.body_spool,
+            ctx.shared_ctx.memory_budget.as_ref(),
+            ctx.shared_ctx.body_inspector.as_deref(),
+            &ctx.shared_ctx.buffers) {
         Err(read_request::Error::InvalidRequest) => {
             let mut resp = WebResponse::new();
             resp.set_code(400, "Bad Request");
             resp.set_body_str("Error 400: Bad Request");
-            write_response(&mut *stream, None, &resp, log);
+            let error = ServerError::BadRequest(400);
+            resp.cause = Some(error.to_string());
+            log.log_error(error, Some(peer_addr), None);
+            write_response(&mut *stream, None, &mut resp, log, &conn);
             return;
         },
         Err(read_request::Error::LengthRequired) => {
             let mut resp = WebResponse::new();
             resp.set_code(411, "Length Required");
             resp.set_body_str("Error 411: Length Required");
-            write_response(&mut *stream, None, &resp, log);
+            let error = ServerError::BadRequest(411);
+            resp.cause = Some(error.to_string());
+            log.log_error(error, Some(peer_addr), None);
+            write_response(&mut *stream, None, &mut resp, log, &conn);
             return;
         },
         Err(read_request::Error::InvalidVersion) => {
             let mut resp = WebResponse::new();
             resp.set_code(505, "Version not Supported");
             resp.set_body_str("Error 505: Version not Supported");
-            write_response(&mut *stream, None, &resp, log);
+            let error = ServerError::BadRequest(505);
+            resp.cause = Some(error.to_string());
+            log.log_error(error, Some(peer_addr), None);
+            write_response(&mut *stream, None, &mut resp, log, &conn);
             return;
         },
         Err(read_request::Error::TooLarge) => {
             let mut resp = WebResponse::new();
             resp.set_code(413, "Request Entity Too Large");
             resp.set_body_str("Error 413: Request Entity Too Large");
-            write_response(&mut *stream, None, &resp, log);
+            let error = ServerError::BadRequest(413);
+            resp.cause = Some(error.to_string());
+            log.log_error(error, Some(peer_addr), None);
+            write_response(&mut *stream, None, &mut resp, log, &conn);
+            return;
+        },
+        Err(read_request::Error::HeadTooLarge) => {
+            let mut resp = WebResponse::new();
+            resp.set_code(431, "Request Header Fields Too Large");
+            resp.set_body_str("Error 431: Request Header Fields Too Large");
+            let error = ServerError::BadRequest(431);
+            resp.cause = Some(error.to_string());
+            log.log_error(error, Some(peer_addr), None);
+            write_response(&mut *stream, None, &mut resp, log, &conn);
+            return;
+        },
+        Err(read_request::Error::OverBudget) => {
+            let mut resp = WebResponse::new();
+            resp.set_code(503, "Service Unavailable");
+            resp.set_header("Retry-After", "1");
+            resp.set_body_str("Error 503: Service Unavailable");
+            resp.cause = Some("Over the memory budget for request bodies"
+                    .to_string());
+            write_response(&mut *stream, None, &mut resp, log, &conn);
+            return;
+        },
+        Err(read_request::Error::Rejected(code, status)) => {
+            let mut resp = WebResponse::new();
+            resp.set_code(code, &status);
+            resp.set_body_str(&format!("Error {}: {}", code, status));
+            let error = ServerError::Rejected(code);
+            resp.cause = Some(error.to_string());
+            log.log_error(error, Some(peer_addr), None);
+            write_response(&mut *stream, None, &mut resp, log, &conn);
             return;
         },
         Err(read_request::Error::IoError(e)) => {
-            log.log_read_request_error(e);
+            log.log_error(read_error(&e), Some(peer_addr), None);
             return;
         },
-        Ok(req) => req,
+        Ok(ret) => ret,
     };
 
+    drop(waiting);
+    conn.timings.read = Some(Instant::now());
+    tracer.emit(TraceEvent::HeadParsed, peer_addr, Some(&req), None);
+
     // Add socket specific attributes 
     let val = format!("{}", peer_addr);
     req.environ.insert(b"remote_address".to_vec(), val.as_bytes().to_vec());
 
-    // Do routing
-    let ret = ctx.shared_ctx.router.route(&req);
-    let page_fn = match ret {
-        RoutingResult::FoundRule(page_fn) => page_fn,
-        RoutingResult::NoPathMatch => {
-            let mut resp = WebResponse::new();
-            resp.set_code(404, "Not Found");
-            resp.set_body_str("Error 404: Resource not found");
-            write_response(&mut *stream, Some(&req), &resp, log);
-            return;
-        }
-        RoutingResult::NoMethodMatch(methods) => {
-            let mut resp = WebResponse::new();
-            resp.set_code(405, "Method not allowed");
-            resp.set_body_str("Error 405: Method not allowed");
-            let methods_joined = methods.connect(", ");
-            resp.set_header("Allow", &methods_joined);
-            write_response(&mut *stream, Some(&req), &resp, log);
+    // An upgrade to HTTP/2, where the request gets its response
+    if let Some(writer) = h2_writer {
+        if let Some(settings) = http2::upgrade_settings(&req) {
+            let head = b"HTTP/1.1 101 Switching Protocols\r\n\
+                Connection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+            if stream.write_all(head).is_ok() {
+                http2::serve(ctx.shared_ctx.clone(),
+                        Box::new(Upgraded::new(stream, extra)),
+                        Box::new(writer), peer_addr, Some((req, settings)));
+            }
             return;
         }
-    };
-
+    }
 
     // Run the handler.  If it panics, the sentinel will send a 500.
     // We clone the shared_ctx Arc just so we have access to the logging object
@@ -385,21 +1228,61 @@ fn process_http_connection(ctx: &WorkerPrivateContext,
         stream: stream, 
         shared_ctx: ctx.shared_ctx.clone(),
         request: req,
-        armed: true 
+        conn: conn,
+        armed: true,
+        cause: None,
+    };
+    let in_flight = (log.metrics().map(|m| m.track_request()),
+            Open::new(&ctx.shared_ctx.stats.requests));
+    tracer.emit(TraceEvent::HandlerStart, peer_addr, Some(&sentinel.request),
+            None);
+    sentinel.conn.timings.handler_start = Some(Instant::now());
+    let handler = &ctx.shared_ctx.handler;
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+        return handler.handle(&mut sentinel.request);
+    }));
+    let mut response = match handled {
+        Ok(response) => response,
+        Err(payload) => {
+            let error = ServerError::Panic(panic_message(&*payload));
+            sentinel.cause = Some(error.to_string());
+            log.log_error(error, Some(peer_addr), Some(&sentinel.request));
+            // The sentinel sends the 500, and the worker is replaced
+            panic::resume_unwind(payload);
+        },
     };
-    let response = (page_fn)(&sentinel.request);
     sentinel.armed = false;
+    sentinel.conn.timings.handler_end = Some(Instant::now());
+    tracer.emit(TraceEvent::HandlerEnd, peer_addr, Some(&sentinel.request),
+            Some(&response));
     write_response(&mut *sentinel.stream, Some(&sentinel.request),
-            &response, log);
+            &mut response, log, &sentinel.conn);
+    tracer.emit(TraceEvent::ResponseFlushed, peer_addr,
+            Some(&sentinel.request), Some(&response));
+    drop(in_flight);
+    ctx.shared_ctx.stats.record(&sentinel.request,
+            sentinel.conn.start.elapsed());
+
+    // Switching protocols: the connection is the upgrade's now
+    if response.code == 101 {
+        if let Some(upgrade) = response.upgrade.take() {
+            let stream = mem::replace(&mut sentinel.stream,
+                    Box::new(io::Cursor::new(Vec::new())));
+            upgrade(Upgraded::new(stream, extra).with_socket(socket));
+        }
+    }
 }
 
 
 // A sentinel that sends a 500 error unless armed=false
 struct HTTPConnectionSentinel {
-    stream: Box<GenericSocket>,
+    stream: Box<dyn GenericSocket>,
     shared_ctx: Arc<WorkerSharedContext>,
     armed: bool,
     request: WebRequest,
+    conn: ConnInfo,
+    // For the 500
+    cause: Option<String>,
 }
 
 impl Drop for HTTPConnectionSentinel {
@@ -409,10 +1292,12 @@ impl Drop for HTTPConnectionSentinel {
             let mut resp = WebResponse::new();
             resp.set_code(500, "Uh oh :-(");
             resp.set_body_str("Error 500: Internal error in handler function");
+            resp.cause = self.cause.take();
             write_response(&mut *self.stream, 
                 Some(&self.request), 
-                &resp,
-                &self.shared_ctx.logger);
+                &mut resp,
+                &self.shared_ctx.logger,
+                &self.conn);
         }
     }
 }
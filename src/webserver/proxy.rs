@@ -0,0 +1,1218 @@
+//! Forwarding requests to another HTTP server
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Handler, WebRequest, WebResponse, Cidr, Upgraded, LogExtras};
+use super::ip_filter::parse_or_panic;
+use utils::chunked::ChunkedReader;
+use utils::http_response::{read_response_head, BodyLength};
+
+// Idle upstream connections older than this aren't reused; the upstream
+// has likely closed them
+static IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Headers for one connection only, not passed on (RFC 7230 6.1).  Headers
+// named in Connection are dropped too.
+static HOP_BY_HOP: [&str; 9] = ["connection", "keep-alive", "proxy-connection",
+    "proxy-authenticate", "proxy-authorization", "te", "trailer",
+    "transfer-encoding", "upgrade"];
+
+// Requests that can be sent again (RFC 7231 4.2.2)
+static IDEMPOTENT: [&str; 6] = ["get", "head", "put", "delete", "options",
+    "trace"];
+
+// How many retries the budget can save up
+static RETRY_BURST: f64 = 10.0;
+
+// Set by the proxy; what the client sent is only kept from trusted proxies
+static FORWARDING: [&str; 4] = ["forwarded", "x-forwarded-for",
+    "x-forwarded-host", "x-forwarded-proto"];
+
+
+/// A handler that sends requests on to another HTTP server, the upstream,
+/// and its responses back to the client.
+///
+/// The request goes to the upstream with the same method, path and query
+/// string; mounted with `Router::mount`, the mount prefix is removed from
+/// the path.  Hop-by-hop headers (Connection, Transfer-Encoding, ...) are
+/// left out both ways.  The Host header is the upstream's name, or the
+/// client's with `set_preserve_host`.
+///
+/// The upstream is told about the client with X-Forwarded-For,
+/// X-Forwarded-Host, X-Forwarded-Proto, and Forwarded (RFC 7239) headers.
+/// If the client is a proxy added with `add_trusted_proxy`, what it sent
+/// in those is passed on too, with the client added to the lists.  From
+/// anyone else, they're dropped first, since they'd be made up.
+///
+/// The request body is sent as read by the server; the response body is
+/// streamed to the client as the upstream sends it.  If the upstream
+/// can't be reached or sends something that isn't HTTP, the client gets a
+/// 502 (Bad Gateway), or a 504 (Gateway Timeout) if it doesn't answer in
+/// time.
+///
+/// With several upstreams (see `add_upstream`), each request goes to one
+/// picked as `set_balance` says.  Connections to upstreams are kept open
+/// after a response and reused, and upstreams can be limited in how many
+/// requests they get at once (`set_max_connections`); if they're all
+/// busy, the client gets a 503 (Service Unavailable).
+///
+/// Upstreams that are down are skipped, as found by health checks
+/// (`set_health_check`) or by requests failing (`set_passive_check`).  If
+/// they're all down, they're all tried anyway.  Requests that failed can
+/// be tried again on another upstream (`set_retries`).
+///
+/// Requests to upgrade the connection, ex: to a websocket, are passed on
+/// with their Upgrade header.  If the upstream switches protocols, so
+/// does the client, and what either sends is passed on to the other
+/// until one of them closes its connection.
+///
+/// For the access log (see `LogField::Extra`), the request's `LogExtras`
+/// get "upstream_time_us", the microseconds the last try took until the
+/// upstream's response head came, and "upstream_retries", how many times
+/// it was retried (the X-Retry-Count sent with the last try, or 0).
+///
+/// ex:
+///
+/// ```ignore
+/// let mut app = ReverseProxy::new("10.0.0.1:8080");
+/// app.add_upstream("10.0.0.2:8080");
+/// app.set_balance(Balance::LeastConnections);
+/// let mut router = Router::new();
+/// router.mount("/app", app);
+/// ```
+pub struct ReverseProxy {
+    upstreams: Vec<Arc<Upstream>>,
+    balance: Balance,
+    // Where round-robin goes next
+    next: AtomicUsize,
+    max_connections: usize,
+    max_idle: usize,
+    health_check: Option<Arc<HealthCheck>>,
+    // Failures in a row, and how long to skip the upstream after
+    max_failures: usize,
+    recovery: Duration,
+    preserve_host: bool,
+    timeout: Duration,
+    trusted_proxies: Vec<Cidr>,
+    retries: usize,
+    // Retries left, and what each request adds
+    retry_budget: Mutex<f64>,
+    retry_ratio: f64,
+}
+
+/// How `ReverseProxy` picks the upstream for a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Balance {
+    /// Each in turn (the default)
+    RoundRobin,
+    /// The one with the fewest requests in progress
+    LeastConnections,
+    /// By a hash of the client's IP address, so each client sticks to one
+    /// upstream, as long as the upstreams stay the same
+    IpHash,
+}
+
+impl ReverseProxy {
+    /// Forward to `upstream`, ex: "127.0.0.1:8080" or "backend:80"
+    pub fn new(upstream: &str) -> ReverseProxy {
+        let mut proxy = ReverseProxy {
+            upstreams: Vec::new(),
+            balance: Balance::RoundRobin,
+            next: AtomicUsize::new(0),
+            max_connections: usize::MAX,
+            max_idle: 8,
+            health_check: None,
+            max_failures: 0,
+            recovery: Duration::from_secs(0),
+            preserve_host: false,
+            timeout: Duration::from_secs(60),
+            trusted_proxies: Vec::new(),
+            retries: 0,
+            retry_budget: Mutex::new(RETRY_BURST),
+            retry_ratio: 0.2,
+        };
+        proxy.add_upstream(upstream);
+        return proxy;
+    }
+
+    /// Share the requests with another upstream
+    pub fn add_upstream(&mut self, upstream: &str) {
+        let upstream = Arc::new(Upstream {
+            addr: upstream.to_string(),
+            active: AtomicUsize::new(0),
+            idle: Mutex::new(Vec::new()),
+            check: Mutex::new(self.health_check.clone()),
+            checked: AtomicBool::new(false),
+            healthy: AtomicBool::new(true),
+            failures: AtomicUsize::new(0),
+            down_until: Mutex::new(None),
+        });
+        if self.health_check.is_some() {
+            watch(&upstream);
+        }
+        self.upstreams.push(upstream);
+    }
+
+    /// Check each upstream every `interval`, in a thread, by sending a
+    /// `method` request (ex: "HEAD") for `path`.  Unless the answer has
+    /// status code `expect` within `interval`, the upstream is down until
+    /// a check succeeds.
+    pub fn set_health_check(&mut self, method: &str, path: &str,
+            expect: i32, interval: Duration) {
+        let check = Arc::new(HealthCheck {
+            method: method.to_ascii_uppercase(),
+            path: path.to_string(),
+            expect: expect,
+            interval: interval,
+        });
+        for upstream in self.upstreams.iter() {
+            *upstream.check.lock().unwrap() = Some(check.clone());
+            watch(upstream);
+        }
+        self.health_check = Some(check);
+    }
+
+    /// Take an upstream as down for `recovery`, after `failures` requests
+    /// in a row couldn't get a response from it.  Responses that are
+    /// errors don't count.  After that, it's down again on the next
+    /// failure, until a request succeeds.  Default off.
+    pub fn set_passive_check(&mut self, failures: usize, recovery: Duration) {
+        self.max_failures = failures;
+        self.recovery = recovery;
+    }
+
+    /// How to pick the upstream for each request.  Default round-robin.
+    pub fn set_balance(&mut self, balance: Balance) {
+        self.balance = balance;
+    }
+
+    /// The most requests each upstream gets at once.  Default unlimited.
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.max_connections = max;
+    }
+
+    /// The most idle connections kept open to each upstream, for reuse.
+    /// Default 8; 0 opens a connection for every request.
+    pub fn set_max_idle(&mut self, max: usize) {
+        self.max_idle = max;
+    }
+
+    /// Send the client's Host header to the upstream, instead of the
+    /// upstream's own name.  Default false.
+    pub fn set_preserve_host(&mut self, on: bool) {
+        self.preserve_host = on;
+    }
+
+    /// How long to wait to connect, and for each read or write, before
+    /// giving up with a 504.  Default 60 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Try idempotent requests (GET, HEAD, PUT, DELETE, OPTIONS, TRACE)
+    /// again with another upstream, up to `retries` times, when the
+    /// upstream can't be reached or the response is a 502 or a 504.  The
+    /// upstream can tell a retry by its X-Retry-Count header.  Default 0.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Limit retries to `percent` of the requests, so an outage doesn't
+    /// get the upstreams even more requests.  There's a little room
+    /// besides, for the first few.  Default 20.
+    pub fn set_retry_budget(&mut self, percent: u32) {
+        self.retry_ratio = percent as f64 / 100.0;
+    }
+
+    /// Pass on the forwarding headers from clients in this network.
+    /// Panics if `cidr` is invalid.
+    pub fn add_trusted_proxy(&mut self, cidr: &str) {
+        self.trusted_proxies.push(parse_or_panic(cidr));
+    }
+
+    // X-Forwarded-* and Forwarded, for the upstream
+    fn forwarding_headers(&self, req: &WebRequest) -> Vec<(String, String)> {
+        let ip = req.get_remote_addr().map(|addr| addr.ip());
+        let trusted = ip.is_some_and(|ip| {
+            self.trusted_proxies.iter().any(|c| c.contains(ip))
+        });
+        let incoming = |name: &str| match req.get_header(name) {
+            Some(value) if trusted && !value.trim().is_empty() =>
+                Some(value.trim()),
+            _ => None,
+        };
+        // Over plain TCP; a proxy in front may have had TLS.  The same in
+        // both headers, so they agree.
+        let proto = incoming("X-Forwarded-Proto").unwrap_or("http");
+        let host = incoming("X-Forwarded-Host").or(req.get_header("Host"));
+
+        let mut headers = Vec::new();
+        if let Some(ip) = ip {
+            let list = match incoming("X-Forwarded-For") {
+                Some(list) => format!("{}, {}", list, ip),
+                None => ip.to_string(),
+            };
+            headers.push(("X-Forwarded-For".to_string(), list));
+        }
+        if let Some(host) = host {
+            headers.push(("X-Forwarded-Host".to_string(), host.to_string()));
+        }
+        headers.push(("X-Forwarded-Proto".to_string(), proto.to_string()));
+
+        let mut element = match ip {
+            Some(IpAddr::V6(ip)) => format!("for=\"[{}]\"", ip),
+            Some(ip) => format!("for={}", ip),
+            None => "for=unknown".to_string(),
+        };
+        if let Some(host) = req.get_header("Host") {
+            element.push_str(&format!(";host={}", forwarded_value(host)));
+        }
+        element.push_str(&format!(";proto={}", forwarded_value(proto)));
+        let list = match incoming("Forwarded") {
+            Some(list) => format!("{}, {}", list, element),
+            None => element,
+        };
+        headers.push(("Forwarded".to_string(), list));
+        return headers;
+    }
+
+    // An upstream that can take another request, and isn't in `tried`
+    fn pick(&self, req: &WebRequest, tried: &[Arc<Upstream>])
+            -> Option<Lease> {
+        let n = self.upstreams.len();
+        let start = match self.balance {
+            Balance::IpHash => {
+                let mut hasher = DefaultHasher::new();
+                req.get_remote_addr().map(|addr| addr.ip()).hash(&mut hasher);
+                hasher.finish() as usize
+            },
+            // Retries don't move round-robin on again
+            _ if !tried.is_empty() => self.next.load(Ordering::Relaxed),
+            _ => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        let mut order: Vec<&Arc<Upstream>> = (0..n)
+            .map(|i| &self.upstreams[(start + i) % n])
+            .filter(|u| !tried.iter().any(|t| Arc::ptr_eq(t, u)))
+            .collect();
+        if order.iter().any(|u| u.is_up()) {
+            order.retain(|u| u.is_up());
+        }
+        if self.balance == Balance::LeastConnections {
+            // Stable, so ties go round-robin
+            order.sort_by_key(|u| u.active.load(Ordering::Relaxed));
+        }
+        for upstream in order {
+            let max = self.max_connections;
+            let taken = upstream.active.fetch_update(Ordering::SeqCst,
+                    Ordering::SeqCst, |n| if n < max { Some(n + 1) } else {
+                        None
+                    });
+            if taken.is_ok() {
+                return Some(Lease {
+                    upstream: upstream.clone(),
+                    max_idle: self.max_idle,
+                });
+            }
+        }
+        return None;
+    }
+
+    // Send the request with `lease`, noting its upstream in `tried`.
+    // Failures are a 502 or a 504.
+    fn forward(&self, req: &WebRequest, lease: Lease,
+            tried: &mut Vec<Arc<Upstream>>) -> WebResponse {
+        let upstream = lease.upstream.clone();
+        tried.push(upstream.clone());
+        let ret = self.forward_to(req, lease, tried.len() - 1);
+        match ret {
+            Ok(_) => upstream.failures.store(0, Ordering::SeqCst),
+            Err(_) => {
+                let failures = upstream.failures
+                    .fetch_add(1, Ordering::SeqCst) + 1;
+                // Kept until a success, so one more failure after the
+                // recovery is enough
+                if self.max_failures > 0 && failures >= self.max_failures {
+                    *upstream.down_until.lock().unwrap() =
+                        Some(Instant::now() + self.recovery);
+                }
+            },
+        }
+        match ret {
+            Ok(resp) => return resp,
+            Err(err) => {
+                let mut resp = match err.kind() {
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock =>
+                        error_response(504, "Gateway Timeout"),
+                    _ => error_response(502, "Bad Gateway"),
+                };
+                resp.cause = Some(format!("Upstream {}: {}", upstream.addr,
+                        err));
+                return resp;
+            },
+        }
+    }
+
+    // Whether to try again after `resp`, with another upstream
+    fn should_retry(&self, req: &WebRequest, resp: &WebResponse,
+            tries: usize) -> bool {
+        let code = resp.get_code();
+        return tries <= self.retries && (code == 502 || code == 504)
+            && IDEMPOTENT.contains(&&req.method[..])
+            && upgrade_protocol(req).is_none();
+    }
+
+    // Take a retry from the budget, if there's one left
+    fn take_retry(&self) -> bool {
+        let mut budget = self.retry_budget.lock().unwrap();
+        if *budget < 1.0 {
+            return false;
+        }
+        *budget -= 1.0;
+        return true;
+    }
+
+    fn forward_to(&self, req: &WebRequest, lease: Lease, retries: usize)
+            -> io::Result<WebResponse> {
+
+        let host = match req.get_header("Host") {
+            Some(host) if self.preserve_host => host,
+            _ => &lease.upstream.addr,
+        };
+        let mut head = request_line(req);
+        head.extend_from_slice(format!("Host: {}\r\n", host).as_bytes());
+        for (name, value) in request_headers(req) {
+            head.extend_from_slice(name);
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+        for (name, value) in self.forwarding_headers(req) {
+            head.extend_from_slice(format!("{}: {}\r\n", name, value)
+                    .as_bytes());
+        }
+        if retries > 0 {
+            head.extend_from_slice(format!("X-Retry-Count: {}\r\n",
+                    retries).as_bytes());
+        }
+        let upgrade = upgrade_protocol(req);
+        if let Some(protocol) = upgrade {
+            head.extend_from_slice(format!("Connection: Upgrade\r\n\
+                    Upgrade: {}\r\n", protocol).as_bytes());
+        }
+        let has_length = req.environ.contains_key(&b"http_content-length"[..]);
+        if has_length || req.body_len() > 0 {
+            head.extend_from_slice(format!("Content-Length: {}\r\n",
+                    req.body_len()).as_bytes());
+        }
+        head.extend_from_slice(b"\r\n");
+
+        let mut reader = loop {
+            let (stream, reused) = match lease.upstream.reuse() {
+                Some(stream) => (stream, true),
+                None => (connect(&lease.upstream.addr, self.timeout)?, false),
+            };
+            let mut body = req.body_reader()?;
+            match send_request(stream, self.timeout, &head, &mut body) {
+                Ok(reader) => break reader,
+                // The upstream closed the idle connection; try another.
+                // Not if it's just slow, since it may be at work on it.
+                Err(ref err) if reused && err.kind() != io::ErrorKind::TimedOut
+                        && err.kind() != io::ErrorKind::WouldBlock => (),
+                Err(err) => return Err(err),
+            }
+        };
+        let head = loop {
+            let head = read_response_head(&mut reader)?;
+            // Interim responses, ex: 100 Continue, are the upstream's and
+            // ours to drop
+            if head.code >= 200 || (head.code == 101 && upgrade.is_some()) {
+                break head;
+            }
+            if head.code == 101 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        "upstream switched protocols unasked"));
+            }
+        };
+        let headers = &head.headers;
+
+        let mut resp = WebResponse::new();
+        resp.set_code(head.code, &head.status);
+        let dropped = connection_tokens(headers.iter()
+            .filter(|h| h.0.eq_ignore_ascii_case("connection"))
+            .map(|h| &h.1[..]));
+        for (name, value) in headers.iter() {
+            let lower = name.to_ascii_lowercase();
+            if HOP_BY_HOP.contains(&&lower[..]) || dropped.contains(&lower) {
+                continue;
+            }
+            resp.add_header(name, value);
+        }
+        let reuse = head.http11 && !dropped.iter().any(|t| t == "close");
+
+        if head.code == 101 {
+            let protocol = head.get_all("Upgrade").join(", ");
+            resp.set_header("Connection", "Upgrade");
+            resp.set_header("Upgrade", &protocol);
+            resp.set_upgrade(move |client: Upgraded| {
+                // The upstream's connection until it closes
+                let _lease = lease;
+                splice(client, reader);
+            });
+            return Ok(resp);
+        }
+
+        // No body; a Content-Length is the one a GET would get
+        let no_body = req.method == "head" || head.code == 204
+            || head.code == 304;
+        if !no_body {
+            resp.remove_header("Content-Length");
+        }
+        let length = match head.body_length(no_body)? {
+            BodyLength::Chunked => {
+                resp.set_body_reader(UpstreamBody {
+                    framing: Some(Framing::Chunked(ChunkedReader::new(reader))),
+                    lease: lease,
+                    reuse: reuse,
+                }, None);
+                return Ok(resp);
+            },
+            BodyLength::Length(len) => Some(len),
+            // The upstream closes at the end
+            BodyLength::Close => None,
+        };
+
+        match length {
+            Some(0) => {
+                if resp.get_header("Content-Length").is_some() {
+                    // For HEAD: don't let it be replaced with 0
+                    resp.set_body_reader(io::empty(), None);
+                } else {
+                    resp.set_body(b"");
+                }
+                if reuse {
+                    lease.put_back(reader);
+                }
+            },
+            Some(len) => resp.set_body_reader(UpstreamBody {
+                framing: Some(Framing::Length(reader, len)),
+                lease: lease,
+                reuse: reuse,
+            }, Some(len)),
+            None => resp.set_body_reader(UpstreamBody {
+                framing: Some(Framing::Close(reader)),
+                lease: lease,
+                reuse: false,
+            }, None),
+        }
+        return Ok(resp);
+    }
+}
+
+impl Handler for ReverseProxy {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        let mut tried = Vec::new();
+        let lease = match self.pick(req, &tried) {
+            Some(lease) => lease,
+            None => return error_response(503, "Service Unavailable"),
+        };
+        {
+            let mut budget = self.retry_budget.lock().unwrap();
+            *budget = (*budget + self.retry_ratio).min(RETRY_BURST);
+        }
+        let start = Instant::now();
+        let mut resp = self.forward(req, lease, &mut tried);
+        let mut took = start.elapsed();
+        while self.should_retry(req, &resp, tried.len()) {
+            let lease = match self.pick(req, &tried) {
+                Some(lease) if self.take_retry() => lease,
+                _ => break,
+            };
+            let start = Instant::now();
+            resp = self.forward(req, lease, &mut tried);
+            took = start.elapsed();
+        }
+        let extras = req.get_extensions_mut().get_or_default::<LogExtras>();
+        extras.set("upstream_time_us", &took.as_micros().to_string());
+        extras.set("upstream_retries", &(tried.len() - 1).to_string());
+        return resp;
+    }
+}
+
+
+// An upstream server, shared with the response bodies coming from it
+struct Upstream {
+    // host:port
+    addr: String,
+    // Requests in progress
+    active: AtomicUsize,
+    // Open connections to reuse, and when they were last used
+    idle: Mutex<Vec<(TcpStream, Instant)>>,
+    // The health check, and whether a thread does it
+    check: Mutex<Option<Arc<HealthCheck>>>,
+    checked: AtomicBool,
+    // Passed the last health check
+    healthy: AtomicBool,
+    // Requests that failed in a row, and until when it's taken as down
+    failures: AtomicUsize,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn is_up(&self) -> bool {
+        if !self.healthy.load(Ordering::SeqCst) {
+            return false;
+        }
+        let down_until = self.down_until.lock().unwrap();
+        return down_until.is_none_or(|until| Instant::now() >= until);
+    }
+
+    fn reuse(&self) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some((stream, since)) = idle.pop() {
+            if since.elapsed() < IDLE_TIMEOUT {
+                return Some(stream);
+            }
+        }
+        return None;
+    }
+}
+
+struct HealthCheck {
+    method: String,
+    path: String,
+    expect: i32,
+    interval: Duration,
+}
+
+// Health check `upstream` in a thread, until it's dropped
+fn watch(upstream: &Arc<Upstream>) {
+    if upstream.checked.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let upstream = Arc::downgrade(upstream);
+    thread::spawn(move || {
+        loop {
+            let upstream = match upstream.upgrade() {
+                Some(upstream) => upstream,
+                None => return,
+            };
+            let check = upstream.check.lock().unwrap().clone().unwrap();
+            let healthy = match health_check(&upstream.addr, &check) {
+                Ok(code) => code == check.expect,
+                Err(_) => false,
+            };
+            upstream.healthy.store(healthy, Ordering::SeqCst);
+            drop(upstream);
+            thread::sleep(check.interval);
+        }
+    });
+}
+
+// The status code the upstream answers a health check with
+fn health_check(addr: &str, check: &HealthCheck) -> io::Result<i32> {
+    let stream = connect(addr, check.interval)?;
+    let head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\
+            \r\n", check.method, check.path, addr);
+    let mut reader = send_request(stream, check.interval, head.as_bytes(),
+            &mut io::empty())?;
+    loop {
+        let head = read_response_head(&mut reader)?;
+        if head.code >= 200 {
+            return Ok(head.code);
+        }
+    }
+}
+
+// A request counted against its upstream, until dropped
+struct Lease {
+    upstream: Arc<Upstream>,
+    max_idle: usize,
+}
+
+impl Lease {
+    // Keep a connection that's done with a response, to reuse
+    fn put_back(&self, reader: BufReader<TcpStream>) {
+        // Anything more from the upstream would be garbage
+        if !reader.buffer().is_empty() {
+            return;
+        }
+        let mut idle = self.upstream.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push((reader.into_inner(), Instant::now()));
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// How a response body ends
+enum Framing {
+    // With this many bytes left
+    Length(BufReader<TcpStream>, u64),
+    Chunked(ChunkedReader<BufReader<TcpStream>>),
+    // When the upstream closes the connection
+    Close(BufReader<TcpStream>),
+}
+
+// A response body streamed from an upstream.  At the end, the connection
+// is put back to be reused, if it can be.
+struct UpstreamBody {
+    // None once done
+    framing: Option<Framing>,
+    lease: Lease,
+    reuse: bool,
+}
+
+impl Read for UpstreamBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (n, done) = match self.framing {
+            None => return Ok(0),
+            Some(Framing::Length(ref mut reader, ref mut left)) => {
+                let max = (buf.len() as u64).min(*left) as usize;
+                let n = reader.read(&mut buf[..max])?;
+                if n == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                            "upstream response cut short"));
+                }
+                *left -= n as u64;
+                (n, *left == 0)
+            },
+            Some(Framing::Chunked(ref mut reader)) => {
+                let n = reader.read(buf)?;
+                (n, n == 0)
+            },
+            Some(Framing::Close(ref mut reader)) => {
+                let n = reader.read(buf)?;
+                (n, n == 0)
+            },
+        };
+        if done {
+            let reader = match self.framing.take().unwrap() {
+                Framing::Length(reader, _) | Framing::Close(reader) => reader,
+                Framing::Chunked(reader) => reader.into_inner(),
+            };
+            if self.reuse {
+                self.lease.put_back(reader);
+            }
+        }
+        return Ok(n);
+    }
+}
+
+
+fn connect(upstream: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in upstream.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    return Err(last_err.unwrap_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound, "upstream has no addresses")));
+}
+
+// Write a request, and wait for the start of the response
+fn send_request(stream: TcpStream, timeout: Duration, head: &[u8],
+        body: &mut dyn Read) -> io::Result<BufReader<TcpStream>> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    (&stream).write_all(head)?;
+    io::copy(body, &mut &stream)?;
+    let mut reader = BufReader::new(stream);
+    if reader.fill_buf()?.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "upstream closed the connection"));
+    }
+    return Ok(reader);
+}
+
+// ex: "GET /path?query HTTP/1.1\r\n"
+fn request_line(req: &WebRequest) -> Vec<u8> {
+    let mut line = req.method.to_ascii_uppercase().into_bytes();
+    line.push(b' ');
+    line.extend_from_slice(&req.environ[&b"path"[..]]);
+    let query = &req.environ[&b"query_string"[..]];
+    if !query.is_empty() {
+        line.push(b'?');
+        line.extend_from_slice(query);
+    }
+    line.extend_from_slice(b" HTTP/1.1\r\n");
+    return line;
+}
+
+// The headers to pass on, sorted by name
+fn request_headers(req: &WebRequest) -> Vec<(&[u8], &[u8])> {
+    let dropped = match req.get_header("Connection") {
+        Some(connection) => connection_tokens(Some(connection).into_iter()),
+        None => Vec::new(),
+    };
+    let mut headers: Vec<(&[u8], &[u8])> = req.environ.iter()
+        .filter(|&(k, _)| k.starts_with(b"http_"))
+        .map(|(k, v)| (&k[5..], &v[..]))
+        .filter(|&(k, _)| {
+            let name = String::from_utf8_lossy(k);
+            return !HOP_BY_HOP.contains(&&name[..])
+                && !FORWARDING.contains(&&name[..])
+                && !dropped.contains(&name.into_owned())
+                && k != b"host" && k != b"content-length"
+                && k != b"x-retry-count";
+        })
+        .collect();
+    headers.sort();
+    return headers;
+}
+
+// A token as is, anything else as a quoted string (RFC 7239 4)
+fn forwarded_value(value: &str) -> String {
+    let token = !value.is_empty() && value.bytes().all(|c| {
+        c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+    });
+    if token {
+        return value.to_string();
+    }
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    return format!("\"{}\"", escaped);
+}
+
+// The protocol a request asks to upgrade to, if any
+fn upgrade_protocol(req: &WebRequest) -> Option<&str> {
+    let connection = req.get_header("Connection")?;
+    if !connection_tokens(Some(connection).into_iter()).iter()
+            .any(|t| t == "upgrade") {
+        return None;
+    }
+    return req.get_header("Upgrade");
+}
+
+// Pass what the client and the upstream send on to the other, until
+// either closes its connection
+fn splice(mut client: Upgraded, mut upstream: BufReader<TcpStream>) {
+    let (mut to_client, mut to_upstream) = match (client.try_clone(),
+            upstream.get_ref().try_clone()) {
+        (Ok(to_client), Ok(to_upstream)) => (to_client, to_upstream),
+        _ => return,
+    };
+    // Upgraded connections may be quiet for long
+    let _ = upstream.get_ref().set_read_timeout(None);
+    let _ = to_upstream.set_write_timeout(None);
+    let thread = thread::spawn(move || {
+        let _ = io::copy(&mut upstream, &mut to_client);
+        let _ = to_client.shutdown();
+    });
+    let _ = io::copy(&mut client, &mut to_upstream);
+    let _ = to_upstream.shutdown(Shutdown::Both);
+    let _ = thread.join();
+}
+
+// The header names listed in Connection headers, lowercased
+fn connection_tokens<'a, I>(values: I) -> Vec<String>
+        where I: Iterator<Item = &'a str> {
+    return values.flat_map(|v| v.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect();
+}
+
+fn error_response(code: i32, status: &str) -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(code, status);
+    resp.set_body_str(&format!("Error {}: {}", code, status));
+    return resp;
+}
+
+
+// An upstream that answers one connection with `response`, and hands back
+// what it was sent
+#[cfg(test)]
+fn test_upstream(response: &'static [u8])
+        -> (String, ::std::thread::JoinHandle<String>) {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let upstream = ::std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = String::new();
+        while !request.ends_with("\r\n\r\n") {
+            reader.read_line(&mut request).unwrap();
+        }
+        let length = request.lines()
+            .find(|l| l.starts_with("Content-Length: "))
+            .map(|l| l[16..].parse().unwrap()).unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        request.push_str(&String::from_utf8(body).unwrap());
+        stream.write_all(response).unwrap();
+        return request;
+    });
+    return (addr, upstream);
+}
+
+#[test]
+fn test_reverse_proxy() {
+    let (addr, upstream) = test_upstream(b"HTTP/1.1 100 Continue\r\n\r\n\
+        HTTP/1.1 201 Created\r\nContent-Length: 5\r\nConnection: close, X-Hop\r\n\
+        X-Hop: 1\r\nKeep-Alive: timeout=5\r\nSet-Cookie: a=1\r\n\
+        Set-Cookie: b=2\r\n\r\nhello, and more");
+    let proxy = ReverseProxy::new(&addr);
+    let mut req = WebRequest::parse_for_test("POST /a%20b?x=1 HTTP/1.1\r\n\
+        Host: example.com\r\nContent-Length: 4\r\nConnection: X-Private\r\n\
+        X-Private: 1\r\nTE: trailers\r\nUpgrade: h2c\r\nAccept: */*\r\n\r\n");
+    req.body = b"data".to_vec();
+    let mut resp = proxy.handle(&mut req);
+    assert_eq!(upstream.join().unwrap(), format!("POST /a%20b?x=1 HTTP/1.1\r\n\
+        Host: {}\r\naccept: */*\r\nX-Forwarded-Host: example.com\r\n\
+        X-Forwarded-Proto: http\r\n\
+        Forwarded: for=unknown;host=example.com;proto=http\r\n\
+        Content-Length: 4\r\n\r\ndata", addr));
+    assert_eq!(resp.get_code(), 201);
+    assert_eq!(resp.get_status(), "Created");
+    let headers: Vec<(String, String)> = resp.get_headers().cloned().collect();
+    assert_eq!(headers, [("Set-Cookie".to_string(), "a=1".to_string()),
+        ("Set-Cookie".to_string(), "b=2".to_string())]);
+    assert_eq!(resp.reader_len, Some(5));
+    assert_eq!(&resp.body_for_test()[..5], b"hello");
+
+    // The client's Host, and a chunked response
+    let (addr, upstream) = test_upstream(b"HTTP/1.1 200 OK\r\n\
+        Transfer-Encoding: chunked\r\nContent-Length: 99\r\n\r\n\
+        5\r\nhello\r\n0\r\n\r\n");
+    let mut proxy = ReverseProxy::new(&addr);
+    proxy.set_preserve_host(true);
+    let mut req = WebRequest::parse_for_test("GET / HTTP/1.1\r\n\
+        Host: example.com\r\n\r\n");
+    let mut resp = proxy.handle(&mut req);
+    assert_eq!(upstream.join().unwrap(), "GET / HTTP/1.1\r\n\
+        Host: example.com\r\nX-Forwarded-Host: example.com\r\n\
+        X-Forwarded-Proto: http\r\n\
+        Forwarded: for=unknown;host=example.com;proto=http\r\n\r\n");
+    assert_eq!(resp.get_header("Content-Length"), None);
+    assert_eq!(resp.body_for_test(), b"hello");
+}
+
+#[test]
+fn test_reverse_proxy_errors() {
+    // Nothing listening
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let mut req = WebRequest::new_for_test("GET", "/");
+    assert_eq!(ReverseProxy::new(&addr).handle(&mut req).get_code(), 502);
+
+    let (addr, upstream) = test_upstream(b"SSH-2.0-OpenSSH\r\n\r\n");
+    assert_eq!(ReverseProxy::new(&addr).handle(&mut req).get_code(), 502);
+    upstream.join().unwrap();
+
+    // Accepted, but never answered
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut proxy = ReverseProxy::new(
+            &listener.local_addr().unwrap().to_string());
+    proxy.set_timeout(Duration::from_millis(50));
+    let resp = proxy.handle(&mut req);
+    assert_eq!((resp.get_code(), resp.get_status()), (504, "Gateway Timeout"));
+
+    // A HEAD response keeps its Content-Length, with no body
+    let (addr, upstream) = test_upstream(b"HTTP/1.0 200 OK\r\n\
+        Content-Length: 12\r\n\r\n");
+    let mut req = WebRequest::new_for_test("HEAD", "/");
+    let mut resp = ReverseProxy::new(&addr).handle(&mut req);
+    upstream.join().unwrap();
+    assert_eq!(resp.get_header("Content-Length"), Some("12"));
+    assert!(resp.is_streamed());
+    assert_eq!(resp.body_for_test(), b"");
+}
+
+// An upstream answering every request with its name and the number of the
+// connection, optionally closing the connection after each response
+#[cfg(test)]
+fn test_server(name: &'static str, close: bool) -> String {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    ::std::thread::spawn(move || {
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            ::std::thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    while line != "\r\n" {
+                        line.clear();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                    }
+                    let body = format!("{} {}", name, i + 1);
+                    write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\
+                            \r\n{}", body.len(), body).unwrap();
+                    if close {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    return addr;
+}
+
+#[cfg(test)]
+fn test_get(proxy: &ReverseProxy, from: &str) -> WebResponse {
+    let mut req = WebRequest::new_for_test("GET", "/");
+    req.environ.insert(b"remote_address".to_vec(), from.into());
+    return proxy.handle(&mut req);
+}
+
+#[test]
+fn test_balance() {
+    let body = |mut resp: WebResponse| {
+        return String::from_utf8(resp.body_for_test()).unwrap();
+    };
+    let mut proxy = ReverseProxy::new(&test_server("a", false));
+    proxy.add_upstream(&test_server("b", false));
+    // Connections are reused
+    let bodies: Vec<String> = (0..4)
+        .map(|_| body(test_get(&proxy, "192.0.2.1:1"))).collect();
+    assert_eq!(bodies, ["a 1", "b 1", "a 1", "b 1"]);
+
+    // A body still being sent holds its connection
+    let held = test_get(&proxy, "192.0.2.1:1");
+    assert_eq!(body(test_get(&proxy, "192.0.2.1:1")), "b 1");
+    proxy.set_balance(Balance::LeastConnections);
+    assert_eq!(body(test_get(&proxy, "192.0.2.1:1")), "b 1");
+    assert_eq!(body(held), "a 1");
+    proxy.set_balance(Balance::RoundRobin);
+
+    proxy.set_max_connections(1);
+    let held = test_get(&proxy, "192.0.2.1:1");
+    let held_too = test_get(&proxy, "192.0.2.1:1");
+    assert_eq!(test_get(&proxy, "192.0.2.1:1").get_code(), 503);
+    // Not read to the end, so its connection isn't reused
+    drop(held);
+    assert_eq!(body(test_get(&proxy, "192.0.2.1:1")), "b 2");
+    drop(held_too);
+
+    proxy.set_balance(Balance::IpHash);
+    let first = body(test_get(&proxy, "192.0.2.1:1"));
+    for _ in 0..5 {
+        assert_eq!(body(test_get(&proxy, "192.0.2.1:2")), first);
+    }
+}
+
+#[test]
+fn test_reuse_closed() {
+    // Each idle connection was closed by the upstream, and a new one is made
+    let proxy = ReverseProxy::new(&test_server("a", true));
+    for i in 1..4 {
+        let mut resp = test_get(&proxy, "192.0.2.1:1");
+        assert_eq!(resp.body_for_test(), format!("a {}", i).as_bytes());
+    }
+
+    let mut proxy = ReverseProxy::new(&test_server("a", false));
+    proxy.set_max_idle(0);
+    for i in 1..4 {
+        let mut resp = test_get(&proxy, "192.0.2.1:1");
+        assert_eq!(resp.body_for_test(), format!("a {}", i).as_bytes());
+    }
+}
+
+#[test]
+fn test_forwarding_headers() {
+    let mut proxy = ReverseProxy::new("127.0.0.1:1");
+    proxy.add_trusted_proxy("10.0.0.0/8");
+    let forwarded = |from: &str, headers: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET / HTTP/1.1\r\n{}\r\n", headers));
+        req.environ.insert(b"remote_address".to_vec(), from.into());
+        let names = request_headers(&req).iter()
+            .map(|h| String::from_utf8_lossy(h.0).into_owned())
+            .collect::<Vec<String>>();
+        assert!(!names.iter().any(|n| FORWARDING.contains(&&n[..])));
+        return proxy.forwarding_headers(&req).into_iter()
+            .map(|(k, v)| format!("{}: {}", k, v)).collect::<Vec<String>>();
+    };
+    let incoming = "Host: example.com:8080\r\nX-Forwarded-For: 1.1.1.1\r\n\
+        X-Forwarded-Proto: https\r\nX-Forwarded-Host: example.org\r\n\
+        Forwarded: for=1.1.1.1;proto=https\r\n";
+
+    // Made up by the client
+    assert_eq!(forwarded("192.0.2.1:1234", incoming), [
+        "X-Forwarded-For: 192.0.2.1", "X-Forwarded-Host: example.com:8080",
+        "X-Forwarded-Proto: http",
+        "Forwarded: for=192.0.2.1;host=\"example.com:8080\";proto=http"]);
+    // From a proxy in front
+    assert_eq!(forwarded("10.0.0.2:1234", incoming), [
+        "X-Forwarded-For: 1.1.1.1, 10.0.0.2", "X-Forwarded-Host: example.org",
+        "X-Forwarded-Proto: https", "Forwarded: for=1.1.1.1;proto=https, \
+        for=10.0.0.2;host=\"example.com:8080\";proto=https"]);
+    // Only the proto from the proxy
+    assert_eq!(forwarded("10.0.0.2:1234", "X-Forwarded-Proto: https\r\n"), [
+        "X-Forwarded-For: 10.0.0.2", "X-Forwarded-Proto: https",
+        "Forwarded: for=10.0.0.2;proto=https"]);
+    assert_eq!(forwarded("[2001:db8::1]:1234", ""), [
+        "X-Forwarded-For: 2001:db8::1", "X-Forwarded-Proto: http",
+        "Forwarded: for=\"[2001:db8::1]\";proto=http"]);
+}
+
+#[test]
+fn test_health_check() {
+    let mut dead = ReverseProxy::new("127.0.0.1:1");
+    let dead_addr = {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let body = |mut resp: WebResponse| {
+        return String::from_utf8(resp.body_for_test()).unwrap();
+    };
+
+    // Found by checks
+    let mut proxy = ReverseProxy::new(&dead_addr);
+    proxy.add_upstream(&test_server("a", false));
+    proxy.set_health_check("GET", "/health", 200, Duration::from_millis(20));
+    thread::sleep(Duration::from_millis(100));
+    // The checks' connections are counted too
+    for _ in 0..4 {
+        assert!(body(test_get(&proxy, "192.0.2.1:1")).starts_with("a "));
+    }
+    // None answer as expected: try them all anyway
+    proxy.set_health_check("HEAD", "/", 204, Duration::from_millis(20));
+    thread::sleep(Duration::from_millis(100));
+    let codes: Vec<i32> = (0..2)
+        .map(|_| test_get(&proxy, "192.0.2.1:1").get_code()).collect();
+    assert!(codes.contains(&502) && codes.contains(&200));
+
+    // Found by failed requests
+    let mut proxy = ReverseProxy::new(&dead_addr);
+    proxy.add_upstream(&test_server("b", false));
+    proxy.set_passive_check(2, Duration::from_millis(100));
+    let codes: Vec<i32> = (0..8)
+        .map(|_| test_get(&proxy, "192.0.2.1:1").get_code()).collect();
+    assert_eq!(codes, [502, 200, 502, 200, 200, 200, 200, 200]);
+    thread::sleep(Duration::from_millis(150));
+    let codes: Vec<i32> = (0..4)
+        .map(|_| test_get(&proxy, "192.0.2.1:1").get_code()).collect();
+    assert_eq!(codes, [502, 200, 200, 200]);
+
+    // The threads end with the proxy
+    dead.set_health_check("GET", "/", 200, Duration::from_millis(10));
+    let upstream = Arc::downgrade(&dead.upstreams[0]);
+    drop(dead);
+    thread::sleep(Duration::from_millis(50));
+    assert!(upstream.upgrade().is_none());
+}
+
+#[test]
+fn test_upgrade() {
+    use std::net::TcpListener;
+    use super::upgrade::SharedStream;
+
+    // Switching to echoing what it gets, uppercased
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = String::new();
+        while !request.ends_with("\r\n\r\n") {
+            reader.read_line(&mut request).unwrap();
+        }
+        let asked = request.contains("\r\nConnection: Upgrade\r\n\
+                Upgrade: echo\r\n");
+        write!(stream, "HTTP/1.1 101 Switching Protocols\r\n\
+                Connection: Upgrade\r\nUpgrade: echo\r\nX-Asked: {}\r\n\r\n\
+                hi", asked).unwrap();
+        let mut buf = [0; 64];
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            stream.write_all(&buf[..n].to_ascii_uppercase()).unwrap();
+        }
+    });
+    let proxy = ReverseProxy::new(&addr);
+    let mut req = WebRequest::parse_for_test("GET /ws HTTP/1.1\r\n\
+            Connection: keep-alive, Upgrade\r\nUpgrade: echo\r\n\r\n");
+    let mut resp = proxy.handle(&mut req);
+    assert_eq!(resp.get_code(), 101);
+    assert_eq!(resp.get_header("Connection"), Some("Upgrade"));
+    assert_eq!(resp.get_header("Upgrade"), Some("echo"));
+    assert_eq!(resp.get_header("X-Asked"), Some("true"));
+
+    // The client's connection, as the server hands it over
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+        .unwrap();
+    let socket = Arc::new(listener.accept().unwrap().0);
+    let conn = Upgraded::new(Box::new(SharedStream(socket.clone())),
+            b"abc".to_vec()).with_socket(socket);
+    let upgrade = resp.upgrade.take().unwrap();
+    let spliced = thread::spawn(move || upgrade(conn));
+    client.write_all(b"def").unwrap();
+    let mut echo = [0; 8];
+    client.read_exact(&mut echo).unwrap();
+    assert_eq!(&echo, b"hiABCDEF");
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(client.read(&mut echo).unwrap(), 0);
+    spliced.join().unwrap();
+
+    // Not asked for
+    let (addr, upstream) = test_upstream(b"HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: echo\r\n\r\n");
+    let mut req = WebRequest::new_for_test("GET", "/");
+    assert_eq!(ReverseProxy::new(&addr).handle(&mut req).get_code(), 502);
+    upstream.join().unwrap();
+}
+
+#[test]
+fn test_retry() {
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead = listener.local_addr().unwrap().to_string();
+    drop(listener);
+
+    // Round-robin starts with the first upstream
+    let mut proxy = ReverseProxy::new(&dead);
+    let (addr, upstream) = test_upstream(b"HTTP/1.1 200 OK\r\n\
+        Content-Length: 2\r\n\r\nok");
+    proxy.add_upstream(&addr);
+    proxy.set_retries(1);
+    let mut req = WebRequest::parse_for_test("GET / HTTP/1.1\r\n\
+            X-Retry-Count: 5\r\n\r\n");
+    assert_eq!(proxy.handle(&mut req).body_for_test(), b"ok");
+    let request = upstream.join().unwrap();
+    assert!(request.contains("\r\nX-Retry-Count: 1\r\n"), "{}", request);
+    assert!(!request.contains("X-Retry-Count: 5"));
+    let extras = req.get_extensions().get::<LogExtras>().unwrap();
+    assert_eq!(extras.get("upstream_retries"), Some("1"));
+    assert!(extras.get("upstream_time_us").unwrap().parse::<u64>().is_ok());
+
+    // A 502 from the upstream, but not for a POST
+    let (addr, upstream) = test_upstream(b"HTTP/1.1 502 Bad Gateway\r\n\
+        Content-Length: 0\r\n\r\n");
+    let mut proxy = ReverseProxy::new(&addr);
+    proxy.add_upstream(&test_server("b", false));
+    proxy.set_retries(3);
+    assert_eq!(test_get(&proxy, "127.0.0.1").body_for_test(), b"b 1");
+    upstream.join().unwrap();
+    let mut proxy = ReverseProxy::new(&dead);
+    proxy.add_upstream(&test_server("b", false));
+    proxy.set_retries(1);
+    let mut req = WebRequest::new_for_test("POST", "/");
+    assert_eq!(proxy.handle(&mut req).get_code(), 502);
+
+    // With no budget, only the first few are retried: every other request,
+    // starting with the second, goes to the dead upstream first
+    proxy.set_retry_budget(0);
+    let codes: Vec<i32> = (0..22).map(|_| test_get(&proxy, "127.0.0.1")
+        .get_code()).collect();
+    assert_eq!(codes.iter().filter(|&&code| code == 502).count(), 1);
+    assert_eq!(codes[21], 502);
+}
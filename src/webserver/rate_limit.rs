@@ -0,0 +1,288 @@
+//! Rate limiting middleware, per client and global (token buckets)
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{WebRequest, WebResponse, Middleware, Next};
+
+
+// How often to look for idle buckets
+static SWEEP_INTERVAL_SECS: u64 = 60;
+
+type KeyFn = Box<dyn Fn(&WebRequest) -> Option<String> + Send + Sync>;
+
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // Refill for the time since the last take, and take a token.
+    // Err(seconds until one is available) if empty.
+    fn take(&mut self, rate: f64, burst: f64, now: Instant)
+            -> Result<(), u64> {
+        let elapsed = duration_secs(now.duration_since(self.updated));
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = (1.0 - self.tokens) / rate;
+        return Err(wait.ceil().max(1.0) as u64);
+    }
+}
+
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    last_sweep: Instant,
+}
+
+
+/// Middleware that limits each client to `rate` requests per second on
+/// average, with bursts of up to `burst` requests.
+///
+/// Clients are keyed by IP address by default; see `set_key_fn`.  Requests
+/// over the limit get a `429 Too Many Requests` with a Retry-After header.
+///
+/// Buckets are kept in memory.  Once a bucket has refilled it carries no
+/// information, so such idle buckets are dropped periodically.  If there
+/// are still more than `max_keys` clients, new clients are refused.
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+    max_keys: usize,
+    key_fn: KeyFn,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimit {
+    /// `rate` must be > 0, `burst` >= 1.
+    pub fn new(rate: f64, burst: u32) -> RateLimit {
+        assert!(rate > 0.0 && burst >= 1);
+        return RateLimit {
+            rate: rate,
+            burst: burst as f64,
+            max_keys: 100_000,
+            key_fn: Box::new(|req: &WebRequest| {
+                return req.get_remote_addr().map(|a| a.ip().to_string());
+            }),
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        };
+    }
+
+    /// Key clients by something other than IP address, ex: an API key
+    /// header.  Requests for which `f` returns None are not limited.
+    pub fn set_key_fn<F>(&mut self, f: F)
+            where F: Fn(&WebRequest) -> Option<String> + Send + Sync + 'static {
+        self.key_fn = Box::new(f);
+    }
+
+    /// Maximum number of clients tracked at once (default 100000)
+    pub fn set_max_keys(&mut self, n: usize) {
+        self.max_keys = n;
+    }
+
+    // Take a token for key.  Err(seconds until one is available) if empty.
+    fn take(&self, key: &str, now: Instant) -> Result<(), u64> {
+        let mut buckets = match self.buckets.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if now.duration_since(buckets.last_sweep)
+                >= Duration::from_secs(SWEEP_INTERVAL_SECS) {
+            self.sweep(&mut buckets, now);
+        }
+        if !buckets.map.contains_key(key) {
+            if buckets.map.len() >= self.max_keys {
+                self.sweep(&mut buckets, now);
+                if buckets.map.len() >= self.max_keys {
+                    return Err(1);
+                }
+            }
+            buckets.map.insert(key.to_string(),
+                    Bucket { tokens: self.burst, updated: now });
+        }
+
+        let bucket = buckets.map.get_mut(key).unwrap();
+        return bucket.take(self.rate, self.burst, now);
+    }
+
+    // Drop buckets that would be full by now
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        buckets.map.retain(|_, b| {
+            let elapsed = duration_secs(now.duration_since(b.updated));
+            return b.tokens + elapsed * rate < burst;
+        });
+        buckets.last_sweep = now;
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let key = match (self.key_fn)(req) {
+            Some(key) => key,
+            None => return next.run(req),
+        };
+        match self.take(&key, Instant::now()) {
+            Ok(()) => return next.run(req),
+            Err(retry_after) => {
+                let mut resp = WebResponse::new();
+                resp.set_code(429, "Too Many Requests");
+                resp.set_header("Retry-After", &retry_after.to_string());
+                resp.set_body_str("Error 429: Too Many Requests");
+                return resp;
+            }
+        }
+    }
+}
+
+
+/// Middleware that limits all clients together to `rate` requests per
+/// second on average, with bursts of up to `burst` requests, to spare what
+/// the handler relies on (ex: a database) during a spike.
+///
+/// Requests over the limit get a `503 Service Unavailable` with a
+/// Retry-After header.  It goes well with a `RateLimit` after it, so one
+/// client can't take all of the budget.
+///
+/// ex:
+///
+/// ```ignore
+/// let mut chain = Chain::new(handler);
+/// chain.add(Throttle::new(200.0, 50));
+/// chain.add(RateLimit::new(10.0, 20));
+/// ```
+pub struct Throttle {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    /// `rate` must be > 0, `burst` >= 1.
+    pub fn new(rate: f64, burst: u32) -> Throttle {
+        assert!(rate > 0.0 && burst >= 1);
+        return Throttle {
+            rate: rate,
+            burst: burst as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: burst as f64,
+                updated: Instant::now(),
+            }),
+        };
+    }
+
+    fn take(&self, now: Instant) -> Result<(), u64> {
+        let mut bucket = match self.bucket.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        return bucket.take(self.rate, self.burst, now);
+    }
+}
+
+impl Middleware for Throttle {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        match self.take(Instant::now()) {
+            Ok(()) => return next.run(req),
+            Err(retry_after) => {
+                let mut resp = WebResponse::new();
+                resp.set_code(503, "Service Unavailable");
+                resp.set_header("Retry-After", &retry_after.to_string());
+                resp.set_body_str("Error 503: Service Unavailable");
+                return resp;
+            }
+        }
+    }
+}
+
+
+fn duration_secs(d: Duration) -> f64 {
+    return d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9;
+}
+
+
+#[test]
+fn test_rate_limit_bucket() {
+    let limit = RateLimit::new(2.0, 3);
+    let t = Instant::now();
+    assert_eq!(limit.take("a", t), Ok(()));
+    assert_eq!(limit.take("a", t), Ok(()));
+    assert_eq!(limit.take("a", t), Ok(()));
+    assert_eq!(limit.take("a", t), Err(1));
+    assert_eq!(limit.take("b", t), Ok(()));
+    // 2 per second: half a second buys one more
+    assert_eq!(limit.take("a", t + Duration::from_millis(500)), Ok(()));
+    assert_eq!(limit.take("a", t + Duration::from_millis(500)), Err(1));
+
+    let slow = RateLimit::new(0.1, 1);
+    assert_eq!(slow.take("a", t), Ok(()));
+    assert_eq!(slow.take("a", t), Err(10));
+}
+
+#[test]
+fn test_rate_limit_expiry() {
+    let mut limit = RateLimit::new(1.0, 2);
+    limit.set_max_keys(2);
+    let t = Instant::now();
+    assert_eq!(limit.take("a", t), Ok(()));
+    assert_eq!(limit.take("b", t), Ok(()));
+    assert_eq!(limit.take("c", t), Err(1));
+    // a and b have refilled, so they are dropped to make room
+    assert_eq!(limit.take("c", t + Duration::from_secs(1)), Ok(()));
+    assert_eq!(limit.buckets.lock().unwrap().map.len(), 1);
+
+    assert_eq!(limit.take("d", t + Duration::from_secs(1)), Ok(()));
+    assert_eq!(limit.take("c", t + Duration::from_secs(120)), Ok(()));
+    // The periodic sweep dropped d
+    assert_eq!(limit.buckets.lock().unwrap().map.len(), 1);
+}
+
+#[test]
+fn test_rate_limit_middleware() {
+    use super::{Chain, Handler};
+
+    let mut chain = Chain::new(|_req: &WebRequest| WebResponse::new());
+    chain.add(RateLimit::new(0.001, 1));
+    let mut req = WebRequest::new_for_test("GET", "/");
+    req.environ.insert(b"remote_address".to_vec(), b"10.0.0.1:5000".to_vec());
+    assert_eq!(chain.handle(&mut req).code, 200);
+    let resp = chain.handle(&mut req);
+    assert_eq!(resp.code, 429);
+    assert_eq!(resp.get_header("Retry-After"), Some("1000"));
+    // No address, so no key
+    assert_eq!(chain.handle(&mut WebRequest::new_for_test("GET", "/")).code,
+            200);
+}
+
+#[test]
+fn test_throttle() {
+    use super::{Chain, Handler};
+
+    let throttle = Throttle::new(4.0, 2);
+    let t = Instant::now();
+    assert_eq!(throttle.take(t), Ok(()));
+    assert_eq!(throttle.take(t), Ok(()));
+    assert_eq!(throttle.take(t), Err(1));
+    assert_eq!(throttle.take(t + Duration::from_millis(250)), Ok(()));
+    assert_eq!(throttle.take(t + Duration::from_millis(250)), Err(1));
+
+    let mut chain = Chain::new(|_req: &WebRequest| WebResponse::new());
+    chain.add(Throttle::new(0.01, 1));
+    let mut a = WebRequest::new_for_test("GET", "/");
+    a.environ.insert(b"remote_address".to_vec(), b"10.0.0.1:5000".to_vec());
+    let mut b = WebRequest::new_for_test("GET", "/");
+    b.environ.insert(b"remote_address".to_vec(), b"10.0.0.2:5000".to_vec());
+    assert_eq!(chain.handle(&mut a).code, 200);
+    // Shared between clients
+    let resp = chain.handle(&mut b);
+    assert_eq!(resp.code, 503);
+    assert_eq!(resp.get_header("Retry-After"), Some("100"));
+}
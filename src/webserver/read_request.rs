@@ -2,20 +2,43 @@
 
 use std;
 use std::io;
-use std::ascii::AsciiExt; 
+use std::sync::Arc;
 
-use super::WebRequest;
+use super::{WebRequest, BodyInspector, BodyInspection};
+use super::body_spool::Spool;
+use super::memory_budget::MemoryBudget;
 use utils::genericsocket::GenericSocket;
+use utils::buffer_pool::BufferPool;
 use utils;
 
+// Read from the socket this much at a time, by default
+pub static CHUNK_SIZE: usize = 4096;
+
 
 // Possible errors from `read_request`
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     IoError(io::Error),
     InvalidRequest,
     InvalidVersion,
     LengthRequired,
     TooLarge,
+    // The head is longer than max_head
+    HeadTooLarge,
+    // The body doesn't fit in what's left of the memory budget
+    OverBudget,
+    // By the BodyInspector: the code and status to send
+    Rejected(i32, String),
+}
+
+/// Why `parse_head` returned no request
+#[derive(Debug, PartialEq)]
+pub enum HeadError {
+    /// The head isn't complete: call again once more bytes came
+    NeedMore,
+    /// The head isn't valid, and should be answered with this error code:
+    /// 400, or 505 for an HTTP version other than 1.0 and 1.1
+    Invalid(i32),
 }
 
 // Auto convert io::IOError into our module specific error
@@ -26,29 +49,78 @@ impl std::convert::From<io::Error> for Error {
 }
 
 
+/// Parse the head (request line and headers) of an HTTP/1 request from the
+/// start of `bytes`, as the server does for each connection.
+///
+/// Returns the request, without its body, and the size of its head: the
+/// bytes up to and including the \r\n\r\n ending it.  Whatever follows
+/// (the body, or the start of another protocol) isn't looked at.  When
+/// `bytes` has no \r\n\r\n yet, `HeadError::NeedMore` is returned and
+/// nothing is consumed: append what comes next and call again with all of
+/// it.  Doesn't panic on any input, so it can be fuzzed directly.
+///
+/// ex:
+///
+/// ```ignore
+/// match parse_head(&buf) {
+///     Ok((req, size)) => body = buf.split_off(size),
+///     Err(HeadError::NeedMore) => (), // read more
+///     Err(HeadError::Invalid(code)) => return error_page(code),
+/// }
+/// ```
+pub fn parse_head(bytes: &[u8]) -> Result<(WebRequest, usize), HeadError> {
+    let size = match utils::byteutils::find_crlf_crlf(bytes) {
+        Some(pos) => pos + 4,
+        None => return Err(HeadError::NeedMore),
+    };
+    match utils::http_request::parse(&bytes[..size]) {
+        Err(utils::http_request::ParseError::BadVersion) =>
+            return Err(HeadError::Invalid(505)),
+        Err(..) => return Err(HeadError::Invalid(400)),
+        Ok(parsed) => return Ok((WebRequest::from_parsed(parsed), size)),
+    }
+}
+
+
 // TODO: split the body reading out
 // Read a full request from the client (headers and body)
 // max_size: max body size
+// max_head: max head size, up to and including its final \r\n\r\n
+// spool_over: bodies larger than this go to a temporary file
+// budget: what the bodies kept in memory are taken from
 //
 // We need a Reader+Writer, due to stupid HTTP 100-continue.
 // We transparently send the 100-Continue if expected of us.  However, the more
 // educated thing to do, for apps that actually care about this, would be to
 // call the app code first and let it validate the headers.
-pub fn read_request(stream: &mut GenericSocket, max_size: usize) 
-        -> Result<WebRequest, Error> {
-    let mut req_buffer = Vec::<u8>::with_capacity(4096);
-    let req_size = try!(read_until_headers_end(&mut req_buffer, stream));
+//
+// Also returns what was read past the end of the request, ex: the start of
+// a pipelined request, or of the protocol after an upgrade.
+//
+// The inspector, if any, is shown the body as it's read.  The buffers for
+// reading the head come from `buffers`, and reads are of their size.
+pub fn read_request(stream: &mut dyn GenericSocket, max_size: usize,
+        max_head: usize, spool_over: Option<usize>,
+        budget: Option<&Arc<MemoryBudget>>,
+        inspector: Option<&dyn BodyInspector>, buffers: &BufferPool)
+        -> Result<(WebRequest, Vec<u8>), Error> {
+    let mut req_buffer = buffers.get();
+    let mut chunk = buffers.get();
+    chunk.resize(buffers.buffer_size().max(1), 0);
+    read_until_headers_end(&mut req_buffer, stream, max_head, &mut chunk)?;
 
     // Try to parse it
-    let req = match utils::http_request::parse(&req_buffer[..req_size]) {
-        Err(utils::http_request::ParseError::BadVersion) => 
-            return Err(Error::InvalidVersion),
+    let (req, req_size) = match parse_head(&req_buffer) {
+        Err(HeadError::Invalid(505)) => return Err(Error::InvalidVersion),
         Err(..) => return Err(Error::InvalidRequest),
-        Ok(parsed_req) => parsed_req,
+        Ok(ret) => ret,
     };
 
     // See if there's a body to read too.  
     let mut body = Vec::new();
+    let mut spool = None;
+    let mut reserved = None;
+    let mut extra = req_buffer[req_size..].to_vec();
 
     // We don't currently support chunked
     if req.environ.contains_key(&b"http_transfer-encoding"[..]) {
@@ -57,8 +129,8 @@ pub fn read_request(stream: &mut GenericSocket, max_size: usize)
 
     { // borrow scope for req.environ
     let clen = req.environ.get(&b"http_content-length"[..]);
-    if clen.is_some() {
-        let clen = match utils::byteutils::parse_u64(&clen.unwrap()) {
+    if let Some(clen) = clen {
+        let clen = match utils::byteutils::parse_u64(clen) {
             // unparseable content-length
             None => return Err(Error::InvalidRequest),
             Some(clen) => clen,
@@ -70,42 +142,73 @@ pub fn read_request(stream: &mut GenericSocket, max_size: usize)
 
         // Cast it down, as we read in memory
         let clen = clen as usize;
+        let spooled = spool_over.map(|size| clen > size).unwrap_or(false);
+
+        // Before sending 100-continue: the client needn't send it then
+        if let Some(budget) = budget.filter(|_| !spooled) {
+            match MemoryBudget::reserve(budget, clen) {
+                Some(reservation) => reserved = Some(reservation),
+                None => return Err(Error::OverBudget),
+            }
+        }
+
+        let mut inspection = match inspector {
+            Some(inspector) => Some(rejected(inspector.start(&req))?),
+            None => None,
+        };
 
         // Send 100-continue if needed
         if needs_100_continue(&req) {
             let cont = b"HTTP/1.1 100 Continue\r\n\r\n";
-            try!(stream.write_all(cont));
+            stream.write_all(cont)?;
         }
 
-        // Start one new buffer, so we don't copy when done
-        let mut body_buffer = req_buffer[req_size..].to_vec();
         // Can free some memory
         drop(req_buffer);
 
-        // Read the body
-        try!(read_until_size(&mut body_buffer, stream, clen));
-        assert!(body_buffer.len() >= clen);
+        if spooled {
+            let mut file = Spool::create()?;
+            extra = read_into_spool(&mut file, extra, stream, clen,
+                    &mut inspection, &mut chunk)?;
+            spool = Some(file);
+        } else {
+            // Start one new buffer, so we don't copy when done
+            let mut body_buffer = extra;
+
+            // Read the body
+            read_until_size(&mut body_buffer, stream, clen, &mut inspection,
+                    &mut chunk)?;
+            assert!(body_buffer.len() >= clen);
 
-        // Make sure not to include an extra pipelined request
-        body_buffer.truncate(clen);
-        assert!(body_buffer.len() == clen);
+            // Make sure not to include an extra pipelined request
+            extra = body_buffer.split_off(clen);
+            assert!(body_buffer.len() == clen);
 
-        body = body_buffer;
+            body = body_buffer;
+        }
+        if let Some(ref mut inspection) = inspection {
+            rejected(inspection.end())?;
+        }
+    } else if let Some(inspector) = inspector {
+        // No body, but the inspector may still want to know
+        rejected(rejected(inspector.start(&req))?.end())?;
     }
     }
 
     // All done
-    let ret = WebRequest {
-        environ: req.environ,
-        path: req.path,
-        method: req.method,
-        body: body,
-    };
-    return Ok(ret);
+    let mut ret = req;
+    ret.body = body;
+    ret.spool = spool;
+    ret.budget = reserved;
+    return Ok((ret, extra));
+}
+
+fn rejected<T>(ret: Result<T, (i32, String)>) -> Result<T, Error> {
+    return ret.map_err(|(code, status)| Error::Rejected(code, status));
 }
 
 
-fn needs_100_continue(req: &utils::http_request::Request) -> bool {
+fn needs_100_continue(req: &WebRequest) -> bool {
     let val = req.environ.get(&b"http_expect"[..]);
     if val.is_none() {
         return false;
@@ -121,53 +224,192 @@ fn needs_100_continue(req: &utils::http_request::Request) -> bool {
 }
 
 
-// Read until \r\n\r\n, which terminates the request headers
+// Read until \r\n\r\n, which terminates the request headers, if that's
+// within max_head bytes
 // Note: extra data may be in the buffer.
 fn read_until_headers_end(buffer: &mut Vec<u8>,
-        stream: &mut GenericSocket) -> Result<usize, io::Error> 
+        stream: &mut dyn GenericSocket, max_head: usize,
+        chunk_buff: &mut [u8]) -> Result<(), Error>
 {
-    // Craptastic new io copying; with_extra isn't supported yet
-    // and is unsafe.
-    let chunk_size = 4096;
-    let mut chunk_buff = Vec::with_capacity(0);
-    chunk_buff.reserve_exact(chunk_size);
-
     loop { 
         // Try to read some more data
-        let size = try!(stream.read(&mut chunk_buff));
+        let size = stream.read(chunk_buff)?;
         if size == 0 {
-            return Err(io::Error::new(
+            return Err(Error::IoError(io::Error::new(
                     io::ErrorKind::BrokenPipe,
-                    "connection closed while reading request headers"));
+                    "connection closed while reading request headers")));
         }
+        // Only look at the new data, and the end of what came before it
+        let start = buffer.len().saturating_sub(3);
         buffer.extend(&chunk_buff[0..size]);
 
-        let split_pos = utils::byteutils::memmem(&buffer, b"\r\n\r\n");
-        if split_pos.is_none() {
-            continue;
+        match utils::byteutils::find_crlf_crlf(&buffer[start..]) {
+            Some(pos) if start + pos + 4 <= max_head => return Ok(()),
+            Some(_) => return Err(Error::HeadTooLarge),
+            None if buffer.len() >= max_head => return Err(Error::HeadTooLarge),
+            None => (),
         }
-        return Ok(split_pos.unwrap() + 4);
     }
 }
 
 
-// Read until the buffer is at least size bytes long
+// Read until the buffer is at least size bytes long, showing the inspection
+// the first size bytes as they come
 // Note: extra data may be in the buffer.
 fn read_until_size(buffer: &mut Vec<u8>,
-        stream: &mut GenericSocket, size: usize) -> Result<(), io::Error>
+        stream: &mut dyn GenericSocket, size: usize,
+        inspection: &mut Option<Box<dyn BodyInspection>>,
+        chunk_buff: &mut [u8]) -> Result<(), Error>
 {
-    let chunk_size = 4096;
-    let mut chunk_buff = Vec::with_capacity(0);
-    chunk_buff.reserve_exact(chunk_size);
+    let mut inspected = 0;
 
-    while buffer.len() < size {
-        let size = try!(stream.read(&mut chunk_buff));
+    loop {
+        if let Some(ref mut inspection) = *inspection {
+            let end = buffer.len().min(size);
+            if end > inspected {
+                rejected(inspection.data(&buffer[inspected..end]))?;
+                inspected = end;
+            }
+        }
+        if buffer.len() >= size {
+            return Ok(());
+        }
+        let size = stream.read(chunk_buff)?;
         if size == 0 {
-            return Err(io::Error::new(
+            return Err(Error::IoError(io::Error::new(
                     io::ErrorKind::BrokenPipe,
-                    "connection closed while reading request body"));
+                    "connection closed while reading request body")));
         }
         buffer.extend(&chunk_buff[0..size]);
     }
-    return Ok(());
+}
+
+
+
+// Write size bytes of body to the spool, starting with those in extra, and
+// showing them to the inspection on the way.  Returns what was read past
+// them.
+fn read_into_spool(spool: &mut Spool, extra: Vec<u8>,
+        stream: &mut dyn GenericSocket, size: usize,
+        inspection: &mut Option<Box<dyn BodyInspection>>,
+        chunk_buff: &mut [u8]) -> Result<Vec<u8>, Error>
+{
+    let mut left = size;
+    let mut pending = extra;
+    loop {
+        let n = pending.len().min(left);
+        if let Some(ref mut inspection) = *inspection {
+            if n > 0 {
+                rejected(inspection.data(&pending[..n]))?;
+            }
+        }
+        spool.write_all(&pending[..n])?;
+        left -= n;
+        if left == 0 {
+            return Ok(pending.split_off(n));
+        }
+        let read = stream.read(chunk_buff)?;
+        if read == 0 {
+            return Err(Error::IoError(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "connection closed while reading request body")));
+        }
+        pending.clear();
+        pending.extend_from_slice(&chunk_buff[..read]);
+    }
+}
+
+
+#[test]
+fn test_parse_head() {
+    let raw = b"GET /a?b HTTP/1.1\r\nHost: x\r\n\r\nbody";
+    let (req, size) = parse_head(raw).unwrap();
+    assert_eq!(size, raw.len() - 4);
+    assert_eq!(req.get_path(), "/a");
+    assert_eq!(req.get_header("Host"), Some("x"));
+    // Nothing until the head is complete
+    for end in 0..size {
+        assert_eq!(parse_head(&raw[..end]).err(), Some(HeadError::NeedMore));
+    }
+
+    assert_eq!(parse_head(b"GET /a HTTP/2.0\r\n\r\n").err(),
+            Some(HeadError::Invalid(505)));
+    for bad in [&b"\r\n\r\n"[..], b"GET  /a HTTP/1.1\r\n\r\n",
+            b"GET a HTTP/1.1\r\n\r\n", b"GET /a HTTP/1.1\r\nHost\r\n\r\n",
+            b"GET /a HTTP/1.1\r\n Host: x\r\n\r\n", b"\xff\xfe \x00 \r\n\r\n"] {
+        assert_eq!(parse_head(bad).err(), Some(HeadError::Invalid(400)));
+    }
+}
+
+#[test]
+fn test_read_request_head_size() {
+    let buffers = BufferPool::new(8, 2);
+    let read = |raw: &str, max_head: usize| {
+        let mut stream = io::Cursor::new(raw.as_bytes().to_vec());
+        return read_request(&mut stream, 1000, max_head, None, None, None,
+                &buffers);
+    };
+    let raw = "GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+    assert!(read(raw, raw.len()).is_ok());
+    assert!(matches!(read(raw, raw.len() - 1), Err(Error::HeadTooLarge)));
+    // Without all of it
+    assert!(matches!(read("GET / HTTP/1.1\r\nHost: xxxxxxxxxxxxxxxxxxxxx", 16),
+            Err(Error::HeadTooLarge)));
+}
+
+#[test]
+fn test_read_request_spool() {
+    use std::io::Read;
+
+    let buffers = BufferPool::new(8, 2);
+    let raw = "POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello worldGET";
+    let read = |spool_over: usize| {
+        let mut stream = io::Cursor::new(raw.as_bytes().to_vec());
+        return read_request(&mut stream, 1000, 1000, Some(spool_over), None,
+                None, &buffers).ok().unwrap();
+    };
+    let (req, extra) = read(4);
+    assert!(req.is_body_spooled() && req.body.is_empty());
+    assert_eq!((req.body_len(), &extra[..]), (11, &b"GET"[..]));
+    let mut body = String::new();
+    req.body_reader().unwrap().read_to_string(&mut body).unwrap();
+    assert_eq!(body, "hello world");
+    assert_eq!(req.get_body(), b"hello world");
+
+    let (req, extra) = read(11);
+    assert!(!req.is_body_spooled());
+    assert_eq!((req.get_body(), &extra[..]),
+            (&b"hello world"[..], &b"GET"[..]));
+}
+
+#[test]
+fn test_read_request_budget() {
+    let buffers = BufferPool::new(8, 2);
+    let budget = Arc::new(MemoryBudget::new(15));
+    let raw = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789";
+    let read = |spool_over: Option<usize>| {
+        let mut stream = io::Cursor::new(raw.as_bytes().to_vec());
+        return read_request(&mut stream, 1000, 1000, spool_over,
+                Some(&budget), None, &buffers);
+    };
+    let first = read(None).ok().unwrap();
+    // Only 5 bytes left while the first is held
+    assert!(matches!(read(None), Err(Error::OverBudget)));
+    // Spooled bodies aren't counted
+    assert!(read(Some(4)).ok().unwrap().0.is_body_spooled());
+    drop(first);
+    assert_eq!(read(None).ok().unwrap().0.get_body(), b"0123456789");
+}
+
+#[test]
+fn test_read_request_spooled_form() {
+    let raw = "POST / HTTP/1.1\r\n\
+            Content-Type: application/x-www-form-urlencoded\r\n\
+            Content-Length: 13\r\n\r\ntoken=x&a=b+c";
+    let mut stream = io::Cursor::new(raw.as_bytes().to_vec());
+    let (req, _) = read_request(&mut stream, 1000, 1000, Some(4), None, None,
+            &BufferPool::new(8, 2)).ok().unwrap();
+    assert!(req.is_body_spooled());
+    assert_eq!(req.get_form(), [("token".to_string(), "x".to_string()),
+            ("a".to_string(), "b c".to_string())]);
 }
@@ -0,0 +1,124 @@
+//! Request ID middleware, for correlating logs across services
+
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{WebRequest, WebResponse, Middleware, Next, LogExtras};
+
+
+/// The id of the current request, stored in the request extensions by
+/// `SetRequestId`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+
+/// Middleware that gives every request an id.
+///
+/// The id is taken from the incoming `X-Request-Id` header if there is a
+/// sane one, otherwise a new one is generated.  It is stored as a
+/// `RequestId` extension and as the "request_id" `LogExtras` field (see
+/// `LogFormat::json`), and sent back in the same response header.
+pub struct SetRequestId {
+    header: String,
+    trust_incoming: bool,
+    prefix: String,
+    counter: AtomicUsize,
+}
+
+impl Default for SetRequestId {
+    fn default() -> SetRequestId {
+        return SetRequestId::new();
+    }
+}
+
+impl SetRequestId {
+    pub fn new() -> SetRequestId {
+        // Unique per process start; the counter makes it unique per request
+        let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64,
+            Err(..) => 0,
+        };
+        return SetRequestId {
+            header: "X-Request-Id".to_string(),
+            trust_incoming: true,
+            prefix: format!("{:x}{:04x}", nanos, process::id() & 0xffff),
+            counter: AtomicUsize::new(0),
+        };
+    }
+
+    /// Use a different header name, ex: "X-Correlation-Id"
+    pub fn set_header(&mut self, name: &str) {
+        self.header = name.to_string();
+    }
+
+    /// Whether to adopt an id sent by the client (default true).  Turn this
+    /// off if clients are untrusted and ids must be unique.
+    pub fn set_trust_incoming(&mut self, on: bool) {
+        self.trust_incoming = on;
+    }
+
+    fn generate(&self) -> String {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        return format!("{}-{:x}", self.prefix, n);
+    }
+}
+
+impl Middleware for SetRequestId {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let incoming = match self.trust_incoming {
+            true => req.get_header(&self.header)
+                .filter(|id| valid_id(id))
+                .map(|id| id.to_string()),
+            false => None,
+        };
+        let id = match incoming {
+            Some(id) => id,
+            None => self.generate(),
+        };
+
+        req.get_extensions_mut().insert(RequestId(id.clone()));
+        req.get_extensions_mut().get_or_default::<LogExtras>()
+            .set("request_id", &id);
+        let mut resp = next.run(req);
+        resp.set_header(&self.header, &id);
+        return resp;
+    }
+}
+
+
+// Don't echo anything that could break a header or a log line
+fn valid_id(id: &str) -> bool {
+    return !id.is_empty() && id.len() <= 200
+        && id.bytes().all(|b| b > b' ' && b < 0x7f);
+}
+
+
+#[test]
+fn test_request_id() {
+    use super::{Chain, Handler};
+
+    let mut chain = Chain::new(|req: &WebRequest| {
+        let id = req.get_extensions().get::<RequestId>().unwrap();
+        let extras = req.get_extensions().get::<LogExtras>().unwrap();
+        assert_eq!(extras.get("request_id"), Some(&id.0[..]));
+        return WebResponse::new_html(id.0.clone());
+    });
+    chain.add(SetRequestId::new());
+
+    let a = chain.handle(&mut WebRequest::new_for_test("GET", "/"));
+    let b = chain.handle(&mut WebRequest::new_for_test("GET", "/"));
+    let id_a = a.get_header("x-request-id").unwrap();
+    assert_eq!(a.body, id_a.as_bytes());
+    assert!(id_a != b.get_header("X-Request-Id").unwrap());
+
+    let mut req = WebRequest::parse_for_test(
+            "GET / HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n");
+    let resp = chain.handle(&mut req);
+    assert_eq!(resp.get_header("X-Request-Id"), Some("abc-123"));
+
+    let mut req = WebRequest::parse_for_test(
+            "GET / HTTP/1.1\r\nX-Request-Id: a b\r\n\r\n");
+    let resp = chain.handle(&mut req);
+    assert!(resp.get_header("X-Request-Id").unwrap() != "a b");
+}
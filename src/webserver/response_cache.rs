@@ -0,0 +1,340 @@
+//! An in-memory cache of whole responses
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use utils::byteutils;
+use super::{WebRequest, WebResponse, Middleware, Next};
+
+// Codes cacheable without being told so (RFC 7231 6.1), apart from 206
+static CACHEABLE: [i32; 7] = [200, 203, 204, 300, 301, 404, 410];
+
+
+/// Middleware that keeps responses in memory for a while, and answers the
+/// same requests from there without running the handler: for pages that
+/// are asked for often but change rarely.
+///
+/// Responses to GET are kept for the TTL, or their `max-age` when their
+/// Cache-Control has one, by Host, path and query, and the request
+/// headers their Vary names; HEAD requests are answered from them too.
+/// So a handler answering by Accept-Language, with `Vary: Accept-Language`,
+/// gets a response kept for each language asked for.  The values are
+/// compared without the spaces around their commas, so "gzip, br" and
+/// "gzip,br" share one.
+/// Only codes cacheable by default (200, 404, and the like) are kept, and
+/// not responses that are streamed, set cookies, have Cache-Control
+/// no-store, no-cache or private, or `Vary: *`, or answer a request with
+/// Authorization.  Answers from the cache have an Age header.
+///
+/// The handler's response is kept as it leaves the cache, so add
+/// middleware that changes responses for each request (ex: `SetRequestId`)
+/// before the cache, and `Compress` after it.
+///
+/// ex:
+///
+/// ```ignore
+/// let mut chain = Chain::new(report);
+/// chain.add(ResponseCache::new(Duration::from_secs(10)));
+/// router.get("/report", chain);
+/// ```
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    honor_no_cache: bool,
+    entries: Mutex<HashMap<String, Vec<Cached>>>,
+}
+
+// One response, for the request header values its Vary names
+struct Cached {
+    vary: Vec<(String, Option<String>)>,
+    code: i32,
+    status: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration) -> ResponseCache {
+        return ResponseCache {
+            ttl: ttl,
+            max_entries: 1000,
+            honor_no_cache: false,
+            entries: Mutex::new(HashMap::new()),
+        };
+    }
+
+    /// Most responses kept at once (default 1000).  Once full, new ones
+    /// aren't kept until others expire.
+    pub fn set_max_entries(&mut self, n: usize) {
+        self.max_entries = n;
+    }
+
+    /// Run the handler, and keep its new response, for requests with
+    /// Cache-Control: no-cache (or Pragma: no-cache), as browsers send when
+    /// reloading.  Off by default, so clients can't get around the cache.
+    pub fn set_honor_no_cache(&mut self, on: bool) {
+        self.honor_no_cache = on;
+    }
+
+    /// Forget all responses, ex: when what they were made from changed
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<Cached>>> {
+        match self.entries.lock() {
+            Ok(guard) => return guard,
+            Err(poisoned) => return poisoned.into_inner(),
+        }
+    }
+
+    fn lookup(&self, key: &str, req: &WebRequest, now: Instant)
+            -> Option<WebResponse> {
+        let entries = self.lock();
+        let cached = entries.get(key)?.iter().find(|c| c.expires > now
+                && c.vary.iter().all(|(name, value)|
+                    vary_value(req, name) == *value))?;
+        let mut resp = WebResponse::new();
+        resp.set_code(cached.code, &cached.status);
+        resp.headers = cached.headers.clone();
+        resp.set_header("Age",
+                &now.duration_since(cached.stored).as_secs().to_string());
+        resp.set_body(&cached.body);
+        return Some(resp);
+    }
+
+    fn store(&self, key: String, req: &WebRequest, resp: &WebResponse,
+            now: Instant) {
+        let ttl = match self.response_ttl(req, resp) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let vary: Vec<(String, Option<String>)> = vary_names(resp)
+            .into_iter().map(|name| {
+                let value = vary_value(req, &name);
+                return (name, value);
+            })
+            .collect();
+
+        let mut entries = self.lock();
+        let count = |entries: &HashMap<String, Vec<Cached>>| -> usize {
+            return entries.values().map(|v| v.len()).sum();
+        };
+        if count(&entries) >= self.max_entries {
+            for variants in entries.values_mut() {
+                variants.retain(|c| c.expires > now);
+            }
+            entries.retain(|_, variants| !variants.is_empty());
+        }
+        // In place of the old one for these header values
+        let replacing = entries.get(&key)
+            .map(|variants| variants.iter().any(|c| c.vary == vary));
+        if count(&entries) >= self.max_entries && replacing != Some(true) {
+            return;
+        }
+        let variants = entries.entry(key).or_default();
+        variants.retain(|c| c.vary != vary);
+        variants.push(Cached {
+            vary: vary,
+            code: resp.code,
+            status: resp.status.clone(),
+            headers: resp.headers.clone(),
+            body: resp.body.clone(),
+            stored: now,
+            expires: now + ttl,
+        });
+    }
+
+    // How long to keep the response for, if at all
+    fn response_ttl(&self, req: &WebRequest, resp: &WebResponse)
+            -> Option<Duration> {
+        if !CACHEABLE.contains(&resp.code) || resp.is_streamed()
+                || resp.get_header("Set-Cookie").is_some()
+                || vary_names(resp).iter().any(|name| name == "*")
+                || req.get_header("Authorization").is_some() {
+            return None;
+        }
+        let mut ttl = self.ttl;
+        let cache_control = resp.get_header("Cache-Control").unwrap_or("");
+        for directive in cache_control.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                _ => (),
+            }
+            if let Some(secs) = directive.strip_prefix("max-age=") {
+                ttl = Duration::from_secs(secs.trim_matches('"').parse()
+                        .ok()?);
+            }
+        }
+        if ttl == Duration::from_secs(0) {
+            return None;
+        }
+        return Some(ttl);
+    }
+}
+
+impl Middleware for ResponseCache {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let head = match req.get_method() {
+            "get" => false,
+            "head" => true,
+            _ => return next.run(req),
+        };
+        let key = format!("{} {}", req.get_header("Host").unwrap_or(""),
+                String::from_utf8_lossy(&req.environ[&b"request_uri"[..]]));
+        let now = Instant::now();
+        if !(self.honor_no_cache && wants_no_cache(req)) {
+            if let Some(resp) = self.lookup(&key, req, now) {
+                return resp;
+            }
+        }
+        let resp = next.run(req);
+        // A handler may leave the body out for HEAD
+        if !head {
+            self.store(key, req, &resp, now);
+        }
+        return resp;
+    }
+}
+
+// The request headers named by all the response's Vary headers, in
+// lowercase, each once
+fn vary_names(resp: &WebResponse) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for (_, value) in resp.get_headers().filter(|(k, _)|
+            k.eq_ignore_ascii_case("Vary")) {
+        for name in byteutils::split_list(value.as_bytes()) {
+            let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    return names;
+}
+
+// The value of a request header the response varies by, without the
+// spaces around its commas
+fn vary_value(req: &WebRequest, name: &str) -> Option<String> {
+    let value = req.get_header(name)?;
+    let items: Vec<&[u8]> = byteutils::split_list(value.as_bytes()).collect();
+    return Some(String::from_utf8_lossy(&items.join(&b","[..])).into_owned());
+}
+
+fn wants_no_cache(req: &WebRequest) -> bool {
+    let no_cache = |header: &str| byteutils::has_token(
+            req.get_header(header).unwrap_or("").as_bytes(), b"no-cache");
+    return no_cache("Cache-Control") || no_cache("Pragma");
+}
+
+
+#[test]
+fn test_response_cache() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::{Chain, Handler};
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let handler_runs = runs.clone();
+    let mut chain = Chain::new(move |req: &WebRequest| {
+        let n = handler_runs.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut resp = WebResponse::new();
+        resp.set_header("Vary", "Accept-Language");
+        resp.set_body_str(&format!("{} {}", req.get_path(), n));
+        match req.get_path() {
+            "/cookie" => resp.set_header("Set-Cookie", "a=1"),
+            "/private" => resp.set_header("Cache-Control", "private"),
+            _ => (),
+        }
+        return resp;
+    });
+    let mut cache = ResponseCache::new(Duration::from_secs(60));
+    cache.set_honor_no_cache(true);
+    chain.add(cache);
+    let get = |raw: &str| {
+        let mut resp = chain.handle(&mut WebRequest::parse_for_test(raw));
+        return (String::from_utf8(resp.body_for_test()).unwrap(),
+                resp.get_header("Age").is_some());
+    };
+
+    let a = "GET /a HTTP/1.1\r\nAccept-Language: en\r\n\r\n";
+    assert_eq!(get(a), ("/a 1".to_string(), false));
+    assert_eq!(get(a), ("/a 1".to_string(), true));
+    assert_eq!(get("HEAD /a HTTP/1.1\r\nAccept-Language: en\r\n\r\n").0,
+            "/a 1");
+    // Another variant, and another path
+    assert_eq!(get("GET /a HTTP/1.1\r\nAccept-Language: fr\r\n\r\n").0,
+            "/a 2");
+    assert_eq!(get("GET /a?x HTTP/1.1\r\nAccept-Language: en\r\n\r\n").0,
+            "/a 3");
+    assert_eq!(get("POST /a HTTP/1.1\r\nAccept-Language: en\r\n\r\n").0,
+            "/a 4");
+    // Reloaded
+    assert_eq!(get("GET /a HTTP/1.1\r\nAccept-Language: en\r\n\
+            Cache-Control: no-cache\r\n\r\n").0, "/a 5");
+    assert_eq!(get(a).0, "/a 5");
+
+    assert_eq!(get("GET /cookie HTTP/1.1\r\n\r\n").0, "/cookie 6");
+    assert_eq!(get("GET /cookie HTTP/1.1\r\n\r\n").0, "/cookie 7");
+    assert_eq!(get("GET /private HTTP/1.1\r\n\r\n").0, "/private 8");
+    assert_eq!(get("GET /private HTTP/1.1\r\n\r\n").0, "/private 9");
+    assert_eq!(runs.load(Ordering::SeqCst), 9);
+}
+
+#[test]
+fn test_response_cache_expiry() {
+    let mut cache = ResponseCache::new(Duration::from_secs(10));
+    cache.set_max_entries(2);
+    let t = Instant::now();
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    cache.store("a".to_string(), &req, &resp, t);
+    assert!(cache.lookup("a", &req, t + Duration::from_secs(9)).is_some());
+    assert!(cache.lookup("a", &req, t + Duration::from_secs(10)).is_none());
+
+    resp.set_header("Cache-Control", "public, max-age=100");
+    cache.store("b".to_string(), &req, &resp, t);
+    // Full
+    cache.store("c".to_string(), &req, &resp, t);
+    assert!(cache.lookup("c", &req, t).is_none());
+    // Until a expires
+    cache.store("c".to_string(), &req, &resp, t + Duration::from_secs(10));
+    assert!(cache.lookup("b", &req, t + Duration::from_secs(99)).is_some());
+    assert!(cache.lookup("c", &req, t + Duration::from_secs(99)).is_some());
+
+    let mut page = cache.lookup("b", &req, t + Duration::from_secs(5)).unwrap();
+    assert_eq!(page.get_header("Age"), Some("5"));
+    assert!(page.body_for_test().is_empty());
+    cache.clear();
+    assert!(cache.lookup("b", &req, t).is_none());
+}
+
+#[test]
+fn test_response_cache_vary() {
+    let cache = ResponseCache::new(Duration::from_secs(10));
+    let t = Instant::now();
+    let ask = |encoding: &str, language: &str| WebRequest::parse_for_test(
+            &format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\
+            Accept-Language: {}\r\n\r\n", encoding, language));
+    let mut resp = WebResponse::new();
+    resp.add_header("Vary", "Accept-Encoding");
+    resp.add_header("vary", "accept-language, Accept-Encoding");
+    assert_eq!(vary_names(&resp), ["accept-encoding", "accept-language"]);
+
+    cache.store("a".to_string(), &ask("gzip, br", "en"), &resp, t);
+    assert!(cache.lookup("a", &ask("gzip,br", "en"), t).is_some());
+    assert!(cache.lookup("a", &ask("  gzip ,  br ", "en"), t).is_some());
+    assert!(cache.lookup("a", &ask("gzip", "en"), t).is_none());
+    assert!(cache.lookup("a", &ask("gzip, br", "fr"), t).is_none());
+    // Both kept
+    cache.store("a".to_string(), &ask("gzip", "en"), &resp, t);
+    assert!(cache.lookup("a", &ask("gzip, br", "en"), t).is_some());
+    assert!(cache.lookup("a", &ask("gzip", "en"), t).is_some());
+
+    resp.add_header("Vary", "*");
+    assert!(cache.response_ttl(&ask("gzip", "en"), &resp).is_none());
+}
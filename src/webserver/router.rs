@@ -1,90 +1,667 @@
-use std::collections::HashSet;
-use std::ascii::AsciiExt;
+//! Path based request routing
 
-use super::PageFunction;
-use super::WebRequest;
+use std::collections::BTreeSet;
+use std::slice;
 
+use utils::byteutils;
+use utils::escape::percent_encode;
+use utils::regex::Regex;
+use super::{Handler, Middleware, Next, WebRequest, WebResponse, Guard};
 
-pub enum RoutingResult {
-    FoundRule(PageFunction),
-    NoPathMatch,
-    NoMethodMatch(Vec<String>),
+
+/// Parameters captured from the path by a `Router` pattern.
+///
+/// Stored in the request extensions before the route's handler is called:
+///
+/// ```ignore
+/// let params = req.get_extensions().get::<RouteParams>().unwrap();
+/// let user_id = params.get("id").unwrap();
+/// ```
+///
+/// Values are percent decoded and utf8 (lossy) decoded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteParams {
+    params: Vec<(String, String)>,
+}
+
+impl RouteParams {
+    /// Value of the parameter `name`, e.g. "id" for a `:id` segment.
+    ///
+    /// If the name was captured more than once, e.g. by a `mount` prefix and
+    /// by a route of the mounted router, the innermost value is returned.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        for (k, v) in self.params.iter().rev() {
+            if k == name {
+                return Some(v);
+            }
+        }
+        return None;
+    }
+
+    /// All (name, value) pairs, in pattern order
+    pub fn iter(&self) -> slice::Iter<'_, (String, String)> {
+        return self.params.iter();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.params.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.params.is_empty();
+    }
+}
+
+
+/// The route a `Router` matched a request with, as it was added: ex:
+/// "/users/:id" for "/users/12".  Routes of mounted routers are prefixed
+/// with the mount's.
+///
+/// Stored in the request extensions, like `RouteParams`, for handlers and
+/// middleware that want a label for their path without its values, ex:
+/// for metrics.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchedRoute(pub String);
+
+// The prefix of the mounts a request went through
+struct MountedRoute(String);
+
+
+// One '/' separated component of a pattern
+enum Segment {
+    Literal(String),
+    // ":name", matches a single non-empty segment
+    Param(String),
+    // "*name", matches the rest of the path; must be last
+    Rest(String),
+}
+
+enum Matcher {
+    Exact(String),
+    Prefix(String),
+    Pattern(Vec<Segment>),
+    Regex(Regex),
+    Mount(Vec<Segment>),
 }
 
 struct Rule {
-    path: String,
-    is_prefix: bool,
+    matcher: Matcher,
+    // As given, for MatchedRoute
+    pattern: String,
+    // Lowercase.  Empty means any method.
     methods: Vec<String>,
-    page_fn: PageFunction,
+    handler: Box<dyn Handler>,
+    // For url_for
+    name: Option<String>,
+    guards: Vec<Box<dyn Guard>>,
 }
 
 
+/// Dispatches requests to handlers by path.
+///
+/// A `Router` is itself a `Handler`, so it can be installed with
+/// `WebServer::set_handler`.  Rules are checked in the order they were added,
+/// and the first match wins, except that regex rules (see `add_regex`) are
+/// only checked after all other rules.
+///
+/// Requests that match no rule are passed to the not found handler (see
+/// `set_not_found`), which by default sends a plain 404.
 pub struct Router {
-    rules: Vec<Rule>
+    rules: Vec<Rule>,
+    not_found: Option<Box<dyn Handler>>,
+    // Not found handlers by mount style prefix
+    fallbacks: Vec<(Vec<Segment>, Box<dyn Handler>)>,
+    trailing_slash: TrailingSlash,
+    middleware: Vec<Box<dyn Middleware>>,
 }
 
 
+/// How a `Router` treats a trailing '/' on the request path.
+///
+/// The policy only applies when no rule matches the path as requested, so
+/// rules registered with and without the slash still work as registered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrailingSlash {
+    /// "/foo" and "/foo/" are different paths.  The default.
+    Strict,
+    /// Try the path with the slash added or removed, and dispatch to that
+    /// rule as if it had been requested.
+    Ignore,
+    /// Like `Ignore`, but redirect the client to the other path instead.  The
+    /// code must be 301, 302, 307, or 308.  Note that 301/302 may turn a
+    /// POST into a GET; 307/308 keep the method and body.
+    Redirect(i32),
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        return Router::new();
+    }
+}
+
 impl Router {
     pub fn new() -> Router {
-        Router { rules: Vec::new() }
+        return Router {
+            rules: Vec::new(),
+            not_found: None,
+            fallbacks: Vec::new(),
+            trailing_slash: TrailingSlash::Strict,
+            middleware: Vec::new(),
+        };
+    }
+
+    /// Add a pattern rule, matching any method.
+    ///
+    /// The pattern is a '/' separated path, where a segment can be:
+    ///
+    /// * `name` - matches exactly (after percent decoding)
+    /// * `:name` - captures one non-empty segment
+    /// * `*name` - captures the rest of the path, which may be empty.  Only
+    ///   allowed as the last segment.
+    ///
+    /// ex: `/users/:id/posts/*rest` matches `/users/42/posts/2015/06`, with
+    /// id = "42" and rest = "2015/06".  Captures are stored as `RouteParams`
+    /// in the request extensions.
+    ///
+    /// # Panics
+    /// If the pattern doesn't start with '/', or is otherwise malformed.
+    pub fn add<H: Handler + 'static>(&mut self, pattern: &str, handler: H) {
+        self.push_rule(Matcher::Pattern(parse_pattern(pattern)), pattern,
+                Vec::new(), handler);
+    }
+
+    /// Add a pattern rule for the given methods (comma separated, like
+    /// `add_path`).  See `add` for the pattern syntax.
+    ///
+    /// If the path matches a rule, but the method doesn't match any rule for
+    /// that path, a 405 is sent with an `Allow` header listing the methods
+    /// that would have matched.  A rule allowing GET also allows HEAD.
+    pub fn route<H: Handler + 'static>(&mut self, methods: &str,
+            pattern: &str, handler: H) {
+        self.push_rule(Matcher::Pattern(parse_pattern(pattern)), pattern,
+                parse_methods(methods), handler);
+    }
+
+    /// Like `route`, but also name the rule, so its path can be built with
+    /// `url_for`.
+    ///
+    /// # Panics
+    /// If the name was used before.
+    pub fn route_named<H: Handler + 'static>(&mut self, name: &str,
+            methods: &str, pattern: &str, handler: H) {
+        assert!(!self.rules.iter().any(|r| r.name.as_deref() == Some(name)),
+                "duplicate route name {}", name);
+        self.route(methods, pattern, handler);
+        self.rules.last_mut().unwrap().name = Some(name.to_string());
+    }
+
+    /// Build the path for the rule called `name` (see `route_named`), filling
+    /// in its parameters from `params`, ex: `url_for("user_detail",
+    /// &[("id", "42")])` gives "/users/42".
+    ///
+    /// Values are percent encoded, except that the '/' in a `*name` value
+    /// is kept.  Returns None if there's no such rule, or a parameter is
+    /// missing or an empty `:name`.  Parameters the pattern doesn't use are
+    /// ignored.
+    ///
+    /// For a mounted router the path is relative to the mount point; prefix
+    /// it with environ[script_name].
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)])
+            -> Option<String> {
+        let rule = self.rules.iter()
+            .find(|r| r.name.as_deref() == Some(name))?;
+        let segments = match rule.matcher {
+            Matcher::Pattern(ref segments) => segments,
+            _ => return None,
+        };
+        let param = |name: &str| params.iter()
+            .find(|&&(k, _)| k == name).map(|&(_, v)| v);
+        let mut ret = String::new();
+        for segment in segments.iter() {
+            ret.push('/');
+            match *segment {
+                Segment::Literal(ref s) => ret.push_str(&percent_encode(s)),
+                Segment::Param(ref name) => {
+                    let value = param(name).filter(|v| !v.is_empty())?;
+                    ret.push_str(&percent_encode(value));
+                }
+                Segment::Rest(ref name) => {
+                    let value = param(name)?;
+                    let parts: Vec<String> = value.split('/')
+                        .map(percent_encode).collect();
+                    ret.push_str(&parts.join("/"));
+                }
+            }
+        }
+        return Some(ret);
+    }
+
+    /// Add a regular expression rule for the given methods.
+    ///
+    /// The regex must match the whole (percent decoded) path.  Named groups,
+    /// `(?P<name>...)` or `(?<name>...)`, are stored as `RouteParams`.
+    ///
+    /// Regex rules have lower precedence than all exact, prefix, and pattern
+    /// rules, regardless of the order they were added in.  So they can be
+    /// used for the odd URL scheme without shadowing the simple routes.
+    ///
+    /// ex: `router.add_regex("get", "/archive/(?P<year>\\d{4})(-\\d\\d)?",
+    /// archive_page)`
+    ///
+    /// # Panics
+    /// If the regex is invalid.
+    pub fn add_regex<H: Handler + 'static>(&mut self, methods: &str,
+            regex: &str, handler: H) {
+        let anchored = format!("^(?:{})$", regex);
+        let compiled = match Regex::new(&anchored) {
+            Ok(r) => r,
+            Err(err) => panic!("invalid route regex {}: {}", regex, err),
+        };
+        self.push_rule(Matcher::Regex(compiled), regex, parse_methods(methods),
+                handler);
+    }
+
+    /// Pass all requests under `prefix` to `handler`, typically another
+    /// `Router`, with the prefix removed from the path it sees.
+    ///
+    /// The prefix matches whole segments: "/api" matches "/api" and
+    /// "/api/users", but not "/apiary".  It may contain `:name` segments (see
+    /// `add`), but not `*name`.
+    ///
+    /// For the inner handler, `get_path` and environ[path] have the prefix
+    /// stripped (or are "/" if nothing is left), and the stripped raw prefix
+    /// is appended to environ[script_name].  So the original path can be
+    /// rebuilt as script_name + path, like CGI's SCRIPT_NAME and PATH_INFO.
+    ///
+    /// Any method matches, and the mounted handler's response is final: if a
+    /// mounted router has no matching rule, its own not found handler runs.
+    pub fn mount<H: Handler + 'static>(&mut self, prefix: &str, handler: H) {
+        let segments = parse_mount_prefix(prefix);
+        self.push_rule(Matcher::Mount(segments), prefix, Vec::new(), handler);
+    }
+
+    /// Shortcut for `route("get", pattern, handler)`.  Also allows HEAD.
+    pub fn get<H: Handler + 'static>(&mut self, pattern: &str, handler: H) {
+        self.route("get", pattern, handler);
+    }
+
+    /// Shortcut for `route("post", pattern, handler)`
+    pub fn post<H: Handler + 'static>(&mut self, pattern: &str, handler: H) {
+        self.route("post", pattern, handler);
+    }
+
+    /// Shortcut for `route("put", pattern, handler)`
+    pub fn put<H: Handler + 'static>(&mut self, pattern: &str, handler: H) {
+        self.route("put", pattern, handler);
+    }
+
+    /// Shortcut for `route("delete", pattern, handler)`
+    pub fn delete<H: Handler + 'static>(&mut self, pattern: &str,
+            handler: H) {
+        self.route("delete", pattern, handler);
+    }
+
+    /// Shortcut for `route("patch", pattern, handler)`
+    pub fn patch<H: Handler + 'static>(&mut self, pattern: &str,
+            handler: H) {
+        self.route("patch", pattern, handler);
+    }
+
+    /// Shortcut for `route("options", pattern, handler)`
+    pub fn options<H: Handler + 'static>(&mut self, pattern: &str,
+            handler: H) {
+        self.route("options", pattern, handler);
+    }
+
+    /// Add an exact path match rule.  See `WebServer::add_path`.
+    pub fn add_path<H: Handler + 'static>(&mut self, methods: &str,
+            path: &str, handler: H) {
+        self.push_rule(Matcher::Exact(path.to_string()), path,
+                parse_methods(methods), handler);
+    }
+
+    /// Add a prefix path match rule.  See `WebServer::add_path_prefix`.
+    pub fn add_path_prefix<H: Handler + 'static>(&mut self, methods: &str,
+            path: &str, handler: H) {
+        self.push_rule(Matcher::Prefix(path.to_string()), path,
+                parse_methods(methods), handler);
+    }
+
+    /// Add a guard to the rule added last.  The rule only handles requests
+    /// that pass all its guards; others go on to the next matching rule.
+    ///
+    /// If no rule handles the request, and some rule was skipped only
+    /// because of a guard, the response is that guard's rejection, ex: a
+    /// 415 for a `ContentTypeGuard`.
+    ///
+    /// ex:
+    ///
+    /// ```ignore
+    /// router.post("/items", create_item_json);
+    /// router.add_guard(ContentTypeGuard::new("application/json"));
+    /// router.post("/items", create_item_form);
+    /// router.add_guard(ContentTypeGuard::new(
+    ///     "application/x-www-form-urlencoded"));
+    /// ```
+    ///
+    /// # Panics
+    /// If no rule was added yet.
+    pub fn add_guard<G: Guard + 'static>(&mut self, guard: G) {
+        let rule = self.rules.last_mut().expect("add_guard: no rule added");
+        rule.guards.push(Box::new(guard));
+    }
+
+    /// Set the handler for requests that match no rule.
+    pub fn set_not_found<H: Handler + 'static>(&mut self, handler: H) {
+        self.not_found = Some(Box::new(handler));
     }
 
-    pub fn add_path(&mut self, methods: &str, path: &str, 
-            page_fn: PageFunction, is_prefix: bool) {
-        let rule = Rule { 
-            path: path.to_string(), 
-            is_prefix: is_prefix,
-            page_fn: page_fn,
-            methods: parse_methods(methods),
+    /// Set the handler for requests under `prefix` that match no rule,
+    /// instead of the not found handler, ex: to send JSON errors for
+    /// unknown API endpoints.
+    ///
+    /// The prefix matches whole segments and may contain `:name` segments,
+    /// like `mount`.  If several fallback prefixes match, the longest one
+    /// wins.  The handler sees the full path, not stripped like for `mount`.
+    pub fn add_fallback<H: Handler + 'static>(&mut self, prefix: &str,
+            handler: H) {
+        let segments = parse_mount_prefix(prefix);
+        self.fallbacks.push((segments, Box::new(handler)));
+    }
+
+    /// Run `middleware` around every request this router handles, including
+    /// 404 and 405 responses.  The first middleware added is the outermost.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Set the trailing slash policy.  Mounted routers have their own policy.
+    pub fn set_trailing_slash(&mut self, policy: TrailingSlash) {
+        if let TrailingSlash::Redirect(code) = policy {
+            assert!([301, 302, 307, 308].contains(&code),
+                    "not a redirect code: {}", code);
+        }
+        self.trailing_slash = policy;
+    }
+
+    fn push_rule<H: Handler + 'static>(&mut self, matcher: Matcher,
+            pattern: &str, methods: Vec<String>, handler: H) {
+        let rule = Rule {
+            matcher: matcher,
+            pattern: pattern.to_string(),
+            methods: methods,
+            handler: Box::new(handler),
+            name: None,
+            guards: Vec::new(),
         };
         self.rules.push(rule);
     }
+}
+
+impl Rule {
+    fn is_regex(&self) -> bool {
+        match self.matcher {
+            Matcher::Regex(..) => return true,
+            _ => return false,
+        }
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        if self.methods.is_empty() {
+            return true;
+        }
+        for m in self.methods.iter() {
+            if m == method || (m == "get" && method == "head") {
+                return true;
+            }
+        }
+        return false;
+    }
+}
+
+// Result of looking up the rule for a request
+enum Lookup<'a> {
+    Found(&'a Rule, Option<RouteParams>, Option<(Vec<u8>, Vec<u8>)>),
+    // Path matched, but not the method.  Contains the allowed methods.
+    WrongMethod(BTreeSet<String>),
+    // Path and method matched, but a guard failed: its rejection
+    Rejected(i32, String),
+    NotFound,
+}
 
-    pub fn route(&self, req: &WebRequest) -> RoutingResult {
+impl Router {
+    fn lookup(&self, req: &WebRequest) -> Lookup<'_> {
         let mut found_path_match = false;
-        let mut found_methods = HashSet::<&str>::new();
+        let mut found_methods = BTreeSet::<String>::new();
+        let mut rejection = None;
 
-        for rule in self.rules.iter() {
-            let matched = {
-                if rule.is_prefix {
-                    req.path.starts_with(&rule.path)
-                } else {
-                    req.path == rule.path
+        let plain_rules = self.rules.iter().filter(|r| !r.is_regex());
+        let regex_rules = self.rules.iter().filter(|r| r.is_regex());
+        for rule in plain_rules.chain(regex_rules) {
+            let mut mount_split = None;
+            let params = match rule.matcher {
+                Matcher::Exact(ref path) => {
+                    if req.path != *path { continue; }
+                    None
+                }
+                Matcher::Prefix(ref path) => {
+                    if !req.path.starts_with(&**path) { continue; }
+                    None
+                }
+                Matcher::Pattern(ref segments) => {
+                    match match_pattern(segments, req) {
+                        Some(params) => Some(params),
+                        None => continue,
+                    }
+                }
+                Matcher::Regex(ref regex) => {
+                    match match_regex(regex, req) {
+                        Some(params) => Some(params),
+                        None => continue,
+                    }
+                }
+                Matcher::Mount(ref segments) => {
+                    match match_mount(segments, req) {
+                        Some((params, prefix, rest)) => {
+                            mount_split = Some((prefix, rest));
+                            Some(params)
+                        }
+                        None => continue,
+                    }
                 }
             };
-            if matched {
-                found_path_match = true;
-                // Now check methods
+
+            found_path_match = true;
+            if !rule.allows_method(&req.method) {
+                // Method doesn't match, but save them for possible error
                 for method in rule.methods.iter() {
-                    if *method == req.method {
-                        // Found a rule match
-                        return RoutingResult::FoundRule(rule.page_fn);
+                    found_methods.insert(method.to_ascii_uppercase());
+                    if method == "get" {
+                        found_methods.insert("HEAD".to_string());
                     }
-
-                    // Method doesn't match, but save it for possible error
-                    found_methods.insert(&method);
                 }
+                continue;
+            }
+            if let Some(guard) = rule.guards.iter().find(|g| !g.check(req)) {
+                if rejection.is_none() {
+                    rejection = Some(guard.rejection());
+                }
+                continue;
             }
+
+            return Lookup::Found(rule, params, mount_split);
         }
 
+        match rejection {
+            Some((404, _)) => return Lookup::NotFound,
+            Some((code, status)) =>
+                return Lookup::Rejected(code, status.to_string()),
+            None => (),
+        }
         if found_path_match {
-            // A path matched but didn't support the requested method
-            // Return the available methods
-            let mut methods = Vec::new();
-            for method in found_methods.iter() {
-                methods.push(method.to_string());
+            return Lookup::WrongMethod(found_methods);
+        }
+        return Lookup::NotFound;
+    }
+
+    // Retry a failed lookup with the trailing slash added or removed
+    fn lookup_other_slash(&self, req: &mut WebRequest) -> WebResponse {
+        let raw_path = req.environ[&b"path"[..]].clone();
+        let other = match toggle_trailing_slash(&raw_path) {
+            Some(other) => other,
+            None => return self.respond(req, Lookup::NotFound),
+        };
+        let saved_path = req.path.clone();
+        req.path = decode_segment(&other);
+        req.environ.insert(b"path".to_vec(), other.clone());
+
+        let lookup = self.lookup(req);
+        if let Lookup::NotFound = lookup {
+            req.path = saved_path;
+            req.environ.insert(b"path".to_vec(), raw_path);
+            return self.respond(req, lookup);
+        }
+        match self.trailing_slash {
+            TrailingSlash::Redirect(code) => {
+                let mut location = req.environ[&b"script_name"[..]].clone();
+                location.extend(other);
+                let query = &req.environ[&b"query_string"[..]];
+                if !query.is_empty() {
+                    location.push(b'?');
+                    location.extend(query);
+                }
+                let location = String::from_utf8_lossy(&location);
+                let status = match code {
+                    301 => "Moved Permanently",
+                    302 => "Found",
+                    307 => "Temporary Redirect",
+                    _ => "Permanent Redirect",
+                };
+                let mut resp = WebResponse::new();
+                resp.set_code(code, status);
+                resp.set_body_str(&format!("Redirecting to {}", location));
+                resp.set_header("Location", &location);
+                return resp;
             }
-            return RoutingResult::NoMethodMatch(methods);
-        } else {
-            return RoutingResult::NoPathMatch;
+            _ => return self.respond(req, lookup),
+        }
+    }
+
+    fn respond(&self, req: &mut WebRequest, lookup: Lookup) -> WebResponse {
+        match lookup {
+            Lookup::Found(rule, params, mount_split) => {
+                if let Some(params) = params {
+                    add_params(req, params);
+                }
+                let mounted = req.get_extensions().get::<MountedRoute>()
+                    .map(|m| m.0.clone()).unwrap_or_default();
+                let route = format!("{}{}", mounted, rule.pattern);
+                if let Some((prefix, rest)) = mount_split {
+                    strip_mount_prefix(req, prefix, rest);
+                    // For the mounted router's routes
+                    let prefix = route.trim_end_matches('/').to_string();
+                    req.get_extensions_mut().insert(MountedRoute(prefix));
+                    let resp = rule.handler.handle(req);
+                    req.get_extensions_mut().insert(MountedRoute(mounted));
+                    return resp;
+                }
+                req.get_extensions_mut().insert(MatchedRoute(route));
+                return rule.handler.handle(req);
+            }
+            Lookup::WrongMethod(found_methods) => {
+                // A path matched but didn't support the requested method
+                // Return the available methods, sorted so the header is stable
+                let methods: Vec<String> = found_methods.into_iter().collect();
+                let mut resp = WebResponse::new();
+                resp.set_code(405, "Method not allowed");
+                resp.set_body_str("Error 405: Method not allowed");
+                resp.set_header("Allow", &methods.join(", "));
+                return resp;
+            }
+            Lookup::Rejected(code, status) => {
+                let mut resp = WebResponse::new();
+                resp.set_code(code, &status);
+                resp.set_body_str(&format!("Error {}: {}", code, status));
+                return resp;
+            }
+            Lookup::NotFound => {
+                let mut fallback = None;
+                for (segments, handler) in self.fallbacks.iter() {
+                    if let Some((params, _, _)) = match_mount(segments, req) {
+                        let longer = fallback.as_ref()
+                            .is_none_or(|&(n, _, _)| segments.len() > n);
+                        if longer {
+                            fallback = Some((segments.len(), params, handler));
+                        }
+                    }
+                }
+                if let Some((_, params, handler)) = fallback {
+                    add_params(req, params);
+                    return handler.handle(req);
+                }
+                match self.not_found {
+                    Some(ref handler) => return handler.handle(req),
+                    None => {
+                        let mut resp = WebResponse::new();
+                        resp.set_code(404, "Not Found");
+                        resp.set_body_str("Error 404: Resource not found");
+                        return resp;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        if self.middleware.is_empty() {
+            return self.dispatch(req);
         }
+        return Next::new(&self.middleware, &Dispatch(self)).run(req);
     }
 }
 
+impl Router {
+    fn dispatch(&self, req: &mut WebRequest) -> WebResponse {
+        let lookup = self.lookup(req);
+        if let Lookup::NotFound = lookup {
+            if self.trailing_slash != TrailingSlash::Strict {
+                return self.lookup_other_slash(req);
+            }
+        }
+        return self.respond(req, lookup);
+    }
+}
+
+// The end of the router's middleware chain
+struct Dispatch<'a>(&'a Router);
+
+impl<'a> Handler for Dispatch<'a> {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        return self.0.dispatch(req);
+    }
+}
+
+
+// "/foo" <-> "/foo/".  None for "/" and non-paths like "*"
+fn toggle_trailing_slash(raw_path: &[u8]) -> Option<Vec<u8>> {
+    if raw_path.len() < 2 || raw_path[0] != b'/' {
+        return None;
+    }
+    let mut ret = raw_path.to_vec();
+    if ret.ends_with(b"/") {
+        ret.pop();
+    } else {
+        ret.push(b'/');
+    }
+    return Some(ret);
+}
+
 
 // Return: array of methods, trimmed and in lowercase
 fn parse_methods(methods: &str) -> Vec<String> {
-    let parts = methods.split(",");
+    let parts = methods.split(',');
     let mut ret = Vec::new();
     for p in parts {
         let method = p.trim().to_string().to_ascii_lowercase();
@@ -92,3 +669,522 @@ fn parse_methods(methods: &str) -> Vec<String> {
     }
     return ret;
 }
+
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(pattern.starts_with('/'), "pattern must start with '/'");
+    let parts: Vec<&str> = pattern[1..].split('/').collect();
+    let mut ret = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        if let Some(name) = part.strip_prefix(':') {
+            assert!(!name.is_empty(), "empty parameter name in {}", pattern);
+            ret.push(Segment::Param(name.to_string()));
+        } else if let Some(name) = part.strip_prefix('*') {
+            assert!(!name.is_empty(), "empty parameter name in {}", pattern);
+            assert!(i == parts.len() - 1, "*{} must be last in {}",
+                    name, pattern);
+            ret.push(Segment::Rest(name.to_string()));
+        } else {
+            ret.push(Segment::Literal(part.to_string()));
+        }
+    }
+    return ret;
+}
+
+
+fn parse_mount_prefix(prefix: &str) -> Vec<Segment> {
+    let trimmed = prefix.trim_end_matches('/');
+    let segments = if trimmed.is_empty() {
+        assert!(prefix == "/", "prefix must start with '/'");
+        Vec::new()
+    } else {
+        parse_pattern(trimmed)
+    };
+    for segment in segments.iter() {
+        if let Segment::Rest(..) = *segment {
+            panic!("*name is not allowed in mount prefix {}", prefix);
+        }
+    }
+    return segments;
+}
+
+
+fn decode_segment(raw: &[u8]) -> String {
+    return String::from_utf8_lossy(&byteutils::percent_decode_cow(raw))
+        .into_owned();
+}
+
+
+// Split the raw (not percent decoded) path into segments, without the
+// leading '/'.  Matching on the raw path means an encoded '/' in a parameter
+// doesn't split it.
+fn raw_path_parts(req: &WebRequest) -> Option<Vec<&[u8]>> {
+    match req.environ.get(&b"path"[..]) {
+        Some(p) if p.starts_with(b"/") =>
+            return Some(p[1..].split(|c| *c == b'/').collect()),
+        _ => return None,
+    }
+}
+
+
+// Match segments against the start of parts.  Return the captures and the
+// number of parts consumed.
+fn match_segments(segments: &[Segment], parts: &[&[u8]])
+        -> Option<(RouteParams, usize)> {
+    let mut params = RouteParams::default();
+    for (i, segment) in segments.iter().enumerate() {
+        match *segment {
+            Segment::Rest(ref name) => {
+                if i >= parts.len() {
+                    return None;
+                }
+                let rest = parts[i..].join(&b'/');
+                params.params.push((name.clone(), decode_segment(&rest)));
+                return Some((params, parts.len()));
+            }
+            _ if i >= parts.len() => return None,
+            Segment::Literal(ref lit) => {
+                if decode_segment(parts[i]) != *lit {
+                    return None;
+                }
+            }
+            Segment::Param(ref name) => {
+                if parts[i].is_empty() {
+                    return None;
+                }
+                params.params.push((name.clone(), decode_segment(parts[i])));
+            }
+        }
+    }
+    return Some((params, segments.len()));
+}
+
+
+fn match_pattern(segments: &[Segment], req: &WebRequest) -> Option<RouteParams> {
+    let parts = raw_path_parts(req)?;
+    let (params, consumed) = match_segments(segments, &parts)?;
+    if consumed != parts.len() {
+        return None;
+    }
+    return Some(params);
+}
+
+
+// Return: captures, raw prefix matched, raw remainder of the path
+fn match_mount(segments: &[Segment], req: &WebRequest)
+        -> Option<(RouteParams, Vec<u8>, Vec<u8>)> {
+    let parts = raw_path_parts(req)?;
+    let (params, consumed) = match_segments(segments, &parts)?;
+    let mut prefix = Vec::new();
+    for part in parts[..consumed].iter() {
+        prefix.push(b'/');
+        prefix.extend(*part);
+    }
+    let mut rest = Vec::new();
+    for part in parts[consumed..].iter() {
+        rest.push(b'/');
+        rest.extend(*part);
+    }
+    return Some((params, prefix, rest));
+}
+
+
+fn strip_mount_prefix(req: &mut WebRequest, prefix: Vec<u8>, rest: Vec<u8>) {
+    let rest = if rest.is_empty() { b"/".to_vec() } else { rest };
+    req.path = decode_segment(&rest);
+    req.environ.insert(b"path".to_vec(), rest);
+    let mut script_name = req.environ.get(&b"script_name"[..])
+        .cloned().unwrap_or_default();
+    script_name.extend(prefix);
+    req.environ.insert(b"script_name".to_vec(), script_name);
+}
+
+
+// Add to any params captured by an enclosing router
+fn add_params(req: &mut WebRequest, params: RouteParams) {
+    if let Some(existing) = req.extensions.get_mut::<RouteParams>() {
+        existing.params.extend(params.params);
+        return;
+    }
+    req.extensions.insert(params);
+}
+
+
+fn match_regex(regex: &Regex, req: &WebRequest) -> Option<RouteParams> {
+    let caps = regex.captures(&req.path)?;
+    let mut params = RouteParams::default();
+    for (name, val) in caps.named() {
+        params.params.push((name.to_string(), val.to_string()));
+    }
+    return Some(params);
+}
+
+
+#[cfg(test)]
+fn test_request(method: &str, path: &str) -> WebRequest {
+    return WebRequest::new_for_test(method, path);
+}
+
+#[cfg(test)]
+fn show_params(req: &WebRequest) -> WebResponse {
+    let mut body = String::new();
+    if let Some(params) = req.get_extensions().get::<RouteParams>() {
+        for (k, v) in params.iter() {
+            body.push_str(&format!("{}={};", k, v));
+        }
+    }
+    let mut resp = WebResponse::new();
+    resp.set_body_str(&body);
+    return resp;
+}
+
+#[test]
+fn test_router_patterns() {
+    let mut router = Router::new();
+    router.add("/users/:id/posts/*rest", show_params);
+    router.add("/users/:id", show_params);
+    router.add("/", show_params);
+
+    let resp = router.handle(&mut test_request("GET", "/users/42/posts/a/b"));
+    assert_eq!(resp.body, b"id=42;rest=a/b;");
+    let resp = router.handle(&mut test_request("GET", "/users/42/posts/"));
+    assert_eq!(resp.body, b"id=42;rest=;");
+    let resp = router.handle(&mut test_request("PUT", "/users/a%2Fb%20c"));
+    assert_eq!(resp.body, b"id=a/b c;");
+    let resp = router.handle(&mut test_request("GET", "/"));
+    assert_eq!(resp.code, 200);
+
+    assert_eq!(router.handle(&mut test_request("GET", "/users")).code, 404);
+    assert_eq!(router.handle(&mut test_request("GET", "/users/")).code, 404);
+    assert_eq!(router.handle(&mut test_request("GET", "/users/1/x")).code,
+            404);
+    assert_eq!(router.handle(&mut test_request("GET", "/users/1/posts"))
+            .code, 404);
+}
+
+#[test]
+fn test_router_methods_and_not_found() {
+    let mut router = Router::new();
+    router.add_path("get, head", "/exact", show_params);
+    router.add_path_prefix("post", "/prefix/", show_params);
+
+    assert_eq!(router.handle(&mut test_request("HEAD", "/exact")).code, 200);
+    let resp = router.handle(&mut test_request("POST", "/exact"));
+    assert_eq!(resp.code, 405);
+    assert_eq!(resp.get_header("Allow").unwrap(), "GET, HEAD");
+    assert_eq!(router.handle(&mut test_request("POST", "/prefix/x")).code,
+            200);
+    assert_eq!(router.handle(&mut test_request("GET", "/exact/")).code, 404);
+
+    router.set_not_found(|_req: &WebRequest| {
+        let mut resp = WebResponse::new();
+        resp.set_code(404, "Nope");
+        return resp;
+    });
+    assert_eq!(router.handle(&mut test_request("GET", "/x")).status, "Nope");
+}
+
+#[test]
+fn test_router_method_helpers() {
+    let mut router = Router::new();
+    router.get("/items/:id", show_params);
+    router.put("/items/:id", show_params);
+    router.route("delete, patch", "/items/:id", show_params);
+    router.post("/items", show_params);
+
+    assert_eq!(router.handle(&mut test_request("GET", "/items/1")).body,
+            b"id=1;");
+    assert_eq!(router.handle(&mut test_request("HEAD", "/items/1")).code, 200);
+    assert_eq!(router.handle(&mut test_request("PATCH", "/items/1")).code,
+            200);
+    assert_eq!(router.handle(&mut test_request("POST", "/items")).code, 200);
+
+    let resp = router.handle(&mut test_request("POST", "/items/1"));
+    assert_eq!(resp.code, 405);
+    assert_eq!(resp.get_header("Allow").unwrap(),
+            "DELETE, GET, HEAD, PATCH, PUT");
+    let resp = router.handle(&mut test_request("GET", "/items"));
+    assert_eq!(resp.get_header("Allow").unwrap(), "POST");
+    assert_eq!(router.handle(&mut test_request("GET", "/other")).code, 404);
+}
+
+#[test]
+fn test_router_regex() {
+    let mut router = Router::new();
+    router.add_regex("get", "/archive/(?P<year>\\d{4})(-(?P<month>\\d\\d))?",
+            show_params);
+    router.add_regex("get", "/files/.*", |_req: &WebRequest| {
+        let mut resp = WebResponse::new();
+        resp.set_body_str("regex");
+        return resp;
+    });
+    // Added later, but wins over the regex rule
+    router.get("/files/:name", show_params);
+
+    assert_eq!(router.handle(&mut test_request("GET", "/archive/2015")).body,
+            b"year=2015;");
+    assert_eq!(router.handle(&mut test_request("GET", "/archive/2015-06"))
+            .body, b"year=2015;month=06;");
+    assert_eq!(router.handle(&mut test_request("GET", "/archive/2015-6"))
+            .code, 404);
+    assert_eq!(router.handle(&mut test_request("GET", "/x/archive/2015"))
+            .code, 404);
+    assert_eq!(router.handle(&mut test_request("POST", "/archive/2015"))
+            .code, 405);
+
+    assert_eq!(router.handle(&mut test_request("GET", "/files/a")).body,
+            b"name=a;");
+    assert_eq!(router.handle(&mut test_request("GET", "/files/a/b")).body,
+            b"regex");
+}
+
+#[cfg(test)]
+fn show_mount(req: &WebRequest) -> WebResponse {
+    let env = req.get_environ();
+    let body = format!("{} {} {}", req.get_path(),
+        String::from_utf8_lossy(&env[&b"path"[..]]),
+        String::from_utf8_lossy(&env[&b"script_name"[..]]));
+    let mut resp = show_params(req);
+    resp.body.extend(body.as_bytes());
+    return resp;
+}
+
+#[test]
+fn test_router_mount() {
+    let mut inner = Router::new();
+    inner.get("/", show_mount);
+    inner.get("/items/:id", show_mount);
+
+    let mut api = Router::new();
+    api.mount("/v1/", inner);
+
+    let mut router = Router::new();
+    router.mount("/api", api);
+    router.mount("/users/:user", show_mount);
+    router.get("/apiary", show_mount);
+
+    let resp = router.handle(&mut test_request("GET", "/api/v1/items/a%20b"));
+    assert_eq!(resp.body, &b"id=a b;/items/a b /items/a%20b /api/v1"[..]);
+    let resp = router.handle(&mut test_request("GET", "/api/v1"));
+    assert_eq!(resp.body, &b"/ / /api/v1"[..]);
+    let resp = router.handle(&mut test_request("GET", "/api/v1/"));
+    assert_eq!(resp.body, &b"/ / /api/v1"[..]);
+    let resp = router.handle(&mut test_request("GET", "/users/bob/x/y"));
+    assert_eq!(resp.body, &b"user=bob;/x/y /x/y /users/bob"[..]);
+    let resp = router.handle(&mut test_request("GET", "/apiary"));
+    assert_eq!(resp.body, &b"/apiary /apiary "[..]);
+
+    // The mounted router's 404 is final
+    assert_eq!(router.handle(&mut test_request("GET", "/api/v2")).code, 404);
+    assert_eq!(router.handle(&mut test_request("POST", "/api/v1")).code, 405);
+}
+
+#[test]
+fn test_router_trailing_slash() {
+    let mut router = Router::new();
+    router.get("/foo", show_mount);
+    router.get("/bar/", show_mount);
+    router.get("/both", show_mount);
+    router.post("/both/", show_params);
+
+    assert_eq!(router.handle(&mut test_request("GET", "/foo/")).code, 404);
+
+    router.set_trailing_slash(TrailingSlash::Ignore);
+    let resp = router.handle(&mut test_request("GET", "/foo/"));
+    assert_eq!(resp.body, &b"/foo /foo "[..]);
+    let resp = router.handle(&mut test_request("GET", "/bar"));
+    assert_eq!(resp.body, &b"/bar/ /bar/ "[..]);
+    // Exact matches still win
+    assert_eq!(router.handle(&mut test_request("POST", "/both/")).code, 200);
+    assert_eq!(router.handle(&mut test_request("POST", "/both")).code, 405);
+    assert_eq!(router.handle(&mut test_request("GET", "/baz/")).code, 404);
+
+    let mut outer = Router::new();
+    router.set_trailing_slash(TrailingSlash::Redirect(308));
+    outer.mount("/m", router);
+    let resp = outer.handle(&mut test_request("GET", "/m/foo/?a=b"));
+    assert_eq!(resp.code, 308);
+    assert_eq!(resp.get_header("Location").unwrap(), "/m/foo?a=b");
+    let resp = outer.handle(&mut test_request("GET", "/m/bar"));
+    assert_eq!(resp.get_header("Location").unwrap(), "/m/bar/");
+    assert_eq!(outer.handle(&mut test_request("GET", "/m/baz")).code, 404);
+}
+
+#[test]
+fn test_router_middleware() {
+    let mut inner = Router::new();
+    inner.get("/x", show_mount);
+    inner.add_middleware(::webserver::After::new(
+            |_req: &WebRequest, resp: &mut WebResponse| {
+        resp.body.extend(b" inner");
+    }));
+
+    let mut router = Router::new();
+    router.mount("/m", inner);
+    router.add_middleware(|req: &mut WebRequest, next: Next| {
+        let mut resp = next.run(req);
+        resp.body.extend(b" outer");
+        return resp;
+    });
+
+    let resp = router.handle(&mut test_request("GET", "/m/x"));
+    assert_eq!(resp.body, &b"/x /x /m inner outer"[..]);
+    let resp = router.handle(&mut test_request("GET", "/nope"));
+    assert_eq!(resp.body, &b"Error 404: Resource not found outer"[..]);
+}
+
+#[test]
+#[should_panic]
+fn test_router_bad_redirect_code() {
+    Router::new().set_trailing_slash(TrailingSlash::Redirect(200));
+}
+
+#[test]
+#[should_panic]
+fn test_router_bad_mount() {
+    Router::new().mount("/files/*rest", show_params);
+}
+
+#[test]
+#[should_panic]
+fn test_router_bad_regex() {
+    Router::new().add_regex("get", "/(unclosed", show_params);
+}
+
+#[test]
+#[should_panic]
+fn test_router_bad_pattern() {
+    Router::new().add("/a/*rest/b", show_params);
+}
+
+#[test]
+fn test_router_url_for() {
+    let mut router = Router::new();
+    router.route_named("user_detail", "get", "/users/:id", show_params);
+    router.route_named("files", "get", "/files/*path", show_params);
+    router.route_named("home", "get", "/", show_params);
+    router.get("/unnamed", show_params);
+
+    assert_eq!(router.url_for("user_detail", &[("id", "42"), ("x", "y")]),
+            Some("/users/42".to_string()));
+    assert_eq!(router.url_for("user_detail", &[("id", "a/b c")]),
+            Some("/users/a%2Fb%20c".to_string()));
+    assert_eq!(router.url_for("user_detail", &[("id", "")]), None);
+    assert_eq!(router.url_for("user_detail", &[]), None);
+    assert_eq!(router.url_for("files", &[("path", "docs/read me.txt")]),
+            Some("/files/docs/read%20me.txt".to_string()));
+    assert_eq!(router.url_for("files", &[("path", "")]),
+            Some("/files/".to_string()));
+    assert_eq!(router.url_for("home", &[]), Some("/".to_string()));
+    assert_eq!(router.url_for("nope", &[]), None);
+
+    // The built path routes back to the rule
+    let path = router.url_for("user_detail", &[("id", "a/b c")]).unwrap();
+    let resp = router.handle(&mut WebRequest::new_for_test("GET", &path));
+    assert_eq!(resp.body, &b"id=a/b c;"[..]);
+}
+
+#[test]
+fn test_router_guards() {
+    use super::{ContentTypeGuard, AcceptGuard};
+
+    let mut router = Router::new();
+    router.post("/items", |_req: &WebRequest|
+            WebResponse::new_html("json".to_string()));
+    router.add_guard(ContentTypeGuard::new("application/json"));
+    router.post("/items", |_req: &WebRequest|
+            WebResponse::new_html("form".to_string()));
+    router.add_guard(ContentTypeGuard::new(
+            "application/x-www-form-urlencoded"));
+    router.get("/report", show_params);
+    router.add_guard(AcceptGuard::new("text/csv"));
+    router.get("/admin", show_params);
+    router.add_guard(|req: &WebRequest| req.get_header("X-Admin").is_some());
+
+    let post = |content_type: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "POST /items HTTP/1.1\r\nContent-Type: {}\r\n\r\n",
+                content_type));
+        return router.handle(&mut req);
+    };
+    assert_eq!(post("application/json").body, b"json");
+    assert_eq!(post("application/x-www-form-urlencoded").body, b"form");
+    assert_eq!(post("text/plain").code, 415);
+
+    let mut req = WebRequest::parse_for_test(
+            "GET /report HTTP/1.1\r\nAccept: text/html\r\n\r\n");
+    assert_eq!(router.handle(&mut req).code, 406);
+    // Method checks come first
+    assert_eq!(router.handle(&mut test_request("PUT", "/items")).code, 405);
+    // Closure guards reject with a 404
+    assert_eq!(router.handle(&mut test_request("GET", "/admin")).code, 404);
+    let mut req = WebRequest::parse_for_test(
+            "GET /admin HTTP/1.1\r\nX-Admin: 1\r\n\r\n");
+    assert_eq!(router.handle(&mut req).code, 200);
+}
+
+#[test]
+fn test_router_fallback() {
+    let mut router = Router::new();
+    router.get("/api/users/:id", show_params);
+    router.add_fallback("/api", |req: &WebRequest| {
+        let mut resp = WebResponse::new();
+        resp.set_code(404, "Not Found");
+        resp.set_body_str(&format!("{{\"error\":\"unknown endpoint {}\"}}",
+                req.get_path()));
+        return resp;
+    });
+    router.add_fallback("/api/:version/admin", show_params);
+
+    let resp = router.handle(&mut test_request("GET", "/api/users/1"));
+    assert_eq!(resp.body, &b"id=1;"[..]);
+    let resp = router.handle(&mut test_request("GET", "/api/nope"));
+    assert_eq!(resp.code, 404);
+    assert_eq!(resp.body, &b"{\"error\":\"unknown endpoint /api/nope\"}"[..]);
+    let resp = router.handle(&mut test_request("GET", "/api/v2/admin/x"));
+    assert_eq!(resp.body, &b"version=v2;"[..]);
+    // Not under a prefix: the usual 404, and 405s are unchanged
+    let resp = router.handle(&mut test_request("GET", "/apiary"));
+    assert_eq!(resp.body, &b"Error 404: Resource not found"[..]);
+    assert_eq!(router.handle(&mut test_request("POST", "/api/users/1")).code,
+            405);
+}
+
+#[test]
+fn test_router_matched_route() {
+    fn show_route(req: &WebRequest) -> WebResponse {
+        let mut resp = WebResponse::new();
+        if let Some(route) = req.get_extensions().get::<MatchedRoute>() {
+            resp.set_body_str(&route.0);
+        }
+        return resp;
+    }
+    let mut inner = Router::new();
+    inner.get("/", show_route);
+    inner.get("/items/:id", show_route);
+    let mut router = Router::new();
+    router.mount("/api/:version/", inner);
+    router.mount("/raw", show_route);
+    router.add_path("GET", "/exact", show_route);
+    router.add_regex("GET", "/n/[0-9]+", show_route);
+
+    let route = |path: &str| {
+        let mut req = test_request("GET", path);
+        let resp = router.handle(&mut req);
+        // It stays in the request, after the handler saw it
+        let route = req.get_extensions().get::<MatchedRoute>()
+            .map(|r| r.0.clone()).unwrap_or_default();
+        if resp.code == 200 {
+            assert_eq!(resp.body, route.as_bytes());
+        }
+        return route;
+    };
+    assert_eq!(route("/api/v1/items/12"), "/api/:version/items/:id");
+    assert_eq!(route("/api/v1"), "/api/:version/");
+    assert_eq!(route("/exact"), "/exact");
+    assert_eq!(route("/n/12"), "/n/[0-9]+");
+    // No route matched: the mount's isn't one
+    assert_eq!(route("/raw/x"), "");
+    assert_eq!(route("/api/v1/nope"), "");
+    assert_eq!(route("/nope"), "");
+}
@@ -0,0 +1,184 @@
+//! An SCGI client, for applications served with SCGI (ex: trac, uwsgi)
+//!
+//! https://python.ca/scgi/protocol.txt
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use super::{Handler, WebRequest, WebResponse};
+use super::cgi::{environment, mount_prefix, read_headers, script_response};
+use super::fastcgi::Stream;
+
+
+/// A handler that sends requests to an SCGI application.
+///
+/// Each request gets a connection of its own.  The application is told
+/// about it with the CGI variables (see `Cgi`), SCRIPT_NAME being the
+/// mount prefix (see `Router::mount`) and PATH_INFO the request path, and
+/// is sent the body after them.  Its output is a CGI script's, or starts
+/// with an HTTP status line instead of a Status header; the body is
+/// streamed to the client until the application closes the connection.
+///
+/// If the application can't be reached, or doesn't answer within the
+/// timeout (see `set_timeout`), the client gets a 502 or a 504.
+///
+/// ex:
+///
+/// ```ignore
+/// router.mount("/trac", Scgi::new("127.0.0.1:4000"));
+/// ```
+pub struct Scgi {
+    addr: String,
+    timeout: Duration,
+}
+
+impl Scgi {
+    /// Connect to the application at `addr`: "host:port", or "unix:path"
+    /// for a unix socket.
+    pub fn new(addr: &str) -> Scgi {
+        return Scgi {
+            addr: addr.to_string(),
+            timeout: Duration::from_secs(60),
+        };
+    }
+
+    /// How long to wait to connect, and for each read of the response.
+    /// Default 60 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn run(&self, req: &WebRequest) -> io::Result<WebResponse> {
+        let mut stream = Stream::connect(&self.addr, self.timeout)?;
+        stream.write_all(&request_head(req))?;
+        io::copy(&mut req.body_reader()?, &mut stream)?;
+
+        let mut body = BufReader::new(stream);
+        let mut headers = Vec::new();
+        if body.fill_buf()?.starts_with(b"HTTP/") {
+            // "HTTP/1.1 200 OK" rather than "Status: 200 OK"
+            let mut line = String::new();
+            body.read_line(&mut line)?;
+            let status = match line.trim_end().split_once(' ') {
+                Some((_, status)) => status.to_string(),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        "bad status line")),
+            };
+            headers.push(("Status".to_string(), status));
+        }
+        headers.extend(read_headers(&mut body)?);
+        let (mut resp, length) = script_response(headers)?;
+        resp.set_body_reader(body, length);
+        return Ok(resp);
+    }
+}
+
+impl Handler for Scgi {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        match self.run(req) {
+            Ok(resp) => return resp,
+            Err(err) => match err.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock =>
+                    return error_response(504, "Gateway Timeout"),
+                _ => return error_response(502, "Bad Gateway"),
+            },
+        }
+    }
+}
+
+
+// The headers netstring: CONTENT_LENGTH first, then SCGI, then the rest,
+// each name and value ending with a NUL
+fn request_head(req: &WebRequest) -> Vec<u8> {
+    let mut headers = Vec::new();
+    let mut push = |name: &str, value: &str| {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    };
+    push("CONTENT_LENGTH", &req.body_len().to_string());
+    push("SCGI", "1");
+    let env = environment(req, Path::new(""), &mount_prefix(req), &req.path);
+    for (name, value) in env {
+        // Values can't have NULs; names are safe
+        if name != "CONTENT_LENGTH" && !value.contains('\0') {
+            push(&name, &value);
+        }
+    }
+    let mut out = format!("{}:", headers.len()).into_bytes();
+    out.extend(headers);
+    out.push(b',');
+    return out;
+}
+
+fn error_response(code: i32, status: &str) -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(code, status);
+    resp.set_body_str(&format!("Error {}: {}", code, status));
+    return resp;
+}
+
+
+// An SCGI application answering with its headers, for each connection
+#[cfg(test)]
+fn test_app(status_line: bool) -> String {
+    use std::io::Read;
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    ::std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut len = Vec::new();
+            reader.read_until(b':', &mut len).unwrap();
+            len.pop();
+            let len: usize = String::from_utf8(len).unwrap().parse().unwrap();
+            let mut head = vec![0; len + 1];
+            reader.read_exact(&mut head).unwrap();
+            assert_eq!(head.pop(), Some(b','));
+            let fields: Vec<_> = head.split(|&c| c == 0)
+                .map(|f| String::from_utf8_lossy(f).into_owned()).collect();
+            let length: usize = fields[1].parse().unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut stream = reader.into_inner();
+            if status_line {
+                write!(stream, "HTTP/1.0 201 Created\r\n").unwrap();
+            }
+            write!(stream, "Content-Type: text/plain\r\n\r\n{}|{}",
+                    fields.join(" "), String::from_utf8(body).unwrap())
+                .unwrap();
+        }
+    });
+    return addr;
+}
+
+#[test]
+fn test_scgi() {
+    let app = Scgi::new(&test_app(false));
+    let mut req = WebRequest::parse_for_test("POST /a/b?x=1 HTTP/1.1\r\n\
+            Host: example.com\r\nContent-Length: 5\r\n\r\n");
+    req.body = b"hello".to_vec();
+    let mut resp = app.handle(&mut req);
+    assert_eq!(resp.get_code(), 200);
+    assert_eq!(resp.get_header("Content-Type"), Some("text/plain"));
+    let body = String::from_utf8(resp.body_for_test()).unwrap();
+    assert!(body.starts_with("CONTENT_LENGTH 5 SCGI 1 GATEWAY_INTERFACE "),
+            "{}", body);
+    assert!(body.contains(" SCRIPT_NAME  PATH_INFO /a/b QUERY_STRING x=1 "));
+    assert!(body.contains(" HTTP_HOST example.com "));
+    assert!(!body.contains("SCRIPT_FILENAME"));
+    assert!(body.ends_with(" |hello"));
+
+    let app = Scgi::new(&test_app(true));
+    let mut req = WebRequest::new_for_test("GET", "/");
+    let mut resp = app.handle(&mut req);
+    assert_eq!(resp.get_code(), 201);
+    assert!(resp.body_for_test().starts_with(b"CONTENT_LENGTH 0 SCGI 1 "));
+
+    let app = Scgi::new("127.0.0.1:1");
+    let mut req = WebRequest::new_for_test("GET", "/");
+    assert_eq!(app.handle(&mut req).get_code(), 502);
+}
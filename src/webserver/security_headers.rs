@@ -0,0 +1,132 @@
+//! Middleware adding the usual security related response headers
+
+use super::{WebRequest, WebResponse, Middleware, Next};
+
+
+/// Middleware that adds security headers to every response, unless the
+/// handler already set them.
+///
+/// The defaults:
+///
+/// ```text
+/// Strict-Transport-Security: max-age=31536000; includeSubDomains
+/// X-Content-Type-Options: nosniff
+/// X-Frame-Options: SAMEORIGIN
+/// Referrer-Policy: strict-origin-when-cross-origin
+/// Content-Security-Policy: default-src 'self'
+/// ```
+///
+/// Browsers ignore Strict-Transport-Security on plain HTTP, so it's safe
+/// to send when the server sits behind a TLS proxy.  Each setter takes
+/// `None` to leave that header out.
+///
+/// ex: `svr.add_middleware(SecurityHeaders::new());`
+pub struct SecurityHeaders {
+    headers: Vec<(&'static str, Option<String>)>,
+}
+
+static HSTS: &str = "Strict-Transport-Security";
+static CONTENT_TYPE_OPTIONS: &str = "X-Content-Type-Options";
+static FRAME_OPTIONS: &str = "X-Frame-Options";
+static REFERRER_POLICY: &str = "Referrer-Policy";
+static CSP: &str = "Content-Security-Policy";
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        return SecurityHeaders::new();
+    }
+}
+
+impl SecurityHeaders {
+    pub fn new() -> SecurityHeaders {
+        let defaults = [
+            (HSTS, "max-age=31536000; includeSubDomains"),
+            (CONTENT_TYPE_OPTIONS, "nosniff"),
+            (FRAME_OPTIONS, "SAMEORIGIN"),
+            (REFERRER_POLICY, "strict-origin-when-cross-origin"),
+            (CSP, "default-src 'self'"),
+        ];
+        return SecurityHeaders {
+            headers: defaults.iter()
+                .map(|&(name, value)| (name, Some(value.to_string())))
+                .collect(),
+        };
+    }
+
+    fn set(&mut self, name: &str, value: Option<&str>) {
+        for header in self.headers.iter_mut() {
+            if header.0 == name {
+                header.1 = value.map(|v| v.to_string());
+            }
+        }
+    }
+
+    /// The Strict-Transport-Security value, ex: "max-age=63072000; preload"
+    pub fn set_hsts(&mut self, value: Option<&str>) {
+        self.set(HSTS, value);
+    }
+
+    /// The X-Content-Type-Options value
+    pub fn set_content_type_options(&mut self, value: Option<&str>) {
+        self.set(CONTENT_TYPE_OPTIONS, value);
+    }
+
+    /// The X-Frame-Options value, "DENY" or "SAMEORIGIN"
+    pub fn set_frame_options(&mut self, value: Option<&str>) {
+        self.set(FRAME_OPTIONS, value);
+    }
+
+    /// The Referrer-Policy value, ex: "no-referrer"
+    pub fn set_referrer_policy(&mut self, value: Option<&str>) {
+        self.set(REFERRER_POLICY, value);
+    }
+
+    /// The Content-Security-Policy value
+    pub fn set_content_security_policy(&mut self, value: Option<&str>) {
+        self.set(CSP, value);
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let mut resp = next.run(req);
+        for &(name, ref value) in self.headers.iter() {
+            if let Some(ref value) = *value {
+                if resp.get_header(name).is_none() {
+                    resp.set_header(name, value);
+                }
+            }
+        }
+        return resp;
+    }
+}
+
+
+#[test]
+fn test_security_headers() {
+    use super::{Chain, Handler};
+
+    let mut chain = Chain::new(|req: &WebRequest| {
+        let mut resp = WebResponse::new();
+        if req.get_path() == "/embed" {
+            resp.set_header("X-Frame-Options", "DENY");
+        }
+        return resp;
+    });
+    let mut headers = SecurityHeaders::new();
+    headers.set_content_security_policy(None);
+    headers.set_referrer_policy(Some("no-referrer"));
+    chain.add(headers);
+
+    let resp = chain.handle(&mut WebRequest::new_for_test("GET", "/"));
+    assert_eq!(resp.get_header("Strict-Transport-Security"),
+            Some("max-age=31536000; includeSubDomains"));
+    assert_eq!(resp.get_header("X-Content-Type-Options"), Some("nosniff"));
+    assert_eq!(resp.get_header("X-Frame-Options"), Some("SAMEORIGIN"));
+    assert_eq!(resp.get_header("Referrer-Policy"), Some("no-referrer"));
+    assert_eq!(resp.get_header("Content-Security-Policy"), None);
+
+    // The handler's own value wins
+    let resp = chain.handle(&mut WebRequest::new_for_test("GET", "/embed"));
+    assert_eq!(resp.get_header("X-Frame-Options"), Some("DENY"));
+}
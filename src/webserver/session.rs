@@ -0,0 +1,420 @@
+//! Cookie based sessions
+
+use std::collections::{BTreeMap, HashMap};
+use std::str;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use utils::base64;
+use utils::byteutils::{constant_time_eq, percent_decode};
+use utils::escape::percent_encode;
+use utils::random;
+use utils::sha256::hmac_sha256;
+use utils::time;
+use super::{WebRequest, WebResponse, Middleware, Next, Cookie, SameSite};
+
+
+/// The key/value pairs of a session
+pub type SessionData = BTreeMap<String, String>;
+
+
+/// Server side storage for session data, by session id.
+///
+/// Implementations must be thread safe; they are shared by all workers.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Option<SessionData>;
+    fn save(&self, id: &str, data: &SessionData);
+    fn remove(&self, id: &str);
+}
+
+
+// When saved, and the data
+type Saved = (Instant, SessionData);
+
+/// A `SessionStore` in process memory.  Sessions not saved for `ttl` are
+/// dropped.
+pub struct MemoryStore {
+    ttl: Duration,
+    sessions: Mutex<(HashMap<String, Saved>, Instant)>,
+}
+
+impl MemoryStore {
+    pub fn new(ttl: Duration) -> MemoryStore {
+        return MemoryStore {
+            ttl: ttl,
+            sessions: Mutex::new((HashMap::new(), Instant::now())),
+        };
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.0.get(id) {
+            Some(&(saved, ref data)) if saved.elapsed() < self.ttl =>
+                return Some(data.clone()),
+            _ => return None,
+        }
+    }
+
+    fn save(&self, id: &str, data: &SessionData) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        // Expire old sessions once per ttl
+        if now.duration_since(sessions.1) >= self.ttl {
+            let ttl = self.ttl;
+            sessions.0.retain(|_, &mut (saved, _)| now.duration_since(saved) < ttl);
+            sessions.1 = now;
+        }
+        sessions.0.insert(id.to_string(), (now, data.clone()));
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().0.remove(id);
+    }
+}
+
+
+struct SessionState {
+    data: SessionData,
+    dirty: bool,
+    rotate: bool,
+}
+
+/// The current session, stored in the request extensions by `Sessions`.
+///
+/// ex: `req.get_extensions().get::<Session>().unwrap().set("user", "bob")`
+///
+/// Changes are saved when the response is sent.
+pub struct Session {
+    state: Mutex<SessionState>,
+}
+
+impl Session {
+    fn new(data: SessionData) -> Session {
+        return Session {
+            state: Mutex::new(SessionState {
+                data: data,
+                dirty: false,
+                rotate: false,
+            }),
+        };
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        return self.state.lock().unwrap().data.get(key).cloned();
+    }
+
+    pub fn set(&self, key: &str, value: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.data.insert(key.to_string(), value.to_string());
+        state.dirty = true;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        state.dirty = true;
+        return state.data.remove(key);
+    }
+
+    /// Remove everything.  An empty session deletes its cookie.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.data.clear();
+        state.dirty = true;
+    }
+
+    /// Issue a new session id (and cookie), keeping the data.  Call this
+    /// when a user logs in, so an id planted beforehand is worthless.
+    pub fn rotate(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.rotate = true;
+        state.dirty = true;
+    }
+}
+
+
+/// Middleware that provides a `Session` for every request.
+///
+/// With `Sessions::signed`, the session data lives in the cookie, signed
+/// with HMAC-SHA256 so clients can read but not change it.  Keep it small,
+/// since browsers limit cookies to about 4 KB.  With `Sessions::with_store`,
+/// the cookie holds only a random (signed) id and the data is kept in a
+/// `SessionStore`.
+///
+/// The cookie is HttpOnly, SameSite=Lax, for path "/", and lasts until the
+/// browser is closed unless `set_max_age` is used.
+pub struct Sessions {
+    key: Vec<u8>,
+    store: Option<Box<dyn SessionStore>>,
+    cookie_name: String,
+    secure: bool,
+    max_age: Option<u64>,
+}
+
+impl Sessions {
+    /// Sessions stored in signed cookies.  `key` must be secret and at
+    /// least 32 bytes; changing it invalidates all sessions.
+    pub fn signed(key: &[u8]) -> Sessions {
+        assert!(key.len() >= 32, "session key must be at least 32 bytes");
+        return Sessions {
+            key: key.to_vec(),
+            store: None,
+            cookie_name: "mudpie_session".to_string(),
+            secure: false,
+            max_age: None,
+        };
+    }
+
+    /// Sessions stored in `store`, with ids signed by `key` (see `signed`)
+    pub fn with_store<S: SessionStore + 'static>(key: &[u8], store: S)
+            -> Sessions {
+        let mut ret = Sessions::signed(key);
+        ret.store = Some(Box::new(store));
+        return ret;
+    }
+
+    /// Cookie name (default "mudpie_session")
+    pub fn set_cookie_name(&mut self, name: &str) {
+        self.cookie_name = name.to_string();
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn set_secure(&mut self, on: bool) {
+        self.secure = on;
+    }
+
+    /// Make the cookie (and with `signed`, the session) expire after this
+    /// many seconds
+    pub fn set_max_age(&mut self, secs: u64) {
+        self.max_age = Some(secs);
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        return format!("{}.{}", payload,
+                base64::encode(&hmac_sha256(&self.key, payload.as_bytes())));
+    }
+
+    // The payload of a correctly signed cookie value
+    fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let dot = value.rfind('.')?;
+        let (payload, sig) = (&value[..dot], &value[dot + 1..]);
+        let expected = base64::encode(&hmac_sha256(&self.key,
+                payload.as_bytes()));
+        match constant_time_eq(sig.as_bytes(), expected.as_bytes()) {
+            true => return Some(payload),
+            false => return None,
+        }
+    }
+
+    // (cookie payload, data) from the request cookie.  With a store, the
+    // payload is the session id.
+    fn load(&self, req: &WebRequest) -> (Option<String>, SessionData) {
+        let payload = match req.get_cookie(&self.cookie_name)
+                .and_then(|v| self.verify(v)) {
+            Some(payload) => payload,
+            None => return (None, SessionData::new()),
+        };
+        let data = match self.store {
+            Some(ref store) => store.load(payload),
+            None => self.decode(payload),
+        };
+        match data {
+            Some(data) => return (Some(payload.to_string()), data),
+            None => return (None, SessionData::new()),
+        }
+    }
+
+    // Signed cookie payload: "issued_at.base64(k=v&k=v)"
+    fn encode(&self, data: &SessionData) -> String {
+        let pairs: Vec<String> = data.iter().map(|(k, v)|
+            format!("{}={}", percent_encode(k), percent_encode(v))).collect();
+        return format!("{}.{}", time::to_unix(SystemTime::now()),
+                base64::encode(pairs.join("&").as_bytes()));
+    }
+
+    fn decode(&self, payload: &str) -> Option<SessionData> {
+        let dot = payload.find('.')?;
+        let issued: i64 = payload[..dot].parse().ok()?;
+        if let Some(max_age) = self.max_age {
+            if time::to_unix(SystemTime::now()) - issued > max_age as i64 {
+                return None;
+            }
+        }
+        let raw = base64::decode(&payload[dot + 1..])?;
+        let raw = str::from_utf8(&raw).ok()?;
+        let mut data = SessionData::new();
+        for pair in raw.split('&').filter(|p| !p.is_empty()) {
+            let eq = pair.find('=')?;
+            let k = percent_decode(&pair.as_bytes()[..eq]);
+            let v = percent_decode(&pair.as_bytes()[eq + 1..]);
+            data.insert(String::from_utf8_lossy(&k).into_owned(),
+                    String::from_utf8_lossy(&v).into_owned());
+        }
+        return Some(data);
+    }
+
+    fn cookie(&self, value: &str) -> Cookie {
+        let mut cookie = Cookie::new(&self.cookie_name, value);
+        cookie.http_only = true;
+        cookie.secure = self.secure;
+        cookie.same_site = Some(SameSite::Lax);
+        cookie.max_age = self.max_age.map(|secs| secs as i64);
+        return cookie;
+    }
+
+    fn save(&self, resp: &mut WebResponse, payload: Option<String>,
+            state: SessionState) {
+        if !state.dirty {
+            return;
+        }
+        if state.data.is_empty() {
+            if let Some(id) = payload {
+                if let Some(ref store) = self.store {
+                    store.remove(&id);
+                }
+                let mut removal = self.cookie("");
+                removal.max_age = Some(0);
+                resp.add_cookie(&removal);
+            }
+            return;
+        }
+        match self.store {
+            Some(ref store) => {
+                let new_id = match payload {
+                    Some(ref id) if !state.rotate => id.clone(),
+                    _ => random::hex_token(16),
+                };
+                store.save(&new_id, &state.data);
+                // The browser already has the cookie, unless it expires
+                match payload {
+                    Some(ref old) if *old == new_id && self.max_age.is_none() =>
+                        return,
+                    Some(ref old) if *old != new_id => store.remove(old),
+                    _ => (),
+                }
+                let value = self.sign(&new_id);
+                resp.add_cookie(&self.cookie(&value));
+            }
+            None => {
+                let value = self.sign(&self.encode(&state.data));
+                resp.add_cookie(&self.cookie(&value));
+            }
+        }
+    }
+}
+
+impl Middleware for Sessions {
+    fn handle(&self, req: &mut WebRequest, next: Next) -> WebResponse {
+        let (id, data) = self.load(req);
+        req.get_extensions_mut().insert(Session::new(data));
+        let mut resp = next.run(req);
+        if let Some(session) = req.get_extensions_mut().remove::<Session>() {
+            let state = session.state.into_inner().unwrap();
+            self.save(&mut resp, id, state);
+        }
+        return resp;
+    }
+}
+
+
+#[cfg(test)]
+fn session_chain(sessions: Sessions) -> super::Chain {
+    let mut chain = super::Chain::new(|req: &WebRequest| {
+        let session = req.get_extensions().get::<Session>().unwrap();
+        let count = session.get("count").unwrap_or("0".to_string());
+        match req.get_path() {
+            "/inc" => {
+                let n: i32 = count.parse().unwrap();
+                session.set("count", &(n + 1).to_string());
+                session.set("note", "a=b&c; d");
+            }
+            "/login" => session.rotate(),
+            "/logout" => session.clear(),
+            _ => (),
+        }
+        return WebResponse::new_html(count);
+    });
+    chain.add(sessions);
+    return chain;
+}
+
+// Send a request with the cookie, return (body, new cookie if any)
+#[cfg(test)]
+fn session_get(chain: &super::Chain, path: &str, cookie: &str)
+        -> (String, Option<String>) {
+    use super::Handler;
+
+    let mut req = WebRequest::parse_for_test(&format!(
+        "GET {} HTTP/1.1\r\nCookie: other=1; {}\r\n\r\n", path, cookie));
+    let resp = chain.handle(&mut req);
+    let set = resp.get_header("Set-Cookie").map(|c| {
+        return c.split(';').next().unwrap().to_string();
+    });
+    return (String::from_utf8(resp.body).unwrap(), set);
+}
+
+#[test]
+fn test_signed_sessions() {
+    let chain = session_chain(Sessions::signed(&[7; 32]));
+    let (body, cookie) = session_get(&chain, "/", "");
+    assert_eq!((&body[..], cookie), ("0", None));
+
+    let (_, cookie) = session_get(&chain, "/inc", "");
+    let cookie = cookie.unwrap();
+    assert!(cookie.starts_with("mudpie_session="));
+    let (_, cookie) = session_get(&chain, "/inc", &cookie);
+    let cookie = cookie.unwrap();
+    let (body, unchanged) = session_get(&chain, "/", &cookie);
+    assert_eq!((&body[..], unchanged), ("2", None));
+
+    // Tampering drops the session
+    let dot = cookie.find('.').unwrap();
+    let forged = format!("{}9{}", &cookie[..dot], &cookie[dot..]);
+    assert_eq!(session_get(&chain, "/", &forged).0, "0");
+    let other_key = session_chain(Sessions::signed(&[8; 32]));
+    assert_eq!(session_get(&other_key, "/", &cookie).0, "0");
+
+    let (_, removal) = session_get(&chain, "/logout", &cookie);
+    assert_eq!(removal.unwrap(), "mudpie_session=");
+}
+
+#[test]
+fn test_store_sessions() {
+    let chain = session_chain(Sessions::with_store(&[7; 32],
+            MemoryStore::new(Duration::from_secs(60))));
+    let (_, cookie) = session_get(&chain, "/inc", "");
+    let cookie = cookie.unwrap();
+    // id + signature only
+    assert!(!cookie.contains("count"));
+    let (_, same) = session_get(&chain, "/inc", &cookie);
+    assert_eq!(same, None);
+    assert_eq!(session_get(&chain, "/", &cookie).0, "2");
+
+    let (_, rotated) = session_get(&chain, "/login", &cookie);
+    let rotated = rotated.unwrap();
+    assert!(rotated != cookie);
+    assert_eq!(session_get(&chain, "/", &rotated).0, "2");
+    assert_eq!(session_get(&chain, "/", &cookie).0, "0");
+
+    session_get(&chain, "/logout", &rotated);
+    assert_eq!(session_get(&chain, "/", &rotated).0, "0");
+
+    // Unknown ids aren't adopted
+    let (_, fresh) = session_get(&chain, "/inc", "mudpie_session=x.y");
+    assert!(!fresh.unwrap().contains("=x."));
+}
+
+#[test]
+fn test_memory_store_expiry() {
+    let store = MemoryStore::new(Duration::from_millis(0));
+    let mut data = SessionData::new();
+    data.insert("a".to_string(), "b".to_string());
+    store.save("id", &data);
+    assert!(store.load("id").is_none());
+    let store = MemoryStore::new(Duration::from_secs(60));
+    store.save("id", &data);
+    assert_eq!(store.load("id"), Some(data));
+    store.remove("id");
+    assert!(store.load("id").is_none());
+}
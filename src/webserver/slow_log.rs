@@ -0,0 +1,159 @@
+//! Logging of slow requests, with where their time went
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{WebRequest, WebResponse, ConnInfo};
+
+
+/// Writes a line for each request that took longer than a threshold, with
+/// the time it took in each step:
+///
+/// ```text
+/// slow request: method=GET path=/report code=200 total=1520.3ms \
+///     read=0.2ms queue=0.1ms handler=1519.6ms write=0.4ms
+/// ```
+///
+/// (on a single line), where `read` is reading the request, `queue` the
+/// wait for the handler to start, `handler` running it, and `write`
+/// sending the response.  Steps a request didn't get to, like the handler
+/// for an invalid one, are `-`.
+pub struct SlowLog {
+    threshold: Duration,
+    handler_threshold: Option<Duration>,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+// When a request got to each step, after the connection's start
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Timings {
+    // Its head and body were read
+    pub(crate) read: Option<Instant>,
+    pub(crate) handler_start: Option<Instant>,
+    pub(crate) handler_end: Option<Instant>,
+}
+
+impl SlowLog {
+    /// Log the requests that took longer than `threshold`, from accepting
+    /// them to sending their response, to `out`
+    pub fn new<W: Write + Send + 'static>(out: W, threshold: Duration)
+            -> SlowLog {
+        return SlowLog {
+            threshold: threshold,
+            handler_threshold: None,
+            out: Mutex::new(Box::new(out)),
+        };
+    }
+
+    /// Append to the file at `path`, creating it if needed.
+    pub fn open(path: &str, threshold: Duration) -> io::Result<SlowLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        return Ok(SlowLog::new(file, threshold));
+    }
+
+    /// Also log the requests whose handler alone took longer than
+    /// `threshold`, however long they took in all
+    pub fn set_handler_threshold(&mut self, threshold: Duration) {
+        self.handler_threshold = Some(threshold);
+    }
+
+    // Called after the response was written, like the access log.  Errors
+    // writing the log are ignored.
+    pub(crate) fn log(&self, req: Option<&WebRequest>, resp: &WebResponse,
+            conn: &ConnInfo) {
+        let now = Instant::now();
+        let timings = &conn.timings;
+        let total = now.duration_since(conn.start);
+        let handler = match (timings.handler_start, timings.handler_end) {
+            (Some(start), Some(end)) => Some(end.duration_since(start)),
+            _ => None,
+        };
+        let slow_handler = match (handler, self.handler_threshold) {
+            (Some(handler), Some(threshold)) => handler > threshold,
+            _ => false,
+        };
+        if total <= self.threshold && !slow_handler {
+            return;
+        }
+
+        let (method, path) = match req {
+            Some(req) => (req.get_method().to_ascii_uppercase(),
+                    req.get_path()),
+            None => ("-".to_string(), "-"),
+        };
+        let between = |from: Option<Instant>, to: Option<Instant>| {
+            match (from, to) {
+                (Some(from), Some(to)) => return ms(to.duration_since(from)),
+                _ => return "-".to_string(),
+            }
+        };
+        let line = format!("slow request: method={} path={} code={} \
+                total={} read={} queue={} handler={} write={}\n",
+                method, path, resp.code, ms(total),
+                between(Some(conn.start), timings.read),
+                between(timings.read, timings.handler_start),
+                between(timings.handler_start, timings.handler_end),
+                between(timings.handler_end, Some(now)));
+        let mut out = match self.out.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let _ = out.write_all(line.as_bytes());
+        let _ = out.flush();
+    }
+}
+
+fn ms(duration: Duration) -> String {
+    return format!("{:.1}ms", duration.as_secs_f64() * 1000.0);
+}
+
+
+#[test]
+fn test_slow_log() {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    use super::access_log::SharedBuf;
+
+    let start = Instant::now() - Duration::from_millis(500);
+    let at = |ms| Some(start + Duration::from_millis(ms));
+    let conn = |timings| ConnInfo {
+        peer_addr: "10.1.2.3:5555".parse().unwrap(),
+        start: start,
+        start_time: SystemTime::now(),
+        timings: timings,
+    };
+    let slow_handler = conn(Timings {
+        read: at(10),
+        handler_start: at(30),
+        handler_end: at(330),
+    });
+    let quick = conn(Timings {
+        read: at(0),
+        handler_start: at(0),
+        handler_end: at(1),
+    });
+    let req = WebRequest::new_for_test("GET", "/report");
+    let resp = WebResponse::new();
+
+    let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+    let mut log = SlowLog::new(buf.clone(), Duration::from_secs(1));
+    log.log(Some(&req), &resp, &slow_handler);
+    assert!(buf.0.lock().unwrap().is_empty());
+    log.set_handler_threshold(Duration::from_millis(200));
+    log.log(Some(&req), &resp, &slow_handler);
+    log.log(Some(&req), &resp, &quick);
+    let log = SlowLog::new(buf.clone(), Duration::from_millis(100));
+    log.log(None, &resp, &conn(Timings::default()));
+
+    let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("slow request: method=GET path=/report \
+            code=200 total="), "{}", lines[0]);
+    assert!(lines[0].contains(" read=10.0ms queue=20.0ms handler=300.0ms \
+            write="), "{}", lines[0]);
+    assert!(lines[1].starts_with("slow request: method=- path=- code=200 "));
+    assert!(lines[1].ends_with(" read=- queue=- handler=- write=-"));
+}
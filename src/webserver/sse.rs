@@ -0,0 +1,179 @@
+//! Server-Sent Events (text/event-stream) responses
+//!
+//! https://html.spec.whatwg.org/multipage/server-sent-events.html
+
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use super::WebResponse;
+
+
+/// Sends events to a client over a streamed `text/event-stream` response.
+///
+/// `SseStream::new` returns the response for the handler to return, and
+/// the stream to send events with, from another thread: the response is
+/// sent (and takes a worker thread) until every clone of the stream is
+/// dropped, which ends it.  Sending fails once the client has gone away.
+/// A reconnecting client sends the id of the last event it got as the
+/// Last-Event-ID request header.
+///
+/// ex:
+///
+/// ```ignore
+/// let (events, resp) = SseStream::new(Some(Duration::from_secs(15)));
+/// thread::spawn(move || {
+///     for i in 0.. {
+///         if events.send_event(Some("tick"), &i.to_string(), None).is_err() {
+///             return;
+///         }
+///         thread::sleep(Duration::from_secs(1));
+///     }
+/// });
+/// return resp;
+/// ```
+#[derive(Clone)]
+pub struct SseStream {
+    sender: Sender<Vec<u8>>,
+}
+
+impl SseStream {
+    /// With a `keepalive`, a comment is sent whenever no event was sent
+    /// for that long, so proxies don't time the connection out.
+    pub fn new(keepalive: Option<Duration>) -> (SseStream, WebResponse) {
+        let (sender, receiver) = mpsc::channel();
+        let mut resp = WebResponse::new();
+        resp.set_header("Content-Type", "text/event-stream");
+        resp.set_header("Cache-Control", "no-cache");
+        // Don't let nginx hold the events back
+        resp.set_header("X-Accel-Buffering", "no");
+        resp.set_body_reader(EventReader {
+            receiver: receiver,
+            keepalive: keepalive,
+            pending: Vec::new(),
+            pos: 0,
+        }, None);
+        return (SseStream { sender: sender }, resp);
+    }
+
+    /// Send an event, with an event type (the default is "message") and
+    /// an id for the client to resume from.  `data` may have several lines;
+    /// `name` and `id` may not.
+    pub fn send_event(&self, name: Option<&str>, data: &str, id: Option<&str>)
+            -> io::Result<()> {
+        let mut event = String::new();
+        if let Some(name) = name {
+            event.push_str(&format!("event: {}\n", single_line(name)?));
+        }
+        if let Some(id) = id {
+            event.push_str(&format!("id: {}\n", single_line(id)?));
+        }
+        for line in lines(data) {
+            event.push_str(&format!("data: {}\n", line));
+        }
+        event.push('\n');
+        return self.send(event);
+    }
+
+    /// Send a comment, which clients ignore
+    pub fn send_comment(&self, text: &str) -> io::Result<()> {
+        let mut comment = String::new();
+        for line in lines(text) {
+            comment.push_str(&format!(":{}\n", line));
+        }
+        comment.push('\n');
+        return self.send(comment);
+    }
+
+    fn send(&self, text: String) -> io::Result<()> {
+        return self.sender.send(text.into_bytes()).map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "event stream closed")
+        });
+    }
+}
+
+
+// Split at CRLF, LF or CR, as clients do
+fn lines(text: &str) -> Vec<String> {
+    return text.replace("\r\n", "\n").split(['\n', '\r'])
+        .map(|line| line.to_string()).collect();
+}
+
+fn single_line(value: &str) -> io::Result<&str> {
+    if value.contains(['\n', '\r']) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "event name or id with a line break"));
+    }
+    return Ok(value);
+}
+
+
+// The response body: events as they're sent, until the streams are gone
+struct EventReader {
+    receiver: Receiver<Vec<u8>>,
+    keepalive: Option<Duration>,
+    // The event being read
+    pending: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for EventReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.pending.len() {
+            let next = match self.keepalive {
+                Some(keepalive) => self.receiver.recv_timeout(keepalive),
+                None => self.receiver.recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            self.pending = match next {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => b":\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.pending.len() - self.pos);
+        buf[..n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+
+#[test]
+fn test_sse_events() {
+    let (events, mut resp) = SseStream::new(None);
+    assert_eq!(resp.get_header("Content-Type"), Some("text/event-stream"));
+    assert!(resp.is_streamed());
+    events.send_event(None, "hello", None).unwrap();
+    events.send_event(Some("update"), "two\r\nlines\rthree", Some("7"))
+        .unwrap();
+    events.clone().send_comment("hi").unwrap();
+    events.send_event(None, "", None).unwrap();
+    assert!(events.send_event(Some("a\nb"), "x", None).is_err());
+    drop(events);
+    assert_eq!(String::from_utf8(resp.body_for_test()).unwrap(),
+            "data: hello\n\n\
+            event: update\nid: 7\ndata: two\ndata: lines\ndata: three\n\n\
+            :hi\n\n\
+            data: \n\n");
+
+    // The client went away
+    let (events, resp) = SseStream::new(None);
+    drop(resp);
+    assert_eq!(events.send_comment("").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn test_sse_keepalive() {
+    let (events, mut resp) = SseStream::new(Some(Duration::from_millis(20)));
+    let sender = ::std::thread::spawn(move || {
+        ::std::thread::sleep(Duration::from_millis(70));
+        events.send_event(None, "late", None).unwrap();
+    });
+    let body = String::from_utf8(resp.body_for_test()).unwrap();
+    sender.join().unwrap();
+    assert!(body.starts_with(":\n\n:\n\n"));
+    assert!(body.ends_with(":\n\ndata: late\n\n"));
+}
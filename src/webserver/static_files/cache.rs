@@ -0,0 +1,188 @@
+//! In-memory LRU cache of small static files
+
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+
+// One way to send a file: as is, or precompressed
+pub struct Variant {
+    // None for the file itself
+    pub encoding: Option<&'static str>,
+    // All the response headers, ready to send
+    pub headers: Vec<(String, String)>,
+    pub body: Arc<Vec<u8>>,
+}
+
+// What a file looked like when cached: size and mtime, None if missing
+pub type Stamp = Option<(u64, Option<SystemTime>)>;
+
+pub fn stamp(meta: Option<&Metadata>) -> Stamp {
+    return meta.map(|meta| (meta.len(), meta.modified().ok()));
+}
+
+
+pub struct CachedFile {
+    // The file itself first
+    pub variants: Vec<Variant>,
+    // The files it was made from, including variants that didn't exist
+    pub sources: Vec<(PathBuf, Stamp)>,
+}
+
+impl CachedFile {
+    fn size(&self) -> usize {
+        return self.variants.iter().map(|v| v.body.len()).sum();
+    }
+}
+
+
+struct Entry {
+    file: Arc<CachedFile>,
+    last_used: u64,
+}
+
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    bytes: usize,
+    tick: u64,
+}
+
+
+// Files by path, evicting the least recently used ones to stay within a
+// total size
+pub struct FileCache {
+    max_bytes: usize,
+    max_file_size: usize,
+    state: Mutex<State>,
+}
+
+impl FileCache {
+    pub fn new(max_bytes: usize, max_file_size: usize) -> FileCache {
+        return FileCache {
+            max_bytes: max_bytes,
+            max_file_size: max_file_size,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                bytes: 0,
+                tick: 0,
+            }),
+        };
+    }
+
+    // The largest file (or variant of it) to cache
+    pub fn max_file_size(&self) -> usize {
+        return self.max_file_size;
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Arc<CachedFile>> {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        let entry = state.entries.get_mut(path)?;
+        entry.last_used = tick;
+        return Some(entry.file.clone());
+    }
+
+    pub fn remove(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.entries.remove(path) {
+            state.bytes -= old.file.size();
+        }
+    }
+
+    // Drop the entries whose files changed
+    pub fn revalidate(&self) {
+        let files: Vec<(PathBuf, Arc<CachedFile>)> = {
+            let state = self.state.lock().unwrap();
+            state.entries.iter().map(|(p, e)| (p.clone(), e.file.clone()))
+                .collect()
+        };
+        // No lock held while checking the files
+        for (path, file) in files {
+            let changed = file.sources.iter().any(|&(ref source, old)| {
+                return stamp(fs::metadata(source).ok().as_ref()) != old;
+            });
+            if changed {
+                self.remove(&path);
+            }
+        }
+    }
+
+    pub fn insert(&self, path: &Path, file: Arc<CachedFile>) {
+        let size = file.size();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.entries.remove(path) {
+            state.bytes -= old.file.size();
+        }
+        while state.bytes + size > self.max_bytes {
+            let oldest = state.entries.iter()
+                .min_by_key(|&(_, e)| e.last_used)
+                .map(|(p, _)| p.clone()).unwrap();
+            let old = state.entries.remove(&oldest).unwrap();
+            state.bytes -= old.file.size();
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.bytes += size;
+        state.entries.insert(path.to_path_buf(),
+                Entry { file: file, last_used: tick });
+    }
+}
+
+
+// Revalidate `cache` every `interval` in a thread, until it's dropped
+pub fn watch(cache: &Arc<FileCache>, interval: Duration) {
+    let cache = Arc::downgrade(cache);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            match cache.upgrade() {
+                Some(cache) => cache.revalidate(),
+                None => return,
+            }
+        }
+    });
+}
+
+
+#[cfg(test)]
+fn test_file(size: usize) -> Arc<CachedFile> {
+    return Arc::new(CachedFile {
+        variants: vec![Variant {
+            encoding: None,
+            headers: Vec::new(),
+            body: Arc::new(vec![0; size]),
+        }],
+        sources: Vec::new(),
+    });
+}
+
+#[test]
+fn test_file_cache_lru() {
+    let cache = FileCache::new(10, 10);
+    cache.insert(Path::new("/a"), test_file(4));
+    cache.insert(Path::new("/b"), test_file(4));
+    assert!(cache.get(Path::new("/a")).is_some());
+    // Evicts /b, the least recently used
+    cache.insert(Path::new("/c"), test_file(4));
+    assert!(cache.get(Path::new("/b")).is_none());
+    assert!(cache.get(Path::new("/a")).is_some());
+    assert!(cache.get(Path::new("/c")).is_some());
+    // Too big to cache at all
+    cache.insert(Path::new("/d"), test_file(11));
+    assert!(cache.get(Path::new("/d")).is_none());
+    assert!(cache.get(Path::new("/a")).is_some());
+    // Replacing an entry frees the old size
+    cache.insert(Path::new("/a"), test_file(6));
+    assert!(cache.get(Path::new("/c")).is_some());
+    assert_eq!(cache.state.lock().unwrap().bytes, 10);
+    cache.remove(Path::new("/a"));
+    assert!(cache.get(Path::new("/a")).is_none());
+    assert_eq!(cache.state.lock().unwrap().bytes, 4);
+}
@@ -0,0 +1,96 @@
+//! WebDAV (RFC 4918) PROPFIND responses
+
+use std::time::SystemTime;
+
+use utils::escape::{html_attribute_escape, percent_encode};
+use utils::time;
+
+
+// The properties of a file or directory, as reported by PROPFIND
+pub struct Resource {
+    // The decoded URL path
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    // Files only
+    pub content_type: Option<String>,
+    pub etag: Option<String>,
+}
+
+
+// A 207 Multi-Status body with all properties of `resources`
+pub fn render_multistatus(resources: &[Resource]) -> String {
+    let mut xml = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <D:multistatus xmlns:D=\"DAV:\">\n".to_string();
+    for resource in resources {
+        let name = resource.path.trim_end_matches('/').rsplit('/').next()
+            .unwrap_or("");
+        xml.push_str(&format!("<D:response><D:href>{}</D:href>\
+            <D:propstat><D:prop>\
+            <D:displayname>{}</D:displayname>",
+            href(&resource.path, resource.is_dir),
+            html_attribute_escape(name)));
+        if resource.is_dir {
+            xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            xml.push_str(&format!("<D:resourcetype/>\
+                <D:getcontentlength>{}</D:getcontentlength>", resource.size));
+        }
+        if let Some(ref content_type) = resource.content_type {
+            xml.push_str(&format!("<D:getcontenttype>{}</D:getcontenttype>",
+                html_attribute_escape(content_type)));
+        }
+        if let Some(ref etag) = resource.etag {
+            xml.push_str(&format!("<D:getetag>{}</D:getetag>",
+                html_attribute_escape(etag)));
+        }
+        if let Some(modified) = resource.modified {
+            xml.push_str(&format!("<D:getlastmodified>{}</D:getlastmodified>",
+                time::format_http_date(modified)));
+        }
+        xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status>\
+            </D:propstat></D:response>\n");
+    }
+    xml.push_str("</D:multistatus>\n");
+    return xml;
+}
+
+
+// The percent encoded URL path, ending in '/' for directories
+fn href(path: &str, is_dir: bool) -> String {
+    let mut ret: Vec<String> = path.split('/').map(percent_encode).collect();
+    if is_dir && !path.ends_with('/') {
+        ret.push(String::new());
+    }
+    return ret.join("/");
+}
+
+
+#[test]
+fn test_render_multistatus() {
+    let resources = vec![
+        Resource { path: "/dav/a dir".to_string(), is_dir: true, size: 0,
+            modified: None, content_type: None, etag: None },
+        Resource { path: "/dav/a dir/<b>.txt".to_string(), is_dir: false,
+            size: 12, modified: Some(SystemTime::UNIX_EPOCH),
+            content_type: Some("text/plain".to_string()),
+            etag: Some("\"c-0\"".to_string()) },
+    ];
+    let xml = render_multistatus(&resources);
+    assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <D:multistatus xmlns:D=\"DAV:\">\n"));
+    assert!(xml.contains("<D:response><D:href>/dav/a%20dir/</D:href>\
+        <D:propstat><D:prop><D:displayname>a dir</D:displayname>\
+        <D:resourcetype><D:collection/></D:resourcetype>\
+        </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>\
+        </D:response>\n"));
+    assert!(xml.contains("<D:href>/dav/a%20dir/%3Cb%3E.txt</D:href>\
+        <D:propstat><D:prop><D:displayname>&lt;b&gt;.txt</D:displayname>\
+        <D:resourcetype/><D:getcontentlength>12</D:getcontentlength>\
+        <D:getcontenttype>text/plain</D:getcontenttype>\
+        <D:getetag>&quot;c-0&quot;</D:getetag>\
+        <D:getlastmodified>Thu, 01 Jan 1970 00:00:00 GMT</D:getlastmodified>\
+        </D:prop>"));
+    assert!(xml.ends_with("</D:multistatus>\n"));
+}
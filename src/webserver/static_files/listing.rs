@@ -0,0 +1,137 @@
+//! Generated directory index pages
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use utils::escape::{html_attribute_escape, json_escape, percent_encode};
+use utils::time;
+
+
+// A directory entry, as listed
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+
+// The entries of `dir`, directories first, then by name.  Names that
+// aren't valid UTF-8 are left out.
+pub fn read_entries(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        // Follows symlinks, so a link to a directory lists as one
+        let meta = match fs::metadata(entry.path()) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        ret.push(Entry {
+            name: name,
+            is_dir: meta.is_dir(),
+            size: meta.len(),
+            modified: meta.modified().ok(),
+        });
+    }
+    ret.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    return Ok(ret);
+}
+
+
+// An HTML index page.  `title_path` is the directory's URL path, shown in
+// the title.  Links are relative, so the page must be served from a URL
+// ending in '/'.
+pub fn render_html(title_path: &str, entries: &[Entry], parent: bool)
+        -> String {
+    let title = html_attribute_escape(&format!("Index of {}", title_path));
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n\
+        <meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n\
+        <h1>{0}</h1>\n<table>\n\
+        <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n", title);
+    if parent {
+        html.push_str("<tr><td><a href=\"../\">../</a></td>\
+            <td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry.modified.map(format_mtime).unwrap_or_default();
+        html.push_str(&format!("<tr><td><a href=\"{}{}\">{}{}</a></td>\
+            <td>{}</td><td>{}</td></tr>\n",
+            percent_encode(&entry.name), slash,
+            html_attribute_escape(&entry.name), slash, size, modified));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    return html;
+}
+
+
+// A JSON array of the entries, ex: `[{"name": "a.txt", "type": "file",
+// "size": 12, "mtime": "2000-10-10T13:55:36Z"}]` (without the spaces).
+// The mtime is null if unknown.
+pub fn render_json(entries: &[Entry]) -> String {
+    let items: Vec<String> = entries.iter().map(|entry| {
+        let mtime = match entry.modified {
+            Some(t) => format!("\"{}\"", time::format_iso8601(t)),
+            None => "null".to_string(),
+        };
+        return format!("{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\
+            \"mtime\":{}}}", json_escape(&entry.name),
+            if entry.is_dir { "directory" } else { "file" }, entry.size, mtime);
+    }).collect();
+    return format!("[{}]", items.join(","));
+}
+
+
+// ex: "2000-10-10 13:55"
+fn format_mtime(t: SystemTime) -> String {
+    let dt = time::from_unix(time::to_unix(t));
+    return format!("{:04}-{:02}-{:02} {:02}:{:02}", dt.year, dt.month, dt.day,
+        dt.hour, dt.minute);
+}
+
+
+#[test]
+fn test_render_html() {
+    let entries = vec![
+        Entry { name: "sub dir".to_string(), is_dir: true, size: 4096,
+            modified: None },
+        Entry { name: "<b>.txt".to_string(), is_dir: false, size: 12,
+            modified: Some(SystemTime::UNIX_EPOCH) },
+    ];
+    let html = render_html("/docs/<x>/", &entries, true);
+    assert!(html.contains("<title>Index of /docs/&lt;x&gt;/</title>"));
+    assert!(html.contains("<a href=\"../\">../</a>"));
+    assert!(html.contains("<tr><td><a href=\"sub%20dir/\">sub dir/</a></td>\
+        <td>-</td><td></td></tr>"));
+    assert!(html.contains("<tr><td><a href=\"%3Cb%3E.txt\">&lt;b&gt;.txt</a>\
+        </td><td>12</td><td>1970-01-01 00:00</td></tr>"));
+    assert!(!render_html("/", &entries, false).contains("../"));
+}
+
+#[test]
+fn test_render_json() {
+    let entries = vec![
+        Entry { name: "sub\"dir".to_string(), is_dir: true, size: 4096,
+            modified: None },
+        Entry { name: "a.txt".to_string(), is_dir: false, size: 12,
+            modified: Some(SystemTime::UNIX_EPOCH) },
+    ];
+    assert_eq!(render_json(&entries), "[\
+        {\"name\":\"sub\\\"dir\",\"type\":\"directory\",\"size\":4096,\
+        \"mtime\":null},\
+        {\"name\":\"a.txt\",\"type\":\"file\",\"size\":12,\
+        \"mtime\":\"1970-01-01T00:00:00Z\"}]");
+    assert_eq!(render_json(&[]), "[]");
+}
@@ -0,0 +1,1730 @@
+//! Serving files from a directory tree
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use utils::byteutils;
+use utils::mime;
+use utils::negotiate;
+use utils::random;
+use utils::range::{self, ByteRange};
+use utils::time;
+use super::{Handler, WebRequest, WebResponse};
+
+use self::cache::{FileCache, CachedFile, Variant};
+
+mod cache;
+mod dav;
+mod listing;
+
+
+/// Which symlinks `StaticFiles` follows
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Symlinks {
+    /// Follow all symlinks, even out of the root
+    Follow,
+    /// Follow symlinks whose target is inside the root.  The default.
+    InsideRoot,
+    /// Don't follow any symlinks below the root
+    Never,
+}
+
+
+/// A handler that serves the files under a root directory.
+///
+/// The request path (as seen by the handler, so without the prefix when
+/// mounted, see `Router::mount`) is taken relative to the root.  Paths
+/// with ".." segments, or that end up outside the root, get a 404.
+///
+/// Files are sent with Content-Type (by extension, see `add_mime_type`),
+/// Content-Length, Last-Modified and an ETag (from the size and
+/// modification time), and are streamed from disk rather than read into
+/// memory.  Requests with a matching If-None-Match or If-Modified-Since get
+/// a 304 without the file being opened.  Only GET and HEAD are
+/// allowed, unless WebDAV is turned on (see `set_webdav`).  A single byte
+/// range can be requested with a Range header (and If-Range), for resuming
+/// downloads and seeking in media.
+///
+/// Dotfiles (names starting with '.', except ".well-known") are hidden,
+/// and symlinks are only followed if they stay inside the root; see
+/// `set_dotfiles` and `set_symlinks`.  Whatever the path, the file is
+/// checked again after resolving symlinks, and anything that isn't allowed
+/// gets the same 404 as a missing file.  Extensions can be hidden too, see
+/// `add_denied_extension`.
+///
+/// Directories are served by their index file (see `set_index_files`), or
+/// else a generated listing if enabled (see `set_listing`), or else a 404.
+///
+/// ex:
+///
+/// ```ignore
+/// let mut router = Router::new();
+/// router.mount("/static", StaticFiles::new("/srv/www/static"));
+/// ```
+pub struct StaticFiles {
+    root: PathBuf,
+    symlinks: Symlinks,
+    dotfiles: bool,
+    // Lowercase, without the '.'
+    denied_extensions: Vec<String>,
+    index_files: Vec<String>,
+    listing: bool,
+    json_listing: bool,
+    precompressed: bool,
+    // By lowercase extension, over the built-in table
+    mime_types: HashMap<String, String>,
+    default_mime_type: String,
+    cache: Option<Arc<FileCache>>,
+    cache_watch: Option<Duration>,
+    spa_fallback: Option<String>,
+    // Lowercase, the default first
+    languages: Vec<String>,
+    webdav: bool,
+    writable: bool,
+    max_upload_size: Option<usize>,
+}
+
+impl StaticFiles {
+    pub fn new<P: AsRef<Path>>(root: P) -> StaticFiles {
+        return StaticFiles {
+            root: root.as_ref().to_path_buf(),
+            symlinks: Symlinks::InsideRoot,
+            dotfiles: false,
+            denied_extensions: Vec::new(),
+            index_files: Vec::new(),
+            listing: false,
+            json_listing: false,
+            precompressed: false,
+            mime_types: HashMap::new(),
+            default_mime_type: "application/octet-stream".to_string(),
+            cache: None,
+            cache_watch: None,
+            spa_fallback: None,
+            languages: Vec::new(),
+            webdav: false,
+            writable: false,
+            max_upload_size: None,
+        };
+    }
+
+    /// File names to look for in a directory, in order, and serve instead
+    /// of the directory, ex: `set_index_files(&["index.html", "index.htm"])`.
+    /// None by default.  As with listings, "/dir" is redirected to "/dir/".
+    pub fn set_index_files(&mut self, names: &[&str]) {
+        self.index_files = names.iter().map(|n| n.to_string()).collect();
+    }
+
+    /// Which symlinks to follow.  Files behind other links get a 404 and
+    /// aren't listed.  The root itself may always be a symlink.
+    pub fn set_symlinks(&mut self, policy: Symlinks) {
+        self.symlinks = policy;
+    }
+
+    /// Serve (and list) dotfiles like other files (off by default).  When
+    /// off, requests for paths with a segment starting with '.' get a 404,
+    /// except for ".well-known" (RFC 8615).
+    pub fn set_dotfiles(&mut self, on: bool) {
+        self.dotfiles = on;
+    }
+
+    /// Never serve (or list) files ending in ".`ext`" (any case), ex:
+    /// `add_denied_extension("bak")` for editor backups left in the tree.
+    /// Like hidden dotfiles, they get a 404.
+    pub fn add_denied_extension(&mut self, ext: &str) {
+        self.denied_extensions.push(ext.trim_start_matches('.')
+                .to_ascii_lowercase());
+    }
+
+    /// Answer requests for directories with a generated HTML index of the
+    /// files in it (off by default).  A directory path without the trailing
+    /// '/' is redirected to the path with it, so relative links work.
+    pub fn set_listing(&mut self, on: bool) {
+        self.listing = on;
+    }
+
+    /// Send listings as JSON to clients that prefer "application/json"
+    /// over "text/html" in their Accept header (off by default).  The body
+    /// is an array of objects like `{"name": "a.txt", "type": "file",
+    /// "size": 12, "mtime": "2000-10-10T13:55:36Z"}`, with "directory" as
+    /// the type for directories.
+    pub fn set_json_listing(&mut self, on: bool) {
+        self.json_listing = on;
+    }
+
+    // The file for a request path, or None if it's not a safe path
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut ret = self.root.clone();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                _ if segment.contains('\0') || segment.contains('\\') =>
+                    return None,
+                _ if !self.allowed(segment) => return None,
+                _ => ret.push(segment),
+            }
+        }
+        return Some(ret);
+    }
+
+    // Whether a file name may be served, by the dotfile and extension rules
+    fn allowed(&self, name: &str) -> bool {
+        if !self.dotfiles && name.starts_with('.') && name != ".well-known" {
+            return false;
+        }
+        let ext = match name.rfind('.') {
+            Some(i) if i > 0 => name[i + 1..].to_ascii_lowercase(),
+            _ => return true,
+        };
+        return !self.denied_extensions.contains(&ext);
+    }
+
+    // The metadata of a file under the root, applying the symlink policy
+    // and the name rules
+    fn stat(&self, path: &Path) -> io::Result<Metadata> {
+        if !self.confined(path)? {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                    "outside the root, or not allowed"));
+        }
+        return fs::metadata(path);
+    }
+
+    // Whether `path` may be served once all symlinks are resolved: the
+    // real file must be inside the (real) root, and be allowed by the name
+    // rules there too, so a link can't be used to get at a dotfile
+    fn confined(&self, path: &Path) -> io::Result<bool> {
+        if self.symlinks == Symlinks::Follow {
+            return Ok(true);
+        }
+        let root = fs::canonicalize(&self.root)?;
+        let real = fs::canonicalize(path)?;
+        let relative = match real.strip_prefix(&root) {
+            Ok(relative) => relative,
+            Err(_) => return Ok(false),
+        };
+        for component in relative.components() {
+            match component.as_os_str().to_str() {
+                Some(name) if self.allowed(name) => (),
+                _ => return Ok(false),
+            }
+        }
+        if self.symlinks == Symlinks::Never {
+            let relative = match path.strip_prefix(&self.root) {
+                Ok(relative) => relative,
+                Err(_) => return Ok(false),
+            };
+            let mut current = self.root.clone();
+            for component in relative.components() {
+                current.push(component);
+                if fs::symlink_metadata(&current)?.file_type().is_symlink() {
+                    return Ok(false);
+                }
+            }
+        }
+        return Ok(true);
+    }
+
+    /// Serve "file.js.br" or "file.js.gz" instead of "file.js" if it exists
+    /// and the client accepts that encoding (off by default).  The variant
+    /// is sent with file.js's Content-Type, and a Content-Encoding.
+    pub fn set_precompressed(&mut self, on: bool) {
+        self.precompressed = on;
+    }
+
+    /// Send files ending in ".`ext`" (any case) as `content_type`, instead
+    /// of what the built-in table says, ex: `add_mime_type("mjs",
+    /// "text/javascript")`.
+    pub fn add_mime_type(&mut self, ext: &str, content_type: &str) {
+        self.mime_types.insert(ext.trim_start_matches('.').to_ascii_lowercase(),
+                content_type.to_string());
+    }
+
+    /// The Content-Type for files with an unknown or no extension
+    /// (default "application/octet-stream")
+    pub fn set_default_mime_type(&mut self, content_type: &str) {
+        self.default_mime_type = content_type.to_string();
+    }
+
+    fn content_type(&self, path: &Path) -> &str {
+        let ext = path.extension().and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        if let Some(t) = ext.as_ref().and_then(|e| self.mime_types.get(e)) {
+            return t;
+        }
+        return mime::from_path(path).unwrap_or(&self.default_mime_type);
+    }
+
+    /// Keep small files in memory, with their headers ready, so serving
+    /// one again needs no filesystem calls at all (off by default).  At
+    /// most `max_bytes` are kept, dropping the least recently used files,
+    /// and files (or precompressed variants) over `max_file_size` bytes
+    /// aren't cached.
+    ///
+    /// Changes to cached files aren't noticed, unless `set_cache_watch` is
+    /// used too (changes made by PUT and DELETE always are).
+    pub fn set_cache(&mut self, max_bytes: usize, max_file_size: usize) {
+        let cache = Arc::new(FileCache::new(max_bytes, max_file_size));
+        if let Some(interval) = self.cache_watch {
+            cache::watch(&cache, interval);
+        }
+        self.cache = Some(cache);
+    }
+
+    /// Check the files in the cache (see `set_cache`) for changes every
+    /// `interval`, and drop the changed ones, so edits show up without a
+    /// restart.  A file counts as changed when its size or modification
+    /// time is different, or a precompressed variant appeared or went away.
+    ///
+    /// This polls, from a thread, rather than using inotify or kqueue, so
+    /// it works everywhere without dependencies; checking takes one stat
+    /// call per cached file (and variant).
+    pub fn set_cache_watch(&mut self, interval: Duration) {
+        self.cache_watch = Some(interval);
+        if let Some(ref cache) = self.cache {
+            cache::watch(cache, interval);
+        }
+    }
+
+    /// Answer requests that would get a 404, from clients that accept HTML,
+    /// with the file at `path` under the root instead, ex:
+    /// `set_spa_fallback(Some("index.html"))`.  For single page apps that
+    /// route on the client, so any of their URLs can be loaded directly.
+    /// None (the default) turns it off.
+    pub fn set_spa_fallback(&mut self, path: Option<&str>) {
+        self.spa_fallback = path.map(|p| p.to_string());
+    }
+
+    /// Serve "page.de.html" or "page.en.html" for "/page.html", whichever
+    /// the client's Accept-Language prefers, if there is no "page.html" (off
+    /// by default).  `languages` are the language tags to look for, ex:
+    /// `set_languages(&["en", "de", "fr-ch"])`; the files for the first one
+    /// found are used when none is acceptable.  Index files are looked up
+    /// like this too.
+    ///
+    /// Files named like this are sent with Content-Language, and Vary:
+    /// Accept-Language; precompressed variants (see `set_precompressed`)
+    /// are named like "page.de.html.gz".
+    pub fn set_languages(&mut self, languages: &[&str]) {
+        self.languages = languages.iter().map(|l| l.to_ascii_lowercase())
+            .collect();
+    }
+
+    // The language of a file named like "page.de.html", if it's one of
+    // the configured ones
+    fn language_of(&self, path: &Path) -> Option<&str> {
+        let name = path.file_name()?.to_str()?;
+        let mut parts = name.rsplit('.');
+        parts.next();
+        let language = parts.next()?.to_ascii_lowercase();
+        parts.next()?;
+        return self.languages.iter().find(|&l| *l == language)
+            .map(|l| &l[..]);
+    }
+
+    // The best language variant of a missing file, see `set_languages`
+    fn language_variant(&self, req: &WebRequest, path: &Path)
+            -> Option<(PathBuf, Metadata)> {
+        if self.languages.is_empty() {
+            return None;
+        }
+        let name = path.file_name()?.to_str()?;
+        let (stem, ext) = match name.rfind('.') {
+            Some(i) if i > 0 => (&name[..i], &name[i..]),
+            _ => return None,
+        };
+        let list = negotiate::parse_quality_list(
+                req.get_header("Accept-Language").unwrap_or(""));
+        let mut best = None;
+        let mut best_q = -1.0;
+        for language in self.languages.iter() {
+            let candidate = path.with_file_name(
+                    format!("{}.{}{}", stem, language, ext));
+            let meta = match self.stat(&candidate) {
+                Ok(ref meta) if meta.is_file() => meta.clone(),
+                _ => continue,
+            };
+            let q = negotiate::language_quality(&list, language);
+            if q > best_q {
+                best = Some((candidate, meta));
+                best_q = q;
+            }
+        }
+        return best;
+    }
+
+    /// Answer the WebDAV (RFC 4918) methods OPTIONS, PROPFIND and, if
+    /// writable (see `set_writable`), MKCOL, so WebDAV clients can mount the
+    /// tree (off by default).  Only class 1 is supported: no locks, and no
+    /// properties besides the live ones for files.  PROPFIND takes Depth 0
+    /// or 1; "infinity" (and no Depth header) gets a 403.
+    pub fn set_webdav(&mut self, on: bool) {
+        self.webdav = on;
+    }
+
+    /// Allow requests that change the tree (off by default), for use as a
+    /// simple artifact store:
+    ///
+    /// * PUT writes the body to the file (201 if it's new, 204 if it was
+    ///   replaced).  The file is replaced atomically, by writing a temporary
+    ///   file next to it and renaming that, so readers never see half a
+    ///   file.  The directory must exist (409 otherwise).  With
+    ///   "If-None-Match: *", an existing file isn't replaced (412).
+    /// * DELETE removes a file or an empty directory (204), or gets a 409
+    ///   for a directory that isn't empty.
+    /// * MKCOL creates a directory, with WebDAV on (see `set_webdav`).
+    ///
+    /// The dotfile and extension rules apply as for reading.  Bodies are
+    /// limited by `WebServer::set_max_request_body_size`, and
+    /// `set_max_upload_size`.  Only turn this on behind authentication.
+    pub fn set_writable(&mut self, on: bool) {
+        self.writable = on;
+    }
+
+    /// Refuse PUT bodies over `size` bytes with a 413 (no limit but the
+    /// server's by default)
+    pub fn set_max_upload_size(&mut self, size: usize) {
+        self.max_upload_size = Some(size);
+    }
+
+    fn allowed_methods(&self) -> String {
+        let mut methods = if self.webdav {
+            "OPTIONS, GET, HEAD, PROPFIND".to_string()
+        } else {
+            "GET, HEAD".to_string()
+        };
+        if self.writable {
+            methods.push_str(", PUT, DELETE");
+            if self.webdav {
+                methods.push_str(", MKCOL");
+            }
+        }
+        return methods;
+    }
+
+    fn serve_file(&self, req: &WebRequest, path: &Path, meta: &Metadata)
+            -> WebResponse {
+        // Index files, language variants and the fallback get here without
+        // going by serve's lookup
+        if let Some(cached) = self.cache.as_ref().and_then(|c| c.get(path)) {
+            return serve_cached(req, &cached);
+        }
+        let variants = if self.precompressed {
+            self.variants(path)
+        } else {
+            Vec::new()
+        };
+        let accept = req.get_header("Accept-Encoding").unwrap_or("");
+        let offered: Vec<&str> = variants.iter().map(|v| v.0).collect();
+        let chosen = negotiate::choose(accept, &offered)
+            .and_then(|chosen| variants.iter().find(|v| v.0 == chosen));
+        let (encoding, file_path, file_meta) = match chosen {
+            Some(&(encoding, ref path, ref meta)) =>
+                (Some(encoding), path.as_path(), meta),
+            None => (None, path, meta),
+        };
+
+        let mut resp = WebResponse::new();
+        self.add_headers(&mut resp, path, !variants.is_empty(), encoding,
+                file_meta);
+        if not_modified(req, &resp) {
+            return not_modified_response(&resp);
+        }
+
+        if let Some(ref cache) = self.cache {
+            let max = cache.max_file_size() as u64;
+            if meta.len() <= max && variants.iter().all(|v| v.2.len() <= max) {
+                match self.load_cached(cache, path, meta, &variants) {
+                    Ok(cached) => return serve_cached(req, &cached),
+                    Err(err) => return io_error_response(&err),
+                }
+            }
+        }
+
+        let mut file = match File::open(file_path) {
+            Ok(file) => file,
+            Err(err) => return io_error_response(&err),
+        };
+        let (start, len) = match apply_range(req, &mut resp, file_meta.len()) {
+            Some(range) => range,
+            None => return range_not_satisfiable(file_meta.len()),
+        };
+        if start > 0 {
+            if let Err(err) = file.seek(SeekFrom::Start(start)) {
+                return io_error_response(&err);
+            }
+        }
+        resp.set_body_reader(file, Some(len));
+        return resp;
+    }
+
+    // The headers for sending `path`, or its variant with `encoding`
+    // (whose metadata `meta` is), before any Range is applied
+    fn add_headers(&self, resp: &mut WebResponse, path: &Path, vary: bool,
+            encoding: Option<&str>, meta: &Metadata) {
+        resp.set_header("Content-Type", self.content_type(path));
+        if vary {
+            resp.append_header("Vary", "Accept-Encoding");
+        }
+        if let Some(language) = self.language_of(path) {
+            resp.append_header("Vary", "Accept-Language");
+            resp.set_header("Content-Language", language);
+        }
+        if let Some(encoding) = encoding {
+            resp.set_header("Content-Encoding", encoding);
+        }
+        let modified = meta.modified().ok();
+        if let Some(modified) = modified {
+            resp.set_header("Last-Modified", &time::format_http_date(modified));
+        }
+        resp.set_header("ETag", &etag(meta.len(), modified, encoding));
+        resp.set_header("Accept-Ranges", "bytes");
+    }
+
+    // Read a file and its variants into the cache
+    fn load_cached(&self, cache: &FileCache, path: &Path, meta: &Metadata,
+            variants: &[(&'static str, PathBuf, Metadata)])
+            -> io::Result<Arc<CachedFile>> {
+        let vary = !variants.is_empty();
+        let variant = |encoding: Option<&'static str>, file_path: &Path,
+                meta: &Metadata| -> io::Result<Variant> {
+            let mut body = Vec::with_capacity(meta.len() as usize);
+            File::open(file_path)?.read_to_end(&mut body)?;
+            let mut resp = WebResponse::new();
+            self.add_headers(&mut resp, path, vary, encoding, meta);
+            return Ok(Variant {
+                encoding: encoding,
+                headers: resp.headers,
+                body: Arc::new(body),
+            });
+        };
+        let mut cached = CachedFile {
+            variants: vec![variant(None, path, meta)?],
+            sources: vec![(path.to_path_buf(), cache::stamp(Some(meta)))],
+        };
+        for &(encoding, ref file_path, ref meta) in variants {
+            cached.variants.push(variant(Some(encoding), file_path, meta)?);
+        }
+        if self.precompressed {
+            for &(_, suffix) in PRECOMPRESSED.iter() {
+                let source = with_suffix(path, suffix);
+                let meta = variants.iter().find(|v| v.1 == source)
+                    .map(|v| &v.2);
+                cached.sources.push((source, cache::stamp(meta)));
+            }
+        }
+        let cached = Arc::new(cached);
+        cache.insert(path, cached.clone());
+        return Ok(cached);
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        match req.get_method() {
+            "get" | "head" => (),
+            "options" if self.webdav => return self.dav_options(),
+            "propfind" if self.webdav => return self.propfind(req),
+            "mkcol" if self.webdav && self.writable => return self.mkcol(req),
+            "put" if self.writable => return self.put(req),
+            "delete" if self.writable => return self.delete(req),
+            _ => return self.method_not_allowed(),
+        }
+        let resp = self.serve(req);
+        if resp.code != 404 {
+            return resp;
+        }
+        let fallback = match self.spa_fallback {
+            Some(ref fallback) => fallback,
+            None => return resp,
+        };
+        let list = negotiate::parse_quality_list(
+                req.get_header("Accept").unwrap_or("*/*"));
+        if negotiate::media_quality(&list, "text/html") <= 0.0 {
+            return resp;
+        }
+        let path = match self.resolve(fallback) {
+            Some(path) => path,
+            None => return resp,
+        };
+        match self.stat(&path) {
+            Ok(ref meta) if meta.is_file() =>
+                return self.serve_file(req, &path, meta),
+            _ => return resp,
+        }
+    }
+}
+
+impl StaticFiles {
+    fn serve(&self, req: &WebRequest) -> WebResponse {
+        let path = match self.resolve(req.get_path()) {
+            Some(path) => path,
+            None => return not_found(),
+        };
+        if let Some(cached) = self.cache.as_ref().and_then(|c| c.get(&path)) {
+            return serve_cached(req, &cached);
+        }
+        let meta = match self.stat(&path) {
+            Ok(meta) => meta,
+            Err(err) => match self.language_variant(req, &path) {
+                Some((path, meta)) => return self.serve_file(req, &path, &meta),
+                None => return io_error_response(&err),
+            },
+        };
+        if meta.is_dir() {
+            return self.serve_dir(req, &path);
+        }
+        return self.serve_file(req, &path, &meta);
+    }
+}
+
+impl StaticFiles {
+    fn serve_dir(&self, req: &WebRequest, path: &Path) -> WebResponse {
+        let mut index = None;
+        for name in self.index_files.iter() {
+            let index_path = path.join(name);
+            if let Ok(meta) = self.stat(&index_path) {
+                if meta.is_file() {
+                    index = Some((index_path, meta));
+                    break;
+                }
+            }
+            if let Some(variant) = self.language_variant(req, &index_path) {
+                index = Some(variant);
+                break;
+            }
+        }
+        if index.is_none() && !self.listing {
+            return not_found();
+        }
+        if !req.get_path().ends_with('/') {
+            return redirect_to_slash(req);
+        }
+        if let Some((index_path, meta)) = index {
+            return self.serve_file(req, &index_path, &meta);
+        }
+        let mut entries = match listing::read_entries(path) {
+            Ok(entries) => entries,
+            Err(err) => return io_error_response(&err),
+        };
+        entries.retain(|e| self.allowed(&e.name)
+            && self.confined(&path.join(&e.name)).unwrap_or(false));
+        let url_path = format!("{}{}", script_name(req), req.get_path());
+        let parent = url_path != "/";
+        let mut resp = WebResponse::new();
+        if self.json_listing {
+            resp.append_header("Vary", "Accept");
+            let list = negotiate::parse_quality_list(
+                    req.get_header("Accept").unwrap_or(""));
+            if negotiate::media_quality(&list, "application/json")
+                    > negotiate::media_quality(&list, "text/html") {
+                resp.set_header("Content-Type", "application/json");
+                resp.set_body_str(&listing::render_json(&entries));
+                return resp;
+            }
+        }
+        resp.set_header("Content-Type", "text/html; charset=utf-8");
+        resp.set_body_str(&listing::render_html(&url_path, &entries, parent));
+        return resp;
+    }
+}
+
+
+// WebDAV, see `set_webdav`
+impl StaticFiles {
+    fn dav_options(&self) -> WebResponse {
+        let mut resp = WebResponse::new();
+        resp.set_header("DAV", "1");
+        resp.set_header("Allow", &self.allowed_methods());
+        // For Windows' WebDAV client
+        resp.set_header("MS-Author-Via", "DAV");
+        return resp;
+    }
+
+    fn propfind(&self, req: &WebRequest) -> WebResponse {
+        let depth = match req.get_header("Depth").map(|d| d.trim()) {
+            Some("0") => 0,
+            Some("1") => 1,
+            _ => return error_response(403, "Forbidden"),
+        };
+        let path = match self.resolve(req.get_path()) {
+            Some(path) => path,
+            None => return not_found(),
+        };
+        let meta = match self.stat(&path) {
+            Ok(meta) => meta,
+            Err(err) => return io_error_response(&err),
+        };
+        let url_path = format!("{}{}", script_name(req), req.get_path());
+        let mut resources = vec![self.dav_resource(&url_path, &path,
+                meta.is_dir(), meta.len(), meta.modified().ok())];
+        if meta.is_dir() && depth == 1 {
+            let mut entries = match listing::read_entries(&path) {
+                Ok(entries) => entries,
+                Err(err) => return io_error_response(&err),
+            };
+            entries.retain(|e| self.allowed(&e.name)
+                && self.confined(&path.join(&e.name)).unwrap_or(false));
+            let base = url_path.trim_end_matches('/');
+            for entry in entries {
+                resources.push(self.dav_resource(
+                        &format!("{}/{}", base, entry.name),
+                        &path.join(&entry.name), entry.is_dir, entry.size,
+                        entry.modified));
+            }
+        }
+        let mut resp = WebResponse::new();
+        resp.set_code(207, "Multi-Status");
+        resp.set_header("Content-Type", "application/xml; charset=utf-8");
+        resp.set_body_str(&dav::render_multistatus(&resources));
+        return resp;
+    }
+
+    fn dav_resource(&self, url_path: &str, path: &Path, is_dir: bool,
+            size: u64, modified: Option<SystemTime>) -> dav::Resource {
+        return dav::Resource {
+            path: url_path.to_string(),
+            is_dir: is_dir,
+            size: size,
+            modified: modified,
+            content_type: if is_dir {
+                None
+            } else {
+                Some(self.content_type(path).to_string())
+            },
+            etag: if is_dir {
+                None
+            } else {
+                Some(etag(size, modified, None))
+            },
+        };
+    }
+
+    fn mkcol(&self, req: &WebRequest) -> WebResponse {
+        if req.body_len() > 0 {
+            return error_response(415, "Unsupported Media Type");
+        }
+        let path = match self.resolve(req.get_path()) {
+            Some(path) => path,
+            None => return not_found(),
+        };
+        if path == self.root || fs::symlink_metadata(&path).is_ok() {
+            return self.method_not_allowed();
+        }
+        match path.parent().map(|parent| self.stat(parent)) {
+            Some(Ok(ref meta)) if meta.is_dir() => (),
+            _ => return error_response(409, "Conflict"),
+        }
+        if let Err(err) = fs::create_dir(&path) {
+            return io_error_response(&err);
+        }
+        let mut resp = WebResponse::new();
+        resp.set_code(201, "Created");
+        return resp;
+    }
+}
+
+
+// Uploads, see `set_writable`
+impl StaticFiles {
+    fn put(&self, req: &WebRequest) -> WebResponse {
+        if req.get_header("Content-Range").is_some() {
+            return error_response(400, "Bad Request");
+        }
+        if self.max_upload_size.is_some_and(|max| req.body_len() > max as u64) {
+            return error_response(413, "Payload Too Large");
+        }
+        let path = match self.resolve(req.get_path()) {
+            Some(ref path) if *path == self.root
+                || req.get_path().ends_with('/') => {
+                return self.method_not_allowed();
+            }
+            Some(path) => path,
+            None => return not_found(),
+        };
+        match path.parent().map(|parent| self.stat(parent)) {
+            Some(Ok(ref meta)) if meta.is_dir() => (),
+            _ => return error_response(409, "Conflict"),
+        }
+        let existed = match self.stat(&path) {
+            Ok(ref meta) if meta.is_dir() => return self.method_not_allowed(),
+            Ok(_) => true,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return io_error_response(&err),
+        };
+        if existed && req.get_header("If-None-Match").map(|v| v.trim())
+                == Some("*") {
+            return error_response(412, "Precondition Failed");
+        }
+
+        let written = req.body_reader()
+            .and_then(|mut body| write_atomic(&path, &mut body));
+        if let Err(err) = written {
+            return io_error_response(&err);
+        }
+        self.invalidate(&path);
+        let mut resp = WebResponse::new();
+        if existed {
+            resp.set_code(204, "No Content");
+        } else {
+            resp.set_code(201, "Created");
+        }
+        return resp;
+    }
+
+    fn delete(&self, req: &WebRequest) -> WebResponse {
+        let path = match self.resolve(req.get_path()) {
+            Some(ref path) if *path == self.root => {
+                return self.method_not_allowed();
+            }
+            Some(path) => path,
+            None => return not_found(),
+        };
+        let meta = match self.stat(&path) {
+            Ok(meta) => meta,
+            Err(err) => return io_error_response(&err),
+        };
+        let ret = if meta.is_dir() {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match ret {
+            Ok(()) => (),
+            Err(ref err) if meta.is_dir()
+                    && err.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                return error_response(409, "Conflict");
+            }
+            Err(err) => return io_error_response(&err),
+        }
+        self.invalidate(&path);
+        let mut resp = WebResponse::new();
+        resp.set_code(204, "No Content");
+        return resp;
+    }
+
+    fn method_not_allowed(&self) -> WebResponse {
+        let mut resp = error_response(405, "Method not allowed");
+        resp.set_header("Allow", &self.allowed_methods());
+        return resp;
+    }
+
+    // Drop a changed file from the cache, and the file it's a
+    // precompressed variant of
+    fn invalidate(&self, path: &Path) {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return,
+        };
+        cache.remove(path);
+        let name = path.as_os_str().to_str().unwrap_or("");
+        for &(_, suffix) in PRECOMPRESSED.iter() {
+            if let Some(base) = name.strip_suffix(suffix) {
+                cache.remove(Path::new(base));
+            }
+        }
+    }
+}
+
+
+// Replace the file at `path` with `data`, by writing a temporary file
+// in the same directory and renaming it over
+fn write_atomic(path: &Path, data: &mut dyn Read) -> io::Result<()> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let mut tries = 0;
+    let (tmp, mut file) = loop {
+        // A dotfile, so it isn't served meanwhile.  The name can't be
+        // guessed, and the file must be new, so a symlink or file put there
+        // first isn't followed or truncated.
+        let tmp = path.with_file_name(format!(".{}.{}.tmp", name,
+                random::hex_token(8)));
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => break (tmp, file),
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists
+                && tries < 8 => tries += 1,
+            // Not ours to remove
+            Err(err) => return Err(err),
+        }
+    };
+    let written = io::copy(data, &mut file).and_then(|_| file.sync_all());
+    drop(file);
+    let ret = written.and_then(|()| fs::rename(&tmp, path));
+    if ret.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    return ret;
+}
+
+
+impl StaticFiles {
+    // The precompressed variants of `path` that exist
+    fn variants(&self, path: &Path) -> Vec<(&'static str, PathBuf, Metadata)> {
+        let mut found = Vec::new();
+        for &(encoding, suffix) in PRECOMPRESSED.iter() {
+            let name = with_suffix(path, suffix);
+            if let Ok(meta) = self.stat(&name) {
+                if meta.is_file() {
+                    found.push((encoding, name, meta));
+                }
+            }
+        }
+        return found;
+    }
+}
+
+
+// The Content-Encodings of precompressed variants, by file suffix, best
+// first
+static PRECOMPRESSED: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+
+// ex: "a.js" + ".gz"
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    return PathBuf::from(name);
+}
+
+
+// A strong entity tag from the size and modification time, ex:
+// "1f4-39e2f3a8" (quotes included), with the encoding added for
+// precompressed variants
+fn etag(len: u64, modified: Option<SystemTime>, encoding: Option<&str>)
+        -> String {
+    let mtime = modified.map(time::to_unix).unwrap_or(0);
+    match encoding {
+        Some(encoding) =>
+            return format!("\"{:x}-{:x}-{}\"", len, mtime, encoding),
+        None => return format!("\"{:x}-{:x}\"", len, mtime),
+    }
+}
+
+
+// Whether a conditional request can be answered with a 304, given the
+// response headers.  If-None-Match wins over If-Modified-Since.
+fn not_modified(req: &WebRequest, resp: &WebResponse) -> bool {
+    if let Some(if_none_match) = req.get_header("If-None-Match") {
+        let etag = match resp.get_header("ETag") {
+            Some(etag) => etag.trim_start_matches("W/"),
+            None => return false,
+        };
+        // Weak comparison
+        return if_none_match.split(',').map(|t| t.trim())
+            .any(|t| t == "*" || t.trim_start_matches("W/") == etag);
+    }
+    if let Some(since) = req.get_header("If-Modified-Since") {
+        let since = time::parse_http_date(since);
+        let modified = resp.get_header("Last-Modified")
+            .and_then(time::parse_http_date);
+        if let (Some(since), Some(modified)) = (since, modified) {
+            return modified <= since;
+        }
+    }
+    return false;
+}
+
+fn not_modified_response(resp: &WebResponse) -> WebResponse {
+    let mut ret = WebResponse::new();
+    ret.set_code(304, "Not Modified");
+    for (k, v) in resp.get_headers() {
+        if matches!(&k[..], "ETag" | "Last-Modified" | "Vary") {
+            ret.add_header(k, v);
+        }
+    }
+    return ret;
+}
+
+
+fn serve_cached(req: &WebRequest, cached: &CachedFile) -> WebResponse {
+    let offered: Vec<&str> = cached.variants.iter()
+        .filter_map(|v| v.encoding).collect();
+    let accept = req.get_header("Accept-Encoding").unwrap_or("");
+    let chosen = negotiate::choose(accept, &offered);
+    let variant = cached.variants.iter().find(|v| v.encoding == chosen)
+        .unwrap();
+
+    let mut resp = WebResponse::new();
+    resp.headers = variant.headers.clone();
+    if not_modified(req, &resp) {
+        return not_modified_response(&resp);
+    }
+    let len = variant.body.len() as u64;
+    let (start, len) = match apply_range(req, &mut resp, len) {
+        Some(range) => range,
+        None => return range_not_satisfiable(len),
+    };
+    let mut body = io::Cursor::new(SharedBytes(variant.body.clone()));
+    body.set_position(start);
+    resp.set_body_reader(body, Some(len));
+    return resp;
+}
+
+
+// A cached body, to stream without copying it
+struct SharedBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        return &self.0;
+    }
+}
+
+
+// Handle the Range header for a body of `len` bytes: the (start, length)
+// to send, having set the 206 headers if it's partial, or None for a 416
+fn apply_range(req: &WebRequest, resp: &mut WebResponse, len: u64)
+        -> Option<(u64, u64)> {
+    match requested_range(req, resp, len) {
+        ByteRange::Full => return Some((0, len)),
+        ByteRange::Partial(start, end) => {
+            resp.set_code(206, "Partial Content");
+            resp.set_header("Content-Range",
+                    &format!("bytes {}-{}/{}", start, end, len));
+            return Some((start, end - start + 1));
+        }
+        ByteRange::Unsatisfiable => return None,
+    }
+}
+
+fn range_not_satisfiable(len: u64) -> WebResponse {
+    let mut resp = error_response(416, "Range Not Satisfiable");
+    resp.set_header("Content-Range", &format!("bytes */{}", len));
+    return resp;
+}
+
+
+// The Range to send, given the response headers so far
+fn requested_range(req: &WebRequest, resp: &WebResponse, len: u64)
+        -> ByteRange {
+    let header = match req.get_header("Range") {
+        Some(header) => header,
+        None => return ByteRange::Full,
+    };
+    if let Some(if_range) = req.get_header("If-Range") {
+        if !range::if_range_matches(if_range, resp.get_header("ETag"),
+                resp.get_header("Last-Modified")) {
+            return ByteRange::Full;
+        }
+    }
+    return range::parse_range(header, len);
+}
+
+
+// The decoded mount prefix, see `Router::mount`
+fn script_name(req: &WebRequest) -> String {
+    let raw = req.environ.get(&b"script_name"[..]).map(|v| &v[..])
+        .unwrap_or(b"");
+    return String::from_utf8_lossy(&byteutils::percent_decode(raw))
+        .into_owned();
+}
+
+
+// 301 to the same URL with a '/' added to the path.  Leading slashes (and
+// backslashes, which browsers take as slashes) are collapsed into one, so
+// "//evil.com" isn't sent to another host.
+fn redirect_to_slash(req: &WebRequest) -> WebResponse {
+    let mut path = req.environ.get(&b"script_name"[..]).cloned()
+        .unwrap_or_default();
+    path.extend(req.environ.get(&b"path"[..]).map(|v| &v[..])
+        .unwrap_or(b""));
+    let start = path.iter().position(|&c| c != b'/' && c != b'\\')
+        .unwrap_or(path.len());
+    let mut location = b"/".to_vec();
+    location.extend_from_slice(&path[start..]);
+    location.push(b'/');
+    let query = req.environ.get(&b"query_string"[..]).map(|v| &v[..])
+        .unwrap_or(b"");
+    if !query.is_empty() {
+        location.push(b'?');
+        location.extend(query);
+    }
+    let location = String::from_utf8_lossy(&location);
+    let mut resp = WebResponse::new();
+    resp.set_code(301, "Moved Permanently");
+    resp.set_body_str(&format!("Redirecting to {}", location));
+    resp.set_header("Location", &location);
+    return resp;
+}
+
+
+fn error_response(code: i32, status: &str) -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(code, status);
+    resp.set_body_str(&format!("Error {}: {}", code, status));
+    return resp;
+}
+
+fn not_found() -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(404, "Not Found");
+    resp.set_body_str("Error 404: Resource not found");
+    return resp;
+}
+
+fn io_error_response(err: &io::Error) -> WebResponse {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory =>
+            return not_found(),
+        io::ErrorKind::PermissionDenied =>
+            return error_response(403, "Forbidden"),
+        _ => return error_response(500, "Internal Server Error"),
+    }
+}
+
+
+// A fresh directory for a test, with the given files
+#[cfg(test)]
+fn test_root(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let root = env::temp_dir().join(format!("mudpie-test-{}-{}", name,
+            process::id()));
+    let _ = fs::remove_dir_all(&root);
+    for &(path, content) in files {
+        let path = root.join(path);
+        if path.to_str().unwrap().ends_with('/') {
+            fs::create_dir_all(path).unwrap();
+            continue;
+        }
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    fs::create_dir_all(&root).unwrap();
+    return root;
+}
+
+#[test]
+fn test_static_files() {
+    let root = test_root("static", &[("index.html", "<p>hi</p>"),
+            ("css/site.css", "body {}"), ("data", "\x00\x01"),
+            ("empty/", "")]);
+    let files = StaticFiles::new(&root);
+
+    let mut resp = files.handle(&mut WebRequest::new_for_test("GET",
+            "/css/site.css"));
+    assert_eq!(resp.code, 200);
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/css; charset=utf-8"));
+    assert!(resp.get_header("Last-Modified").unwrap().ends_with(" GMT"));
+    assert_eq!(resp.reader_len, Some(7));
+    assert_eq!(resp.body_for_test(), b"body {}");
+
+    let resp = files.handle(&mut WebRequest::new_for_test("GET",
+            "/./index.html"));
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/html; charset=utf-8"));
+    let resp = files.handle(&mut WebRequest::new_for_test("HEAD", "/data"));
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("application/octet-stream"));
+
+    for path in &["/nope", "/../etc/passwd", "/css/../index.html", "/empty",
+            "/", "/index.html/x"] {
+        let resp = files.handle(&mut WebRequest::new_for_test("GET", path));
+        assert_eq!(resp.code, 404, "{}", path);
+    }
+    let resp = files.handle(&mut WebRequest::new_for_test("POST",
+            "/index.html"));
+    assert_eq!(resp.code, 405);
+    assert_eq!(resp.get_header("Allow"), Some("GET, HEAD"));
+}
+
+#[test]
+fn test_static_files_listing() {
+    let root = test_root("listing", &[("docs/a b.txt", "hello"),
+            ("docs/sub/x", ""), ("top", "")]);
+    let mut files = StaticFiles::new(&root);
+    assert_eq!(files.handle(&mut WebRequest::new_for_test("GET", "/docs/"))
+            .code, 404);
+    files.set_listing(true);
+
+    let resp = files.handle(&mut WebRequest::new_for_test("GET",
+            "/docs?x=1"));
+    assert_eq!(resp.code, 301);
+    assert_eq!(resp.get_header("Location"), Some("/docs/?x=1"));
+
+    let resp = files.handle(&mut WebRequest::new_for_test("GET", "/docs/"));
+    assert_eq!(resp.code, 200);
+    let html = String::from_utf8(resp.body).unwrap();
+    assert!(html.contains("<title>Index of /docs/</title>"));
+    assert!(html.contains("href=\"../\""));
+    let sub = html.find("href=\"sub/\"").unwrap();
+    let file = html.find("href=\"a%20b.txt\">a b.txt</a></td><td>5</td>")
+        .unwrap();
+    assert!(sub < file);
+
+    let resp = files.handle(&mut WebRequest::new_for_test("GET", "/"));
+    let html = String::from_utf8(resp.body).unwrap();
+    assert!(html.contains("href=\"top\""));
+    assert!(!html.contains("href=\"../\""));
+}
+
+#[test]
+fn test_static_files_index() {
+    let root = test_root("index", &[("docs/index.htm", "htm"),
+            ("docs/index.html/", ""), ("other/x", "")]);
+    let mut files = StaticFiles::new(&root);
+    files.set_index_files(&["index.html", "index.htm"]);
+
+    let mut resp = files.handle(&mut WebRequest::new_for_test("GET",
+            "/docs/"));
+    assert_eq!(resp.code, 200);
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/html; charset=utf-8"));
+    // index.html is a directory, so it's skipped
+    assert_eq!(resp.body_for_test(), b"htm");
+
+    let resp = files.handle(&mut WebRequest::new_for_test("GET", "/docs"));
+    assert_eq!(resp.code, 301);
+    assert_eq!(resp.get_header("Location"), Some("/docs/"));
+    // No index and no listing
+    let resp = files.handle(&mut WebRequest::new_for_test("GET", "/other"));
+    assert_eq!(resp.code, 404);
+}
+
+#[test]
+fn test_static_files_redirect_host() {
+    let root = test_root("redirect-host", &[("evil.com/index.html", "x")]);
+    let mut files = StaticFiles::new(&root);
+    files.set_index_files(&["index.html"]);
+    for path in &["//evil.com", "///evil.com", "/evil.com"] {
+        let resp = files.handle(&mut WebRequest::new_for_test("GET", path));
+        assert_eq!(resp.code, 301, "{}", path);
+        assert_eq!(resp.get_header("Location"), Some("/evil.com/"));
+    }
+}
+
+#[test]
+fn test_static_files_range() {
+    let root = test_root("range", &[("f.txt", "0123456789")]);
+    let files = StaticFiles::new(&root);
+    let get = |headers: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET /f.txt HTTP/1.1\r\n{}\r\n", headers));
+        return files.handle(&mut req);
+    };
+
+    let mut resp = get("");
+    assert_eq!(resp.code, 200);
+    assert_eq!(resp.get_header("Accept-Ranges"), Some("bytes"));
+    assert_eq!(resp.body_for_test(), b"0123456789");
+
+    let mut resp = get("Range: bytes=2-4\r\n");
+    assert_eq!(resp.code, 206);
+    assert_eq!(resp.get_header("Content-Range"), Some("bytes 2-4/10"));
+    assert_eq!(resp.reader_len, Some(3));
+    assert_eq!(resp.body_for_test(), b"234");
+
+    let mut resp = get("Range: bytes=-3\r\n");
+    assert_eq!(resp.body_for_test(), b"789");
+
+    let resp = get("Range: bytes=10-\r\n");
+    assert_eq!(resp.code, 416);
+    assert_eq!(resp.get_header("Content-Range"), Some("bytes */10"));
+
+    // If-Range with the current date is honored, a stale one isn't
+    let modified = get("").get_header("Last-Modified").unwrap().to_string();
+    let resp = get(&format!("Range: bytes=0-0\r\nIf-Range: {}\r\n",
+            modified));
+    assert_eq!(resp.code, 206);
+    let resp = get("Range: bytes=0-0\r\n\
+            If-Range: Tue, 10 Oct 2000 13:55:36 GMT\r\n");
+    assert_eq!(resp.code, 200);
+}
+
+#[test]
+fn test_static_files_precompressed() {
+    let root = test_root("precompressed", &[("app.js", "plain"),
+            ("app.js.gz", "gz"), ("app.js.br", "br"), ("style.css", "css"),
+            ("style.css.gz", "gz")]);
+    let mut files = StaticFiles::new(&root);
+    let get = |files: &StaticFiles, path: &str, accept: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                path, accept));
+        let mut resp = files.handle(&mut req);
+        let body = String::from_utf8(resp.body_for_test()).unwrap();
+        return (resp, body);
+    };
+    assert_eq!(get(&files, "/app.js", "gzip").1, "plain");
+    files.set_precompressed(true);
+
+    let (resp, body) = get(&files, "/app.js", "gzip, br");
+    assert_eq!(body, "br");
+    assert_eq!(resp.get_header("Content-Encoding"), Some("br"));
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/javascript; charset=utf-8"));
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Encoding"));
+    let (resp, body) = get(&files, "/app.js", "gzip;q=1, br;q=0.5");
+    assert_eq!(body, "gz");
+    assert_eq!(resp.get_header("Content-Encoding"), Some("gzip"));
+    let (resp, body) = get(&files, "/style.css", "br");
+    assert_eq!(body, "css");
+    assert_eq!(resp.get_header("Content-Encoding"), None);
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Encoding"));
+    // No variants: no Vary
+    let (resp, _) = get(&files, "/app.js.gz", "gzip");
+    assert_eq!(resp.get_header("Vary"), None);
+}
+
+#[cfg(all(test, unix))]
+#[test]
+fn test_static_files_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let outside = test_root("symlinks-outside", &[("secret", "s")]);
+    let root = test_root("symlinks", &[("real/f", "f"), (".env", "x"),
+            (".well-known/a", "a")]);
+    symlink(outside.join("secret"), root.join("out")).unwrap();
+    symlink(root.join("real"), root.join("in")).unwrap();
+    symlink(root.join(".env"), root.join("env")).unwrap();
+    symlink(&root, outside.join("root")).unwrap();
+
+    let mut files = StaticFiles::new(&root);
+    files.set_listing(true);
+    let code = |files: &StaticFiles, path: &str| {
+        return files.handle(&mut WebRequest::new_for_test("GET", path)).code;
+    };
+    assert_eq!(code(&files, "/in/f"), 200);
+    assert_eq!(code(&files, "/out"), 404);
+    assert_eq!(code(&files, "/.env"), 404);
+    assert_eq!(code(&files, "/.well-known/a"), 200);
+    // Not a way around the dotfile rule
+    assert_eq!(code(&files, "/env"), 404);
+    // The root itself may be a link
+    let linked = StaticFiles::new(outside.join("root"));
+    assert_eq!(code(&linked, "/in/f"), 200);
+    assert_eq!(code(&linked, "/env"), 404);
+    let resp = files.handle(&mut WebRequest::new_for_test("GET", "/"));
+    let html = String::from_utf8(resp.body).unwrap();
+    assert!(html.contains("href=\"in/\""));
+    assert!(!html.contains("href=\"out\""));
+    assert!(!html.contains("href=\".env\""));
+    assert!(!html.contains("href=\"env\""));
+
+    files.set_symlinks(Symlinks::Never);
+    assert_eq!(code(&files, "/in/f"), 404);
+    assert_eq!(code(&files, "/real/f"), 200);
+    files.set_symlinks(Symlinks::Follow);
+    assert_eq!(code(&files, "/out"), 200);
+    files.set_dotfiles(true);
+    assert_eq!(code(&files, "/.env"), 200);
+}
+
+#[test]
+fn test_static_files_mime_types() {
+    let mut files = StaticFiles::new("/nonexistent");
+    assert_eq!(files.content_type(Path::new("a.HTML")),
+            "text/html; charset=utf-8");
+    assert_eq!(files.content_type(Path::new("a.foo")),
+            "application/octet-stream");
+    files.add_mime_type(".FOO", "application/x-foo");
+    files.add_mime_type("html", "text/html");
+    files.set_default_mime_type("text/plain");
+    assert_eq!(files.content_type(Path::new("a.foo")), "application/x-foo");
+    assert_eq!(files.content_type(Path::new("a.Html")), "text/html");
+    assert_eq!(files.content_type(Path::new("a.unknown")), "text/plain");
+    assert_eq!(files.content_type(Path::new("README")), "text/plain");
+}
+
+#[test]
+fn test_static_files_cache() {
+    use std::fs;
+
+    let root = test_root("cache", &[("small.txt", "small"),
+            ("small.txt.gz", "gz"), ("big.txt", "0123456789abcdef")]);
+    let mut files = StaticFiles::new(&root);
+    files.set_precompressed(true);
+    files.set_cache(100, 10);
+    let get = |files: &StaticFiles, path: &str, headers: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET {} HTTP/1.1\r\n{}\r\n", path, headers));
+        let mut resp = files.handle(&mut req);
+        let body = String::from_utf8(resp.body_for_test()).unwrap();
+        return (resp, body);
+    };
+    let (resp, body) = get(&files, "/small.txt", "");
+    assert_eq!(body, "small");
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Encoding"));
+    assert!(resp.get_header("Last-Modified").is_some());
+    assert_eq!(get(&files, "/big.txt", "").1, "0123456789abcdef");
+
+    // Served from memory from now on
+    fs::remove_file(root.join("small.txt")).unwrap();
+    fs::write(root.join("big.txt"), "changed, still big").unwrap();
+    let (resp, body) = get(&files, "/small.txt", "");
+    assert_eq!(resp.code, 200);
+    assert_eq!(body, "small");
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/plain; charset=utf-8"));
+    let (resp, body) = get(&files, "/small.txt", "Accept-Encoding: gzip\r\n");
+    assert_eq!(body, "gz");
+    assert_eq!(resp.get_header("Content-Encoding"), Some("gzip"));
+    let (resp, body) = get(&files, "/small.txt", "Range: bytes=1-2\r\n");
+    assert_eq!(resp.code, 206);
+    assert_eq!(body, "ma");
+    assert_eq!(resp.get_header("Content-Range"), Some("bytes 1-2/5"));
+    assert_eq!(get(&files, "/small.txt", "Range: bytes=9-\r\n").0.code, 416);
+
+    // Too big to cache
+    assert_eq!(get(&files, "/big.txt", "").1, "changed, still big");
+}
+
+#[test]
+fn test_static_files_cache_index() {
+    use std::fs;
+
+    let root = test_root("cache-index", &[("index.html", "OLD")]);
+    let mut files = StaticFiles::new(&root);
+    files.set_index_files(&["index.html"]);
+    files.set_cache(100, 10);
+    let get = |files: &StaticFiles| {
+        let mut req = WebRequest::new_for_test("GET", "/");
+        return files.handle(&mut req).body_for_test();
+    };
+    assert_eq!(get(&files), b"OLD");
+    // Served from the cache, not read again
+    fs::write(root.join("index.html"), "NEW").unwrap();
+    assert_eq!(get(&files), b"OLD");
+}
+
+#[test]
+fn test_static_files_conditional() {
+    let root = test_root("conditional", &[("a.txt", "hello"),
+            ("a.txt.gz", "gz")]);
+    let mut files = StaticFiles::new(&root);
+    let get = |files: &StaticFiles, headers: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET /a.txt HTTP/1.1\r\n{}\r\n", headers));
+        return files.handle(&mut req);
+    };
+    let resp = get(&files, "");
+    let etag = resp.get_header("ETag").unwrap().to_string();
+    let modified = resp.get_header("Last-Modified").unwrap().to_string();
+    assert!(etag.starts_with("\"5-"));
+
+    let resp = get(&files, &format!("If-None-Match: \"x\", {}\r\n", etag));
+    assert_eq!(resp.code, 304);
+    assert_eq!(resp.get_header("ETag"), Some(&etag[..]));
+    assert_eq!(resp.get_header("Content-Type"), None);
+    assert!(resp.get_body().is_empty());
+    assert_eq!(get(&files, &format!("If-None-Match: W/{}\r\n", etag)).code,
+            304);
+    assert_eq!(get(&files, "If-None-Match: *\r\n").code, 304);
+    assert_eq!(get(&files, "If-None-Match: \"x\"\r\n").code, 200);
+    assert_eq!(get(&files, &format!("If-Modified-Since: {}\r\n", modified))
+            .code, 304);
+    assert_eq!(get(&files, "If-Modified-Since: \
+            Thu, 01 Jan 1970 00:00:00 GMT\r\n").code, 200);
+    assert_eq!(get(&files, "If-Modified-Since: garbage\r\n").code, 200);
+    // If-None-Match wins
+    assert_eq!(get(&files, &format!("If-None-Match: \"x\"\r\n\
+            If-Modified-Since: {}\r\n", modified)).code, 200);
+
+    // Variants have their own tag
+    files.set_precompressed(true);
+    let resp = get(&files, "Accept-Encoding: gzip\r\n");
+    assert!(resp.get_header("ETag").unwrap().ends_with("-gzip\""));
+    let resp = get(&files, &format!("Accept-Encoding: gzip\r\n\
+            If-None-Match: {}\r\n", etag));
+    assert_eq!(resp.code, 200);
+    assert_eq!(get(&files, &format!("If-None-Match: {}\r\n", etag)).code,
+            304);
+
+    // And from the cache
+    files.set_cache(100, 100);
+    assert_eq!(get(&files, "").code, 200);
+    let resp = get(&files, &format!("If-None-Match: {}\r\n", etag));
+    assert_eq!(resp.code, 304);
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Encoding"));
+}
+
+#[test]
+fn test_static_files_denied_extensions() {
+    let root = test_root("denied", &[("a.txt", "a"), ("b.BAK", "b"),
+            ("old.bak/c.txt", "c"), ("notes.txt~", "n")]);
+    let mut files = StaticFiles::new(&root);
+    files.set_listing(true);
+    files.add_denied_extension(".bak");
+    files.add_denied_extension("txt~");
+    let code = |files: &StaticFiles, path: &str| {
+        return files.handle(&mut WebRequest::new_for_test("GET", path)).code;
+    };
+    assert_eq!(code(&files, "/a.txt"), 200);
+    assert_eq!(code(&files, "/b.BAK"), 404);
+    assert_eq!(code(&files, "/old.bak/c.txt"), 404);
+    assert_eq!(code(&files, "/notes.txt~"), 404);
+    let resp = files.handle(&mut WebRequest::new_for_test("GET", "/"));
+    let html = String::from_utf8(resp.body).unwrap();
+    assert!(html.contains("href=\"a.txt\""));
+    assert!(!html.contains("b.BAK"));
+    assert!(!html.contains("old.bak"));
+    assert!(!html.contains("notes"));
+}
+
+#[test]
+fn test_static_files_spa_fallback() {
+    let root = test_root("spa", &[("index.html", "app"), ("app.js", "js"),
+            ("assets/", "")]);
+    let mut files = StaticFiles::new(&root);
+    let get = |files: &StaticFiles, path: &str, accept: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET {} HTTP/1.1\r\n{}\r\n", path, accept));
+        let mut resp = files.handle(&mut req);
+        let body = String::from_utf8(resp.body_for_test()).unwrap();
+        return (resp.code, body);
+    };
+    assert_eq!(get(&files, "/users/42", "").0, 404);
+    files.set_spa_fallback(Some("index.html"));
+
+    let html = "Accept: text/html,application/xhtml+xml,*/*;q=0.8\r\n";
+    assert_eq!(get(&files, "/users/42", html), (200, "app".to_string()));
+    assert_eq!(get(&files, "/assets/", html), (200, "app".to_string()));
+    assert_eq!(get(&files, "/users/42", ""), (200, "app".to_string()));
+    assert_eq!(get(&files, "/app.js", html), (200, "js".to_string()));
+    // Not for clients that don't want HTML
+    assert_eq!(get(&files, "/api/x", "Accept: application/json\r\n").0, 404);
+    assert_eq!(get(&files, "/x.png", "Accept: image/*\r\n").0, 404);
+}
+
+#[test]
+fn test_static_files_json_listing() {
+    let root = test_root("json-listing", &[("a.txt", "hello"), ("sub/", "")]);
+    let mut files = StaticFiles::new(&root);
+    files.set_listing(true);
+    let get = |files: &StaticFiles, accept: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET / HTTP/1.1\r\nAccept: {}\r\n\r\n", accept));
+        return files.handle(&mut req);
+    };
+    let json = "application/json, text/html;q=0.5";
+    let resp = get(&files, json);
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/html; charset=utf-8"));
+    assert_eq!(resp.get_header("Vary"), None);
+    files.set_json_listing(true);
+
+    let resp = get(&files, json);
+    assert_eq!(resp.get_header("Content-Type"), Some("application/json"));
+    assert_eq!(resp.get_header("Vary"), Some("Accept"));
+    let body = String::from_utf8(resp.body).unwrap();
+    assert!(body.starts_with("[{\"name\":\"sub\",\"type\":\"directory\","));
+    assert!(body.contains("{\"name\":\"a.txt\",\"type\":\"file\",\"size\":5,\
+            \"mtime\":\""));
+    // Browsers, and curl's "*/*", get HTML
+    let resp = get(&files, "text/html,application/xhtml+xml,*/*;q=0.8");
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/html; charset=utf-8"));
+    let resp = get(&files, "*/*");
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/html; charset=utf-8"));
+}
+
+#[test]
+fn test_static_files_languages() {
+    let root = test_root("languages", &[("page.en.html", "en"),
+            ("page.de.html", "de"), ("page.de.html.gz", "de gz"),
+            ("only.fr.html", "fr"), ("app.min.js", "js"),
+            ("docs/index.de.html", "docs de")]);
+    let mut files = StaticFiles::new(&root);
+    files.set_index_files(&["index.html"]);
+    let get = |files: &StaticFiles, path: &str, headers: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET {} HTTP/1.1\r\n{}\r\n", path, headers));
+        let mut resp = files.handle(&mut req);
+        let body = String::from_utf8(resp.body_for_test()).unwrap();
+        return (resp, body);
+    };
+    assert_eq!(get(&files, "/page.html", "").0.code, 404);
+    files.set_languages(&["en", "DE"]);
+
+    let (resp, body) = get(&files, "/page.html",
+            "Accept-Language: de-CH, de;q=0.9, en;q=0.5\r\n");
+    assert_eq!(body, "de");
+    assert_eq!(resp.get_header("Content-Language"), Some("de"));
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Language"));
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/html; charset=utf-8"));
+    assert_eq!(get(&files, "/page.html", "Accept-Language: en-GB\r\n").1,
+            "en");
+    // Nothing acceptable, or no header: the first language
+    assert_eq!(get(&files, "/page.html", "Accept-Language: ja\r\n").1,
+            "en");
+    assert_eq!(get(&files, "/page.html", "").1, "en");
+    // Not a configured language
+    assert_eq!(get(&files, "/only.html", "Accept-Language: fr\r\n").0.code,
+            404);
+    let (resp, _) = get(&files, "/app.min.js", "");
+    assert_eq!(resp.get_header("Content-Language"), None);
+    // Requested directly
+    let (resp, body) = get(&files, "/page.en.html", "");
+    assert_eq!(body, "en");
+    assert_eq!(resp.get_header("Content-Language"), Some("en"));
+    // Index files
+    assert_eq!(get(&files, "/docs/", "").1, "docs de");
+
+    files.set_precompressed(true);
+    let (resp, body) = get(&files, "/page.html",
+            "Accept-Language: de\r\nAccept-Encoding: gzip\r\n");
+    assert_eq!(body, "de gz");
+    assert_eq!(resp.get_header("Vary"),
+            Some("Accept-Encoding, Accept-Language"));
+    assert_eq!(resp.get_header("Content-Language"), Some("de"));
+}
+
+#[test]
+fn test_static_files_webdav() {
+    let root = test_root("webdav", &[("a b.txt", "hello"), ("sub/c", "c"),
+            (".hidden", "h")]);
+    let mut files = StaticFiles::new(&root);
+    let request = |files: &StaticFiles, head: &str| {
+        return files.handle(&mut WebRequest::parse_for_test(
+                &format!("{}\r\n\r\n", head)));
+    };
+    let resp = request(&files, "PROPFIND / HTTP/1.1\r\nDepth: 1");
+    assert_eq!(resp.code, 405);
+    assert_eq!(resp.get_header("Allow"), Some("GET, HEAD"));
+    files.set_webdav(true);
+
+    let resp = request(&files, "OPTIONS / HTTP/1.1");
+    assert_eq!(resp.code, 200);
+    assert_eq!(resp.get_header("DAV"), Some("1"));
+    assert_eq!(resp.get_header("Allow"),
+            Some("OPTIONS, GET, HEAD, PROPFIND"));
+
+    let resp = request(&files, "PROPFIND / HTTP/1.1\r\nDepth: 1");
+    assert_eq!(resp.code, 207);
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("application/xml; charset=utf-8"));
+    let xml = String::from_utf8(resp.body).unwrap();
+    assert!(xml.contains("<D:href>/</D:href>"));
+    assert!(xml.contains("<D:href>/sub/</D:href>"));
+    assert!(xml.contains("<D:href>/a%20b.txt</D:href>"));
+    assert!(xml.contains("<D:getcontentlength>5</D:getcontentlength>\
+            <D:getcontenttype>text/plain; charset=utf-8</D:getcontenttype>"));
+    assert!(!xml.contains("hidden"));
+    let resp = request(&files, "PROPFIND /sub HTTP/1.1\r\nDepth: 0");
+    let xml = String::from_utf8(resp.body).unwrap();
+    assert_eq!(xml.matches("<D:response>").count(), 1);
+    assert!(xml.contains("<D:href>/sub/</D:href>"));
+    assert_eq!(request(&files, "PROPFIND /sub HTTP/1.1").code, 403);
+    assert_eq!(request(&files, "PROPFIND /x HTTP/1.1\r\nDepth: 0").code, 404);
+
+    // Read-only
+    assert_eq!(request(&files, "MKCOL /new HTTP/1.1").code, 405);
+    files.set_writable(true);
+    assert_eq!(request(&files, "OPTIONS / HTTP/1.1").get_header("Allow"),
+            Some("OPTIONS, GET, HEAD, PROPFIND, PUT, DELETE, MKCOL"));
+    assert_eq!(request(&files, "MKCOL /new HTTP/1.1").code, 201);
+    assert!(root.join("new").is_dir());
+    assert_eq!(request(&files, "MKCOL /new HTTP/1.1").code, 405);
+    assert_eq!(request(&files, "MKCOL /a/b HTTP/1.1").code, 409);
+    assert_eq!(request(&files, "MKCOL /.git HTTP/1.1").code, 404);
+}
+
+#[test]
+fn test_static_files_writable() {
+    use std::fs;
+
+    let root = test_root("writable", &[("old.txt", "old"), ("dir/f", "f"),
+            ("empty/", "")]);
+    let mut files = StaticFiles::new(&root);
+    let request = |files: &StaticFiles, head: &str, body: &str| {
+        let mut req = WebRequest::parse_for_test(
+                &format!("{}\r\n\r\n", head));
+        req.body = body.as_bytes().to_vec();
+        return files.handle(&mut req).code;
+    };
+    assert_eq!(request(&files, "PUT /new.txt HTTP/1.1", "new"), 405);
+    files.set_writable(true);
+    files.set_max_upload_size(10);
+    files.set_cache(100, 100);
+    assert_eq!(request(&files, "GET /old.txt HTTP/1.1", ""), 200);
+
+    assert_eq!(request(&files, "PUT /new.txt HTTP/1.1", "new"), 201);
+    assert_eq!(fs::read_to_string(root.join("new.txt")).unwrap(), "new");
+    assert_eq!(request(&files, "PUT /old.txt HTTP/1.1", "replaced"), 204);
+    let mut req = WebRequest::new_for_test("GET", "/old.txt");
+    assert_eq!(files.handle(&mut req).body_for_test(), b"replaced");
+    assert_eq!(request(&files, "PUT /old.txt HTTP/1.1\r\nIf-None-Match: *",
+            "x"), 412);
+    assert_eq!(request(&files, "PUT /big HTTP/1.1", "01234567890"), 413);
+    assert_eq!(request(&files, "PUT /nodir/x HTTP/1.1", "x"), 409);
+    assert_eq!(request(&files, "PUT /dir HTTP/1.1", "x"), 405);
+    assert_eq!(request(&files, "PUT /dir/ HTTP/1.1", "x"), 405);
+    assert_eq!(request(&files, "PUT /.htaccess HTTP/1.1", "x"), 404);
+    assert_eq!(request(&files, "PUT /a HTTP/1.1\r\n\
+            Content-Range: bytes 0-0/1", "x"), 400);
+    // No temporary files left behind
+    assert_eq!(fs::read_dir(&root).unwrap().count(), 4);
+
+    assert_eq!(request(&files, "DELETE /old.txt HTTP/1.1", ""), 204);
+    assert!(!root.join("old.txt").exists());
+    assert_eq!(request(&files, "GET /old.txt HTTP/1.1", ""), 404);
+    assert_eq!(request(&files, "DELETE /old.txt HTTP/1.1", ""), 404);
+    assert_eq!(request(&files, "DELETE /dir HTTP/1.1", ""), 409);
+    assert_eq!(request(&files, "DELETE /empty/ HTTP/1.1", ""), 204);
+    assert_eq!(request(&files, "DELETE / HTTP/1.1", ""), 405);
+}
+
+#[test]
+fn test_write_atomic() {
+    use std::fs;
+
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            return Err(io::Error::other("gone"));
+        }
+    }
+    let root = test_root("write-atomic", &[("a.txt", "old")]);
+    let path = root.join("a.txt");
+    assert!(write_atomic(&path, &mut Failing).is_err());
+    // Left as it was, and the temporary file removed
+    assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+    assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+    write_atomic(&path, &mut &b"new"[..]).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+}
+
+#[test]
+fn test_static_files_cache_watch() {
+    use std::fs;
+    use std::thread;
+
+    let root = test_root("cache-watch", &[("a.txt", "old")]);
+    let mut files = StaticFiles::new(&root);
+    files.set_cache_watch(Duration::from_millis(10));
+    files.set_cache(100, 100);
+    files.set_precompressed(true);
+    let get = |files: &StaticFiles| {
+        let mut req = WebRequest::parse_for_test(
+                "GET /a.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        return files.handle(&mut req).body_for_test();
+    };
+    assert_eq!(get(&files), b"old");
+    fs::write(root.join("a.txt"), "newer").unwrap();
+    assert_eq!(get(&files), b"old");
+    let cache = files.cache.clone().unwrap();
+    cache.revalidate();
+    assert_eq!(get(&files), b"newer");
+    // A new variant
+    fs::write(root.join("a.txt.gz"), "gz").unwrap();
+    cache.revalidate();
+    assert_eq!(get(&files), b"gz");
+
+    // And from the thread
+    fs::write(root.join("a.txt.gz"), "gzip").unwrap();
+    for _ in 0..200 {
+        if get(&files) == b"gzip" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(get(&files), b"gzip");
+}
@@ -0,0 +1,458 @@
+//! What a running server is doing, for embedders
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{WebRequest, MatchedRoute};
+
+// Latency buckets: exact below 8us, then 8 per power of two, so within
+// 12.5%, up to 2^40us (12 days)
+static SUB_BUCKETS: usize = 8;
+static BUCKETS: usize = 8 + 38 * 8;
+
+// How often workers that stopped accepting look at the load again
+static ACCEPT_PAUSE_POLL: Duration = Duration::from_millis(10);
+
+
+/// A handle on a `WebServer`, from `WebServer::handle`, that stays usable
+/// while the server runs.  Clones are handles on the same server.
+///
+/// ex:
+///
+/// ```ignore
+/// let handle = server.handle();
+/// thread::spawn(move || loop {
+///     thread::sleep(Duration::from_secs(10));
+///     let stats = handle.stats();
+///     println!("{} connections, {} served", stats.active_connections,
+///             stats.total_served);
+/// });
+/// server.run("0.0.0.0", 8080);
+/// ```
+#[derive(Clone)]
+pub struct ServerHandle {
+    stats: Arc<Stats>,
+}
+
+/// A snapshot of a server's state, from `ServerHandle::stats`.
+///
+/// Connections aren't queued by the server: each idle worker accepts its
+/// own, so those not accepted yet are in the system's backlog, out of
+/// sight.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerStats {
+    /// Connections open
+    pub active_connections: usize,
+    /// Of the open connections, those waiting for a request: HTTP/1.1
+    /// ones not done sending it, and HTTP/2 ones with none in progress
+    pub idle_connections: usize,
+    /// Workers with a connection
+    pub busy_workers: usize,
+    /// Workers waiting for a connection
+    pub idle_workers: usize,
+    /// Requests being handled or answered
+    pub requests_in_flight: usize,
+    /// Whether workers stopped accepting connections, for having too many
+    /// requests in flight (see `WebServer::set_accept_watermarks`)
+    pub accept_paused: bool,
+    /// How many times they did, since the server started
+    pub accept_pauses: u64,
+    /// Connections answered 503 for the same reason, with
+    /// `Overload::Reject`, since the server started
+    pub overload_rejected: u64,
+    /// Requests answered by the handler, since the server started
+    pub total_served: u64,
+    /// Since `WebServer::run`, or zero before
+    pub uptime: Duration,
+    /// How long the requests served took, from accepting them to sending
+    /// their response
+    pub latency: Latency,
+    /// The same, for the requests a `Router` routed, by `MatchedRoute`
+    pub route_latency: BTreeMap<String, Latency>,
+}
+
+/// What workers do past the high watermark of requests in flight; see
+/// `WebServer::set_overload_policy`.
+///
+/// There's no queue of accepted connections to shed the oldest of: those
+/// not accepted yet wait in the system's backlog, out of the server's
+/// reach.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overload {
+    /// Stop accepting connections, leaving them in the backlog, until the
+    /// requests are down to the low watermark (the default).  Counted in
+    /// `ServerStats::accept_pauses`.
+    Pause,
+    /// Go on accepting, and answer the new connections `503 Service
+    /// Unavailable` without reading their request, until the requests are
+    /// down to the low watermark.  Counted in
+    /// `ServerStats::overload_rejected`.
+    Reject,
+}
+
+/// A distribution of request durations, from `ServerStats`.
+///
+/// Durations are counted in buckets, exact below 8µs and then within
+/// 12.5%: an eighth of each power of two.
+///
+/// ex:
+///
+/// ```ignore
+/// let latency = handle.stats().latency;
+/// println!("p50 {:?} p99 {:?}", latency.percentile(50.0),
+///         latency.percentile(99.0));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Latency {
+    counts: Vec<u64>,
+    sum: Duration,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(stats: Arc<Stats>) -> ServerHandle {
+        return ServerHandle {
+            stats: stats,
+        };
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        let stats = &self.stats;
+        let load = |n: &AtomicUsize| n.load(Ordering::Relaxed);
+        let busy = load(&stats.busy_workers);
+        let uptime = match *stats.started.lock().unwrap() {
+            Some(started) => started.elapsed(),
+            None => Duration::from_secs(0),
+        };
+        return ServerStats {
+            active_connections: load(&stats.connections),
+            idle_connections: load(&stats.idle_connections),
+            busy_workers: busy,
+            idle_workers: load(&stats.workers).saturating_sub(busy),
+            requests_in_flight: load(&stats.requests),
+            accept_paused: stats.accept_paused.load(Ordering::Relaxed),
+            accept_pauses: stats.accept_pauses.load(Ordering::Relaxed),
+            overload_rejected: stats.overload_rejected.load(Ordering::Relaxed),
+            total_served: stats.served.load(Ordering::Relaxed),
+            uptime: uptime,
+            latency: stats.latency.snapshot(),
+            route_latency: stats.route_latency.read().unwrap().iter()
+                .map(|(route, h)| (route.clone(), h.snapshot())).collect(),
+        };
+    }
+
+    /// Whether `WebServer::run` started serving
+    pub fn is_running(&self) -> bool {
+        return self.stats.started.lock().unwrap().is_some();
+    }
+
+    /// Mark the server as draining, or not anymore.  It goes on serving,
+    /// but its readiness check (see `Health`) fails, for load balancers
+    /// to stop sending it requests before it's stopped.
+    pub fn set_draining(&self, draining: bool) {
+        self.stats.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        return self.stats.draining.load(Ordering::Relaxed);
+    }
+}
+
+
+// The counts behind `ServerHandle`, kept by the server
+#[derive(Default)]
+pub(crate) struct Stats {
+    pub(crate) started: Mutex<Option<Instant>>,
+    pub(crate) workers: AtomicUsize,
+    pub(crate) busy_workers: AtomicUsize,
+    pub(crate) connections: AtomicUsize,
+    pub(crate) idle_connections: AtomicUsize,
+    pub(crate) requests: AtomicUsize,
+    pub(crate) accept_paused: AtomicBool,
+    pub(crate) accept_pauses: AtomicU64,
+    pub(crate) rejecting: AtomicBool,
+    pub(crate) overload_rejected: AtomicU64,
+    pub(crate) served: AtomicU64,
+    pub(crate) draining: AtomicBool,
+    latency: Histogram,
+    route_latency: RwLock<BTreeMap<String, Arc<Histogram>>>,
+}
+
+impl Stats {
+    // A request was served, `duration` after it came
+    pub(crate) fn record(&self, req: &WebRequest, duration: Duration) {
+        self.served.fetch_add(1, Ordering::Relaxed);
+        self.latency.record(duration);
+        let route = match req.get_extensions().get::<MatchedRoute>() {
+            Some(route) => &route.0,
+            None => return,
+        };
+        let found = self.route_latency.read().unwrap().get(route).cloned();
+        let histogram = match found {
+            Some(histogram) => histogram,
+            None => self.route_latency.write().unwrap().entry(route.clone())
+                .or_default().clone(),
+        };
+        histogram.record(duration);
+    }
+
+    // Before accepting a connection: with `high` requests in flight or
+    // more, stop accepting until they're down to `low`.  Meanwhile new
+    // connections wait in the system's backlog.
+    pub(crate) fn wait_to_accept(&self, high: usize, low: usize) {
+        let requests = || self.requests.load(Ordering::Relaxed);
+        // Other workers stopped already: wait with them, down to low
+        if !self.accept_paused.load(Ordering::Relaxed) && requests() < high {
+            return;
+        }
+        if !self.accept_paused.swap(true, Ordering::Relaxed) {
+            self.accept_pauses.fetch_add(1, Ordering::Relaxed);
+        }
+        while requests() > low {
+            thread::sleep(ACCEPT_PAUSE_POLL);
+        }
+        self.accept_paused.store(false, Ordering::Relaxed);
+    }
+
+    // For a new connection: whether to turn it away, from `high` requests
+    // in flight until they're down to `low`
+    pub(crate) fn reject_new(&self, high: usize, low: usize) -> bool {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let rejecting = match self.rejecting.load(Ordering::Relaxed) {
+            true => requests > low,
+            false => requests >= high,
+        };
+        self.rejecting.store(rejecting, Ordering::Relaxed);
+        if rejecting {
+            self.overload_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        return rejecting;
+    }
+}
+
+// Counts by bucket, see Latency
+struct Histogram {
+    counts: Vec<AtomicU64>,
+    micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        return Histogram {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            micros: AtomicU64::new(0),
+        };
+    }
+}
+
+impl Histogram {
+    fn record(&self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        self.counts[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Latency {
+        return Latency {
+            counts: self.counts.iter().map(|n| n.load(Ordering::Relaxed))
+                .collect(),
+            sum: Duration::from_micros(self.micros.load(Ordering::Relaxed)),
+        };
+    }
+}
+
+// The bucket of a duration in microseconds
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS as u64 {
+        return micros as usize;
+    }
+    // 3 and up, the power of two below micros
+    let power = 63 - micros.leading_zeros() as usize;
+    let sub = (micros >> (power - 3)) as usize & (SUB_BUCKETS - 1);
+    return (SUB_BUCKETS + (power - 3) * SUB_BUCKETS + sub).min(BUCKETS - 1);
+}
+
+// The durations in a bucket, in microseconds: (lowest, highest)
+fn bucket_range(bucket: usize) -> (u64, u64) {
+    if bucket < SUB_BUCKETS {
+        return (bucket as u64, bucket as u64);
+    }
+    let power = (bucket - SUB_BUCKETS) / SUB_BUCKETS + 3;
+    let sub = ((bucket - SUB_BUCKETS) % SUB_BUCKETS) as u64;
+    let low = (SUB_BUCKETS as u64 + sub) << (power - 3);
+    if bucket == BUCKETS - 1 {
+        return (low, u64::MAX);
+    }
+    return (low, low + (1 << (power - 3)) - 1);
+}
+
+impl Latency {
+    /// Requests counted
+    pub fn count(&self) -> u64 {
+        return self.counts.iter().sum();
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => return None,
+            count => return Some(self.sum.div_f64(count as f64)),
+        }
+    }
+
+    /// The duration `percent` of the requests took at most, ex: 99.0 for
+    /// the p99; the top of its bucket.  None if no request was counted.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = (percent.clamp(0.0, 100.0) / 100.0 * count as f64).ceil()
+            .max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Duration::from_micros(bucket_range(bucket).1));
+            }
+        }
+        return None;
+    }
+
+    /// The buckets with requests in them, in order: the highest duration
+    /// of each, and its count
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        return self.counts.iter().enumerate().filter(|&(_, &n)| n > 0)
+            .map(|(bucket, &n)| {
+                (Duration::from_micros(bucket_range(bucket).1), n)
+            }).collect();
+    }
+}
+
+
+#[test]
+fn test_server_stats() {
+    use super::metrics::Open;
+
+    let stats = Arc::new(Stats::default());
+    let handle = ServerHandle::new(stats.clone());
+    assert_eq!(handle.stats().uptime, Duration::from_secs(0));
+    *stats.started.lock().unwrap() = Some(Instant::now());
+    let workers: Vec<_> = (0..3).map(|_| Open::new(&stats.workers)).collect();
+    let busy = Open::new(&stats.busy_workers);
+    let conn = Open::new(&stats.connections);
+    let idle = Open::new(&stats.idle_connections);
+    stats.served.fetch_add(2, Ordering::Relaxed);
+
+    let now = handle.clone().stats();
+    assert_eq!(now, ServerStats {
+        active_connections: 1,
+        idle_connections: 1,
+        busy_workers: 1,
+        idle_workers: 2,
+        requests_in_flight: 0,
+        accept_paused: false,
+        accept_pauses: 0,
+        overload_rejected: 0,
+        total_served: 2,
+        uptime: now.uptime,
+        latency: Histogram::default().snapshot(),
+        route_latency: BTreeMap::new(),
+    });
+    drop((workers, busy, conn, idle));
+    let now = handle.stats();
+    assert_eq!((now.active_connections, now.idle_connections,
+            now.busy_workers, now.idle_workers), (0, 0, 0, 0));
+}
+
+#[test]
+fn test_wait_to_accept() {
+    use super::metrics::Open;
+
+    let stats = Arc::new(Stats::default());
+    let handle = ServerHandle::new(stats.clone());
+    let mut requests: Vec<_> = (0..3).map(|_| Open::new(&stats.requests))
+        .collect();
+    stats.wait_to_accept(4, 1);
+    assert_eq!(handle.stats().accept_pauses, 0);
+
+    let more = Open::new(&stats.requests);
+    let worker_stats = stats.clone();
+    let worker = thread::spawn(move || worker_stats.wait_to_accept(4, 1));
+    while !handle.stats().accept_paused {
+        thread::sleep(Duration::from_millis(1));
+    }
+    // Still over the low watermark
+    drop((more, requests.pop()));
+    thread::sleep(Duration::from_millis(30));
+    let now = handle.stats();
+    assert_eq!((now.requests_in_flight, now.accept_paused), (2, true));
+    drop(requests);
+    worker.join().unwrap();
+    let now = handle.stats();
+    assert_eq!((now.accept_paused, now.accept_pauses), (false, 1));
+}
+
+#[test]
+fn test_reject_new() {
+    use super::metrics::Open;
+
+    let stats = Stats::default();
+    let mut requests: Vec<_> = (0..3).map(|_| Open::new(&stats.requests))
+        .collect();
+    assert!(stats.reject_new(3, 1));
+    drop(requests.pop());
+    // Until down to the low watermark
+    assert!(stats.reject_new(3, 1));
+    drop(requests.pop());
+    assert!(!stats.reject_new(3, 1));
+    requests.push(Open::new(&stats.requests));
+    assert!(!stats.reject_new(3, 1));
+    assert_eq!(stats.overload_rejected.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_latency() {
+    for micros in [0, 7, 8, 9, 15, 16, 17, 1000, 123_456_789, u64::MAX] {
+        let (low, high) = bucket_range(bucket(micros));
+        assert!(low <= micros && micros <= high, "{}", micros);
+        // Within 12.5%
+        assert!(high - low <= low / 8 || micros > 1 << 40, "{}", micros);
+    }
+    for i in 1..BUCKETS {
+        assert_eq!(bucket_range(i - 1).1 + 1, bucket_range(i).0);
+    }
+
+    let stats = Stats::default();
+    let mut req = WebRequest::new_for_test("GET", "/users/12");
+    assert_eq!(stats.latency.snapshot().percentile(50.0), None);
+    for ms in 1..=100 {
+        stats.record(&req, Duration::from_millis(ms));
+    }
+    req.get_extensions_mut().insert(MatchedRoute("/users/:id".into()));
+    stats.record(&req, Duration::from_micros(5));
+    stats.record(&req, Duration::from_micros(5));
+
+    let now = ServerHandle::new(Arc::new(stats)).stats();
+    assert_eq!(now.total_served, 102);
+    let latency = now.latency;
+    assert_eq!(latency.count(), 102);
+    let near = |d: Option<Duration>, ms: f64| {
+        let d = d.unwrap().as_secs_f64() * 1000.0;
+        return d >= ms && d <= ms * 1.125;
+    };
+    assert!(near(latency.percentile(50.0), 49.0));
+    assert!(near(latency.percentile(90.0), 89.0));
+    assert!(near(latency.percentile(99.0), 98.0));
+    assert!(near(latency.percentile(100.0), 100.0));
+    assert_eq!(latency.percentile(0.0), Some(Duration::from_micros(5)));
+    assert!(near(latency.mean(), 49.5));
+    assert_eq!(latency.buckets()[0], (Duration::from_micros(5), 2));
+    assert_eq!(latency.buckets().iter().map(|b| b.1).sum::<u64>(), 102);
+
+    let users = &now.route_latency["/users/:id"];
+    assert_eq!(now.route_latency.len(), 1);
+    assert_eq!((users.count(), users.percentile(99.0)),
+            (2, Some(Duration::from_micros(5))));
+}
@@ -0,0 +1,208 @@
+//! A page of what the server is doing, like Apache's mod_status
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use utils::escape::html_element_escape;
+use utils::time;
+use super::{Handler, WebRequest, WebResponse, ServerHandle, MatchedRoute};
+
+// Requests shown as recent
+static RECENT: usize = 20;
+
+
+/// An HTML page of the server's state: its connections and workers, the
+/// last requests, and counts by route (see `MatchedRoute`; those a
+/// `Router` didn't route count as "-").
+///
+/// Given to `WebServer::set_status_page`, it's told about each response;
+/// as a handler it shows the page.  The page shows paths and client
+/// addresses, so keep it private, ex: with `IpFilter` or `BasicAuth`.
+/// Clones share the counts.
+///
+/// ex:
+///
+/// ```ignore
+/// let status = StatusPage::new(server.handle());
+/// server.set_status_page(status.clone());
+/// let mut filter = IpFilter::new();
+/// filter.allow("127.0.0.1/32");
+/// let mut private = Chain::new(status);
+/// private.add(filter);
+/// server.add_path("GET", "/server-status", private);
+/// ```
+#[derive(Clone)]
+pub struct StatusPage {
+    server: ServerHandle,
+    inner: Arc<Mutex<Record>>,
+}
+
+#[derive(Default)]
+struct Record {
+    recent: VecDeque<Recent>,
+    routes: BTreeMap<String, RouteCounts>,
+}
+
+struct Recent {
+    time: SystemTime,
+    peer_addr: SocketAddr,
+    method: String,
+    path: String,
+    code: i32,
+    duration: Duration,
+}
+
+#[derive(Default)]
+struct RouteCounts {
+    // By status class, 1xx to 5xx
+    responses: [u64; 5],
+    duration: Duration,
+}
+
+impl StatusPage {
+    pub fn new(server: ServerHandle) -> StatusPage {
+        return StatusPage {
+            server: server,
+            inner: Arc::new(Mutex::new(Record::default())),
+        };
+    }
+
+    // A response was sent, `duration` after the request came
+    pub(crate) fn record(&self, req: Option<&WebRequest>, code: i32,
+            peer_addr: SocketAddr, duration: Duration) {
+        let (method, path) = match req {
+            Some(req) => (req.get_method().to_ascii_uppercase(),
+                    req.get_path().to_string()),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        let route = req.and_then(|r| r.get_extensions().get::<MatchedRoute>())
+            .map(|r| r.0.clone()).unwrap_or_else(|| "-".to_string());
+        let mut inner = match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let counts = inner.routes.entry(route).or_default();
+        if let 100..=599 = code {
+            counts.responses[code as usize / 100 - 1] += 1;
+        }
+        counts.duration += duration;
+        if inner.recent.len() == RECENT {
+            inner.recent.pop_back();
+        }
+        inner.recent.push_front(Recent {
+            time: SystemTime::now(),
+            peer_addr: peer_addr,
+            method: method,
+            path: path,
+            code: code,
+            duration: duration,
+        });
+    }
+
+    /// The page, in HTML
+    pub fn render(&self) -> String {
+        let stats = self.server.stats();
+        let mut out = String::new();
+        out.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+            <title>Server status</title></head><body>\n\
+            <h1>Server status</h1>\n<table>\n");
+        let rows = [
+            ("Uptime", format!("{}s", stats.uptime.as_secs())),
+            ("Draining", self.server.is_draining().to_string()),
+            ("Connections", format!("{} ({} idle)", stats.active_connections,
+                    stats.idle_connections)),
+            ("Workers", format!("{} busy, {} idle", stats.busy_workers,
+                    stats.idle_workers)),
+            ("Requests served", stats.total_served.to_string()),
+        ];
+        for &(name, ref value) in rows.iter() {
+            let _ = writeln!(out, "<tr><th>{}</th><td>{}</td></tr>", name,
+                    value);
+        }
+        out.push_str("</table>\n");
+
+        let inner = match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        out.push_str("<h2>Recent requests</h2>\n<table>\n<tr><th>Time</th>\
+            <th>Client</th><th>Method</th><th>Path</th><th>Code</th>\
+            <th>ms</th></tr>\n");
+        for r in inner.recent.iter() {
+            let _ = writeln!(out, "<tr><td>{}</td><td>{}</td><td>{}</td>\
+                    <td>{}</td><td>{}</td><td>{:.1}</td></tr>",
+                    time::format_iso8601(r.time), r.peer_addr.ip(),
+                    html_element_escape(&r.method),
+                    html_element_escape(&r.path), r.code, ms(r.duration));
+        }
+        out.push_str("</table>\n");
+
+        out.push_str("<h2>Routes</h2>\n<table>\n<tr><th>Route</th>\
+            <th>Requests</th><th>1xx</th><th>2xx</th><th>3xx</th>\
+            <th>4xx</th><th>5xx</th><th>Mean ms</th></tr>\n");
+        for (route, counts) in inner.routes.iter() {
+            let total: u64 = counts.responses.iter().sum();
+            let _ = write!(out, "<tr><td>{}</td><td>{}</td>",
+                    html_element_escape(route), total);
+            for n in counts.responses.iter() {
+                let _ = write!(out, "<td>{}</td>", n);
+            }
+            let mean = ms(counts.duration) / total.max(1) as f64;
+            let _ = writeln!(out, "<td>{:.1}</td></tr>", mean);
+        }
+        out.push_str("</table>\n</body></html>\n");
+        return out;
+    }
+}
+
+impl Handler for StatusPage {
+    fn handle(&self, _req: &mut WebRequest) -> WebResponse {
+        let mut resp = WebResponse::new();
+        resp.set_header("Content-Type", "text/html; charset=utf-8");
+        resp.set_header("Cache-Control", "no-store");
+        resp.set_body_str(&self.render());
+        return resp;
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    return duration.as_secs_f64() * 1000.0;
+}
+
+
+#[test]
+fn test_status_page() {
+    use super::stats::Stats;
+
+    let stats = Arc::new(Stats::default());
+    stats.served.store(3, ::std::sync::atomic::Ordering::Relaxed);
+    let status = StatusPage::new(ServerHandle::new(stats));
+    let peer = "10.1.2.3:5555".parse().unwrap();
+    let mut req = WebRequest::new_for_test("GET", "/users/<12>");
+    req.get_extensions_mut().insert(MatchedRoute("/users/:id".to_string()));
+    status.record(Some(&req), 200, peer, Duration::from_millis(10));
+    status.record(Some(&req), 404, peer, Duration::from_millis(20));
+    status.clone().record(None, 400, peer, Duration::from_millis(1));
+    for _ in 0..RECENT {
+        status.record(None, 400, peer, Duration::from_millis(1));
+    }
+
+    let mut resp = status.handle(&mut WebRequest::new_for_test("GET", "/"));
+    assert_eq!(resp.get_header("Content-Type"),
+            Some("text/html; charset=utf-8"));
+    let page = String::from_utf8(resp.body_for_test()).unwrap();
+    assert!(page.contains("<tr><th>Requests served</th><td>3</td></tr>"));
+    assert!(page.contains("<tr><td>/users/:id</td><td>2</td><td>0</td>\
+            <td>1</td><td>0</td><td>1</td><td>0</td><td>15.0</td></tr>"));
+    assert!(page.contains("<tr><td>-</td><td>21</td><td>0</td><td>0</td>\
+            <td>0</td><td>21</td><td>0</td><td>1.0</td></tr>"));
+    // Only the last ones, newest first
+    assert_eq!(page.matches("<td>10.1.2.3</td>").count(), RECENT);
+    assert!(!page.contains("/users/&lt;12&gt;"));
+    status.record(Some(&req), 200, peer, Duration::from_millis(10));
+    assert!(status.render().contains("<td>10.1.2.3</td><td>GET</td>\
+            <td>/users/&lt;12&gt;</td><td>200</td>"));
+}
@@ -0,0 +1,148 @@
+//! Bounding how long a handler may take
+
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use super::{Handler, WebRequest, WebResponse, Extensions};
+
+
+/// A handler that runs another with a time limit.
+///
+/// The wrapped handler runs on its own thread.  If it hasn't returned a
+/// response within the limit, the client gets a 503 (see `set_code`) and
+/// the server worker moves on.  The handler's thread can't be killed: it
+/// keeps running, and its response is dropped when it finishes.
+/// `abandoned()` counts such threads that are still running.
+///
+/// After a timeout, the request passed back out has its headers but no
+/// body or extensions, since those went to the abandoned handler.
+///
+/// ex: `svr.set_handler(Timeout::new(Duration::from_secs(30), router));`
+pub struct Timeout {
+    handler: Arc<dyn Handler>,
+    limit: Duration,
+    code: i32,
+    status: String,
+    abandoned: Arc<AtomicUsize>,
+}
+
+impl Timeout {
+    pub fn new<H: Handler + 'static>(limit: Duration, handler: H) -> Timeout {
+        return Timeout {
+            handler: Arc::new(handler),
+            limit: limit,
+            code: 503,
+            status: "Service Unavailable".to_string(),
+            abandoned: Arc::new(AtomicUsize::new(0)),
+        };
+    }
+
+    /// The response code for timeouts, ex: `set_code(504, "Gateway Timeout")`
+    pub fn set_code(&mut self, code: i32, status: &str) {
+        self.code = code;
+        self.status = status.to_string();
+    }
+
+    /// The number of timed out handlers that are still running
+    pub fn abandoned(&self) -> usize {
+        return self.abandoned.load(Ordering::Relaxed);
+    }
+}
+
+impl Handler for Timeout {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        // Leave a copy of the head in place of the request
+        let shell = WebRequest {
+            environ: req.environ.clone(),
+            path: req.path.clone(),
+            method: req.method.clone(),
+            body: Vec::new(),
+            spool: None,
+            budget: None,
+            extensions: Extensions::new(),
+        };
+        let mut owned = mem::replace(req, shell);
+
+        // Set under the lock when we stop waiting, so the handler thread
+        // knows whether anyone will take its response
+        let gave_up = Arc::new(Mutex::new(false));
+        let (tx, rx) = mpsc::channel();
+        let handler = self.handler.clone();
+        let abandoned = self.abandoned.clone();
+        let thread_gave_up = gave_up.clone();
+        let spawned = thread::Builder::new().spawn(move || {
+            let ret = panic::catch_unwind(AssertUnwindSafe(
+                    || handler.handle(&mut owned)));
+            let gave_up = thread_gave_up.lock().unwrap();
+            if *gave_up {
+                abandoned.fetch_sub(1, Ordering::Relaxed);
+            } else {
+                let _ = tx.send((owned, ret));
+            }
+        });
+        if spawned.is_err() {
+            let mut resp = WebResponse::new();
+            resp.set_code(503, "Service Unavailable");
+            resp.set_body_str("Error 503: Service Unavailable");
+            return resp;
+        }
+
+        let mut ret = rx.recv_timeout(self.limit).ok();
+        if ret.is_none() {
+            let mut gave_up = gave_up.lock().unwrap();
+            ret = rx.try_recv().ok();
+            if ret.is_none() {
+                *gave_up = true;
+                self.abandoned.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some((owned, ret)) = ret {
+            *req = owned;
+            match ret {
+                Ok(resp) => return resp,
+                // Let the server send its 500
+                Err(e) => panic::resume_unwind(e),
+            }
+        }
+
+        let mut resp = WebResponse::new();
+        resp.set_code(self.code, &self.status);
+        resp.set_body_str(&format!("Error {}: {}", self.code, self.status));
+        resp.cause = Some(format!("Handler timed out after {:?}", self.limit));
+        return resp;
+    }
+}
+
+
+#[test]
+fn test_timeout() {
+    let mut timeout = Timeout::new(Duration::from_millis(100),
+            |req: &WebRequest| {
+        if req.get_path() == "/slow" {
+            thread::sleep(Duration::from_millis(400));
+        }
+        return WebResponse::new_html(format!("{} {:?}", req.get_path(),
+                req.get_body()));
+    });
+    timeout.set_code(504, "Gateway Timeout");
+
+    let mut req = WebRequest::new_for_test("POST", "/fast");
+    req.body = vec![1, 2];
+    let resp = timeout.handle(&mut req);
+    assert_eq!(resp.code, 200);
+    assert_eq!(resp.body, b"/fast [1, 2]");
+    assert_eq!(req.get_body(), &[1, 2]);
+
+    let mut req = WebRequest::new_for_test("GET", "/slow");
+    let resp = timeout.handle(&mut req);
+    assert_eq!(resp.code, 504);
+    assert_eq!(req.get_path(), "/slow");
+    assert_eq!(timeout.abandoned(), 1);
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(timeout.abandoned(), 0);
+}
@@ -0,0 +1,152 @@
+//! Hooks on the life of requests, for tracing
+
+use std::net::SocketAddr;
+use std::time::{Instant, SystemTime};
+
+use super::{WebRequest, WebResponse, RequestId};
+
+
+/// A point in the life of a connection and its requests
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEvent {
+    /// The connection was accepted
+    Accepted,
+    /// A request was read, head and body
+    HeadParsed,
+    /// The handler was given the request
+    HandlerStart,
+    /// The handler returned the response
+    HandlerEnd,
+    /// The response was sent
+    ResponseFlushed,
+    /// The connection was closed
+    ConnectionClosed,
+}
+
+/// What a `TraceHook` is told about an event
+pub struct Trace<'a> {
+    pub event: TraceEvent,
+    /// When it happened, to export
+    pub time: SystemTime,
+    /// The same, to measure durations with
+    pub instant: Instant,
+    pub peer_addr: SocketAddr,
+    /// The `RequestId`, once `SetRequestId` has given it one
+    pub request_id: Option<&'a str>,
+    /// From HeadParsed on, for the events of a request
+    pub request: Option<&'a WebRequest>,
+    /// For HandlerEnd and ResponseFlushed
+    pub response: Option<&'a WebResponse>,
+}
+
+/// Told about each `TraceEvent` of the server's connections and
+/// requests, on the thread it happens on; see `WebServer::add_trace_hook`.
+///
+/// On HTTP/2 connections, requests run at the same time, each on a
+/// thread of its own: tell them apart by `Trace::request`.
+///
+/// ex:
+///
+/// ```ignore
+/// server.add_trace_hook(|trace: &Trace| {
+///     println!("{:?} {:?} {:?}", trace.instant, trace.event,
+///             trace.request_id);
+/// });
+/// ```
+pub trait TraceHook: Send + Sync {
+    fn trace(&self, trace: &Trace);
+}
+
+impl<F> TraceHook for F where F: Fn(&Trace) + Send + Sync {
+    fn trace(&self, trace: &Trace) {
+        (self)(trace);
+    }
+}
+
+
+// The server's hooks
+#[derive(Default)]
+pub(crate) struct Tracer {
+    hooks: Vec<Box<dyn TraceHook>>,
+}
+
+impl Tracer {
+    pub(crate) fn add(&mut self, hook: Box<dyn TraceHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub(crate) fn emit(&self, event: TraceEvent, peer_addr: SocketAddr,
+            req: Option<&WebRequest>, resp: Option<&WebResponse>) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let request_id = req.and_then(|r| r.get_extensions().get::<RequestId>())
+            .map(|id| &id.0[..]);
+        let trace = Trace {
+            event: event,
+            time: SystemTime::now(),
+            instant: Instant::now(),
+            peer_addr: peer_addr,
+            request_id: request_id,
+            request: req,
+            response: resp,
+        };
+        for hook in self.hooks.iter() {
+            hook.trace(&trace);
+        }
+    }
+
+    // Emits ConnectionClosed when dropped
+    pub(crate) fn connection(&self, peer_addr: SocketAddr) -> Closing<'_> {
+        self.emit(TraceEvent::Accepted, peer_addr, None, None);
+        return Closing {
+            tracer: self,
+            peer_addr: peer_addr,
+        };
+    }
+}
+
+pub(crate) struct Closing<'a> {
+    tracer: &'a Tracer,
+    peer_addr: SocketAddr,
+}
+
+impl<'a> Drop for Closing<'a> {
+    fn drop(&mut self) {
+        self.tracer.emit(TraceEvent::ConnectionClosed, self.peer_addr, None,
+                None);
+    }
+}
+
+
+#[test]
+fn test_tracer() {
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    let mut tracer = Tracer::default();
+    tracer.add(Box::new(move |trace: &Trace| {
+        let path = trace.request.map(|r| r.get_path().to_string());
+        let code = trace.response.map(|r| r.get_code());
+        seen.lock().unwrap().push((trace.event, path,
+                trace.request_id.map(|id| id.to_string()), code));
+    }));
+
+    let peer = "127.0.0.1:1234".parse().unwrap();
+    let closing = tracer.connection(peer);
+    let mut req = WebRequest::new_for_test("GET", "/a");
+    tracer.emit(TraceEvent::HeadParsed, peer, Some(&req), None);
+    req.get_extensions_mut().insert(RequestId("x1".to_string()));
+    tracer.emit(TraceEvent::HandlerEnd, peer, Some(&req),
+            Some(&WebResponse::new()));
+    drop(closing);
+
+    let path = Some("/a".to_string());
+    assert_eq!(*events.lock().unwrap(), [
+        (TraceEvent::Accepted, None, None, None),
+        (TraceEvent::HeadParsed, path.clone(), None, None),
+        (TraceEvent::HandlerEnd, path, Some("x1".to_string()), Some(200)),
+        (TraceEvent::ConnectionClosed, None, None, None),
+    ]);
+}
@@ -0,0 +1,150 @@
+//! Connections handed over to another protocol after a 101 response
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+
+use utils::genericsocket::GenericSocket;
+
+
+/// The client connection, after a 101 (Switching Protocols) response with
+/// `WebResponse::set_upgrade` has been sent.
+///
+/// Reading starts with anything the client sent after the request, which
+/// the server may have read already.  The function given to `set_upgrade`
+/// runs on the worker thread that got the request, and the connection is
+/// closed when the `Upgraded` is dropped.
+///
+/// ex:
+///
+/// ```ignore
+/// router.get("/echo", |req: &mut WebRequest| {
+///     let mut resp = WebResponse::new();
+///     if req.get_header("Upgrade") != Some("echo") {
+///         resp.set_code(426, "Upgrade Required");
+///         resp.set_header("Upgrade", "echo");
+///         return resp;
+///     }
+///     resp.set_code(101, "Switching Protocols");
+///     resp.set_header("Upgrade", "echo");
+///     resp.set_header("Connection", "Upgrade");
+///     resp.set_upgrade(|mut conn: Upgraded| {
+///         let mut buf = [0; 4096];
+///         while let Ok(n) = conn.read(&mut buf) {
+///             if n == 0 || conn.write_all(&buf[..n]).is_err() {
+///                 return;
+///             }
+///         }
+///     });
+///     return resp;
+/// });
+/// ```
+pub struct Upgraded {
+    stream: Box<dyn GenericSocket>,
+    // Read past the request already
+    buffered: io::Cursor<Vec<u8>>,
+    // The TCP connection under `stream`, for clones
+    socket: Option<Arc<TcpStream>>,
+}
+
+impl Upgraded {
+    pub(crate) fn new(stream: Box<dyn GenericSocket>, buffered: Vec<u8>)
+            -> Upgraded {
+        return Upgraded {
+            stream: stream,
+            buffered: io::Cursor::new(buffered),
+            socket: None,
+        };
+    }
+
+    // A connection that can be cloned, `stream` being `socket`'s
+    pub(crate) fn with_socket(mut self, socket: Arc<TcpStream>) -> Upgraded {
+        self.socket = Some(socket);
+        return self;
+    }
+
+    /// Another handle on the connection, ex: to write from one thread
+    /// while another reads.  Reading from it doesn't get what was read
+    /// past the request.  Fails with Unsupported if the connection can't
+    /// be shared.
+    pub fn try_clone(&self) -> io::Result<Upgraded> {
+        let socket = match self.socket {
+            Some(ref socket) => socket.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported,
+                    "connection can't be shared")),
+        };
+        return Ok(Upgraded::new(Box::new(SharedStream(socket.clone())),
+                Vec::new()).with_socket(socket));
+    }
+
+    /// Shut the connection down both ways, waking up any thread reading
+    /// from it or a clone.  Fails with Unsupported as `try_clone` does.
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self.socket {
+            Some(ref socket) => return socket.shutdown(Shutdown::Both),
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported,
+                    "connection can't be shared")),
+        }
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.buffered.position() as usize) < self.buffered.get_ref().len() {
+            return Read::read(&mut self.buffered, buf);
+        }
+        return self.stream.read(buf);
+    }
+}
+
+impl Write for Upgraded {
+    // Nothing is buffered: all of `buf` is sent
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write_all(buf)?;
+        return Ok(buf.len());
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let slices: Vec<&[u8]> = bufs.iter().map(|buf| &**buf).collect();
+        self.stream.write_all_vectored(&slices)?;
+        return Ok(slices.iter().map(|buf| buf.len()).sum());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+
+// A TCP connection with several owners, who can all read and write
+pub(crate) struct SharedStream(pub(crate) Arc<TcpStream>);
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return Read::read(&mut &*self.0, buf);
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return Write::write(&mut &*self.0, buf);
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        return Write::write_vectored(&mut &*self.0, bufs);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Write::flush(&mut &*self.0);
+    }
+}
+
+
+#[test]
+fn test_upgraded() {
+    let stream = io::Cursor::new(b" world".to_vec());
+    let mut conn = Upgraded::new(Box::new(stream), b"hello".to_vec());
+    let mut input = String::new();
+    conn.read_to_string(&mut input).unwrap();
+    assert_eq!(input, "hello world");
+}
@@ -0,0 +1,148 @@
+//! Host header based dispatch, for serving several sites from one server
+
+use std::cmp;
+
+use super::{Handler, WebRequest, WebResponse};
+
+
+/// Dispatches requests to handlers by the Host header.
+///
+/// Patterns are host names, matched without the port and case
+/// insensitively.  A leading "*." matches one or more labels, so
+/// "*.example.com" matches "www.example.com" and "a.b.example.com" but not
+/// "example.com".  Exact names are checked first, then the wildcards, the
+/// most specific (longest) one first.
+///
+/// Requests for other hosts, or without a Host header, go to the default
+/// handler (see `set_default`), which by default sends a plain 404.
+///
+/// ex:
+///
+/// ```ignore
+/// let mut vhosts = VHostMap::new();
+/// vhosts.add("example.com", site_router);
+/// vhosts.add("*.example.com", site_router_too);
+/// vhosts.add("api.example.org", api_router);
+/// svr.set_handler(vhosts);
+/// ```
+pub struct VHostMap {
+    exact: Vec<(String, Box<dyn Handler>)>,
+    // Stored without the '*', ex: ".example.com"
+    wildcard: Vec<(String, Box<dyn Handler>)>,
+    default: Option<Box<dyn Handler>>,
+}
+
+impl Default for VHostMap {
+    fn default() -> VHostMap {
+        return VHostMap::new();
+    }
+}
+
+impl VHostMap {
+    pub fn new() -> VHostMap {
+        return VHostMap {
+            exact: Vec::new(),
+            wildcard: Vec::new(),
+            default: None,
+        };
+    }
+
+    /// Serve `host` (ex: "example.com" or "*.example.com") with `handler`
+    pub fn add<H: Handler + 'static>(&mut self, host: &str, handler: H) {
+        let host = normalize_host(host);
+        if host.starts_with("*.") {
+            self.wildcard.push((host[1..].to_string(), Box::new(handler)));
+            // Longest suffix first; the sort is stable, so ties keep the
+            // order they were added in
+            self.wildcard.sort_by_key(|w| cmp::Reverse(w.0.len()));
+        } else {
+            self.exact.push((host, Box::new(handler)));
+        }
+    }
+
+    /// Set the handler for unknown hosts
+    pub fn set_default<H: Handler + 'static>(&mut self, handler: H) {
+        self.default = Some(Box::new(handler));
+    }
+
+    fn lookup(&self, host: &str) -> Option<&dyn Handler> {
+        for (name, handler) in self.exact.iter() {
+            if *name == host {
+                return Some(&**handler);
+            }
+        }
+        for (suffix, handler) in self.wildcard.iter() {
+            if host.len() > suffix.len() && host.ends_with(&suffix[..]) {
+                return Some(&**handler);
+            }
+        }
+        return None;
+    }
+}
+
+impl Handler for VHostMap {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        let host = req.get_header("Host").map(normalize_host);
+        if let Some(handler) = host.as_ref().and_then(|h| self.lookup(h)) {
+            return handler.handle(req);
+        }
+        if let Some(ref handler) = self.default {
+            return handler.handle(req);
+        }
+        let mut resp = WebResponse::new();
+        resp.set_code(404, "Not Found");
+        resp.set_body_str("Error 404: Unknown host");
+        return resp;
+    }
+}
+
+
+// Lowercase, without the port or a trailing '.'.  IPv6 literals keep
+// their brackets, ex: "[::1]:8080" -> "[::1]".
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let end = if host.starts_with('[') {
+        host.find(']').map(|i| i + 1).unwrap_or(host.len())
+    } else {
+        host.find(':').unwrap_or(host.len())
+    };
+    return host[..end].trim_end_matches('.').to_ascii_lowercase();
+}
+
+
+#[test]
+fn test_normalize_host() {
+    assert_eq!(normalize_host("Example.COM:8080"), "example.com");
+    assert_eq!(normalize_host("example.com."), "example.com");
+    assert_eq!(normalize_host("[::1]:80"), "[::1]");
+    assert_eq!(normalize_host("10.0.0.1"), "10.0.0.1");
+}
+
+#[test]
+fn test_vhost_map() {
+    let mut vhosts = VHostMap::new();
+    vhosts.add("example.com",
+            |_req: &WebRequest| WebResponse::new_html("main".to_string()));
+    vhosts.add("*.example.com",
+            |_req: &WebRequest| WebResponse::new_html("sub".to_string()));
+    vhosts.add("*.api.Example.com",
+            |_req: &WebRequest| WebResponse::new_html("api".to_string()));
+
+    let get = |vhosts: &VHostMap, host: &str| {
+        let mut req = WebRequest::parse_for_test(&format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host));
+        let resp = vhosts.handle(&mut req);
+        return (resp.code, String::from_utf8(resp.body).unwrap());
+    };
+    assert_eq!(get(&vhosts, "EXAMPLE.com:8080").1, "main");
+    assert_eq!(get(&vhosts, "www.example.com").1, "sub");
+    assert_eq!(get(&vhosts, "v1.api.example.com").1, "api");
+    assert_eq!(get(&vhosts, "other.org").0, 404);
+    assert_eq!(get(&vhosts, "xexample.com").0, 404);
+
+    vhosts.set_default(
+            |_req: &WebRequest| WebResponse::new_html("default".to_string()));
+    assert_eq!(get(&vhosts, "other.org").1, "default");
+    let mut req = WebRequest::new_for_test("GET", "/");
+    assert_eq!(vhosts.handle(&mut req).body, b"default");
+}
@@ -0,0 +1,775 @@
+//! WebSocket (RFC 6455) server side
+//!
+//! https://tools.ietf.org/html/rfc6455
+
+use std::io;
+use std::sync::Arc;
+
+use utils::base64;
+use utils::byteutils;
+use utils::deflate;
+use utils::genericsocket::{GenericSocket, read_exact};
+use utils::sha1;
+use super::{Handler, WebRequest, WebResponse};
+
+
+static GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+static DEFAULT_MAX_MESSAGE_SIZE: usize = 1_000_000;
+// Smaller messages aren't worth compressing
+static MIN_COMPRESS_SIZE: usize = 32;
+static MAX_WINDOW: usize = 32768;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+// The "compressed" bit, with permessage-deflate
+const RSV1: u8 = 0x40;
+
+// Close codes
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+
+
+/// A message received on a `WebSocketConnection`
+#[derive(Debug, PartialEq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Answered with a pong already
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The client closed the connection, with a status code and reason if
+    /// it gave one.  Answered already; nothing more can be received.
+    Close(Option<(u16, String)>),
+}
+
+
+type ConnectionFn = dyn Fn(WebSocketConnection) + Send + Sync;
+
+/// A handler that accepts WebSocket connections, and runs a function with
+/// each one.
+///
+/// Requests that aren't a valid version 13 handshake get a 400 or 426.  The
+/// function runs on the worker thread that got the request, so each open
+/// connection takes a thread from the server's pool until the function
+/// returns (see `WebServer::set_num_threads`); spawn a thread for it to
+/// keep many connections open.  Use `WebSocket::accept` in a handler to
+/// look at the request first.
+///
+/// Messages are compressed with the permessage-deflate extension (RFC
+/// 7692) when the client supports it, as browsers do; see
+/// `set_compression`.
+///
+/// ex:
+///
+/// ```ignore
+/// router.get("/echo", WebSocket::new(|mut ws: WebSocketConnection| {
+///     while let Ok(WebSocketMessage::Text(text)) = ws.recv() {
+///         let _ = ws.send_text(&text);
+///     }
+/// }));
+/// ```
+pub struct WebSocket {
+    handler: Arc<ConnectionFn>,
+    compression: bool,
+}
+
+impl WebSocket {
+    pub fn new<F>(handler: F) -> WebSocket
+            where F: Fn(WebSocketConnection) + Send + Sync + 'static {
+        return WebSocket {
+            handler: Arc::new(handler),
+            compression: true,
+        };
+    }
+
+    /// Whether to agree to permessage-deflate when the client offers it
+    /// (default true).  Turn it off for messages that are compressed
+    /// already, ex: images, to save the CPU time.
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    /// Answer a WebSocket handshake with the 101 response to return, and
+    /// run `f` with the connection once it has been sent.  If `req` isn't
+    /// a valid handshake, the error response is returned instead, and `f`
+    /// is never run.  Compression is agreed to if the client offers it.
+    pub fn accept<F>(req: &WebRequest, f: F) -> WebResponse
+            where F: FnOnce(WebSocketConnection) + Send + 'static {
+        return WebSocket::accept_with(req, true, f);
+    }
+
+    fn accept_with<F>(req: &WebRequest, compression: bool, f: F)
+            -> WebResponse
+            where F: FnOnce(WebSocketConnection) + Send + 'static {
+        if req.get_method() != "get" {
+            let mut resp = error_response(405, "Method not allowed");
+            resp.set_header("Allow", "GET");
+            return resp;
+        }
+        let upgrade = req.get_header("Upgrade").unwrap_or("");
+        let connection = req.get_header("Connection").unwrap_or("");
+        if !has_token(upgrade, "websocket")
+                || !has_token(connection, "upgrade") {
+            let mut resp = error_response(426, "Upgrade Required");
+            resp.set_header("Upgrade", "websocket");
+            resp.set_header("Connection", "Upgrade");
+            return resp;
+        }
+        if req.get_header("Sec-WebSocket-Version").map(|v| v.trim())
+                != Some("13") {
+            let mut resp = error_response(426, "Upgrade Required");
+            resp.set_header("Sec-WebSocket-Version", "13");
+            return resp;
+        }
+        let key = req.get_header("Sec-WebSocket-Key").unwrap_or("").trim();
+        if base64::decode(key).map(|k| k.len()) != Some(16) {
+            return error_response(400, "Bad Request");
+        }
+
+        let mut resp = WebResponse::new();
+        resp.set_code(101, "Switching Protocols");
+        resp.set_header("Upgrade", "websocket");
+        resp.set_header("Connection", "Upgrade");
+        resp.set_header("Sec-WebSocket-Accept", &accept_key(key));
+        let offers = req.get_header("Sec-WebSocket-Extensions").unwrap_or("");
+        let deflate = match negotiate_deflate(offers) {
+            Some((response, deflate)) if compression => {
+                resp.set_header("Sec-WebSocket-Extensions", &response);
+                Some(deflate)
+            }
+            _ => None,
+        };
+        resp.set_upgrade(move |stream| {
+            f(WebSocketConnection::new(Box::new(stream), deflate));
+        });
+        return resp;
+    }
+}
+
+impl Handler for WebSocket {
+    fn handle(&self, req: &mut WebRequest) -> WebResponse {
+        let handler = self.handler.clone();
+        return WebSocket::accept_with(req, self.compression,
+                move |conn| handler(conn));
+    }
+}
+
+
+/// An open WebSocket connection, see `WebSocket`.
+///
+/// Fragmented messages are put back together, pings are answered, and a
+/// Close from the client is answered too.  Protocol errors from the client
+/// close the connection with the right status code and make `recv` fail.
+/// Dropping the connection closes it with 1000 (normal closure) if that
+/// hasn't been done yet.
+pub struct WebSocketConnection {
+    stream: Box<dyn GenericSocket>,
+    max_message_size: usize,
+    // A fragmented message being received: opcode, whether it's
+    // compressed, and data so far
+    partial: Option<(u8, bool, Vec<u8>)>,
+    sent_close: bool,
+    received_close: bool,
+    deflate: Option<Deflate>,
+}
+
+// The permessage-deflate parameters agreed to.  Messages sent are always
+// compressed on their own (server_no_context_takeover).
+struct Deflate {
+    // The end of what the client sent so far, which its next message may
+    // refer back to
+    window: Vec<u8>,
+    // Unless the client agreed to client_no_context_takeover
+    context_takeover: bool,
+    // How far back messages sent may refer (server_max_window_bits)
+    max_window: usize,
+}
+
+struct Frame {
+    fin: bool,
+    rsv: u8,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl WebSocketConnection {
+    fn new(stream: Box<dyn GenericSocket>, deflate: Option<Deflate>)
+            -> WebSocketConnection {
+        return WebSocketConnection {
+            stream: stream,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            partial: None,
+            sent_close: false,
+            received_close: false,
+            deflate: deflate,
+        };
+    }
+
+    /// The largest message to accept, in bytes (default 1MB).  Larger
+    /// ones close the connection with 1009 (message too big).
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Wait for the next message.  Fails once the connection is closed,
+    /// ex: after a `Close` has been returned.
+    pub fn recv(&mut self) -> io::Result<WebSocketMessage> {
+        loop {
+            if self.received_close {
+                return Err(closed());
+            }
+            let frame = self.read_frame()?;
+            // Only the first frame of a message may say it's compressed
+            let compressed = frame.rsv == RSV1 && self.deflate.is_some()
+                && (frame.opcode == OP_TEXT || frame.opcode == OP_BINARY);
+            if frame.rsv != 0 && !compressed {
+                return Err(self.fail(PROTOCOL_ERROR, "reserved bits set"));
+            }
+            if frame.opcode >= OP_CLOSE {
+                if !frame.fin || frame.payload.len() > 125 {
+                    return Err(self.fail(PROTOCOL_ERROR, "bad control frame"));
+                }
+                return self.control(frame.opcode, frame.payload);
+            }
+
+            match (frame.opcode, self.partial.take()) {
+                (OP_TEXT, None) | (OP_BINARY, None) => self.partial =
+                    Some((frame.opcode, compressed, frame.payload)),
+                (OP_CONTINUATION, Some((opcode, compressed, mut data))) => {
+                    let size = data.len() + frame.payload.len();
+                    if size > self.max_message_size {
+                        return Err(self.fail(TOO_BIG, "message too big"));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    self.partial = Some((opcode, compressed, data));
+                }
+                _ => return Err(self.fail(PROTOCOL_ERROR, "bad fragmentation")),
+            }
+            if !frame.fin {
+                continue;
+            }
+            let (opcode, compressed, mut data) = self.partial.take().unwrap();
+            if compressed {
+                data = self.inflate(data)?;
+            }
+            if opcode == OP_BINARY {
+                return Ok(WebSocketMessage::Binary(data));
+            }
+            match String::from_utf8(data) {
+                Ok(text) => return Ok(WebSocketMessage::Text(text)),
+                Err(_) => return Err(self.fail(INVALID_DATA, "invalid UTF-8")),
+            }
+        }
+    }
+
+    /// Send a text message
+    pub fn send_text(&mut self, text: &str) -> io::Result<()> {
+        return self.send(OP_TEXT, text.as_bytes());
+    }
+
+    /// Send a binary message
+    pub fn send_binary(&mut self, data: &[u8]) -> io::Result<()> {
+        return self.send(OP_BINARY, data);
+    }
+
+    /// Send a ping; at most 125 bytes of `data`
+    pub fn send_ping(&mut self, data: &[u8]) -> io::Result<()> {
+        return self.send(OP_PING, data);
+    }
+
+    /// Send an unsolicited pong (pings are answered already), ex: as a
+    /// heartbeat; at most 125 bytes of `data`
+    pub fn send_pong(&mut self, data: &[u8]) -> io::Result<()> {
+        return self.send(OP_PONG, data);
+    }
+
+    /// Start closing the connection, with a status code (ex: 1000 for a
+    /// normal closure) and a reason (at most 123 bytes).  Nothing can be
+    /// sent after this; keep calling `recv` until it returns the client's
+    /// `Close` (or fails).
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        if self.sent_close {
+            return Ok(());
+        }
+        if reason.len() > 123 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    "close reason too long"));
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        self.sent_close = true;
+        return self.write_frame(OP_CLOSE, 0, &payload);
+    }
+
+    fn send(&mut self, opcode: u8, data: &[u8]) -> io::Result<()> {
+        if self.sent_close {
+            return Err(closed());
+        }
+        if opcode >= OP_CLOSE && data.len() > 125 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    "control frame payload too long"));
+        }
+        if opcode < OP_CLOSE && data.len() >= MIN_COMPRESS_SIZE {
+            if let Some(max_window) = self.deflate.as_ref().map(|d| d.max_window) {
+                // A final block, then the empty stored block a sync flush
+                // would add, less the 00 00 ff ff the client puts back
+                let mut packed = deflate::compress_window(data, max_window);
+                packed.push(0);
+                if packed.len() < data.len() {
+                    return self.write_frame(opcode, RSV1, &packed);
+                }
+            }
+        }
+        return self.write_frame(opcode, 0, data);
+    }
+
+    // Decompress a message
+    fn inflate(&mut self, mut data: Vec<u8>) -> io::Result<Vec<u8>> {
+        data.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+        let result = {
+            let deflate = self.deflate.as_ref().unwrap();
+            deflate::decompress_sync(&deflate.window, &data,
+                    self.max_message_size)
+        };
+        let data = match result {
+            Ok(data) => data,
+            Err(deflate::InflateError::TooLarge) =>
+                return Err(self.fail(TOO_BIG, "message too big")),
+            Err(_) => return Err(self.fail(INVALID_DATA, "bad compression")),
+        };
+        let deflate = self.deflate.as_mut().unwrap();
+        if deflate.context_takeover {
+            deflate.window.extend_from_slice(&data);
+            let excess = deflate.window.len().saturating_sub(MAX_WINDOW);
+            deflate.window.drain(..excess);
+        }
+        return Ok(data);
+    }
+
+    // Handle a ping, pong or close
+    fn control(&mut self, opcode: u8, payload: Vec<u8>)
+            -> io::Result<WebSocketMessage> {
+        match opcode {
+            OP_PING => {
+                if !self.sent_close {
+                    self.write_frame(OP_PONG, 0, &payload)?;
+                }
+                return Ok(WebSocketMessage::Ping(payload));
+            }
+            OP_PONG => return Ok(WebSocketMessage::Pong(payload)),
+            OP_CLOSE => (),
+            _ => return Err(self.fail(PROTOCOL_ERROR, "unknown opcode")),
+        }
+        let status = match payload.len() {
+            0 => None,
+            1 => return Err(self.fail(PROTOCOL_ERROR, "bad close frame")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => Some((code, reason)),
+                    Err(_) => return Err(self.fail(INVALID_DATA,
+                            "invalid UTF-8")),
+                }
+            }
+        };
+        self.received_close = true;
+        if !self.sent_close {
+            let code = status.as_ref().map(|s| s.0).unwrap_or(1000);
+            let _ = self.close(code, "");
+        }
+        return Ok(WebSocketMessage::Close(status));
+    }
+
+    fn read_frame(&mut self) -> io::Result<Frame> {
+        let mut head = [0; 2];
+        read_exact(&mut *self.stream, &mut head)?;
+        let mut len = (head[1] & 0x7f) as u64;
+        if len == 126 {
+            let mut buf = [0; 2];
+            read_exact(&mut *self.stream, &mut buf)?;
+            len = u16::from_be_bytes(buf) as u64;
+        } else if len == 127 {
+            let mut buf = [0; 8];
+            read_exact(&mut *self.stream, &mut buf)?;
+            len = u64::from_be_bytes(buf);
+        }
+        // Clients must mask everything
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(PROTOCOL_ERROR, "unmasked frame"));
+        }
+        if len > self.max_message_size as u64 {
+            return Err(self.fail(TOO_BIG, "message too big"));
+        }
+        let mut mask = [0; 4];
+        read_exact(&mut *self.stream, &mut mask)?;
+        let mut payload = vec![0; len as usize];
+        read_exact(&mut *self.stream, &mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        return Ok(Frame {
+            fin: head[0] & 0x80 != 0,
+            rsv: head[0] & 0x70,
+            opcode: head[0] & 0x0f,
+            payload: payload,
+        });
+    }
+
+    // A whole message in one (unmasked) frame
+    fn write_frame(&mut self, opcode: u8, rsv: u8, payload: &[u8])
+            -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | rsv | opcode);
+        let len = payload.len();
+        if len < 126 {
+            frame.push(len as u8);
+        } else if len <= 0xffff {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+        return self.stream.write_all(&frame);
+    }
+
+    // Close the connection for a protocol error, and the error to return
+    fn fail(&mut self, code: u16, message: &str) -> io::Error {
+        let _ = self.close(code, "");
+        self.received_close = true;
+        return io::Error::new(io::ErrorKind::InvalidData, message);
+    }
+}
+
+impl Drop for WebSocketConnection {
+    fn drop(&mut self) {
+        let _ = self.close(1000, "");
+    }
+}
+
+
+// The Sec-WebSocket-Accept for a Sec-WebSocket-Key
+fn accept_key(key: &str) -> String {
+    return base64::encode(&sha1::sha1(format!("{}{}", key, GUID).as_bytes()));
+}
+
+
+// Pick the first permessage-deflate offer in a Sec-WebSocket-Extensions
+// header that we can do, with the response to it
+fn negotiate_deflate(offers: &str) -> Option<(String, Deflate)> {
+    'offers: for offer in offers.split(',') {
+        let mut params = offer.split(';').map(|p| p.trim());
+        if !params.next().unwrap().eq_ignore_ascii_case("permessage-deflate") {
+            continue;
+        }
+        let mut response = "permessage-deflate; server_no_context_takeover"
+            .to_string();
+        let mut deflate = Deflate {
+            window: Vec::new(),
+            context_takeover: true,
+            max_window: MAX_WINDOW,
+        };
+        let mut seen = Vec::new();
+        for param in params {
+            let (name, value) = match param.find('=') {
+                Some(i) => (param[..i].trim(), Some(param[i + 1..].trim()
+                        .trim_matches('"'))),
+                None => (param, None),
+            };
+            let name = name.to_ascii_lowercase();
+            if seen.contains(&name) {
+                continue 'offers;
+            }
+            let bits = value.map(|v| v.parse::<u8>().ok()
+                    .filter(|b| (8..=15).contains(b)));
+            match (name.as_str(), bits) {
+                ("server_no_context_takeover", None) => (),
+                ("client_no_context_takeover", None) => {
+                    deflate.context_takeover = false;
+                    response.push_str("; client_no_context_takeover");
+                }
+                ("server_max_window_bits", Some(Some(bits))) => {
+                    deflate.max_window = 1 << bits;
+                    response.push_str(&format!("; server_max_window_bits={}",
+                            bits));
+                }
+                // We take any window the client uses
+                ("client_max_window_bits", None)
+                    | ("client_max_window_bits", Some(Some(_))) => (),
+                _ => continue 'offers,
+            }
+            seen.push(name);
+        }
+        return Some((response, deflate));
+    }
+    return None;
+}
+
+
+// Whether a comma separated header value has `token` (any case)
+fn has_token(value: &str, token: &str) -> bool {
+    return byteutils::has_token(value.as_bytes(), token.as_bytes());
+}
+
+
+fn closed() -> io::Error {
+    return io::Error::new(io::ErrorKind::NotConnected,
+            "WebSocket connection closed");
+}
+
+
+fn error_response(code: i32, status: &str) -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_code(code, status);
+    resp.set_body_str(&format!("Error {}: {}", code, status));
+    return resp;
+}
+
+
+// Input to read from, and where the output goes
+#[cfg(test)]
+struct TestStream {
+    input: io::Cursor<Vec<u8>>,
+    output: Arc<::std::sync::Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl io::Read for TestStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return io::Read::read(&mut self.input, buf);
+    }
+}
+
+#[cfg(test)]
+impl io::Write for TestStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.lock().unwrap().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+// A connection that reads `input`, and where its output goes
+#[cfg(test)]
+fn test_connection(input: Vec<u8>)
+        -> (WebSocketConnection, Arc<::std::sync::Mutex<Vec<u8>>>) {
+    let output = Arc::new(::std::sync::Mutex::new(Vec::new()));
+    let stream = TestStream {
+        input: io::Cursor::new(input),
+        output: output.clone(),
+    };
+    return (WebSocketConnection::new(Box::new(stream), None), output);
+}
+
+// A masked frame, as a client sends it
+#[cfg(test)]
+fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    return frame;
+}
+
+#[test]
+fn test_websocket_handshake() {
+    let handshake = "GET /chat HTTP/1.1\r\nHost: example.com\r\n\
+        Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n";
+    let accept = |head: &str| {
+        let req = WebRequest::parse_for_test(&format!("{}\r\n", head));
+        return WebSocket::accept(&req, |_| ());
+    };
+    let resp = accept(handshake);
+    assert_eq!(resp.code, 101);
+    assert_eq!(resp.get_header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert_eq!(resp.get_header("Upgrade"), Some("websocket"));
+    assert!(resp.upgrade.is_some());
+
+    let resp = accept(&handshake.replace("Version: 13", "Version: 8"));
+    assert_eq!(resp.code, 426);
+    assert_eq!(resp.get_header("Sec-WebSocket-Version"), Some("13"));
+    assert_eq!(accept(&handshake.replace("Upgrade: websocket", "Upgrade: h2c"))
+            .code, 426);
+    assert_eq!(accept(&handshake.replace("dGhlIHNhbXBsZSBub25jZQ==", "abc"))
+            .code, 400);
+    assert_eq!(accept(&handshake.replace("GET", "POST")).code, 405);
+    assert!(accept(&handshake.replace("GET", "POST")).upgrade.is_none());
+}
+
+#[test]
+fn test_websocket_messages() {
+    let mut input = client_frame(true, OP_TEXT, b"hello");
+    // Fragmented, with a ping in the middle
+    input.extend(client_frame(false, OP_BINARY, &[1, 2]));
+    input.extend(client_frame(true, OP_PING, b"p"));
+    input.extend(client_frame(true, OP_CONTINUATION, &[3]));
+    input.extend(client_frame(true, OP_TEXT, &[b'x'; 300]));
+    input.extend(client_frame(true, OP_CLOSE, b"\x03\xe8bye"));
+    let (mut ws, output) = test_connection(input);
+
+    assert_eq!(ws.recv().unwrap(), WebSocketMessage::Text("hello".to_string()));
+    assert_eq!(ws.recv().unwrap(), WebSocketMessage::Ping(b"p".to_vec()));
+    assert_eq!(ws.recv().unwrap(), WebSocketMessage::Binary(vec![1, 2, 3]));
+    ws.send_text("hi").unwrap();
+    let long = String::from_utf8(vec![b'x'; 300]).unwrap();
+    assert_eq!(ws.recv().unwrap(), WebSocketMessage::Text(long.clone()));
+    ws.send_text(&long).unwrap();
+    assert_eq!(ws.recv().unwrap(),
+            WebSocketMessage::Close(Some((1000, "bye".to_string()))));
+    assert!(ws.recv().is_err());
+    assert!(ws.send_text("late").is_err());
+
+    let mut expected = vec![0x8a, 1, b'p', 0x81, 2, b'h', b'i',
+            0x81, 126, 1, 44];
+    expected.extend_from_slice(long.as_bytes());
+    expected.extend_from_slice(&[0x88, 2, 0x03, 0xe8]);
+    drop(ws);
+    assert_eq!(*output.lock().unwrap(), expected);
+}
+
+#[test]
+fn test_websocket_protocol_errors() {
+    let close_code = |input: Vec<u8>| {
+        let (mut ws, output) = test_connection(input);
+        assert!(ws.recv().is_err());
+        drop(ws);
+        let output = output.lock().unwrap();
+        assert_eq!(&output[..2], &[0x88, 2]);
+        return u16::from_be_bytes([output[2], output[3]]);
+    };
+    // Unmasked
+    assert_eq!(close_code(vec![0x81, 1, b'a']), PROTOCOL_ERROR);
+    assert_eq!(close_code(client_frame(true, OP_CONTINUATION, b"a")),
+            PROTOCOL_ERROR);
+    assert_eq!(close_code(client_frame(false, OP_PING, b"a")), PROTOCOL_ERROR);
+    assert_eq!(close_code(client_frame(true, 0x3, b"a")), PROTOCOL_ERROR);
+    assert_eq!(close_code(client_frame(true, 0x40 | OP_TEXT, b"a")),
+            PROTOCOL_ERROR);
+    assert_eq!(close_code(client_frame(true, OP_TEXT, &[0xff])), INVALID_DATA);
+
+    let mut input = client_frame(false, OP_TEXT, &[b'a'; 6]);
+    input.extend(client_frame(true, OP_CONTINUATION, &[b'a'; 6]));
+    let (mut ws, output) = test_connection(input);
+    ws.set_max_message_size(10);
+    assert!(ws.recv().is_err());
+    assert_eq!(&output.lock().unwrap()[..], &[0x88, 2, 0x03, 0xf1]);
+
+    // The client just going away
+    let (mut ws, _) = test_connection(Vec::new());
+    assert_eq!(ws.recv().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_websocket_deflate_negotiation() {
+    let response = |offers: &str| negotiate_deflate(offers).map(|r| r.0);
+    assert_eq!(response("permessage-deflate; client_max_window_bits"),
+            Some("permessage-deflate; server_no_context_takeover".to_string()));
+    assert_eq!(response("x-webkit-deflate-frame, permessage-deflate; \
+            Client_No_Context_Takeover; server_max_window_bits=\"10\""),
+            Some("permessage-deflate; server_no_context_takeover; \
+            client_no_context_takeover; server_max_window_bits=10"
+            .to_string()));
+    // The first offer we can do
+    assert_eq!(response("permessage-deflate; server_max_window_bits=7, \
+            permessage-deflate; server_max_window_bits, \
+            permessage-deflate; foo, \
+            permessage-deflate; server_no_context_takeover; \
+            server_no_context_takeover, \
+            permessage-deflate; server_no_context_takeover"),
+            Some("permessage-deflate; server_no_context_takeover".to_string()));
+    assert_eq!(response("permessage-deflate; client_max_window_bits=16"), None);
+    assert_eq!(response(""), None);
+    assert_eq!(negotiate_deflate("permessage-deflate; server_max_window_bits=9")
+            .unwrap().1.max_window, 512);
+
+    let handshake = "GET /chat HTTP/1.1\r\nUpgrade: websocket\r\n\
+        Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Extensions: permessage-deflate\r\n\r\n";
+    let req = WebRequest::parse_for_test(handshake);
+    assert_eq!(WebSocket::accept(&req, |_| ())
+            .get_header("Sec-WebSocket-Extensions"),
+            Some("permessage-deflate; server_no_context_takeover"));
+    assert_eq!(WebSocket::accept_with(&req, false, |_| ())
+            .get_header("Sec-WebSocket-Extensions"), None);
+}
+
+#[test]
+fn test_websocket_deflate_messages() {
+    // RFC 7692 7.2.3: "Hello" twice with context takeover, then fragmented,
+    // then in a stored block
+    let mut input = client_frame(true, RSV1 | OP_TEXT,
+            &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+    input.extend(client_frame(true, RSV1 | OP_TEXT,
+            &[0xf2, 0x00, 0x11, 0x00, 0x00]));
+    input.extend(client_frame(false, RSV1 | OP_TEXT, &[0xf2, 0x48, 0xcd]));
+    input.extend(client_frame(true, OP_CONTINUATION,
+            &[0xc9, 0xc9, 0x07, 0x00]));
+    input.extend(client_frame(true, RSV1 | OP_BINARY,
+            &[0x00, 0x05, 0x00, 0xfa, 0xff, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x00]));
+    // Not compressed
+    input.extend(client_frame(true, OP_TEXT, b"plain"));
+    // A compressed continuation
+    input.extend(client_frame(false, RSV1 | OP_TEXT, &[0xf2, 0x48, 0xcd]));
+    input.extend(client_frame(true, RSV1 | OP_CONTINUATION,
+            &[0xc9, 0xc9, 0x07, 0x00]));
+    let (mut ws, output) = test_connection(input);
+    ws.deflate = negotiate_deflate("permessage-deflate").map(|r| r.1);
+
+    let hello = WebSocketMessage::Text("Hello".to_string());
+    assert_eq!(ws.recv().unwrap(), hello);
+    assert_eq!(ws.recv().unwrap(), hello);
+    assert_eq!(ws.recv().unwrap(), hello);
+    assert_eq!(ws.recv().unwrap(), WebSocketMessage::Binary(b"Hello".to_vec()));
+    assert_eq!(ws.recv().unwrap(), WebSocketMessage::Text("plain".to_string()));
+    assert_eq!(ws.deflate.as_ref().unwrap().window, b"HelloHelloHelloHello");
+    assert!(ws.recv().is_err());
+
+    output.lock().unwrap().clear();
+    ws.sent_close = false;
+    // Short, and not compressible
+    ws.send_text("hi").unwrap();
+    ws.send_binary(&(0..64).collect::<Vec<u8>>()).unwrap();
+    let text = "compress me! ".repeat(10);
+    ws.send_text(&text).unwrap();
+    let output = output.lock().unwrap().clone();
+    assert_eq!(&output[..4], &[0x81, 2, b'h', b'i']);
+    assert_eq!(&output[4..6], &[0x82, 64]);
+    let packed = &output[70..];
+    assert_eq!(packed[0], 0xc1);
+    assert_eq!(packed[1] as usize, packed.len() - 2);
+    assert!(packed.len() < 50);
+    let mut data = packed[2..].to_vec();
+    data.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+    assert_eq!(deflate::decompress_sync(b"", &data, 1000).unwrap(),
+            text.as_bytes());
+
+    // No context takeover, and a message that inflates too far
+    let input = client_frame(true, RSV1 | OP_TEXT,
+            &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+    let (mut ws, _) = test_connection(input.repeat(2));
+    ws.deflate = negotiate_deflate("permessage-deflate; \
+            client_no_context_takeover").map(|r| r.1);
+    assert_eq!(ws.recv().unwrap(), hello);
+    assert!(ws.deflate.as_ref().unwrap().window.is_empty());
+    ws.set_max_message_size(4);
+    assert!(ws.recv().is_err());
+}
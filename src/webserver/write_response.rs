@@ -1,66 +1,472 @@
-//use std;
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
 
-use super::{WebRequest, WebResponse, Logger};
+use super::{WebRequest, WebResponse, Logger, ConnInfo};
+use super::error_sink::ServerError;
 use utils::genericsocket::GenericSocket;
+use utils::time;
 
+// Buffered bodies up to this size are copied after the headers, to send
+// the response in one buffer and write; bigger ones go in a writev
+static COPY_BODY_MAX: usize = 16 * 1024;
 
 // Send response headers and body.
 // Body will not be sent if the request was a HEAD request.
 // Headers will be sent as UTF-8 bytes, but you need to stay in ASCII/Latin-1
 // range to be safe.
-pub fn write_response(stream: &mut GenericSocket, 
+pub fn write_response(stream: &mut dyn GenericSocket, 
         request: Option<&WebRequest>, 
-        response: &WebResponse, 
-        log: &Logger) {
+        response: &mut WebResponse,
+        log: &Logger,
+        conn: &ConnInfo) {
+    let body_bytes = match send(stream, request, response) {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            log.log_error(ServerError::Write(&e), Some(conn.peer_addr),
+                    request);
+            0
+        },
+    };
+    log.log_request_response(request, response, conn, body_bytes);
+}
+
+
+// Return: number of body bytes sent, or the error sending them
+fn send(stream: &mut dyn GenericSocket,
+        request: Option<&WebRequest>,
+        response: &mut WebResponse) -> io::Result<usize> {
 
     // Respond with the max version the client requested
     let mut protocol = "HTTP/1.1";
-    if request.is_some() {
-        let req = request.unwrap();
+    if let Some(req) = request {
         if &**req.environ.get(&b"protocol"[..]).unwrap() 
                 == b"http/1.0" {
             protocol = "HTTP/1.0";
         }
-
-        log.log_request_response(req.get_method(), req.get_path(),
-                response.code, response.body.len());
-    } else {
-        // Didn't get a valid request
-        log.log_request_response("", "", 
-                response.code, response.body.len());
     }
 
-    let mut resp = String::new();
+    // Room for the headers, and the body if it's copied after them
+    let copied = match response.reader {
+        None if response.body.len() <= COPY_BODY_MAX => response.body.len(),
+        _ => 0,
+    };
+    let head_size: usize = 128 + response.status.len() + response.headers
+        .iter().map(|(k, v)| k.len() + v.len() + 4).sum::<usize>();
+    let mut resp = String::with_capacity(head_size + copied);
     resp.push_str(&format!("{} {} {}\r\n", 
-                protocol, response.code, response.status));
-    resp.push_str("Connection: close\r\n");
-    resp.push_str(&format!("Content-Length: {}\r\n", 
-                response.body.len()));
+                protocol, response.code, clean_header_value(&response.status)));
+    // After a 101 the connection stays open, for the new protocol; the
+    // response has its own Connection: Upgrade
+    if response.code != 101 {
+        resp.push_str("Connection: close\r\n");
+    }
+    // These never have a body (RFC 7230 3.3.2)
+    let bodiless = response.code < 200 || response.code == 204
+        || response.code == 304;
+    // A streamed body of unknown length goes in chunks to HTTP/1.1
+    // clients, so they can tell its end from the connection dropping; to
+    // HTTP/1.0 ones it ends when the connection closes
+    let chunked = !bodiless && response.reader.is_some()
+        && response.reader_len.is_none() && protocol == "HTTP/1.1";
+    match response.reader {
+        _ if bodiless => (),
+        Some(_) => match response.reader_len {
+            Some(len) => resp.push_str(&format!("Content-Length: {}\r\n",
+                    len)),
+            None if chunked =>
+                resp.push_str("Transfer-Encoding: chunked\r\n"),
+            None => (),
+        },
+        None => resp.push_str(&format!("Content-Length: {}\r\n",
+                response.body.len())),
+    }
+    // Unless the handler set one
+    if response.get_header("Date").is_none() {
+        time::with_http_date_now(|date| {
+            resp.push_str("Date: ");
+            resp.push_str(date);
+            resp.push_str("\r\n");
+        });
+    }
 
     for (k, v) in response.headers.iter() {
-        resp.push_str(&k);
+        if !valid_header_name(k) || framing_header(k, response.code) {
+            continue;
+        }
+        resp.push_str(k);
         resp.push_str(": ");
-        resp.push_str(&v);
+        resp.push_str(&clean_header_value(v));
         resp.push_str("\r\n");
     }
     resp.push_str("\r\n");
 
-    // Note that success still doesn't guarantee the client got the data.
-    let ioret = stream.write_all(resp.as_bytes());
-    if ioret.is_err() {
-        return;
-    }
-
     // Send the body unless it was a HEAD request.
     // HTTP HEAD is so retarded because you can't see error bodies.
     let mut send_body = true;
-    if request.is_some() && request.unwrap().method == "head" {
+    if bodiless || (request.is_some() && request.unwrap().method == "head") {
         send_body = false;
     }
-    if send_body {
-        let ioret = stream.write_all(&response.body);
-        if ioret.is_err() {
-            return;
-        } 
+
+    // Note that success still doesn't guarantee the client got the data.
+    // A buffered body goes with the headers, in one write (or writev).  A
+    // streamed one comes after them, since the reader may take its time
+    // (ex: events).
+    if send_body && response.reader.is_none() {
+        if response.body.len() <= COPY_BODY_MAX {
+            let mut out = resp.into_bytes();
+            out.extend_from_slice(&response.body);
+            stream.write_all(&out)?;
+        } else {
+            stream.write_all_vectored(&[resp.as_bytes(), &response.body])?;
+        }
+        return Ok(response.body.len());
+    }
+    stream.write_all(resp.as_bytes())?;
+    if !send_body {
+        return Ok(0);
+    }
+    if let Some(reader) = response.reader.take() {
+        let mut reader = match response.reader_len {
+            Some(len) => reader.take(len),
+            None => reader.take(u64::MAX),
+        };
+        if chunked {
+            return send_chunked(stream, &mut reader, response.chunk_size);
+        }
+        let size = match response.chunk_size {
+            Some(size) => size,
+            None => {
+                let mut writer = SocketWriter(stream);
+                return Ok(io::copy(&mut reader, &mut writer)? as usize);
+            },
+        };
+        let mut buf = vec![0; size];
+        let mut sent = 0;
+        loop {
+            let n = read_chunk(&mut reader, &mut buf)?;
+            if n == 0 {
+                return Ok(sent);
+            }
+            stream.write_all(&buf[..n])?;
+            sent += n;
+        }
+    }
+    return Ok(0);
+}
+
+
+// Send a body in chunks: of `size` bytes gathered from the reader, or one
+// for each read without a size.  Ends with the last, empty chunk.  Returns
+// the body bytes sent.
+fn send_chunked(stream: &mut dyn GenericSocket, reader: &mut dyn Read,
+        size: Option<usize>) -> io::Result<usize> {
+    let mut buf = vec![0; size.unwrap_or(8 * 1024)];
+    let mut sent = 0;
+    loop {
+        let n = match size {
+            Some(_) => read_chunk(reader, &mut buf)?,
+            None => match reader.read(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted =>
+                    continue,
+                Err(e) => return Err(e),
+            },
+        };
+        if n == 0 {
+            stream.write_all(b"0\r\n\r\n")?;
+            return Ok(sent);
+        }
+        let line = format!("{:x}\r\n", n);
+        stream.write_all_vectored(&[line.as_bytes(), &buf[..n], b"\r\n"])?;
+        sent += n;
+    }
+}
+
+// Read until buf is full, or the end.  Returns how much was read.
+pub(crate) fn read_chunk(reader: &mut dyn Read, buf: &mut [u8])
+        -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(len);
+}
+
+
+// Whether a header can have this name: a token (RFC 7230 3.2.6).  Headers
+// with other names are dropped.
+pub(crate) fn valid_header_name(name: &str) -> bool {
+    return !name.is_empty() && name.bytes().all(|c| c.is_ascii_alphanumeric()
+        || b"!#$%&'*+-.^_`|~".contains(&c));
+}
+
+// Whether a handler's header is one the server frames the response with
+// itself, and dropped: a second Content-Length, or a Transfer-Encoding
+// with it, could make a proxy read the response differently than the
+// client.  A 101's Connection is the handler's.
+fn framing_header(name: &str, code: i32) -> bool {
+    return name.eq_ignore_ascii_case("Content-Length")
+        || name.eq_ignore_ascii_case("Transfer-Encoding")
+        || (code != 101 && name.eq_ignore_ascii_case("Connection"));
+}
+
+// A header value or reason phrase as it can be sent: with control
+// characters (CR and LF above all, which would let a value taken from the
+// request split the response) replaced by spaces.  Tabs are fine.
+pub(crate) fn clean_header_value(value: &str) -> Cow<'_, str> {
+    let bad = |c: char| c.is_ascii_control() && c != '\t';
+    if !value.contains(bad) {
+        return Cow::Borrowed(value);
+    }
+    return Cow::Owned(value.replace(bad, " "));
+}
+
+
+// io::Write for streaming bodies with io::copy
+struct SocketWriter<'a>(&'a mut dyn GenericSocket);
+
+impl<'a> Write for SocketWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
     }
 }
+
+
+// A response sent, without the Date header it must have, which changes
+#[cfg(test)]
+fn without_date(mut out: Vec<u8>) -> Vec<u8> {
+    use utils::byteutils::memmem;
+    let start = memmem(&out, b"\r\nDate: ").unwrap() + 2;
+    let len = memmem(&out[start..], b"\r\n").unwrap() + 2;
+    out.drain(start..start + len);
+    return out;
+}
+
+#[test]
+fn test_send_date() {
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    let mut out = io::Cursor::new(Vec::new());
+    send(&mut out, Some(&req), &mut resp).unwrap();
+    let out = String::from_utf8(out.into_inner()).unwrap();
+    let date = out.split("\r\nDate: ").nth(1).unwrap().split("\r\n")
+        .next().unwrap();
+    let now = time::to_unix(::std::time::SystemTime::now());
+    let sent = time::parse_http_date(date).unwrap();
+    assert!(sent <= now && now - sent <= 1, "{}", date);
+
+    // The handler's own
+    resp.set_header("Date", "Tue, 10 Oct 2000 13:55:36 GMT");
+    let mut out = io::Cursor::new(Vec::new());
+    send(&mut out, Some(&req), &mut resp).unwrap();
+    let out = String::from_utf8(out.into_inner()).unwrap();
+    assert_eq!(out.matches("Date: ").count(), 1);
+    assert!(out.contains("\r\nDate: Tue, 10 Oct 2000 13:55:36 GMT\r\n"));
+}
+
+#[test]
+fn test_send_streamed() {
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    resp.set_body_reader(&b"hello world"[..], Some(5));
+    let mut out = io::Cursor::new(Vec::new());
+    assert_eq!(send(&mut out, Some(&req), &mut resp).unwrap(), 5);
+    assert_eq!(without_date(out.into_inner()),
+            &b"HTTP/1.1 200 OK\r\nConnection: close\r\n\
+            Content-Length: 5\r\n\r\nhello"[..]);
+
+    // Unknown length: chunked
+    resp.set_body_reader(&b"hello world"[..], None);
+    let mut out = io::Cursor::new(Vec::new());
+    assert_eq!(send(&mut out, Some(&req), &mut resp).unwrap(), 11);
+    assert_eq!(without_date(out.into_inner()),
+            &b"HTTP/1.1 200 OK\r\nConnection: close\r\n\
+            Transfer-Encoding: chunked\r\n\r\nb\r\nhello world\r\n\
+            0\r\n\r\n"[..]);
+
+    // And to HTTP/1.0, ends on close
+    let old = WebRequest::parse_for_test("GET / HTTP/1.0\r\n\r\n");
+    resp.set_body_reader(&b"hello world"[..], None);
+    let mut out = io::Cursor::new(Vec::new());
+    assert_eq!(send(&mut out, Some(&old), &mut resp).unwrap(), 11);
+    assert_eq!(without_date(out.into_inner()),
+            &b"HTTP/1.0 200 OK\r\nConnection: close\r\n\
+            \r\nhello world"[..]);
+}
+
+#[test]
+fn test_send_not_modified() {
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    resp.set_code(304, "Not Modified");
+    resp.set_body_str("ignored");
+    let mut out = io::Cursor::new(Vec::new());
+    assert_eq!(send(&mut out, Some(&req), &mut resp).unwrap(), 0);
+    assert_eq!(without_date(out.into_inner()),
+            &b"HTTP/1.1 304 Not Modified\r\n\
+            Connection: close\r\n\r\n"[..]);
+}
+
+#[test]
+fn test_send_switching_protocols() {
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    resp.set_code(101, "Switching Protocols");
+    resp.set_header("Connection", "Upgrade");
+    let mut out = io::Cursor::new(Vec::new());
+    assert_eq!(send(&mut out, Some(&req), &mut resp).unwrap(), 0);
+    assert_eq!(without_date(out.into_inner()),
+            &b"HTTP/1.1 101 Switching Protocols\r\n\
+            Connection: Upgrade\r\n\r\n"[..]);
+}
+
+#[test]
+fn test_send_header_injection() {
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    resp.set_code(200, "OK\r\nX-Evil: 1");
+    resp.set_header("Location", "/a\r\n\r\n<script>\0");
+    resp.set_header("Bad Name", "x");
+    resp.set_header("X-Split\r\nX-Evil", "x");
+    resp.set_header("X-Tab", "a\tb");
+    let mut out = io::Cursor::new(Vec::new());
+    send(&mut out, Some(&req), &mut resp).unwrap();
+    assert_eq!(String::from_utf8(without_date(out.into_inner())).unwrap(),
+            "HTTP/1.1 200 OK  X-Evil: 1\r\nConnection: close\r\n\
+            Content-Length: 0\r\nLocation: /a    <script> \r\n\
+            X-Tab: a\tb\r\n\r\n");
+}
+
+#[test]
+fn test_send_framing_headers() {
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    resp.set_body_str("hello");
+    resp.set_header("Content-Length", "99");
+    resp.set_header("transfer-encoding", "chunked");
+    resp.set_header("Connection", "keep-alive");
+    resp.set_header("X-Kept", "1");
+    let mut out = io::Cursor::new(Vec::new());
+    send(&mut out, Some(&req), &mut resp).unwrap();
+    assert_eq!(without_date(out.into_inner()),
+            &b"HTTP/1.1 200 OK\r\nConnection: close\r\n\
+            Content-Length: 5\r\nX-Kept: 1\r\n\r\nhello"[..]);
+}
+
+#[test]
+fn test_send_vectored() {
+    // Counts the calls to write
+    struct Out(Vec<u8>, usize);
+    impl Read for Out {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            return Ok(0);
+        }
+    }
+    impl Write for Out {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.write_vectored(&[io::IoSlice::new(buf)]);
+        }
+        fn write_vectored(&mut self, bufs: &[io::IoSlice])
+                -> io::Result<usize> {
+            self.1 += 1;
+            let len = self.0.len();
+            for buf in bufs {
+                self.0.extend_from_slice(buf);
+            }
+            return Ok(self.0.len() - len);
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    resp.set_body_str("hello");
+    let mut out = Out(Vec::new(), 0);
+    assert_eq!(send(&mut out, Some(&req), &mut resp).unwrap(), 5);
+    assert_eq!(out.1, 1);
+    assert_eq!(&without_date(out.0)[..], &b"HTTP/1.1 200 OK\r\n\
+            Connection: close\r\nContent-Length: 5\r\n\r\nhello"[..]);
+
+    let head = WebRequest::new_for_test("HEAD", "/");
+    let mut out = Out(Vec::new(), 0);
+    assert_eq!(send(&mut out, Some(&head), &mut resp).unwrap(), 0);
+    assert_eq!(out.1, 1);
+    assert!(without_date(out.0).ends_with(b"Content-Length: 5\r\n\r\n"));
+
+    // Too big to copy
+    resp.set_body(&vec![b'a'; COPY_BODY_MAX + 1]);
+    let mut out = Out(Vec::new(), 0);
+    assert_eq!(send(&mut out, Some(&req), &mut resp).unwrap(),
+            COPY_BODY_MAX + 1);
+    assert_eq!(out.1, 1);
+    let head = out.0.len() - (COPY_BODY_MAX + 1);
+    assert!(out.0[..head].ends_with(b"\r\n\r\n") && out.0[head..].iter()
+            .all(|&b| b == b'a'));
+}
+
+#[test]
+fn test_send_chunk_size() {
+    // Returns a byte at a time
+    struct Trickle(Vec<u8>);
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0.remove(0);
+            return Ok(1);
+        }
+    }
+    // Each write on its own
+    struct Writes(Vec<Vec<u8>>);
+    impl Read for Writes {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            return Ok(0);
+        }
+    }
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            return Ok(buf.len());
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    let req = WebRequest::new_for_test("GET", "/");
+    let mut resp = WebResponse::new();
+    let chunks = |resp: &mut WebResponse| {
+        let mut out = io::Cursor::new(Vec::new());
+        assert_eq!(send(&mut out, Some(&req), resp).unwrap(), 10);
+        let out = out.into_inner();
+        let start = ::utils::byteutils::memmem(&out, b"\r\n\r\n").unwrap();
+        return String::from_utf8(out[start + 4..].to_vec()).unwrap();
+    };
+    // A chunk for each read
+    resp.set_body_reader(Trickle(b"0123456789".to_vec()), None);
+    assert!(chunks(&mut resp).starts_with("1\r\n0\r\n1\r\n1\r\n"));
+    resp.set_body_reader(Trickle(b"0123456789".to_vec()), None);
+    resp.set_body_chunk_size(4);
+    assert_eq!(chunks(&mut resp),
+            "4\r\n0123\r\n4\r\n4567\r\n2\r\n89\r\n0\r\n\r\n");
+
+    resp.set_body_reader(Trickle(b"0123456789".to_vec()), Some(9));
+    resp.set_body_chunk_size(4);
+    let mut out = Writes(Vec::new());
+    assert_eq!(send(&mut out, Some(&req), &mut resp).unwrap(), 9);
+    let body: Vec<&[u8]> = out.0[1..].iter().map(|w| &w[..]).collect();
+    assert_eq!(body, [&b"0123"[..], b"4567", b"8"]);
+}